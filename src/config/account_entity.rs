@@ -1,16 +1,22 @@
 use anyhow::{anyhow, Context, Error, Result};
-use lettre::transport::smtp::authentication::Credentials as SmtpCredentials;
+use lettre::{message::Mailbox, transport::smtp::authentication::Credentials as SmtpCredentials};
 use log::{debug, trace};
-use std::{convert::TryFrom, env, fs, path::PathBuf};
+use once_cell::sync::OnceCell;
+use std::{collections::HashMap, convert::TryFrom, env, fmt, fs, path::PathBuf};
 
 use crate::{
     config::{Config, DEFAULT_PAGE_SIZE, DEFAULT_SIG_DELIM},
-    output::run_cmd,
+    output::{run_cmd, run_cmd_checked},
 };
 
 pub const DEFAULT_INBOX_FOLDER: &str = "INBOX";
 pub const DEFAULT_SENT_FOLDER: &str = "Sent";
 pub const DEFAULT_DRAFT_FOLDER: &str = "Drafts";
+pub const DEFAULT_REPLY_QUOTE_PREFIX: &str = "> ";
+pub const DEFAULT_REPLY_ATTRIBUTION: &str = "On {date}, {sender} wrote:";
+pub const DEFAULT_REPLY_DATE_FMT: &str = "%d %b %Y, at %H:%M";
+pub const DEFAULT_SEND_RETRIES: usize = 3;
+pub const DEFAULT_SEND_RETRY_DELAY: u64 = 1000;
 
 /// Represent a user account.
 #[derive(Debug, Default)]
@@ -19,6 +25,10 @@ pub struct Account {
     pub from: String,
     pub downloads_dir: PathBuf,
     pub sig: Option<String>,
+    /// Defines the signature delimiter, used to find signatures in quoted bodies.
+    pub sig_delim: String,
+    /// Defines named signatures, selectable instead of the default one.
+    pub signatures: HashMap<String, String>,
     pub default_page_size: usize,
     /// Defines the inbox folder name for this account
     pub inbox_folder: String,
@@ -29,25 +39,192 @@ pub struct Account {
     /// Defines the IMAP query used to fetch new messages.
     pub notify_query: String,
     pub watch_cmds: Vec<String>,
+    /// Defines the `X-Mailer` header of sent messages. An empty string disables it.
+    pub mailer: String,
+    /// Keeps blank lines of plain text bodies as they are instead of merging them.
+    pub keep_blank_lines: bool,
+    /// Keeps the template body empty for messages without text part (attachments only),
+    /// instead of noting their attachments.
+    pub keep_empty_body: bool,
+    /// Reorders the headers of templates: listed headers come first, in the given order, then
+    /// the other ones as they are. An empty list keeps the default order.
+    pub template_header_order: Vec<String>,
+    /// Overrides the prefix of quoted lines when replying.
+    pub reply_quote_prefix: Option<String>,
+    /// Overrides the attribution line when replying. The `{date}` and `{sender}` placeholders
+    /// are replaced by the date and the sender of the original message.
+    pub reply_attribution: Option<String>,
+    /// Overrides the strftime format of the `{date}` placeholder of the attribution line.
+    pub reply_date_fmt: Option<String>,
+    /// Converts the `{date}` placeholder of the attribution line to the local timezone.
+    pub reply_date_local: bool,
+    /// Replies to the `From` address instead of the `Reply-To` one (like the address of a
+    /// mailing list) when both are present.
+    pub reply_to_sender: bool,
+    /// Removes every signature block of the quoted body when replying, instead of cutting the
+    /// body at the first signature delimiter.
+    pub reply_strip_all_sigs: bool,
+    /// Defines the prefix of quoted lines when forwarding. The forwarded body is not quoted when
+    /// unset.
+    pub forward_quote_prefix: Option<String>,
+    /// Defines addresses always added to the Cc of new messages.
+    pub default_cc: Option<Vec<String>>,
+    /// Defines addresses always added to the Bcc of new messages (like an archive address).
+    pub default_bcc: Option<Vec<String>>,
+    /// Defines the maximum size (in bytes) of each attachment. No limit when unset.
+    pub max_attachment_size: Option<u64>,
+    /// Defines the size (in bytes) above which sending a message asks for a confirmation, see
+    /// [`Msg::estimated_size`](crate::domain::msg::Msg::estimated_size). No confirmation when
+    /// unset.
+    pub max_msg_size: Option<u64>,
+    /// Defines the width at which long lines of plain text bodies are wrapped when sending. No
+    /// wrapping when unset.
+    pub wrap_width: Option<usize>,
+    /// Defines how many times a message is sent again after a transient failure.
+    pub send_retries: usize,
+    /// Defines the delay (in milliseconds) before the first retry, doubled for each new retry.
+    pub send_retry_delay: u64,
+    /// Defines the editor command used to compose messages, see [`crate::ui::editor`].
+    pub editor: Option<String>,
+    /// Defines the template file used as a skeleton to compose messages.
+    pub template: Option<PathBuf>,
+    /// Fails to compose when the template file contains unknown placeholders.
+    pub template_strict: bool,
+    /// Generates predictable MIME boundaries when building sendable messages, so that the same
+    /// message always produces the same bytes (see golden tests). Not exposed in the config: it
+    /// must not be used in production, since predictable boundaries can be guessed and injected
+    /// into bodies.
+    pub deterministic_boundaries: bool,
     pub default: bool,
     pub email: String,
+    /// Caches the parsed address of the account, see [`Account::validated_address`].
+    pub parsed_addr: OnceCell<Mailbox>,
 
     pub imap_host: String,
     pub imap_port: u16,
     pub imap_starttls: bool,
     pub imap_insecure: bool,
     pub imap_login: String,
-    pub imap_passwd_cmd: String,
+    /// Holds the IMAP password, unused with [`Auth::OAuth2`].
+    pub imap_passwd: Passwd,
 
     pub smtp_host: String,
     pub smtp_port: u16,
     pub smtp_starttls: bool,
     pub smtp_insecure: bool,
     pub smtp_login: String,
-    pub smtp_passwd_cmd: String,
+    /// Holds the SMTP password, unused with [`Auth::OAuth2`].
+    pub smtp_passwd: Passwd,
+    /// Defines how the account authenticates to both the IMAP and the SMTP servers.
+    pub auth: Auth,
 
     pub pgp_encrypt_cmd: Option<String>,
     pub pgp_decrypt_cmd: Option<String>,
+    pub pgp_sign_cmd: Option<String>,
+}
+
+/// Represents a password, given either as is or as a command printing it (like
+/// `pass show email/work`). The password itself is never printed, even in logs.
+#[derive(Clone, PartialEq)]
+pub enum Passwd {
+    Raw(String),
+    Cmd(String),
+}
+
+impl Default for Passwd {
+    fn default() -> Self {
+        Self::Raw(String::default())
+    }
+}
+
+impl fmt::Debug for Passwd {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Raw(_) => write!(f, "Raw(********)"),
+            Self::Cmd(cmd) => f.debug_tuple("Cmd").field(cmd).finish(),
+        }
+    }
+}
+
+impl Passwd {
+    /// Builds the password from the raw and the command config options, only one of them can
+    /// be set.
+    fn from_config(
+        account: &str,
+        key: &str,
+        raw: Option<&String>,
+        cmd: Option<&String>,
+    ) -> Result<Self> {
+        match (raw, cmd) {
+            (Some(raw), None) => Ok(Self::Raw(raw.to_owned())),
+            (None, Some(cmd)) => Ok(Self::Cmd(cmd.to_owned())),
+            (Some(_), Some(_)) => Err(anyhow!(
+                r#"account "{}" cannot have both "{}" and "{}-cmd""#,
+                account,
+                key,
+                key
+            )),
+            (None, None) => Err(anyhow!(
+                r#"account "{}" is missing "{}" or "{}-cmd""#,
+                account,
+                key,
+                key
+            )),
+        }
+    }
+}
+
+/// Represents the way an account authenticates to the IMAP and SMTP servers.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub enum Auth {
+    /// Authenticates with the IMAP and SMTP logins and passwords.
+    #[default]
+    Password,
+    /// Authenticates with an OAuth 2.0 access token, using the SASL XOAUTH2 mechanism. The token
+    /// is printed by the given command, which is also in charge of refreshing it.
+    OAuth2 { token_cmd: String },
+}
+
+impl Auth {
+    /// Returns the OAuth 2.0 access token by running the token command, or `None` when
+    /// authenticating with passwords.
+    pub fn token(&self) -> Result<Option<String>> {
+        match self {
+            Self::Password => Ok(None),
+            Self::OAuth2 { token_cmd } => {
+                let token = run_cmd_checked(token_cmd)
+                    .context(format!("cannot run OAuth2 token command {:?}", token_cmd))?;
+                let token = token.trim();
+                if token.is_empty() {
+                    return Err(anyhow!(
+                        "OAuth2 token command {:?} printed nothing",
+                        token_cmd
+                    ));
+                }
+                Ok(Some(token.to_owned()))
+            }
+        }
+    }
+}
+
+/// Builds the SASL XOAUTH2 initial client response for the given user and access token. The
+/// exact format sent to the server is:
+///
+/// ```text
+/// base64("user=" {user} "^A" "auth=Bearer " {token} "^A" "^A")
+/// ```
+///
+/// where `^A` stands for the `\x01` control character. The returned string is not encoded yet:
+/// the IMAP and SMTP clients encode their responses in base64 themselves. For example, the user
+/// `someuser@example.com` with the token `ya29.vF9dft4qmTc2Nvb3RlckBhdHRhdmlzdGEuY29tCg` gives
+/// once encoded:
+///
+/// ```text
+/// dXNlcj1zb21ldXNlckBleGFtcGxlLmNvbQFhdXRoPUJlYXJlciB5YTI5LnZGOWRmdDRxbVRjMk52
+/// YjNSbGNrQmhkSFJoZG1semRHRXVZMjl0Q2cBAQ==
+/// ```
+pub fn xoauth2_string(user: &str, token: &str) -> String {
+    format!("user={}\x01auth=Bearer {}\x01\x01", user, token)
 }
 
 impl Account {
@@ -65,27 +242,62 @@ impl Account {
         }
     }
 
-    pub fn imap_passwd(&self) -> Result<String> {
-        let passwd = run_cmd(&self.imap_passwd_cmd).context("cannot run IMAP passwd cmd")?;
-        let passwd = passwd
-            .trim_end_matches(|c| c == '\r' || c == '\n')
-            .to_owned();
+    /// Parses the address of the account. The parsed address is cached, so the parsing is done
+    /// only once.
+    pub fn validated_address(&self) -> Result<Mailbox> {
+        self.parsed_addr
+            .get_or_try_init(|| {
+                let addr = self.address();
+                addr.parse().context(format!(
+                    r#"account "{}" has invalid address "{}""#,
+                    self.name, addr
+                ))
+            })
+            .map(|addr| addr.to_owned())
+    }
 
-        Ok(passwd)
+    /// Returns the given password of the account, running its command if needed. Only the
+    /// trailing new lines of the command output are trimmed, since spaces can be part of a
+    /// password.
+    pub fn resolve_password(&self, passwd: &Passwd) -> Result<String> {
+        match passwd {
+            Passwd::Raw(passwd) => Ok(passwd.to_owned()),
+            Passwd::Cmd(cmd) => {
+                let passwd = run_cmd_checked(cmd).context(format!(
+                    r#"cannot run passwd command {:?} of account "{}""#,
+                    cmd, self.name
+                ))?;
+                let passwd = passwd.trim_end_matches(&['\r', '\n'][..]);
+                if passwd.is_empty() {
+                    return Err(anyhow!(
+                        r#"passwd command {:?} of account "{}" printed nothing"#,
+                        cmd,
+                        self.name
+                    ));
+                }
+                Ok(passwd.to_owned())
+            }
+        }
+    }
+
+    pub fn imap_passwd(&self) -> Result<String> {
+        self.resolve_password(&self.imap_passwd)
+            .context("cannot get IMAP password")
     }
 
     pub fn smtp_creds(&self) -> Result<SmtpCredentials> {
-        let passwd = run_cmd(&self.smtp_passwd_cmd).context("cannot run SMTP passwd cmd")?;
-        let passwd = passwd
-            .trim_end_matches(|c| c == '\r' || c == '\n')
-            .to_owned();
+        let passwd = self
+            .resolve_password(&self.smtp_passwd)
+            .context("cannot get SMTP password")?;
 
         Ok(SmtpCredentials::new(self.smtp_login.to_owned(), passwd))
     }
 
-    pub fn pgp_encrypt_file(&self, addr: &str, path: PathBuf) -> Result<Option<String>> {
+    /// Encrypts the given file for all the given recipients. Addresses are passed to the encrypt
+    /// command separated by spaces, followed by the path of the file.
+    pub fn pgp_encrypt_file(&self, addrs: &[String], path: PathBuf) -> Result<Option<String>> {
         if let Some(cmd) = self.pgp_encrypt_cmd.as_ref() {
-            let encrypt_file_cmd = format!("{} {} {:?}", cmd, addr, path);
+            let encrypt_file_cmd = format!("{} {} {:?}", cmd, addrs.join(" "), path);
             run_cmd(&encrypt_file_cmd).map(Some).context(format!(
                 "cannot run pgp encrypt command {:?}",
                 encrypt_file_cmd
@@ -95,6 +307,39 @@ impl Account {
         }
     }
 
+    /// Returns the line delimiting the signature from the body (without its trailing spaces),
+    /// falling back to the default one when the configured delimiter is blank.
+    pub fn sig_delim_line(&self) -> &str {
+        match self.sig_delim.trim_end() {
+            "" => DEFAULT_SIG_DELIM.trim_end(),
+            sig_delim => sig_delim,
+        }
+    }
+
+    /// Finds the named signature of the account. The error lists the available names.
+    pub fn find_sig(&self, name: &str) -> Result<&String> {
+        self.signatures.get(name).ok_or_else(|| {
+            let mut names = self.signatures.keys().cloned().collect::<Vec<_>>();
+            names.sort();
+            anyhow!(
+                "cannot find signature {:?} (available: {})",
+                name,
+                names.join(", ")
+            )
+        })
+    }
+
+    pub fn pgp_sign_file(&self, path: PathBuf) -> Result<Option<String>> {
+        if let Some(cmd) = self.pgp_sign_cmd.as_ref() {
+            let sign_file_cmd = format!("{} {:?}", cmd, path);
+            run_cmd(&sign_file_cmd)
+                .map(Some)
+                .context(format!("cannot run pgp sign command {:?}", sign_file_cmd))
+        } else {
+            Ok(None)
+        }
+    }
+
     pub fn pgp_decrypt_file(&self, path: PathBuf) -> Result<Option<String>> {
         if let Some(cmd) = self.pgp_decrypt_cmd.as_ref() {
             let decrypt_file_cmd = format!("{} {:?}", cmd, path);
@@ -156,22 +401,60 @@ impl<'a> TryFrom<(&'a Config, Option<&str>)> for Account {
             .as_ref()
             .or_else(|| config.signature_delimiter.as_ref())
             .unwrap_or(&default_sig_delim);
+        let resolve_sig = |sig: &String| {
+            let sig = shellexpand::full(sig)
+                .ok()
+                .map(String::from)
+                .and_then(|sig| fs::read_to_string(sig).ok())
+                .unwrap_or_else(|| sig.to_owned());
+            format!("{}{}", sig_delim, sig.trim_end())
+        };
         let sig = account
             .signature
             .as_ref()
-            .or_else(|| config.signature.as_ref());
-        let sig = sig
-            .and_then(|sig| shellexpand::full(sig).ok())
-            .map(String::from)
-            .and_then(|sig| fs::read_to_string(sig).ok())
-            .or_else(|| sig.map(|sig| sig.to_owned()))
-            .map(|sig| format!("{}{}", sig_delim, sig.trim_end()));
+            .or_else(|| config.signature.as_ref())
+            .map(resolve_sig);
+        let signatures = config
+            .signatures
+            .iter()
+            .chain(account.signatures.iter())
+            .flatten()
+            .map(|(name, sig)| (name.to_owned(), resolve_sig(sig)))
+            .collect();
+
+        // Passwords are not needed when authenticating with OAuth2
+        let (auth, imap_passwd, smtp_passwd) = match account.oauth2_token_cmd.as_ref() {
+            Some(token_cmd) => (
+                Auth::OAuth2 {
+                    token_cmd: token_cmd.to_owned(),
+                },
+                Passwd::default(),
+                Passwd::default(),
+            ),
+            None => (
+                Auth::Password,
+                Passwd::from_config(
+                    &name,
+                    "imap-passwd",
+                    account.imap_passwd.as_ref(),
+                    account.imap_passwd_cmd.as_ref(),
+                )?,
+                Passwd::from_config(
+                    &name,
+                    "smtp-passwd",
+                    account.smtp_passwd.as_ref(),
+                    account.smtp_passwd_cmd.as_ref(),
+                )?,
+            ),
+        };
 
         let account = Account {
             name,
             from: account.name.as_ref().unwrap_or(&config.name).to_owned(),
             downloads_dir,
             sig,
+            sig_delim: sig_delim.to_owned(),
+            signatures,
             default_page_size,
             inbox_folder: account
                 .inbox_folder
@@ -203,28 +486,228 @@ impl<'a> TryFrom<(&'a Config, Option<&str>)> for Account {
                 .or_else(|| config.watch_cmds.as_ref())
                 .unwrap_or(&vec![])
                 .to_owned(),
+            mailer: account
+                .mailer
+                .as_deref()
+                .or(config.mailer.as_deref())
+                .map(String::from)
+                .unwrap_or_else(|| format!("himalaya {}", env!("CARGO_PKG_VERSION"))),
+            keep_blank_lines: account
+                .keep_blank_lines
+                .or(config.keep_blank_lines)
+                .unwrap_or_default(),
+            keep_empty_body: account
+                .keep_empty_body
+                .or(config.keep_empty_body)
+                .unwrap_or_default(),
+            template_header_order: account
+                .template_header_order
+                .as_ref()
+                .or(config.template_header_order.as_ref())
+                .cloned()
+                .unwrap_or_default(),
+            reply_quote_prefix: account
+                .reply_quote_prefix
+                .as_ref()
+                .or(config.reply_quote_prefix.as_ref())
+                .cloned(),
+            reply_attribution: account
+                .reply_attribution
+                .as_ref()
+                .or(config.reply_attribution.as_ref())
+                .cloned(),
+            reply_date_fmt: account
+                .reply_date_fmt
+                .as_ref()
+                .or(config.reply_date_fmt.as_ref())
+                .cloned(),
+            reply_date_local: account
+                .reply_date_local
+                .or(config.reply_date_local)
+                .unwrap_or_default(),
+            reply_to_sender: account
+                .reply_to_sender
+                .or(config.reply_to_sender)
+                .unwrap_or_default(),
+            reply_strip_all_sigs: account
+                .reply_strip_all_sigs
+                .or(config.reply_strip_all_sigs)
+                .unwrap_or_default(),
+            forward_quote_prefix: account
+                .forward_quote_prefix
+                .as_ref()
+                .or(config.forward_quote_prefix.as_ref())
+                .cloned(),
+            default_cc: account
+                .default_cc
+                .as_ref()
+                .or(config.default_cc.as_ref())
+                .cloned(),
+            default_bcc: account
+                .default_bcc
+                .as_ref()
+                .or(config.default_bcc.as_ref())
+                .cloned(),
+            max_attachment_size: account.max_attachment_size.or(config.max_attachment_size),
+            max_msg_size: account.max_msg_size.or(config.max_msg_size),
+            wrap_width: account.wrap_width.or(config.wrap_width),
+            send_retries: account
+                .send_retries
+                .or(config.send_retries)
+                .unwrap_or(DEFAULT_SEND_RETRIES),
+            send_retry_delay: account
+                .send_retry_delay
+                .or(config.send_retry_delay)
+                .unwrap_or(DEFAULT_SEND_RETRY_DELAY),
+            editor: account.editor.as_ref().or(config.editor.as_ref()).cloned(),
+            template: account
+                .template
+                .as_ref()
+                .or(config.template.as_ref())
+                .and_then(|path| path.to_str())
+                .and_then(|path| shellexpand::full(path).ok())
+                .map(|path| PathBuf::from(path.to_string())),
+            template_strict: account
+                .template_strict
+                .or(config.template_strict)
+                .unwrap_or_default(),
+            deterministic_boundaries: false,
             default: account.default.unwrap_or(false),
             email: account.email.to_owned(),
+            parsed_addr: OnceCell::new(),
 
             imap_host: account.imap_host.to_owned(),
             imap_port: account.imap_port,
             imap_starttls: account.imap_starttls.unwrap_or_default(),
             imap_insecure: account.imap_insecure.unwrap_or_default(),
             imap_login: account.imap_login.to_owned(),
-            imap_passwd_cmd: account.imap_passwd_cmd.to_owned(),
+            imap_passwd,
 
             smtp_host: account.smtp_host.to_owned(),
             smtp_port: account.smtp_port,
             smtp_starttls: account.smtp_starttls.unwrap_or_default(),
             smtp_insecure: account.smtp_insecure.unwrap_or_default(),
             smtp_login: account.smtp_login.to_owned(),
-            smtp_passwd_cmd: account.smtp_passwd_cmd.to_owned(),
+            smtp_passwd,
+            auth,
 
             pgp_encrypt_cmd: account.pgp_encrypt_cmd.to_owned(),
             pgp_decrypt_cmd: account.pgp_decrypt_cmd.to_owned(),
+            pgp_sign_cmd: account.pgp_sign_cmd.to_owned(),
         };
 
+        account.validated_address()?;
+
         trace!("account: {:?}", account);
         Ok(account)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_validate_address() {
+        let account = Account {
+            name: "work".into(),
+            from: "Test".into(),
+            email: "test@localhost".into(),
+            ..Account::default()
+        };
+        assert_eq!(
+            "Test <test@localhost>".parse::<Mailbox>().unwrap(),
+            account.validated_address().unwrap()
+        );
+        assert!(account.parsed_addr.get().is_some());
+
+        let account = Account {
+            name: "work".into(),
+            email: "test".into(),
+            ..Account::default()
+        };
+        assert_eq!(
+            r#"account "work" has invalid address "test""#,
+            account.validated_address().unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn it_should_build_passwd_from_config() {
+        let passwd = String::from("secret");
+        let cmd = String::from("pass show work");
+        assert_eq!(
+            Passwd::Raw("secret".into()),
+            Passwd::from_config("work", "imap-passwd", Some(&passwd), None).unwrap()
+        );
+        assert_eq!(
+            Passwd::Cmd("pass show work".into()),
+            Passwd::from_config("work", "imap-passwd", None, Some(&cmd)).unwrap()
+        );
+        assert_eq!(
+            r#"account "work" cannot have both "imap-passwd" and "imap-passwd-cmd""#,
+            Passwd::from_config("work", "imap-passwd", Some(&passwd), Some(&cmd))
+                .unwrap_err()
+                .to_string()
+        );
+        assert!(Passwd::from_config("work", "smtp-passwd", None, None).is_err());
+    }
+
+    #[test]
+    fn it_should_resolve_passwd() {
+        let account = Account {
+            name: "work".into(),
+            ..Account::default()
+        };
+        let resolve = |passwd| account.resolve_password(&passwd);
+        assert_eq!(
+            "my secret",
+            resolve(Passwd::Raw("my secret".into())).unwrap()
+        );
+        assert_eq!(
+            "my secret ",
+            resolve(Passwd::Cmd("echo 'my secret '".into())).unwrap()
+        );
+        assert_eq!(
+            r#"passwd command "printf ''" of account "work" printed nothing"#,
+            resolve(Passwd::Cmd("printf ''".into()))
+                .unwrap_err()
+                .to_string()
+        );
+
+        let err = resolve(Passwd::Cmd("echo secret; exit 1".into())).unwrap_err();
+        assert!(!format!("{:?}", err).contains("secret\n"));
+    }
+
+    #[test]
+    fn it_should_build_xoauth2_string() {
+        assert_eq!(
+            "user=someuser@example.com\x01auth=Bearer ya29.vF9dft4qmTc2Nvb3RlckBhdHRhdmlzdGEuY29tCg\x01\x01",
+            xoauth2_string(
+                "someuser@example.com",
+                "ya29.vF9dft4qmTc2Nvb3RlckBhdHRhdmlzdGEuY29tCg"
+            )
+        );
+    }
+
+    #[test]
+    fn it_should_get_oauth2_token() {
+        assert_eq!(None, Auth::Password.token().unwrap());
+        let auth = Auth::OAuth2 {
+            token_cmd: "echo ' token '".into(),
+        };
+        assert_eq!(Some("token".into()), auth.token().unwrap());
+        let auth = Auth::OAuth2 {
+            token_cmd: "printf ''".into(),
+        };
+        assert!(auth.token().is_err());
+    }
+
+    #[test]
+    fn it_should_not_debug_raw_passwd() {
+        assert_eq!(
+            "Raw(********)",
+            format!("{:?}", Passwd::Raw("secret".into()))
+        );
+    }
+}
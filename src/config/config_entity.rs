@@ -21,6 +21,8 @@ pub struct Config {
     pub signature_delimiter: Option<String>,
     /// Defines the signature.
     pub signature: Option<String>,
+    /// Defines named signatures, selectable with `--signature-name`.
+    pub signatures: Option<HashMap<String, String>>,
     /// Defines the default page size for listings.
     pub default_page_size: Option<usize>,
     /// Defines the inbox folder name.
@@ -35,6 +37,61 @@ pub struct Config {
     pub notify_query: Option<String>,
     /// Defines the watch commands.
     pub watch_cmds: Option<Vec<String>>,
+    /// Overrides the `X-Mailer` header of sent messages. An empty string disables it.
+    pub mailer: Option<String>,
+    /// Keeps blank lines of plain text bodies as they are instead of merging them.
+    pub keep_blank_lines: Option<bool>,
+    /// Keeps the template body empty for messages without text part, instead of noting their
+    /// attachments.
+    pub keep_empty_body: Option<bool>,
+    /// Reorders the headers of templates, like `["From", "To", "Cc", "Bcc", "Subject"]`.
+    /// Unlisted headers are appended in the default order.
+    pub template_header_order: Option<Vec<String>>,
+    /// Overrides the prefix of quoted lines when replying (default "`> `").
+    pub reply_quote_prefix: Option<String>,
+    /// Overrides the attribution line when replying. Supports `{date}` and `{sender}`
+    /// placeholders.
+    pub reply_attribution: Option<String>,
+    /// Overrides the strftime format of the date of the attribution line.
+    #[serde(rename = "reply-date-format")]
+    pub reply_date_fmt: Option<String>,
+    /// Converts the date of the attribution line to the local timezone instead of keeping the
+    /// sender's one.
+    pub reply_date_local: Option<bool>,
+    /// Replies to the `From` address even when a `Reply-To` is present (`Reply-To` is preferred
+    /// by default).
+    pub reply_to_sender: Option<bool>,
+    /// Removes every signature block of the quoted body when replying (nested replies or
+    /// forwards can contain several).
+    #[serde(rename = "reply-strip-all-signatures")]
+    pub reply_strip_all_sigs: Option<bool>,
+    /// Defines the prefix of quoted lines when forwarding (the body is not quoted by default).
+    pub forward_quote_prefix: Option<String>,
+    /// Defines addresses always added to the Cc of new messages.
+    pub default_cc: Option<Vec<String>>,
+    /// Defines addresses always added to the Bcc of new messages.
+    pub default_bcc: Option<Vec<String>>,
+    /// Defines the maximum size (in bytes) of each attachment.
+    pub max_attachment_size: Option<u64>,
+    /// Defines the size (in bytes) above which sending a message asks for a confirmation.
+    pub max_msg_size: Option<u64>,
+    /// Defines the width at which long lines of plain text bodies are wrapped when sending (like
+    /// 72). Quoted lines and the signature are left untouched.
+    pub wrap_width: Option<usize>,
+    /// Defines how many times a message is sent again after a transient failure (like a network
+    /// error or a 4xx SMTP reply). Permanent failures (5xx SMTP replies) are not retried.
+    pub send_retries: Option<usize>,
+    /// Defines the delay (in milliseconds) before the first retry, doubled for each new retry.
+    pub send_retry_delay: Option<u64>,
+    /// Defines the editor command used to compose messages, with its arguments (like
+    /// `code --wait`). Falls back to `$VISUAL`, then `$EDITOR`.
+    pub editor: Option<String>,
+    /// Defines a template file used as a skeleton to compose messages. The `{{from}}`, `{{to}}`,
+    /// `{{subject}}`, `{{body}}` and `{{signature}}` placeholders are filled in.
+    pub template: Option<PathBuf>,
+    /// Fails to compose when the template file contains unknown placeholders, instead of leaving
+    /// them as they are.
+    pub template_strict: Option<bool>,
 
     #[serde(flatten)]
     pub accounts: ConfigAccountsMap,
@@ -51,6 +108,7 @@ pub struct ConfigAccountEntry {
     pub downloads_dir: Option<PathBuf>,
     pub signature_delimiter: Option<String>,
     pub signature: Option<String>,
+    pub signatures: Option<HashMap<String, String>>,
     pub default_page_size: Option<usize>,
     /// Defines a specific inbox folder name for this account.
     pub inbox_folder: Option<String>,
@@ -61,6 +119,31 @@ pub struct ConfigAccountEntry {
     /// Customizes the IMAP query used to fetch new messages.
     pub notify_query: Option<String>,
     pub watch_cmds: Option<Vec<String>>,
+    /// Overrides the `X-Mailer` header of sent messages for this account.
+    pub mailer: Option<String>,
+    /// Keeps blank lines of plain text bodies for this account.
+    pub keep_blank_lines: Option<bool>,
+    pub keep_empty_body: Option<bool>,
+    pub template_header_order: Option<Vec<String>>,
+    pub reply_quote_prefix: Option<String>,
+    pub reply_attribution: Option<String>,
+    #[serde(rename = "reply-date-format")]
+    pub reply_date_fmt: Option<String>,
+    pub reply_date_local: Option<bool>,
+    pub reply_to_sender: Option<bool>,
+    #[serde(rename = "reply-strip-all-signatures")]
+    pub reply_strip_all_sigs: Option<bool>,
+    pub forward_quote_prefix: Option<String>,
+    pub default_cc: Option<Vec<String>>,
+    pub default_bcc: Option<Vec<String>>,
+    pub max_attachment_size: Option<u64>,
+    pub max_msg_size: Option<u64>,
+    pub wrap_width: Option<usize>,
+    pub send_retries: Option<usize>,
+    pub send_retry_delay: Option<u64>,
+    pub editor: Option<String>,
+    pub template: Option<PathBuf>,
+    pub template_strict: Option<bool>,
     pub default: Option<bool>,
     pub email: String,
 
@@ -69,17 +152,27 @@ pub struct ConfigAccountEntry {
     pub imap_starttls: Option<bool>,
     pub imap_insecure: Option<bool>,
     pub imap_login: String,
-    pub imap_passwd_cmd: String,
+    /// Defines the IMAP password. Prefer `imap-passwd-cmd`, which keeps it out of the config.
+    pub imap_passwd: Option<String>,
+    /// Defines the command printing the IMAP password (like `pass show email/work`).
+    pub imap_passwd_cmd: Option<String>,
 
     pub smtp_host: String,
     pub smtp_port: u16,
     pub smtp_starttls: Option<bool>,
     pub smtp_insecure: Option<bool>,
     pub smtp_login: String,
-    pub smtp_passwd_cmd: String,
+    /// Defines the SMTP password. Prefer `smtp-passwd-cmd`, which keeps it out of the config.
+    pub smtp_passwd: Option<String>,
+    /// Defines the command printing the SMTP password.
+    pub smtp_passwd_cmd: Option<String>,
+    /// Defines the command printing an OAuth 2.0 access token. When set, the account
+    /// authenticates to both the IMAP and the SMTP servers with XOAUTH2 instead of passwords.
+    pub oauth2_token_cmd: Option<String>,
 
     pub pgp_encrypt_cmd: Option<String>,
     pub pgp_decrypt_cmd: Option<String>,
+    pub pgp_sign_cmd: Option<String>,
 }
 
 impl Config {
@@ -3,18 +3,141 @@
 //! This module exposes a service that can interact with IMAP servers.
 
 use anyhow::{anyhow, Context, Result};
-use log::{debug, log_enabled, trace, Level};
+use imap::types::{Mailbox, UnsolicitedResponse};
+use imap_proto::{BodyStructure, ContentEncoding, MailboxDatum, Response, SectionPath, Status};
+use log::{debug, log_enabled, trace, warn, Level};
 use native_tls::{TlsConnector, TlsStream};
-use std::{collections::HashSet, convert::TryFrom, net::TcpStream, thread};
+use std::{
+    collections::HashSet,
+    convert::TryFrom,
+    net::TcpStream,
+    thread,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    config::{Account, Config},
-    domain::{Envelope, Envelopes, Flags, Mbox, Mboxes, Msg, RawEnvelopes, RawMboxes},
+    config::{xoauth2_string, Account, Config},
+    domain::{Envelope, Envelopes, Flags, Mbox, Mboxes, Msg, RawEnvelopes, RawMboxes, SearchQuery},
     output::run_cmd,
 };
 
 type ImapSession = imap::Session<TlsStream<TcpStream>>;
 
+/// Authenticates to the IMAP server with the SASL XOAUTH2 mechanism, see [`xoauth2_string`].
+struct XOAuth2 {
+    user: String,
+    token: String,
+}
+
+impl imap::Authenticator for XOAuth2 {
+    type Response = String;
+
+    fn process(&self, challenge: &[u8]) -> Self::Response {
+        // On failure, the server sends an error challenge which must be answered with an empty
+        // response before it rejects the authentication.
+        if challenge.is_empty() {
+            xoauth2_string(&self.user, &self.token)
+        } else {
+            String::new()
+        }
+    }
+}
+
+/// Wraps the IMAP session in order to log out from the server when dropped.
+struct ImapSessionGuard {
+    sess: ImapSession,
+    logged_out: bool,
+}
+
+impl ImapSessionGuard {
+    fn logout(&mut self) -> imap::Result<()> {
+        self.logged_out = true;
+        self.sess.logout()
+    }
+}
+
+impl Drop for ImapSessionGuard {
+    fn drop(&mut self) {
+        if !self.logged_out {
+            debug!("logout from IMAP server");
+            if let Err(err) = self.logout() {
+                warn!("cannot logout from IMAP server: {}", err);
+            }
+        }
+    }
+}
+
+/// Defines the maximum interval (in seconds) at which the IDLE command is re-issued, as advised by
+/// [RFC2177].
+///
+/// [RFC2177]: https://datatracker.ietf.org/doc/html/rfc2177
+const IDLE_KEEPALIVE: u64 = 29 * 60;
+
+/// Defines the interval (in seconds) at which the mailbox is polled when the server does not
+/// support the IDLE command.
+const IDLE_POLL_INTERVAL: u64 = 60;
+
+/// Defines the delay (in seconds) after which the session is checked with a NOOP command before
+/// being reused, since servers may close inactive connections.
+const LIVENESS_CHECK_DELAY: u64 = 60;
+
+/// Represents a change of the watched mailbox, notified by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImapEvent {
+    /// The mailbox now contains the given number of messages.
+    Exists(u32),
+    /// The message with the given sequence number has been expunged.
+    Expunge(u32),
+    /// The given number of messages have the `\Recent` flag.
+    Recent(u32),
+    /// The attributes (usually the flags) of the message with the given sequence number changed.
+    Fetch(u32),
+}
+
+/// Represents a leaf MIME part of a message, as described by its body structure.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartStructure {
+    /// The part number (like `1.2`), to use with [`ImapServiceInterface::fetch_part`].
+    pub num: String,
+    /// The MIME type, like `text/plain`.
+    pub mime: String,
+    /// The charset of the content, if given (usually for text parts).
+    pub charset: Option<String>,
+    /// The content transfer encoding, like `base64`.
+    pub encoding: String,
+}
+
+impl PartStructure {
+    /// Decodes the raw content of the part (as fetched by [`ImapServiceInterface::fetch_part`])
+    /// into text, according to its transfer encoding and charset.
+    pub fn decode_text(&self, content: &[u8]) -> Result<String> {
+        let mut headers = format!("Content-Type: {}", self.mime);
+        if let Some(ref charset) = self.charset {
+            headers.push_str(&format!("; charset=\"{}\"", charset));
+        }
+        headers.push_str(&format!(
+            "\r\nContent-Transfer-Encoding: {}\r\n\r\n",
+            self.encoding
+        ));
+        let raw_part = [headers.as_bytes(), content].concat();
+        mailparse::parse_mail(&raw_part)
+            .and_then(|part| part.get_body())
+            .context(format!(r#"cannot decode part "{}""#, self.num))
+    }
+}
+
+impl ImapEvent {
+    fn from_unsolicited_response(res: UnsolicitedResponse) -> Option<Self> {
+        match res {
+            UnsolicitedResponse::Exists(n) => Some(Self::Exists(n)),
+            UnsolicitedResponse::Expunge(seq) => Some(Self::Expunge(seq)),
+            UnsolicitedResponse::Recent(n) => Some(Self::Recent(n)),
+            UnsolicitedResponse::Fetch { id, .. } => Some(Self::Fetch(id)),
+            _ => None,
+        }
+    }
+}
+
 pub trait ImapServiceInterface<'a> {
     fn notify(&mut self, config: &Config, account: &Account, keepalive: u64) -> Result<()>;
     fn watch(&mut self, account: &Account, keepalive: u64) -> Result<()>;
@@ -22,7 +145,7 @@ pub trait ImapServiceInterface<'a> {
     fn fetch_envelopes(&mut self, page_size: &usize, page: &usize) -> Result<Envelopes>;
     fn fetch_envelopes_with(
         &'a mut self,
-        query: &str,
+        query: &SearchQuery,
         page_size: &usize,
         page: &usize,
     ) -> Result<Envelopes>;
@@ -33,6 +156,37 @@ pub trait ImapServiceInterface<'a> {
     fn expunge(&mut self) -> Result<()>;
     fn logout(&mut self) -> Result<()>;
 
+    /// Returns true if the server advertises the given capability (like `MOVE`).
+    fn has_capability(&mut self, cap: &str) -> Result<bool>;
+    /// Move messages within the given sequence range from a mailbox to another. The `MOVE`
+    /// extension is used when the server advertises it, otherwise messages are copied then
+    /// deleted (see [`ImapServiceInterface::has_capability`]).
+    ///
+    /// [RFC6851]: https://datatracker.ietf.org/doc/html/rfc6851
+    fn move_msg(&mut self, from: &Mbox, to: &Mbox, seq_range: &str) -> Result<()>;
+    /// Watches the given mailbox and calls `on_event` for each change notified by the server,
+    /// until an error occurs. The IDLE command is used when the server supports it (and
+    /// re-issued every `keepalive` seconds, at most 29 minutes as advised by [RFC2177]),
+    /// otherwise the mailbox is polled every minute. The service is given back to `on_event`,
+    /// which is called outside of the idle mode so that it can send commands.
+    ///
+    /// [RFC2177]: https://datatracker.ietf.org/doc/html/rfc2177
+    fn idle(
+        &mut self,
+        mbox: &Mbox,
+        keepalive: u64,
+        on_event: impl FnMut(&mut Self, ImapEvent) -> Result<()>,
+    ) -> Result<()>;
+    /// Searches the given mailbox on the server side. Returns the sequence numbers of the
+    /// matching messages, in ascending order.
+    fn search(&mut self, mbox: &Mbox, query: &SearchQuery) -> Result<Vec<u32>>;
+    /// Fetches the raw content of the given MIME part (like `1.2`) of a message, without
+    /// downloading the rest of the message. The content is still transfer-encoded.
+    fn fetch_part(&mut self, mbox: &Mbox, seq: u32, part: &str) -> Result<Vec<u8>>;
+    /// Fetches the MIME structure of a message, without its content. Returns the leaf parts, see
+    /// [`PartStructure`].
+    fn fetch_bodystructure(&mut self, mbox: &Mbox, seq: u32) -> Result<Vec<PartStructure>>;
+
     /// Add flags to all messages within the given sequence range.
     fn add_flags(&mut self, seq_range: &str, flags: &Flags) -> Result<()>;
     /// Replace flags of all messages within the given sequence range.
@@ -41,10 +195,20 @@ pub trait ImapServiceInterface<'a> {
     fn remove_flags(&mut self, seq_range: &str, flags: &Flags) -> Result<()>;
 }
 
+/// Represents the IMAP service. The session is opened on the first command and reused by the
+/// following ones, so that only the first command pays the TCP and TLS handshakes and the
+/// authentication (several round trips), the others cost a single round trip. A session left
+/// unused for a while is checked before being reused and reopened if the server closed it, the
+/// mailbox opened in the previous session being opened again. The session is logged out when the
+/// service is dropped.
 pub struct ImapService<'a> {
     account: &'a Account,
     mbox: &'a Mbox<'a>,
-    sess: Option<ImapSession>,
+    sess: Option<ImapSessionGuard>,
+    last_activity: Option<Instant>,
+    /// The name of the mailbox opened in the session, and whether it is opened in read-only mode
+    /// (with EXAMINE instead of SELECT).
+    opened_mbox: Option<(String, bool)>,
     /// Holds raw mailboxes fetched by the `imap` crate in order to extend mailboxes lifetime
     /// outside of handlers. Without that, it would be impossible for handlers to return a `Mbox`
     /// struct or a `Mboxes` struct due to the `ZeroCopy` constraint.
@@ -52,47 +216,226 @@ pub struct ImapService<'a> {
     _raw_msgs_cache: Option<RawEnvelopes>,
 }
 
+/// Returns true if the error means that the connection is closed, either because the server said
+/// BYE or because the stream is broken.
+fn is_conn_error(err: &imap::Error) -> bool {
+    matches!(
+        err,
+        imap::Error::Io(_)
+            | imap::Error::ConnectionLost
+            | imap::Error::Unexpected(Response::Data {
+                status: Status::Bye,
+                ..
+            })
+    )
+}
+
+/// Parses the sequence numbers of the SEARCH responses. Other responses (like unsolicited ones)
+/// are skipped.
+fn parse_search_response(mut res: &[u8]) -> Result<Vec<u32>> {
+    let mut seqs = vec![];
+    while !res.is_empty() {
+        let (rest, data) = imap_proto::parser::parse_response(res)
+            .map_err(|err| anyhow!("cannot parse search response: {:?}", err))?;
+        if let Response::MailboxData(MailboxDatum::Search(ids)) = data {
+            seqs.extend(ids);
+        }
+        res = rest;
+    }
+    Ok(seqs)
+}
+
+/// Parses a MIME part number (like `1.2`) into a section path.
+fn parse_section_path(part: &str) -> Result<SectionPath> {
+    let nums = part
+        .split('.')
+        .map(|num| match num.parse::<u32>() {
+            Ok(num) if num > 0 => Ok(num),
+            _ => Err(anyhow!(r#"invalid part number "{}""#, part)),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(SectionPath::Part(nums, None))
+}
+
+/// Lists the MIME parts of a body structure. Only leaf parts are listed, since multipart
+/// containers have no content of their own.
+fn list_parts(bs: &BodyStructure) -> Vec<PartStructure> {
+    fn walk(bs: &BodyStructure, num: String, parts: &mut Vec<PartStructure>) {
+        let prefix = |i: usize| {
+            if num.is_empty() {
+                (i + 1).to_string()
+            } else {
+                format!("{}.{}", num, i + 1)
+            }
+        };
+        match bs {
+            BodyStructure::Multipart { bodies, .. } => {
+                for (i, body) in bodies.iter().enumerate() {
+                    walk(body, prefix(i), parts);
+                }
+            }
+            BodyStructure::Basic { common, other, .. }
+            | BodyStructure::Text { common, other, .. }
+            | BodyStructure::Message { common, other, .. } => {
+                // A single part body is numbered 1 (RFC3501 section 6.4.5)
+                let num = if num.is_empty() { prefix(0) } else { num };
+                let mime = format!("{}/{}", common.ty.ty, common.ty.subtype).to_lowercase();
+                let charset = common.ty.params.as_ref().and_then(|params| {
+                    params
+                        .iter()
+                        .find(|(key, _)| key.eq_ignore_ascii_case("charset"))
+                        .map(|(_, val)| val.to_string())
+                });
+                let encoding = match other.transfer_encoding {
+                    ContentEncoding::SevenBit => "7bit".into(),
+                    ContentEncoding::EightBit => "8bit".into(),
+                    ContentEncoding::Binary => "binary".into(),
+                    ContentEncoding::Base64 => "base64".into(),
+                    ContentEncoding::QuotedPrintable => "quoted-printable".into(),
+                    ContentEncoding::Other(ref encoding) => encoding.to_lowercase(),
+                };
+                parts.push(PartStructure {
+                    num,
+                    mime,
+                    charset,
+                    encoding,
+                });
+            }
+        }
+    }
+
+    let mut parts = vec![];
+    walk(bs, String::new(), &mut parts);
+    parts
+}
+
 impl<'a> ImapService<'a> {
+    /// Closes the current session, if any, and opens a new one.
+    pub fn reconnect(&mut self) -> Result<()> {
+        if let Some(mut sess) = self.sess.take() {
+            // The connection may already be broken, so errors are ignored
+            sess.logout().ok();
+        }
+        let mut sess = self.connect()?;
+        // Commands relying on the opened mailbox (like EXPUNGE) would fail otherwise
+        if let Some((ref name, read_only)) = self.opened_mbox {
+            debug!("reopen mailbox {:?}", name);
+            let res = if read_only {
+                sess.examine(name)
+            } else {
+                sess.select(name)
+            };
+            res.context(format!(r#"cannot reopen mailbox "{}""#, name))?;
+        }
+        self.sess = Some(ImapSessionGuard {
+            sess,
+            logged_out: false,
+        });
+        self.last_activity = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Selects the given mailbox, which is selected again if the session is reopened.
+    fn select_mbox(&mut self, name: &str) -> Result<Mailbox> {
+        let mbox = self
+            .sess()?
+            .select(name)
+            .context(format!(r#"cannot select mailbox "{}""#, name))?;
+        self.opened_mbox = Some((name.to_owned(), false));
+        Ok(mbox)
+    }
+
+    /// Same as [`ImapService::select_mbox`], in read-only mode.
+    fn examine_mbox(&mut self, name: &str) -> Result<Mailbox> {
+        let mbox = self
+            .sess()?
+            .examine(name)
+            .context(format!(r#"cannot examine mailbox "{}""#, name))?;
+        self.opened_mbox = Some((name.to_owned(), true));
+        Ok(mbox)
+    }
+
     fn sess(&mut self) -> Result<&mut ImapSession> {
+        let inactive = self
+            .last_activity
+            .map(|t| t.elapsed() >= Duration::from_secs(LIVENESS_CHECK_DELAY))
+            .unwrap_or_default();
+
         if self.sess.is_none() {
-            debug!("create TLS builder");
-            debug!("insecure: {}", self.account.imap_insecure);
-            let builder = TlsConnector::builder()
-                .danger_accept_invalid_certs(self.account.imap_insecure)
-                .danger_accept_invalid_hostnames(self.account.imap_insecure)
-                .build()
-                .context("cannot create TLS connector")?;
-
-            debug!("create client");
-            debug!("host: {}", self.account.imap_host);
-            debug!("port: {}", self.account.imap_port);
-            debug!("starttls: {}", self.account.imap_starttls);
-            let mut client_builder =
-                imap::ClientBuilder::new(&self.account.imap_host, self.account.imap_port);
-            if self.account.imap_starttls {
-                client_builder.starttls();
+            self.reconnect()?;
+        } else if inactive {
+            debug!("check IMAP session liveness");
+            let res = self
+                .sess
+                .as_mut()
+                .map(|guard| guard.sess.noop())
+                .transpose();
+            match res {
+                Err(err) if is_conn_error(&err) => {
+                    warn!("IMAP connection lost ({}), reconnecting", err);
+                    self.reconnect()?;
+                }
+                res => res
+                    .context("cannot check IMAP session")?
+                    .unwrap_or_default(),
             }
-            let client = client_builder
-                .connect(|domain, tcp| Ok(TlsConnector::connect(&builder, domain, tcp)?))
-                .context("cannot connect to IMAP server")?;
-
-            debug!("create session");
-            debug!("login: {}", self.account.imap_login);
-            debug!("passwd cmd: {}", self.account.imap_passwd_cmd);
-            let mut sess = client
-                .login(&self.account.imap_login, &self.account.imap_passwd()?)
-                .map_err(|res| res.0)
-                .context("cannot login to IMAP server")?;
-            sess.debug = log_enabled!(Level::Trace);
-            self.sess = Some(sess);
         }
+        self.last_activity = Some(Instant::now());
 
         match self.sess {
-            Some(ref mut sess) => Ok(sess),
+            Some(ref mut guard) => Ok(&mut guard.sess),
             None => Err(anyhow!("cannot get IMAP session")),
         }
     }
 
+    fn connect(&self) -> Result<ImapSession> {
+        debug!("create TLS builder");
+        debug!("insecure: {}", self.account.imap_insecure);
+        let builder = TlsConnector::builder()
+            .danger_accept_invalid_certs(self.account.imap_insecure)
+            .danger_accept_invalid_hostnames(self.account.imap_insecure)
+            .build()
+            .context("cannot create TLS connector")?;
+
+        debug!("create client");
+        debug!("host: {}", self.account.imap_host);
+        debug!("port: {}", self.account.imap_port);
+        debug!("starttls: {}", self.account.imap_starttls);
+        let mut client_builder =
+            imap::ClientBuilder::new(&self.account.imap_host, self.account.imap_port);
+        if self.account.imap_starttls {
+            client_builder.starttls();
+        }
+        let client = client_builder
+            .connect(|domain, tcp| Ok(TlsConnector::connect(&builder, domain, tcp)?))
+            .context("cannot connect to IMAP server")?;
+
+        debug!("create session");
+        debug!("login: {}", self.account.imap_login);
+        debug!("auth: {:?}", self.account.auth);
+        let mut sess = match self.account.auth.token()? {
+            Some(token) => {
+                let auth = XOAuth2 {
+                    user: self.account.imap_login.to_owned(),
+                    token,
+                };
+                client
+                    .authenticate("XOAUTH2", &auth)
+                    .map_err(|res| res.0)
+                    .context("cannot authenticate to IMAP server with XOAUTH2")?
+            }
+            None => {
+                debug!("passwd: {:?}", self.account.imap_passwd);
+                client
+                    .login(&self.account.imap_login, &self.account.imap_passwd()?)
+                    .map_err(|res| res.0)
+                    .context("cannot login to IMAP server")?
+            }
+        };
+        sess.debug = log_enabled!(Level::Trace);
+        Ok(sess)
+    }
+
     fn search_new_msgs(&mut self, account: &Account) -> Result<Vec<u32>> {
         let uids: Vec<u32> = self
             .sess()?
@@ -105,6 +448,54 @@ impl<'a> ImapService<'a> {
 
         Ok(uids)
     }
+
+    /// Runs the notify command for the new messages not notified yet, then marks them as
+    /// notified.
+    fn notify_new_msgs(
+        &mut self,
+        config: &Config,
+        account: &Account,
+        msgs_set: &mut HashSet<u32>,
+    ) -> Result<()> {
+        let uids: Vec<u32> = self
+            .search_new_msgs(account)?
+            .into_iter()
+            .filter(|uid| -> bool { msgs_set.get(uid).is_none() })
+            .collect();
+        debug!("found {} new messages not in hashset", uids.len());
+        trace!("messages hashet: {:?}", msgs_set);
+
+        if !uids.is_empty() {
+            let uids = uids
+                .iter()
+                .map(|uid| uid.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            let fetches = self
+                .sess()?
+                .uid_fetch(uids, "(UID ENVELOPE)")
+                .context("cannot fetch new messages enveloppe")?;
+
+            for fetch in fetches.iter() {
+                let msg = Envelope::try_from(fetch)?;
+                let uid = fetch
+                    .uid
+                    .ok_or_else(|| anyhow!("cannot retrieve message {}'s UID", fetch.message))?;
+
+                let from = msg.sender.to_owned().into();
+                config.run_notify_cmd(&msg.subject, &from)?;
+
+                debug!("notify message: {}", uid);
+                trace!("message: {:?}", msg);
+
+                debug!("insert message {} in hashset", uid);
+                msgs_set.insert(uid);
+                trace!("messages hashset: {:?}", msgs_set);
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl<'a> ImapServiceInterface<'a> for ImapService<'a> {
@@ -123,11 +514,7 @@ impl<'a> ImapServiceInterface<'a> for ImapService<'a> {
         debug!("page: {:?}", page);
 
         let mbox = self.mbox.to_owned();
-        let last_seq = self
-            .sess()?
-            .select(&mbox.name)
-            .context(format!(r#"cannot select mailbox "{}""#, self.mbox.name))?
-            .exists as i64;
+        let last_seq = self.select_mbox(&mbox.name)?.exists as i64;
         debug!("last sequence number: {:?}", last_seq);
 
         if last_seq == 0 {
@@ -155,24 +542,15 @@ impl<'a> ImapServiceInterface<'a> for ImapService<'a> {
 
     fn fetch_envelopes_with(
         &'a mut self,
-        query: &str,
+        query: &SearchQuery,
         page_size: &usize,
         page: &usize,
     ) -> Result<Envelopes> {
-        let mbox = self.mbox.to_owned();
-        self.sess()?
-            .select(&mbox.name)
-            .context(format!(r#"cannot select mailbox "{}""#, self.mbox.name))?;
-
+        let mbox = self.mbox;
         let begin = page * page_size;
         let end = begin + (page_size - 1);
         let seqs: Vec<String> = self
-            .sess()?
-            .search(query)
-            .context(format!(
-                r#"cannot search in "{}" with query: "{}""#,
-                self.mbox.name, query
-            ))?
+            .search(mbox, query)?
             .iter()
             .map(|seq| seq.to_string())
             .collect();
@@ -194,9 +572,7 @@ impl<'a> ImapServiceInterface<'a> for ImapService<'a> {
     /// Find a message by sequence number.
     fn find_msg(&mut self, account: &Account, seq: &str) -> Result<Msg> {
         let mbox = self.mbox.to_owned();
-        self.sess()?
-            .select(&mbox.name)
-            .context(format!("cannot select mailbox {}", self.mbox.name))?;
+        self.select_mbox(&mbox.name)?;
         let fetches = self
             .sess()?
             .fetch(seq, "(ENVELOPE FLAGS INTERNALDATE BODY[])")
@@ -210,9 +586,7 @@ impl<'a> ImapServiceInterface<'a> for ImapService<'a> {
 
     fn find_raw_msg(&mut self, seq: &str) -> Result<Vec<u8>> {
         let mbox = self.mbox.to_owned();
-        self.sess()?
-            .select(&mbox.name)
-            .context(format!(r#"cannot select mailbox "{}""#, self.mbox.name))?;
+        self.select_mbox(&mbox.name)?;
         let fetches = self
             .sess()?
             .fetch(seq, "BODY[]")
@@ -246,12 +620,10 @@ impl<'a> ImapServiceInterface<'a> for ImapService<'a> {
     fn notify(&mut self, config: &Config, account: &Account, keepalive: u64) -> Result<()> {
         debug!("notify");
 
-        let mbox = self.mbox.to_owned();
+        let mbox = self.mbox;
 
         debug!("examine mailbox {:?}", mbox);
-        self.sess()?
-            .examine(&mbox.name)
-            .context(format!("cannot examine mailbox {}", self.mbox.name))?;
+        self.examine_mbox(&mbox.name)?;
 
         debug!("init messages hashset");
         let mut msgs_set: HashSet<u32> = self
@@ -261,82 +633,22 @@ impl<'a> ImapServiceInterface<'a> for ImapService<'a> {
             .collect::<HashSet<_>>();
         trace!("messages hashset: {:?}", msgs_set);
 
-        loop {
-            debug!("begin loop");
-            self.sess()?
-                .idle()
-                .and_then(|mut idle| {
-                    idle.set_keepalive(std::time::Duration::new(keepalive, 0));
-                    idle.wait_keepalive_while(|res| {
-                        // TODO: handle response
-                        trace!("idle response: {:?}", res);
-                        false
-                    })
-                })
-                .context("cannot start the idle mode")?;
-
-            let uids: Vec<u32> = self
-                .search_new_msgs(account)?
-                .into_iter()
-                .filter(|uid| -> bool { msgs_set.get(uid).is_none() })
-                .collect();
-            debug!("found {} new messages not in hashset", uids.len());
-            trace!("messages hashet: {:?}", msgs_set);
-
-            if !uids.is_empty() {
-                let uids = uids
-                    .iter()
-                    .map(|uid| uid.to_string())
-                    .collect::<Vec<_>>()
-                    .join(",");
-                let fetches = self
-                    .sess()?
-                    .uid_fetch(uids, "(UID ENVELOPE)")
-                    .context("cannot fetch new messages enveloppe")?;
-
-                for fetch in fetches.iter() {
-                    let msg = Envelope::try_from(fetch)?;
-                    let uid = fetch.uid.ok_or_else(|| {
-                        anyhow!("cannot retrieve message {}'s UID", fetch.message)
-                    })?;
-
-                    let from = msg.sender.to_owned().into();
-                    config.run_notify_cmd(&msg.subject, &from)?;
-
-                    debug!("notify message: {}", uid);
-                    trace!("message: {:?}", msg);
-
-                    debug!("insert message {} in hashset", uid);
-                    msgs_set.insert(uid);
-                    trace!("messages hashset: {:?}", msgs_set);
-                }
+        self.idle(mbox, keepalive, |imap, event| match event {
+            ImapEvent::Exists(_) | ImapEvent::Recent(_) => {
+                imap.notify_new_msgs(config, account, &mut msgs_set)
             }
-
-            debug!("end loop");
-        }
+            _ => Ok(()),
+        })
     }
 
     fn watch(&mut self, account: &Account, keepalive: u64) -> Result<()> {
-        debug!("examine mailbox: {}", &self.mbox.name);
-        let mbox = self.mbox.to_owned();
-
-        self.sess()?
-            .examine(&mbox.name)
-            .context(format!("cannot examine mailbox `{}`", &self.mbox.name))?;
+        let mbox = self.mbox;
 
-        loop {
-            debug!("begin loop");
-            self.sess()?
-                .idle()
-                .and_then(|mut idle| {
-                    idle.set_keepalive(std::time::Duration::new(keepalive, 0));
-                    idle.wait_keepalive_while(|res| {
-                        // TODO: handle response
-                        trace!("idle response: {:?}", res);
-                        false
-                    })
-                })
-                .context("cannot start the idle mode")?;
+        self.idle(mbox, keepalive, |_, event| {
+            // New messages are notified by both EXISTS and RECENT, commands run only once
+            if let ImapEvent::Recent(_) = event {
+                return Ok(());
+            }
 
             let cmds = account.watch_cmds.clone();
             thread::spawn(move || {
@@ -347,13 +659,12 @@ impl<'a> ImapServiceInterface<'a> for ImapService<'a> {
                     debug!("{:?}", res);
                 })
             });
-
-            debug!("end loop");
-        }
+            Ok(())
+        })
     }
 
     fn logout(&mut self) -> Result<()> {
-        if let Some(ref mut sess) = self.sess {
+        if let Some(mut sess) = self.sess.take() {
             debug!("logout from IMAP server");
             sess.logout().context("cannot logout from IMAP server")?;
         }
@@ -363,9 +674,7 @@ impl<'a> ImapServiceInterface<'a> for ImapService<'a> {
     fn add_flags(&mut self, seq_range: &str, flags: &Flags) -> Result<()> {
         let mbox = self.mbox;
         let flags: String = flags.to_string();
-        self.sess()?
-            .select(&mbox.name)
-            .context(format!(r#"cannot select mailbox "{}""#, self.mbox.name))?;
+        self.select_mbox(&mbox.name)?;
         self.sess()?
             .store(seq_range, format!("+FLAGS ({})", flags))
             .context(format!(r#"cannot add flags "{}""#, &flags))?;
@@ -374,9 +683,7 @@ impl<'a> ImapServiceInterface<'a> for ImapService<'a> {
 
     fn set_flags(&mut self, seq_range: &str, flags: &Flags) -> Result<()> {
         let mbox = self.mbox;
-        self.sess()?
-            .select(&mbox.name)
-            .context(format!(r#"cannot select mailbox "{}""#, self.mbox.name))?;
+        self.select_mbox(&mbox.name)?;
         self.sess()?
             .store(seq_range, format!("FLAGS ({})", flags))
             .context(format!(r#"cannot set flags "{}""#, &flags))?;
@@ -386,9 +693,7 @@ impl<'a> ImapServiceInterface<'a> for ImapService<'a> {
     fn remove_flags(&mut self, seq_range: &str, flags: &Flags) -> Result<()> {
         let mbox = self.mbox;
         let flags = flags.to_string();
-        self.sess()?
-            .select(&mbox.name)
-            .context(format!(r#"cannot select mailbox "{}""#, self.mbox.name))?;
+        self.select_mbox(&mbox.name)?;
         self.sess()?
             .store(seq_range, format!("-FLAGS ({})", flags))
             .context(format!(r#"cannot remove flags "{}""#, &flags))?;
@@ -401,6 +706,166 @@ impl<'a> ImapServiceInterface<'a> for ImapService<'a> {
             .context(format!(r#"cannot expunge mailbox "{}""#, self.mbox.name))?;
         Ok(())
     }
+
+    fn idle(
+        &mut self,
+        mbox: &Mbox,
+        keepalive: u64,
+        mut on_event: impl FnMut(&mut Self, ImapEvent) -> Result<()>,
+    ) -> Result<()> {
+        debug!("examine mailbox {:?}", mbox.name);
+        self.examine_mbox(&mbox.name)?;
+
+        let has_idle = self.has_capability("IDLE")?;
+        if !has_idle {
+            warn!("IDLE not supported by the server, fall back to polling");
+        }
+
+        // Drops the responses received before watching the mailbox
+        while self.sess()?.unsolicited_responses.try_recv().is_ok() {}
+
+        let keepalive = Duration::from_secs(keepalive.min(IDLE_KEEPALIVE));
+        loop {
+            let mut events = vec![];
+
+            if has_idle {
+                debug!("begin idle loop");
+                self.sess()?
+                    .idle()
+                    .and_then(|mut idle| {
+                        idle.set_keepalive(keepalive);
+                        // The idle mode is left on the first event, so that the session can be
+                        // used to handle it
+                        idle.wait_keepalive_while(|res| {
+                            trace!("idle response: {:?}", res);
+                            match ImapEvent::from_unsolicited_response(res) {
+                                Some(event) => {
+                                    events.push(event);
+                                    false
+                                }
+                                None => true,
+                            }
+                        })
+                    })
+                    .context("cannot start the idle mode")?;
+            } else {
+                thread::sleep(Duration::from_secs(IDLE_POLL_INTERVAL));
+                debug!("poll mailbox {:?}", mbox.name);
+                let sess = self.sess()?;
+                sess.noop()
+                    .context(format!(r#"cannot poll mailbox "{}""#, mbox.name))?;
+                while let Ok(res) = sess.unsolicited_responses.try_recv() {
+                    trace!("unsolicited response: {:?}", res);
+                    events.extend(ImapEvent::from_unsolicited_response(res));
+                }
+            }
+
+            for event in events {
+                debug!("mailbox event: {:?}", event);
+                on_event(self, event)?;
+            }
+        }
+    }
+
+    fn search(&mut self, mbox: &Mbox, query: &SearchQuery) -> Result<Vec<u32>> {
+        self.examine_mbox(&mbox.name)?;
+        let args = query.to_search_args();
+        debug!("search query: {}", args);
+        let mut seqs = if query.is_ascii() {
+            self.sess()?
+                .search(&args)
+                .context(format!(r#"cannot search messages "{}""#, args))?
+                .into_iter()
+                .collect::<Vec<_>>()
+        } else {
+            // Non-ASCII strings are sent as non-synchronizing literals, which LITERAL- only
+            // allows up to 4096 bytes
+            let has_literal_plus = self.has_capability("LITERAL+")?
+                || (args.len() <= 4096 && self.has_capability("LITERAL-")?);
+            if !has_literal_plus {
+                return Err(anyhow!(
+                    "cannot search non-ASCII strings: the server does not support the LITERAL+ extension"
+                ));
+            }
+            let res = self
+                .sess()?
+                .run_command_and_read_response(format!("SEARCH {}", args))
+                .context(format!(r#"cannot search messages "{}""#, args))?;
+            parse_search_response(&res)?
+        };
+        seqs.sort_unstable();
+        Ok(seqs)
+    }
+
+    fn fetch_part(&mut self, mbox: &Mbox, seq: u32, part: &str) -> Result<Vec<u8>> {
+        let path = parse_section_path(part)?;
+        self.examine_mbox(&mbox.name)?;
+        // BODY.PEEK does not set the \Seen flag, the response is still a BODY[part]
+        let fetches = self
+            .sess()?
+            .fetch(seq.to_string(), format!("BODY.PEEK[{}]", part))
+            .context(format!(
+                r#"cannot fetch part "{}" of message "{}""#,
+                part, seq
+            ))?;
+        let fetch = fetches
+            .first()
+            .ok_or_else(|| anyhow!(r#"cannot find message "{}""#, seq))?;
+        let data = fetch
+            .section(&path)
+            .ok_or_else(|| anyhow!(r#"cannot find part "{}" of message "{}""#, part, seq))?;
+        Ok(data.to_vec())
+    }
+
+    fn fetch_bodystructure(&mut self, mbox: &Mbox, seq: u32) -> Result<Vec<PartStructure>> {
+        self.examine_mbox(&mbox.name)?;
+        let fetches = self
+            .sess()?
+            .fetch(seq.to_string(), "BODYSTRUCTURE")
+            .context(format!(
+                r#"cannot fetch body structure of message "{}""#,
+                seq
+            ))?;
+        let bs = fetches
+            .first()
+            .and_then(|fetch| fetch.bodystructure())
+            .ok_or_else(|| anyhow!(r#"cannot find body structure of message "{}""#, seq))?;
+        Ok(list_parts(bs))
+    }
+
+    fn has_capability(&mut self, cap: &str) -> Result<bool> {
+        let caps = self
+            .sess()?
+            .capabilities()
+            .context("cannot fetch server capabilities")?;
+        Ok(caps.has_str(cap))
+    }
+
+    fn move_msg(&mut self, from: &Mbox, to: &Mbox, seq_range: &str) -> Result<()> {
+        self.select_mbox(&from.name)?;
+
+        if self.has_capability("MOVE")? {
+            debug!("move messages {} to {:?} using MOVE", seq_range, to.name);
+            self.sess()?.mv(seq_range, &to.name).context(format!(
+                r#"cannot move messages "{}" to "{}""#,
+                seq_range, to.name
+            ))?;
+        } else {
+            debug!("move messages {} to {:?} using COPY", seq_range, to.name);
+            self.sess()?.copy(seq_range, &to.name).context(format!(
+                r#"cannot copy messages "{}" to "{}""#,
+                seq_range, to.name
+            ))?;
+            self.sess()?
+                .store(seq_range, "+FLAGS (\\Deleted)")
+                .context(format!(r#"cannot delete messages "{}""#, seq_range))?;
+            self.sess()?
+                .expunge()
+                .context(format!(r#"cannot expunge mailbox "{}""#, from.name))?;
+        }
+
+        Ok(())
+    }
 }
 
 impl<'a> From<(&'a Account, &'a Mbox<'a>)> for ImapService<'a> {
@@ -409,8 +874,168 @@ impl<'a> From<(&'a Account, &'a Mbox<'a>)> for ImapService<'a> {
             account,
             mbox,
             sess: None,
+            last_activity: None,
+            opened_mbox: None,
             _raw_mboxes_cache: None,
             _raw_msgs_cache: None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn part<'a>(ty: &'a str, subtype: &'a str) -> BodyStructure<'a> {
+        BodyStructure::Basic {
+            common: imap_proto::BodyContentCommon {
+                ty: imap_proto::ContentType {
+                    ty: ty.into(),
+                    subtype: subtype.into(),
+                    params: None,
+                },
+                disposition: None,
+                language: None,
+                location: None,
+            },
+            other: imap_proto::BodyContentSinglePart {
+                id: None,
+                md5: None,
+                description: None,
+                transfer_encoding: imap_proto::ContentEncoding::Base64,
+                octets: 0,
+            },
+            extension: None,
+        }
+    }
+
+    fn multipart<'a>(subtype: &'a str, bodies: Vec<BodyStructure<'a>>) -> BodyStructure<'a> {
+        match part("multipart", subtype) {
+            BodyStructure::Basic { common, .. } => BodyStructure::Multipart {
+                common,
+                bodies,
+                extension: None,
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn it_should_parse_search_response() {
+        let res = b"* SEARCH 4 2\r\n* 5 EXISTS\r\n* SEARCH 7\r\n";
+        assert_eq!(vec![4, 2, 7], parse_search_response(res).unwrap());
+        assert!(parse_search_response(b"* SEARCH\r\n").unwrap().is_empty());
+        assert!(parse_search_response(b"garbage\r\n").is_err());
+    }
+
+    #[test]
+    fn it_should_parse_section_path() {
+        assert_eq!(
+            SectionPath::Part(vec![1, 2], None),
+            parse_section_path("1.2").unwrap()
+        );
+        assert_eq!(
+            SectionPath::Part(vec![3], None),
+            parse_section_path("3").unwrap()
+        );
+        assert!(parse_section_path("0").is_err());
+        assert!(parse_section_path("1.TEXT").is_err());
+        assert!(parse_section_path("1] BODY[2").is_err());
+    }
+
+    #[test]
+    fn it_should_list_parts() {
+        let nums_and_mimes = |bs| {
+            list_parts(bs)
+                .into_iter()
+                .map(|part| (part.num, part.mime))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec![PartStructure {
+                num: "1".into(),
+                mime: "text/plain".into(),
+                charset: None,
+                encoding: "base64".into(),
+            }],
+            list_parts(&part("TEXT", "PLAIN"))
+        );
+
+        let bs = multipart(
+            "mixed",
+            vec![
+                multipart(
+                    "alternative",
+                    vec![part("text", "plain"), part("text", "html")],
+                ),
+                part("image", "png"),
+            ],
+        );
+        assert_eq!(
+            vec![
+                ("1.1".to_owned(), "text/plain".to_owned()),
+                ("1.2".to_owned(), "text/html".to_owned()),
+                ("2".to_owned(), "image/png".to_owned()),
+            ],
+            nums_and_mimes(&bs)
+        );
+    }
+
+    #[test]
+    fn it_should_decode_text_parts() {
+        let part = PartStructure {
+            num: "1.1".into(),
+            mime: "text/plain".into(),
+            charset: Some("iso-8859-1".into()),
+            encoding: "quoted-printable".into(),
+        };
+        assert_eq!("Café", part.decode_text(b"Caf=E9").unwrap());
+
+        let part = PartStructure {
+            charset: None,
+            encoding: "base64".into(),
+            ..part
+        };
+        assert_eq!("Hello!", part.decode_text(b"SGVsbG8h").unwrap());
+    }
+
+    #[test]
+    fn it_should_detect_conn_errors() {
+        let broken_pipe = std::io::Error::from(std::io::ErrorKind::BrokenPipe);
+        assert!(is_conn_error(&imap::Error::Io(broken_pipe)));
+        assert!(is_conn_error(&imap::Error::ConnectionLost));
+        assert!(is_conn_error(&imap::Error::Unexpected(Response::Data {
+            status: Status::Bye,
+            code: None,
+            information: Some("Autologout; idle for too long".into()),
+        })));
+        assert!(!is_conn_error(&imap::Error::Append));
+    }
+
+    #[test]
+    fn it_should_convert_unsolicited_responses_to_events() {
+        assert_eq!(
+            Some(ImapEvent::Exists(3)),
+            ImapEvent::from_unsolicited_response(UnsolicitedResponse::Exists(3))
+        );
+        assert_eq!(
+            Some(ImapEvent::Expunge(2)),
+            ImapEvent::from_unsolicited_response(UnsolicitedResponse::Expunge(2))
+        );
+        assert_eq!(
+            Some(ImapEvent::Recent(1)),
+            ImapEvent::from_unsolicited_response(UnsolicitedResponse::Recent(1))
+        );
+        assert_eq!(
+            Some(ImapEvent::Fetch(4)),
+            ImapEvent::from_unsolicited_response(UnsolicitedResponse::Fetch {
+                id: 4,
+                attributes: vec![],
+            })
+        );
+        assert_eq!(
+            None,
+            ImapEvent::from_unsolicited_response(UnsolicitedResponse::Flags(vec![]))
+        );
+    }
+}
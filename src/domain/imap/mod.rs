@@ -5,3 +5,6 @@ pub mod imap_handler;
 
 pub mod imap_service;
 pub use imap_service::*;
+
+pub mod search_query_entity;
+pub use search_query_entity::*;
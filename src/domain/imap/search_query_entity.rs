@@ -0,0 +1,228 @@
+//! Search query entity module.
+//!
+//! This module contains the representation of the IMAP SEARCH criteria, see [RFC3501].
+//!
+//! [RFC3501]: https://datatracker.ietf.org/doc/html/rfc3501#section-6.4.4
+
+use chrono::NaiveDate;
+use std::fmt;
+
+/// Represents the criteria of an IMAP search, serialized with [`fmt::Display`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SearchQuery {
+    /// Matches messages with the given string in the `From` header.
+    From(String),
+    /// Matches messages with the given string in the `Subject` header.
+    Subject(String),
+    /// Matches messages with the given string in the body.
+    Body(String),
+    /// Matches messages with the given string in the headers or in the body.
+    Text(String),
+    /// Matches messages whose internal date is on or after the given date.
+    Since(NaiveDate),
+    /// Matches messages without the `\Seen` flag.
+    Unseen,
+    /// Matches messages with the `\Flagged` flag.
+    Flagged,
+    /// Matches messages matching all the given criteria (all messages when empty).
+    And(Vec<SearchQuery>),
+    /// Matches messages matching either of the given criteria.
+    Or(Box<SearchQuery>, Box<SearchQuery>),
+    /// Matches messages not matching the given criteria.
+    Not(Box<SearchQuery>),
+    /// Raw search key, sent as it is.
+    Raw(String),
+}
+
+impl SearchQuery {
+    /// Returns true if a string of the query contains non-ASCII chars, in which case the
+    /// `CHARSET` of the search needs to be specified.
+    pub fn is_ascii(&self) -> bool {
+        match self {
+            Self::From(s) | Self::Subject(s) | Self::Body(s) | Self::Text(s) | Self::Raw(s) => {
+                s.is_ascii()
+            }
+            Self::Since(_) | Self::Unseen | Self::Flagged => true,
+            Self::And(queries) => queries.iter().all(Self::is_ascii),
+            Self::Or(left, right) => left.is_ascii() && right.is_ascii(),
+            Self::Not(query) => query.is_ascii(),
+        }
+    }
+
+    /// Builds the arguments of the IMAP SEARCH command, charset included when needed.
+    pub fn to_search_args(&self) -> String {
+        if self.is_ascii() {
+            self.to_string()
+        } else {
+            format!("CHARSET UTF-8 {}", self)
+        }
+    }
+}
+
+impl fmt::Display for SearchQuery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::From(s) => write!(f, "FROM {}", string(s)),
+            Self::Subject(s) => write!(f, "SUBJECT {}", string(s)),
+            Self::Body(s) => write!(f, "BODY {}", string(s)),
+            Self::Text(s) => write!(f, "TEXT {}", string(s)),
+            Self::Since(date) => write!(f, "SINCE {}", date.format("%-d-%b-%Y")),
+            Self::Unseen => write!(f, "UNSEEN"),
+            Self::Flagged => write!(f, "FLAGGED"),
+            Self::And(queries) => match queries.as_slice() {
+                [] => write!(f, "ALL"),
+                [query] => write!(f, "{}", query),
+                queries => {
+                    let queries = queries.iter().map(|q| q.to_string()).collect::<Vec<_>>();
+                    write!(f, "({})", queries.join(" "))
+                }
+            },
+            Self::Or(left, right) => write!(f, "OR {} {}", left, right),
+            Self::Not(query) => write!(f, "NOT {}", query),
+            Self::Raw(key) => write!(f, "{}", key),
+        }
+    }
+}
+
+impl<'a> From<Vec<&'a str>> for SearchQuery {
+    /// Builds a query from the arguments of the search command, see [`parse_search_key`].
+    fn from(args: Vec<&'a str>) -> Self {
+        let mut queries = vec![];
+        let mut args = args.as_slice();
+        while let Some((query, rest)) = parse_search_key(args) {
+            queries.push(query);
+            args = rest;
+        }
+        Self::And(queries)
+    }
+}
+
+/// Parses the first search key of the given arguments, returned along with the remaining
+/// arguments. Keys are case-insensitive, the strings following `from`, `subject`, `body` and
+/// `text` are escaped when serialized. Arguments that are not modelled (or are incomplete) are
+/// kept as raw search keys.
+fn parse_search_key<'a, 'b>(args: &'b [&'a str]) -> Option<(SearchQuery, &'b [&'a str])> {
+    let (arg, rest) = args.split_first()?;
+    let string = |query: fn(String) -> SearchQuery| {
+        rest.split_first()
+            .map(|(s, rest)| (query(s.to_string()), rest))
+    };
+    let query = match arg.to_lowercase().as_str() {
+        "from" => string(SearchQuery::From),
+        "subject" => string(SearchQuery::Subject),
+        "body" => string(SearchQuery::Body),
+        "text" => string(SearchQuery::Text),
+        "since" => rest.split_first().and_then(|(date, rest)| {
+            let date = NaiveDate::parse_from_str(date, "%d-%b-%Y").ok()?;
+            Some((SearchQuery::Since(date), rest))
+        }),
+        "unseen" => Some((SearchQuery::Unseen, rest)),
+        "flagged" => Some((SearchQuery::Flagged, rest)),
+        "not" => {
+            parse_search_key(rest).map(|(query, rest)| (SearchQuery::Not(Box::new(query)), rest))
+        }
+        "or" => parse_search_key(rest).and_then(|(left, rest)| {
+            let (right, rest) = parse_search_key(rest)?;
+            Some((SearchQuery::Or(Box::new(left), Box::new(right)), rest))
+        }),
+        _ => None,
+    };
+    Some(query.unwrap_or_else(|| (SearchQuery::Raw(arg.to_string()), rest)))
+}
+
+/// Builds an IMAP string. Line breaks are replaced by spaces. ASCII strings are quoted
+/// (backslashes and double quotes are escaped, so the string cannot inject commands), other
+/// strings are sent as non-synchronizing literals (`{size+}` followed by the raw bytes, see
+/// [RFC7888]), since quoted strings can only contain 7-bit chars.
+///
+/// [RFC7888]: https://datatracker.ietf.org/doc/html/rfc7888
+fn string(s: &str) -> String {
+    let s = s.replace(&['\r', '\n'][..], " ");
+    if s.is_ascii() {
+        let escaped = s.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("\"{}\"", escaped)
+    } else {
+        format!("{{{}+}}\r\n{}", s.len(), s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_serialize_search_query() {
+        let query = SearchQuery::And(vec![
+            SearchQuery::Or(
+                Box::new(SearchQuery::From("alice@localhost".into())),
+                Box::new(SearchQuery::Subject("hello".into())),
+            ),
+            SearchQuery::Since(NaiveDate::from_ymd(2022, 2, 8)),
+            SearchQuery::Not(Box::new(SearchQuery::Flagged)),
+            SearchQuery::Unseen,
+        ]);
+        assert_eq!(
+            r#"(OR FROM "alice@localhost" SUBJECT "hello" SINCE 8-Feb-2022 NOT FLAGGED UNSEEN)"#,
+            query.to_search_args()
+        );
+        assert_eq!("ALL", SearchQuery::And(vec![]).to_string());
+        assert_eq!(
+            "UNSEEN",
+            SearchQuery::And(vec![SearchQuery::Unseen]).to_string()
+        );
+    }
+
+    #[test]
+    fn it_should_escape_search_strings() {
+        let query = SearchQuery::Body("a \"quote\" and a \\ \r\nA2 LOGOUT".into());
+        assert_eq!(
+            r#"BODY "a \"quote\" and a \\   A2 LOGOUT""#,
+            query.to_search_args()
+        );
+
+        let query = SearchQuery::Subject("Café \"au\" lait".into());
+        assert_eq!(
+            "CHARSET UTF-8 SUBJECT {15+}\r\nCafé \"au\" lait",
+            query.to_search_args()
+        );
+    }
+
+    #[test]
+    fn it_should_build_search_query_from_args() {
+        let query = SearchQuery::from(vec![
+            "FROM",
+            "alice",
+            "unseen",
+            "since",
+            "1-Feb-2022",
+            "or",
+            "not",
+            "flagged",
+            "to",
+            "bob",
+        ]);
+        assert_eq!(
+            SearchQuery::And(vec![
+                SearchQuery::From("alice".into()),
+                SearchQuery::Unseen,
+                SearchQuery::Since(NaiveDate::from_ymd(2022, 2, 1)),
+                SearchQuery::Or(
+                    Box::new(SearchQuery::Not(Box::new(SearchQuery::Flagged))),
+                    Box::new(SearchQuery::Raw("to".into())),
+                ),
+                SearchQuery::Raw("bob".into()),
+            ]),
+            query
+        );
+        assert_eq!(
+            r#"(FROM "alice" UNSEEN SINCE 1-Feb-2022 OR NOT FLAGGED to bob)"#,
+            query.to_search_args()
+        );
+
+        let query = SearchQuery::from(vec!["text", "hello world", "since", "yesterday", "subject"]);
+        assert_eq!(
+            r#"(TEXT "hello world" since yesterday subject)"#,
+            query.to_search_args()
+        );
+    }
+}
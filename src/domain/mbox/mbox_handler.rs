@@ -30,7 +30,10 @@ mod tests {
 
     use crate::{
         config::{Account, Config},
-        domain::{AttrRemote, Attrs, Envelopes, Flags, Mbox, Mboxes, Msg},
+        domain::{
+            AttrRemote, Attrs, Envelopes, Flags, ImapEvent, Mbox, Mboxes, Msg, PartStructure,
+            SearchQuery,
+        },
         output::{Print, PrintTable, WriteColor},
     };
 
@@ -124,7 +127,12 @@ mod tests {
             fn fetch_envelopes(&mut self, _: &usize, _: &usize) -> Result<Envelopes> {
                 unimplemented!()
             }
-            fn fetch_envelopes_with(&mut self, _: &str, _: &usize, _: &usize) -> Result<Envelopes> {
+            fn fetch_envelopes_with(
+                &mut self,
+                _: &SearchQuery,
+                _: &usize,
+                _: &usize,
+            ) -> Result<Envelopes> {
                 unimplemented!()
             }
             fn find_msg(&mut self, _: &Account, _: &str) -> Result<Msg> {
@@ -145,6 +153,29 @@ mod tests {
             fn logout(&mut self) -> Result<()> {
                 unimplemented!()
             }
+            fn has_capability(&mut self, _: &str) -> Result<bool> {
+                unimplemented!()
+            }
+            fn move_msg(&mut self, _: &Mbox, _: &Mbox, _: &str) -> Result<()> {
+                unimplemented!()
+            }
+            fn idle(
+                &mut self,
+                _: &Mbox,
+                _: u64,
+                _: impl FnMut(&mut Self, ImapEvent) -> Result<()>,
+            ) -> Result<()> {
+                unimplemented!()
+            }
+            fn search(&mut self, _: &Mbox, _: &SearchQuery) -> Result<Vec<u32>> {
+                unimplemented!()
+            }
+            fn fetch_part(&mut self, _: &Mbox, _: u32, _: &str) -> Result<Vec<u8>> {
+                unimplemented!()
+            }
+            fn fetch_bodystructure(&mut self, _: &Mbox, _: u32) -> Result<Vec<PartStructure>> {
+                unimplemented!()
+            }
             fn add_flags(&mut self, _: &str, _: &Flags) -> Result<()> {
                 unimplemented!()
             }
@@ -1,17 +1,22 @@
 use anyhow::{anyhow, Context, Error, Result};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::{borrow::Cow, convert::TryFrom};
 
 use crate::{
-    domain::msg::{Flag, Flags},
+    domain::msg::Flags,
     ui::{Cell, Row, Table},
 };
 
 pub type RawEnvelope = imap::types::Fetch;
 
+/// Defines the maximum width of the sender column, so that long senders leave room for the
+/// subject.
+const MAX_SENDER_WIDTH: usize = 32;
+
 /// Representation of an envelope. An envelope gathers basic information related to a message. It
-/// is mostly used for listings.
-#[derive(Debug, Default, Serialize)]
+/// is mostly used for listings. Its JSON shape is part of the output scripts rely on:
+/// `{"id": 1, "flags": ["Seen"], "subject": "…", "sender": "…", "date": "…"}`.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct Envelope<'a> {
     /// The sequence number of the message.
     ///
@@ -114,7 +119,7 @@ impl<'a> TryFrom<&'a RawEnvelope> for Envelope<'a> {
 impl<'a> Table for Envelope<'a> {
     fn head() -> Row {
         Row::new()
-            .cell(Cell::new("ID").bold().underline().white())
+            .cell(Cell::new("ID").align_right().bold().underline().white())
             .cell(Cell::new("FLAGS").bold().underline().white())
             .cell(Cell::new("SUBJECT").shrinkable().bold().underline().white())
             .cell(Cell::new("SENDER").bold().underline().white())
@@ -124,15 +129,72 @@ impl<'a> Table for Envelope<'a> {
     fn row(&self) -> Row {
         let id = self.id.to_string();
         let flags = self.flags.to_symbols_string();
-        let unseen = !self.flags.contains(&Flag::Seen);
+        let unseen = !self.flags.is_seen();
+        let flagged = self.flags.is_flagged();
+        let deleted = self.flags.is_deleted();
         let subject = &self.subject;
         let sender = &self.sender;
         let date = self.date.as_deref().unwrap_or_default();
+        let subject = Cell::new(subject).shrinkable();
+        let subject = if flagged {
+            subject.red()
+        } else {
+            subject.green()
+        };
         Row::new()
-            .cell(Cell::new(id).bold_if(unseen).red())
-            .cell(Cell::new(flags).bold_if(unseen).white())
-            .cell(Cell::new(subject).shrinkable().bold_if(unseen).green())
-            .cell(Cell::new(sender).bold_if(unseen).blue())
-            .cell(Cell::new(date).bold_if(unseen).yellow())
+            .cell(
+                Cell::new(id)
+                    .align_right()
+                    .bold_if(unseen)
+                    .dimmed_if(deleted)
+                    .red(),
+            )
+            .cell(Cell::new(flags).bold_if(unseen).dimmed_if(deleted).white())
+            .cell(subject.bold_if(unseen).dimmed_if(deleted))
+            .cell(
+                Cell::new(sender)
+                    .max_width(MAX_SENDER_WIDTH)
+                    .bold_if(unseen)
+                    .dimmed_if(deleted)
+                    .blue(),
+            )
+            .cell(Cell::new(date).bold_if(unseen).dimmed_if(deleted).yellow())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn it_should_serialize_envelope_to_json() {
+        let envelope = Envelope {
+            id: 1,
+            flags: Flags::from(vec!["Seen", "$Important"]),
+            subject: "Subject".into(),
+            sender: "Sender <sender@localhost>".into(),
+            date: Some("2022-02-08 12:00:00".into()),
+        };
+
+        let mut value = serde_json::to_value(&envelope).unwrap();
+        value["flags"]
+            .as_array_mut()
+            .unwrap()
+            .sort_by_key(|f| f.to_string());
+        assert_eq!(
+            json!({
+                "id": 1,
+                "flags": ["$Important", "Seen"],
+                "subject": "Subject",
+                "sender": "Sender <sender@localhost>",
+                "date": "2022-02-08 12:00:00",
+            }),
+            value
+        );
+
+        let json = serde_json::to_string(&envelope).unwrap();
+        assert_eq!(envelope, serde_json::from_str::<Envelope>(&json).unwrap());
     }
 }
@@ -60,7 +60,7 @@ pub fn matches<'a>(m: &'a ArgMatches) -> Result<Option<Command<'a>>> {
 fn flags_arg<'a>() -> Arg<'a, 'a> {
     Arg::with_name("flags")
         .help("IMAP flags")
-        .long_help("IMAP flags. Flags are case-insensitive, and they do not need to be prefixed with `\\`.")
+        .long_help("IMAP flags. System flags are case-insensitive, and they do not need to be prefixed with `\\`. Other flags are sent as custom keywords (like `$Label1` or `Junk`).")
         .value_name("FLAGS…")
         .multiple(true)
         .required(true)
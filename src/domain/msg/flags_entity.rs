@@ -1,18 +1,22 @@
 use anyhow::{anyhow, Error, Result};
-use serde::ser::{Serialize, SerializeSeq, Serializer};
+use serde::{
+    de::{Deserialize, Deserializer},
+    ser::{Serialize, SerializeSeq, Serializer},
+};
 use std::{
     borrow::Cow,
     collections::HashSet,
     convert::{TryFrom, TryInto},
     fmt::{self, Display},
     ops::{Deref, DerefMut},
+    str::FromStr,
 };
 
 use crate::domain::msg::{Flag, SerializableFlag};
 
 /// Represents the flags of the message.
 /// A hashset is used to avoid duplicates.
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Flags(pub HashSet<Flag<'static>>);
 
 impl Flags {
@@ -36,29 +40,108 @@ impl Flags {
         });
         flags
     }
+
+    /// Returns true if the message has been read.
+    pub fn is_seen(&self) -> bool {
+        self.0.contains(&Flag::Seen)
+    }
+
+    /// Returns true if the message has been flagged for urgent or special attention.
+    pub fn is_flagged(&self) -> bool {
+        self.0.contains(&Flag::Flagged)
+    }
+
+    /// Returns true if the message is marked for removal by the next expunge.
+    pub fn is_deleted(&self) -> bool {
+        self.0.contains(&Flag::Deleted)
+    }
+
+    /// Adds the given flag, which can be a custom IMAP keyword (like `$Label1` or `Junk`).
+    /// Returns false if the flag was already present.
+    pub fn insert(&mut self, flag: Flag<'_>) -> Result<bool> {
+        Ok(self.0.insert(to_owned_flag(&flag)?))
+    }
+
+    /// Returns true if the given flag is present.
+    pub fn contains(&self, flag: &Flag<'_>) -> bool {
+        to_owned_flag(flag)
+            .map(|flag| self.0.contains(&flag))
+            .unwrap_or(false)
+    }
+}
+
+/// Converts a borrowed flag into an owned one.
+fn to_owned_flag(flag: &Flag<'_>) -> Result<Flag<'static>> {
+    Ok(match flag {
+        Flag::Seen => Flag::Seen,
+        Flag::Answered => Flag::Answered,
+        Flag::Flagged => Flag::Flagged,
+        Flag::Deleted => Flag::Deleted,
+        Flag::Draft => Flag::Draft,
+        Flag::Recent => Flag::Recent,
+        Flag::MayCreate => Flag::MayCreate,
+        Flag::Custom(cow) => Flag::Custom(Cow::Owned(cow.to_string())),
+        flag => return Err(anyhow!(r#"cannot parse flag "{}""#, flag)),
+    })
 }
 
+/// Parses a flag from a string. System flags are case-insensitive and do not need to be
+/// prefixed with `\\`, other strings are kept as they are as custom IMAP keywords.
+fn parse_flag(flag: &str) -> Flag<'static> {
+    match flag.trim_start_matches('\\').to_lowercase().as_str() {
+        "answered" => Flag::Answered,
+        "deleted" => Flag::Deleted,
+        "draft" => Flag::Draft,
+        "flagged" => Flag::Flagged,
+        "maycreate" | "*" => Flag::MayCreate,
+        "recent" => Flag::Recent,
+        "seen" => Flag::Seen,
+        _ => Flag::Custom(Cow::Owned(flag.into())),
+    }
+}
+
+/// Parses a flag from its compact string form, see [`Flags::from_str`].
+fn parse_compact_flag(flag: &str) -> Result<Flag<'static>> {
+    let name = flag.strip_prefix('\\').unwrap_or(flag);
+    if name == "*" && name != flag {
+        return Ok(Flag::MayCreate);
+    }
+    if name.is_empty()
+        || name
+            .chars()
+            .any(|c| c.is_control() || "(){\"]%*\\".contains(c))
+    {
+        return Err(anyhow!("cannot parse flag {:?}", flag));
+    }
+    Ok(if name == flag {
+        Flag::Custom(Cow::Owned(flag.into()))
+    } else {
+        parse_flag(flag)
+    })
+}
+
+/// Formats flags to their compact string form, as expected by IMAP: flags are sorted and
+/// separated by spaces, system flags are prefixed with `\` (like `\Seen \Flagged $Label1`).
 impl Display for Flags {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut glue = "";
+        let mut flags = self.0.iter().map(Flag::to_string).collect::<Vec<_>>();
+        flags.sort();
+        write!(f, "{}", flags.join(" "))
+    }
+}
 
-        for flag in &self.0 {
-            write!(f, "{}", glue)?;
-            match flag {
-                Flag::Seen => write!(f, "\\Seen")?,
-                Flag::Answered => write!(f, "\\Answered")?,
-                Flag::Flagged => write!(f, "\\Flagged")?,
-                Flag::Deleted => write!(f, "\\Deleted")?,
-                Flag::Draft => write!(f, "\\Draft")?,
-                Flag::Recent => write!(f, "\\Recent")?,
-                Flag::MayCreate => write!(f, "\\MayCreate")?,
-                Flag::Custom(cow) => write!(f, "{}", cow)?,
-                _ => (),
-            }
-            glue = " ";
-        }
+/// Parses flags from their compact string form, the opposite of the [`Display`] implementation.
+/// Unlike [`Flags::from`], strings without `\` are always custom keywords, so that flags survive
+/// a round trip.
+impl FromStr for Flags {
+    type Err = Error;
 
-        Ok(())
+    fn from_str(flags: &str) -> Result<Self> {
+        flags
+            .split_whitespace()
+            .map(parse_compact_flag)
+            .collect::<Result<_>>()
+            .map(Self)
     }
 }
 
@@ -66,23 +149,13 @@ impl<'a> TryFrom<Vec<Flag<'a>>> for Flags {
     type Error = Error;
 
     fn try_from(flags: Vec<Flag<'a>>) -> Result<Flags> {
-        let mut set: HashSet<Flag<'static>> = HashSet::new();
+        let mut set = Flags::default();
 
         for flag in flags {
-            set.insert(match flag {
-                Flag::Seen => Flag::Seen,
-                Flag::Answered => Flag::Answered,
-                Flag::Flagged => Flag::Flagged,
-                Flag::Deleted => Flag::Deleted,
-                Flag::Draft => Flag::Draft,
-                Flag::Recent => Flag::Recent,
-                Flag::MayCreate => Flag::MayCreate,
-                Flag::Custom(cow) => Flag::Custom(Cow::Owned(cow.to_string())),
-                flag => return Err(anyhow!(r#"cannot parse flag "{}""#, flag)),
-            });
+            set.insert(flag)?;
         }
 
-        Ok(Self(set))
+        Ok(set)
     }
 }
 
@@ -121,77 +194,103 @@ impl Serialize for Flags {
     }
 }
 
+/// Deserializes flags serialized with [`SerializableFlag`], so that the JSON output can be read
+/// back.
+impl<'de> Deserialize<'de> for Flags {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let flags = Vec::<String>::deserialize(deserializer)?;
+        Ok(Self(flags.iter().map(|flag| parse_flag(flag)).collect()))
+    }
+}
+
 impl<'a> From<Vec<&'a str>> for Flags {
     fn from(flags: Vec<&'a str>) -> Self {
-        let mut map: HashSet<Flag<'static>> = HashSet::new();
-
-        for f in flags {
-            match f.to_lowercase().as_str() {
-                "answered" => map.insert(Flag::Answered),
-                "deleted" => map.insert(Flag::Deleted),
-                "draft" => map.insert(Flag::Draft),
-                "flagged" => map.insert(Flag::Flagged),
-                "maycreate" => map.insert(Flag::MayCreate),
-                "recent" => map.insert(Flag::Recent),
-                "seen" => map.insert(Flag::Seen),
-                custom => map.insert(Flag::Custom(Cow::Owned(custom.into()))),
-            };
-        }
-
-        Self(map)
+        Self(flags.into_iter().map(parse_flag).collect())
     }
 }
 
-// FIXME
-//#[cfg(test)]
-//mod tests {
-//    use crate::domain::msg::flag::entity::Flags;
-//    use imap::types::Flag;
-//    use std::collections::HashSet;
-
-//    #[test]
-//    fn test_get_signs() {
-//        let flags = Flags::from(vec![Flag::Seen, Flag::Answered]);
-
-//        assert_eq!(flags.to_symbols_string(), " ↵ ".to_string());
-//    }
-
-//    #[test]
-//    fn test_from_string() {
-//        let flags = Flags::from("Seen Answered");
-
-//        let expected = Flags::from(vec![Flag::Seen, Flag::Answered]);
-
-//        assert_eq!(flags, expected);
-//    }
-
-//    #[test]
-//    fn test_to_string() {
-//        let flags = Flags::from(vec![Flag::Seen, Flag::Answered]);
-
-//        // since we can't influence the order in the HashSet, we're gonna convert it into a vec,
-//        // sort it according to the names and compare it aftwards.
-//        let flag_string = flags.to_string();
-//        let mut flag_vec: Vec<String> = flag_string
-//            .split_ascii_whitespace()
-//            .map(|word| word.to_string())
-//            .collect();
-//        flag_vec.sort();
-
-//        assert_eq!(
-//            flag_vec,
-//            vec!["\\Answered".to_string(), "\\Seen".to_string()]
-//        );
-//    }
-
-//    #[test]
-//    fn test_from_vec() {
-//        let flags = Flags::from(vec![Flag::Seen, Flag::Answered]);
-
-//        let mut expected = HashSet::new();
-//        expected.insert(Flag::Seen);
-//        expected.insert(Flag::Answered);
-
-//        assert_eq!(flags.0, expected);
-//    }
-//}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_build_symbols_string() {
+        let flags = Flags::try_from(vec![Flag::Seen, Flag::Answered]).unwrap();
+        assert_eq!(" ↵ ", flags.to_symbols_string());
+    }
+
+    #[test]
+    fn it_should_parse_flags_from_strs() {
+        let flags = Flags::from(vec!["seen", "\\Answered", "FLAGGED", "$Label1", "Junk"]);
+
+        let mut expected = HashSet::new();
+        expected.insert(Flag::Seen);
+        expected.insert(Flag::Answered);
+        expected.insert(Flag::Flagged);
+        expected.insert(Flag::Custom("$Label1".into()));
+        expected.insert(Flag::Custom("Junk".into()));
+        assert_eq!(expected, flags.0);
+    }
+
+    #[test]
+    fn it_should_serialize_flags_to_imap() {
+        let flags = Flags::from(vec!["seen", "answered", "$Label1"]);
+        let mut flags = flags
+            .to_string()
+            .split_whitespace()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        flags.sort();
+        assert_eq!(vec!["$Label1", "\\Answered", "\\Seen"], flags);
+    }
+
+    #[test]
+    fn it_should_insert_flags() {
+        let mut flags = Flags::default();
+        let keyword = String::from("Junk");
+
+        assert!(flags.insert(Flag::Seen).unwrap());
+        assert!(!flags.insert(Flag::Seen).unwrap());
+        assert!(flags.insert(Flag::Custom(keyword.as_str().into())).unwrap());
+        assert!(flags.contains(&Flag::Seen));
+        assert!(flags.contains(&Flag::Custom(keyword.as_str().into())));
+        assert!(!flags.contains(&Flag::Flagged));
+        assert_eq!(2, flags.len());
+    }
+
+    #[test]
+    fn it_should_round_trip_compact_string() {
+        let flags = Flags::try_from(vec![
+            Flag::Seen,
+            Flag::Answered,
+            Flag::Flagged,
+            Flag::Deleted,
+            Flag::Draft,
+            Flag::Recent,
+            Flag::MayCreate,
+            Flag::Custom("$Label_1.a+b&c-é".into()),
+            Flag::Custom("seen".into()),
+            Flag::Custom("\\Important".into()),
+        ])
+        .unwrap();
+
+        let compact = flags.to_string();
+        assert_eq!(
+            "$Label_1.a+b&c-é \\* \\Answered \\Deleted \\Draft \\Flagged \\Important \\Recent \\Seen seen",
+            compact
+        );
+        assert_eq!(flags, compact.parse().unwrap());
+        assert_eq!(Flags::default(), "".parse().unwrap());
+        assert_eq!(
+            Flags::try_from(vec![Flag::Seen]).unwrap(),
+            " \\seen  ".parse().unwrap()
+        );
+
+        for compact in ["(Label", "\\", "Label*", "\\Seen \"quoted\""] {
+            assert!(compact.parse::<Flags>().is_err(), "{:?}", compact);
+        }
+    }
+}
@@ -14,6 +14,7 @@
 /// - `copy`
 /// - `move`
 /// - `delete`
+/// - `flush`
 /// - `template`
 ///
 /// Execute `himalaya help <cmd>` where `<cmd>` is one entry of this list above
@@ -46,5 +47,14 @@ pub mod tpl_handler;
 pub mod msg_entity;
 pub use msg_entity::*;
 
+pub mod msg_builder;
+pub use msg_builder::*;
+
 pub mod parts_entity;
 pub use parts_entity::*;
+
+pub mod unsubscribe_entity;
+pub use unsubscribe_entity::*;
+
+pub mod outbox_entity;
+pub use outbox_entity::*;
@@ -2,7 +2,7 @@
 //!
 //! This module provides subcommands, arguments and a command matcher related to message.
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::{self, App, Arg, ArgMatches, SubCommand};
 use log::{debug, info, trace};
 
@@ -10,6 +10,7 @@ use crate::{
     domain::{
         mbox::mbox_arg,
         msg::{flag_arg, msg_arg, tpl_arg},
+        SearchQuery,
     },
     ui::table_arg,
 };
@@ -20,27 +21,42 @@ type Page = usize;
 type Mbox<'a> = &'a str;
 type TextMime<'a> = &'a str;
 type Raw = bool;
+type RawBody = bool;
+type Urls = bool;
+type PartNum<'a> = Option<&'a str>;
 type All = bool;
 type RawMsg<'a> = &'a str;
-type Query = String;
+type Query = SearchQuery;
 type AttachmentPaths<'a> = Vec<&'a str>;
 type MaxTableWidth = Option<usize>;
 type Encrypt = bool;
+type Sign = bool;
+type DryRun = bool;
 
 /// Message commands.
 pub enum Command<'a> {
     Attachments(Seq<'a>),
     Copy(Seq<'a>, Mbox<'a>),
     Delete(Seq<'a>),
-    Forward(Seq<'a>, AttachmentPaths<'a>, Encrypt),
+    Drafts(Option<usize>),
+    Flush,
+    Forward(Seq<'a>, TextMime<'a>, AttachmentPaths<'a>, Encrypt, Sign),
     List(MaxTableWidth, Option<PageSize>, Page),
     Move(Seq<'a>, Mbox<'a>),
-    Read(Seq<'a>, TextMime<'a>, Raw),
-    Reply(Seq<'a>, All, AttachmentPaths<'a>, Encrypt),
+    Read(Seq<'a>, TextMime<'a>, Raw, RawBody, Urls, PartNum<'a>),
+    Reply(
+        Seq<'a>,
+        All,
+        TextMime<'a>,
+        AttachmentPaths<'a>,
+        Encrypt,
+        Sign,
+    ),
     Save(RawMsg<'a>),
     Search(Query, MaxTableWidth, Option<PageSize>, Page),
     Send(RawMsg<'a>),
-    Write(AttachmentPaths<'a>, Encrypt),
+    Unsubscribe(Seq<'a>),
+    Write(AttachmentPaths<'a>, Encrypt, Sign, DryRun),
 
     Flag(Option<flag_arg::Command<'a>>),
     Tpl(Option<tpl_arg::Command<'a>>),
@@ -77,11 +93,15 @@ pub fn matches<'a>(m: &'a ArgMatches) -> Result<Option<Command<'a>>> {
         info!("forward command matched");
         let seq = m.value_of("seq").unwrap();
         debug!("seq: {}", seq);
+        let mime = m.value_of("mime-type").unwrap();
+        debug!("text mime: {}", mime);
         let paths: Vec<&str> = m.values_of("attachments").unwrap_or_default().collect();
         debug!("attachments paths: {:?}", paths);
         let encrypt = m.is_present("encrypt");
         debug!("encrypt: {}", encrypt);
-        return Ok(Some(Command::Forward(seq, paths, encrypt)));
+        let sign = m.is_present("sign");
+        debug!("sign: {}", sign);
+        return Ok(Some(Command::Forward(seq, mime, paths, encrypt, sign)));
     }
 
     if let Some(m) = m.subcommand_matches("list") {
@@ -120,7 +140,13 @@ pub fn matches<'a>(m: &'a ArgMatches) -> Result<Option<Command<'a>>> {
         debug!("text mime: {}", mime);
         let raw = m.is_present("raw");
         debug!("raw: {}", raw);
-        return Ok(Some(Command::Read(seq, mime, raw)));
+        let raw_body = m.is_present("raw-body");
+        debug!("raw body: {}", raw_body);
+        let urls = m.is_present("urls");
+        debug!("urls: {}", urls);
+        let part = m.value_of("part");
+        debug!("part: {:?}", part);
+        return Ok(Some(Command::Read(seq, mime, raw, raw_body, urls, part)));
     }
 
     if let Some(m) = m.subcommand_matches("reply") {
@@ -129,12 +155,35 @@ pub fn matches<'a>(m: &'a ArgMatches) -> Result<Option<Command<'a>>> {
         debug!("seq: {}", seq);
         let all = m.is_present("reply-all");
         debug!("reply all: {}", all);
+        let mime = m.value_of("mime-type").unwrap();
+        debug!("text mime: {}", mime);
         let paths: Vec<&str> = m.values_of("attachments").unwrap_or_default().collect();
         debug!("attachments paths: {:?}", paths);
         let encrypt = m.is_present("encrypt");
         debug!("encrypt: {}", encrypt);
+        let sign = m.is_present("sign");
+        debug!("sign: {}", sign);
+
+        return Ok(Some(Command::Reply(seq, all, mime, paths, encrypt, sign)));
+    }
+
+    if let Some(m) = m.subcommand_matches("drafts") {
+        info!("drafts command matched");
+        let index = match m.value_of("index") {
+            Some(index) => Some(
+                index
+                    .parse()
+                    .context(format!("cannot parse draft index {:?}", index))?,
+            ),
+            None => None,
+        };
+        debug!("index: {:?}", index);
+        return Ok(Some(Command::Drafts(index)));
+    }
 
-        return Ok(Some(Command::Reply(seq, all, paths, encrypt)));
+    if m.subcommand_matches("flush").is_some() {
+        info!("flush command matched");
+        return Ok(Some(Command::Flush));
     }
 
     if let Some(m) = m.subcommand_matches("save") {
@@ -160,30 +209,7 @@ pub fn matches<'a>(m: &'a ArgMatches) -> Result<Option<Command<'a>>> {
             .map(|page| 1.max(page) - 1)
             .unwrap_or_default();
         debug!("page: {}", page);
-        let query = m
-            .values_of("query")
-            .unwrap_or_default()
-            .fold((false, vec![]), |(escape, mut cmds), cmd| {
-                match (cmd, escape) {
-                    // Next command is an arg and needs to be escaped
-                    ("subject", _) | ("body", _) | ("text", _) => {
-                        cmds.push(cmd.to_string());
-                        (true, cmds)
-                    }
-                    // Escaped arg commands
-                    (_, true) => {
-                        cmds.push(format!("\"{}\"", cmd));
-                        (false, cmds)
-                    }
-                    // Regular commands
-                    (_, false) => {
-                        cmds.push(cmd.to_string());
-                        (false, cmds)
-                    }
-                }
-            })
-            .1
-            .join(" ");
+        let query = SearchQuery::from(m.values_of("query").unwrap_or_default().collect::<Vec<_>>());
         debug!("query: {}", query);
         return Ok(Some(Command::Search(
             query,
@@ -200,13 +226,29 @@ pub fn matches<'a>(m: &'a ArgMatches) -> Result<Option<Command<'a>>> {
         return Ok(Some(Command::Send(msg)));
     }
 
+    if let Some(m) = m.subcommand_matches("unsubscribe") {
+        info!("unsubscribe command matched");
+        let seq = m.value_of("seq").unwrap();
+        debug!("seq: {}", seq);
+        return Ok(Some(Command::Unsubscribe(seq)));
+    }
+
     if let Some(m) = m.subcommand_matches("write") {
         info!("write command matched");
         let attachment_paths: Vec<&str> = m.values_of("attachments").unwrap_or_default().collect();
         debug!("attachments paths: {:?}", attachment_paths);
         let encrypt = m.is_present("encrypt");
         debug!("encrypt: {}", encrypt);
-        return Ok(Some(Command::Write(attachment_paths, encrypt)));
+        let sign = m.is_present("sign");
+        debug!("sign: {}", sign);
+        let dry_run = m.is_present("dry-run");
+        debug!("dry run: {}", dry_run);
+        return Ok(Some(Command::Write(
+            attachment_paths,
+            encrypt,
+            sign,
+            dry_run,
+        )));
     }
 
     if let Some(m) = m.subcommand_matches("template") {
@@ -246,6 +288,16 @@ pub fn reply_all_arg<'a>() -> Arg<'a, 'a> {
         .long("all")
 }
 
+/// Message text MIME type argument.
+pub fn text_mime_arg<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("mime-type")
+        .help("MIME type to use")
+        .long("mime-type")
+        .value_name("MIME")
+        .possible_values(&["plain", "html"])
+        .default_value("plain")
+}
+
 /// Message page size argument.
 fn page_size_arg<'a>() -> Arg<'a, 'a> {
     Arg::with_name("page-size")
@@ -283,6 +335,14 @@ pub fn encrypt_arg<'a>() -> Arg<'a, 'a> {
         .long("encrypt")
 }
 
+/// Message sign argument.
+pub fn sign_arg<'a>() -> Arg<'a, 'a> {
+    Arg::with_name("sign")
+        .help("Signs the message")
+        .short("s")
+        .long("sign")
+}
+
 /// Message subcommands.
 pub fn subcmds<'a>() -> Vec<App<'a, 'a>> {
     vec![
@@ -316,44 +376,68 @@ pub fn subcmds<'a>() -> Vec<App<'a, 'a>> {
             SubCommand::with_name("write")
                 .about("Writes a new message")
                 .arg(attachment_arg())
-                .arg(encrypt_arg()),
+                .arg(encrypt_arg())
+                .arg(sign_arg())
+                .arg(
+                    Arg::with_name("dry-run")
+                        .help("Prints the message as it would be sent, without sending it")
+                        .long("dry-run"),
+                ),
             SubCommand::with_name("send")
                 .about("Sends a raw message")
                 .arg(Arg::with_name("message").raw(true).last(true)),
+            SubCommand::with_name("unsubscribe")
+                .about("Unsubscribes from the mailing list of a message")
+                .arg(seq_arg()),
             SubCommand::with_name("save")
                 .about("Saves a raw message")
                 .arg(Arg::with_name("message").raw(true)),
             SubCommand::with_name("read")
                 .about("Reads text bodies of a message")
                 .arg(seq_arg())
-                .arg(
-                    Arg::with_name("mime-type")
-                        .help("MIME type to use")
-                        .short("t")
-                        .long("mime-type")
-                        .value_name("MIME")
-                        .possible_values(&["plain", "html"])
-                        .default_value("plain"),
-                )
+                .arg(text_mime_arg().short("t"))
                 .arg(
                     Arg::with_name("raw")
                         .help("Reads raw message")
                         .long("raw")
                         .short("r"),
+                )
+                .arg(
+                    Arg::with_name("raw-body")
+                        .help("Reads text bodies as they are, without any sanitization")
+                        .long("raw-body")
+                        .conflicts_with("raw"),
+                )
+                .arg(
+                    Arg::with_name("urls")
+                        .help("Lists the URLs of the message, one per line")
+                        .long("urls")
+                        .conflicts_with_all(&["raw", "raw-body"]),
+                )
+                .arg(
+                    Arg::with_name("part")
+                        .help("Reads only the given text part (like 1.2) of the message")
+                        .long("part")
+                        .value_name("NUM")
+                        .conflicts_with_all(&["raw", "raw-body", "urls"]),
                 ),
             SubCommand::with_name("reply")
                 .aliases(&["rep", "r"])
                 .about("Answers to a message")
                 .arg(seq_arg())
                 .arg(reply_all_arg())
+                .arg(text_mime_arg().short("t"))
                 .arg(attachment_arg())
-		.arg(encrypt_arg()),
+                .arg(encrypt_arg())
+                .arg(sign_arg()),
             SubCommand::with_name("forward")
                 .aliases(&["fwd", "f"])
                 .about("Forwards a message")
                 .arg(seq_arg())
+                .arg(text_mime_arg().short("t"))
                 .arg(attachment_arg())
-		.arg(encrypt_arg()),
+                .arg(encrypt_arg())
+                .arg(sign_arg()),
             SubCommand::with_name("copy")
                 .aliases(&["cp", "c"])
                 .about("Copies a message to the targetted mailbox")
@@ -368,6 +452,14 @@ pub fn subcmds<'a>() -> Vec<App<'a, 'a>> {
                 .aliases(&["del", "d", "remove", "rm"])
                 .about("Deletes a message")
                 .arg(seq_arg()),
+            SubCommand::with_name("flush").about("Sends the messages queued in the outbox"),
+            SubCommand::with_name("drafts")
+                .about("Lists the local drafts, or resumes editing one of them")
+                .arg(
+                    Arg::with_name("index")
+                        .help("Specifies the index of the draft to resume, as listed")
+                        .value_name("INDEX"),
+                ),
         ],
     ]
     .concat()
@@ -0,0 +1,146 @@
+//! Message builder module.
+//!
+//! This module contains the builder used to compose a message programmatically.
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::domain::msg::{parse_addrs, Msg, Part, Parts};
+
+/// Builds a message step by step. Addresses are kept raw until [`MsgBuilder::build`], where they
+/// are parsed.
+#[derive(Debug, Default)]
+pub struct MsgBuilder {
+    subject: String,
+    from: Vec<String>,
+    to: Vec<String>,
+    cc: Vec<String>,
+    bcc: Vec<String>,
+    parts: Vec<Part>,
+    attachments: Vec<(String, Vec<u8>)>,
+}
+
+impl MsgBuilder {
+    pub fn subject<S: ToString>(mut self, subject: S) -> Self {
+        self.subject = subject.to_string();
+        self
+    }
+
+    pub fn from<S: ToString>(mut self, addr: S) -> Self {
+        self.from.push(addr.to_string());
+        self
+    }
+
+    pub fn to<S: ToString>(mut self, addr: S) -> Self {
+        self.to.push(addr.to_string());
+        self
+    }
+
+    #[cfg(test)]
+    pub fn cc<S: ToString>(mut self, addr: S) -> Self {
+        self.cc.push(addr.to_string());
+        self
+    }
+
+    #[cfg(test)]
+    pub fn bcc<S: ToString>(mut self, addr: S) -> Self {
+        self.bcc.push(addr.to_string());
+        self
+    }
+
+    pub fn text_plain<S: ToString>(mut self, content: S) -> Self {
+        self.parts.push(Part::new_text_plain(content.to_string()));
+        self
+    }
+
+    #[cfg(test)]
+    pub fn text_html<S: ToString>(mut self, content: S) -> Self {
+        self.parts.push(Part::new_text_html(content.to_string()));
+        self
+    }
+
+    #[cfg(test)]
+    pub fn attachment<S: ToString>(mut self, filename: S, content: Vec<u8>) -> Self {
+        self.attachments.push((filename.to_string(), content));
+        self
+    }
+
+    /// Builds the message. Fails if an address cannot be parsed, or if there is no recipient.
+    pub fn build(self) -> Result<Msg> {
+        let to = parse_addrs(self.to.join(", ")).context("cannot parse \"to\" addresses")?;
+        if to.is_none() {
+            return Err(anyhow!("cannot build message without recipient"));
+        }
+
+        let msg = Msg {
+            subject: self.subject,
+            from: parse_addrs(self.from.join(", ")).context("cannot parse \"from\" addresses")?,
+            to,
+            cc: parse_addrs(self.cc.join(", ")).context("cannot parse \"cc\" addresses")?,
+            bcc: parse_addrs(self.bcc.join(", ")).context("cannot parse \"bcc\" addresses")?,
+            parts: Parts(self.parts),
+            ..Msg::default()
+        };
+
+        Ok(self
+            .attachments
+            .into_iter()
+            .fold(msg, |msg, (filename, content)| {
+                msg.add_attachment_bytes(&filename, content)
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_build_msg() {
+        let msg = Msg::builder()
+            .subject("Hello")
+            .from("From <from@localhost>")
+            .to("to@localhost")
+            .to("to2@localhost")
+            .cc("cc@localhost")
+            .bcc("bcc@localhost")
+            .text_plain("Hello!")
+            .text_html("<p>Hello!</p>")
+            .attachment("notes.txt", b"notes".to_vec())
+            .build()
+            .unwrap();
+
+        assert_eq!("Hello", msg.subject);
+        assert_eq!(
+            Some(vec!["From <from@localhost>".parse().unwrap()]),
+            msg.from
+        );
+        assert_eq!(
+            Some(vec![
+                "to@localhost".parse().unwrap(),
+                "to2@localhost".parse().unwrap()
+            ]),
+            msg.to
+        );
+        assert_eq!(Some(vec!["cc@localhost".parse().unwrap()]), msg.cc);
+        assert_eq!(Some(vec!["bcc@localhost".parse().unwrap()]), msg.bcc);
+        assert_eq!("Hello!", msg.fold_text_plain_parts());
+        assert_eq!(1, msg.parts.iter_text_html().count());
+        assert_eq!("notes.txt", msg.attachments()[0].filename);
+    }
+
+    #[test]
+    fn it_should_not_build_msg_without_recipient() {
+        let err = Msg::builder()
+            .from("from@localhost")
+            .text_plain("Hello!")
+            .build()
+            .unwrap_err();
+        assert_eq!("cannot build message without recipient", err.to_string());
+
+        let err = Msg::builder().to("to").build().unwrap_err();
+        assert_eq!(
+            r#"cannot parse "to" addresses: missing domain in address "to""#,
+            format!("{:#}", err)
+        );
+    }
+}
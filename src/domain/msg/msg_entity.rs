@@ -1,29 +1,49 @@
 use ammonia;
 use anyhow::{anyhow, Context, Error, Result};
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, Local, TimeZone};
+use encoding_rs::Encoding;
 use html_escape;
 use imap::types::Flag;
-use lettre::message::{header::ContentType, Attachment, MultiPart, SinglePart};
-use log::{debug, info, trace};
+use lettre::message::{
+    header::{
+        ContentDisposition, ContentId, ContentTransferEncoding, ContentType, Header, HeaderName,
+    },
+    Body, MultiPart, MultiPartBuilder, SinglePart,
+};
+use log::{debug, info, trace, warn};
+use mailparse::MailHeaderMap;
+use once_cell::sync::Lazy;
 use regex::Regex;
 use rfc2047_decoder;
 use std::{
-    collections::HashSet,
+    borrow::Cow,
+    cell::RefCell,
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
     convert::{TryFrom, TryInto},
     env::temp_dir,
-    fmt::Debug,
+    error,
+    fmt::{self, Debug, Write},
     fs,
-    path::PathBuf,
+    io::{self, Write as _},
+    path::{Path, PathBuf},
 };
+use unicode_width::UnicodeWidthStr;
 use uuid::Uuid;
 
 use crate::{
-    config::{Account, DEFAULT_SIG_DELIM},
+    config::{
+        Account, DEFAULT_REPLY_ATTRIBUTION, DEFAULT_REPLY_DATE_FMT, DEFAULT_REPLY_QUOTE_PREFIX,
+        DEFAULT_SIG_DELIM,
+    },
     domain::{
         imap::ImapServiceInterface,
         mbox::Mbox,
-        msg::{msg_utils, BinaryPart, Flags, Part, Parts, TextPlainPart, TplOverride},
-        smtp::SmtpServiceInterface,
+        msg::{
+            msg_utils, next_binary_order, parse_unsubscribe_targets, BinaryPart, Disposition,
+            Flags, MsgBuilder, Outbox, Part, Parts, TextPlainPart, TplOverride, UnsubscribeTarget,
+        },
+        smtp::{self, SmtpServiceInterface},
     },
     output::PrinterService,
     ui::{
@@ -34,8 +54,212 @@ use crate::{
 
 type Addr = lettre::message::Mailbox;
 
+// Regexes used to fold text parts. They are compiled once, since folding is done for every
+// message of a listing.
+static NEW_LINES_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(\r?\n\s*){2,}").unwrap());
+static HTML_NEW_LINES_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(\r?\n){2,}").unwrap());
+static TABS_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\t").unwrap());
+static TABS_AND_NBSP_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(\t|&nbsp;)").unwrap());
+static SPACES_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r" {2,}").unwrap());
+// Fixed sizes (in bytes) used to estimate the size of messages, see `Msg::estimated_size`: the
+// headers that do not depend on the message (like `Date` or `MIME-Version`), and the MIME
+// headers and boundary of each part.
+const ESTIMATED_HEADERS_SIZE: usize = 200;
+const ESTIMATED_PART_OVERHEAD: usize = 150;
+
+static NO_TEXT_BODY_NOTE_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?m)^\[no text body; \d+ attachment\(s\)\]\r?(\n|$)").unwrap());
+static TPL_PLACEHOLDER_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\{\{\s*(\w+)\s*\}\}").unwrap());
+
+// Subject prefixes of replies and forwards, lowercased, including localized ones (German,
+// Scandinavian, Dutch, Polish, Italian, Portuguese).
+const REPLY_SUBJECT_PREFIXES: &[&str] = &["re", "aw", "sv", "antw", "odp", "rif", "res"];
+const FORWARD_SUBJECT_PREFIXES: &[&str] = &["fwd", "fw"];
+
+// ISO 639-1 codes of the languages known by the language detection, with their ISO 639-3
+// equivalent, used to normalize the `Content-Language` header, see `Msg::detect_language`.
+#[cfg(test)]
+const ISO_639_1_TO_3: &[(&str, &str)] = &[
+    ("af", "afr"),
+    ("ak", "aka"),
+    ("am", "amh"),
+    ("ar", "ara"),
+    ("az", "aze"),
+    ("be", "bel"),
+    ("bg", "bul"),
+    ("bn", "ben"),
+    ("ca", "cat"),
+    ("cs", "ces"),
+    ("da", "dan"),
+    ("de", "deu"),
+    ("el", "ell"),
+    ("en", "eng"),
+    ("eo", "epo"),
+    ("es", "spa"),
+    ("et", "est"),
+    ("fa", "pes"),
+    ("fi", "fin"),
+    ("fr", "fra"),
+    ("gu", "guj"),
+    ("he", "heb"),
+    ("hi", "hin"),
+    ("hr", "hrv"),
+    ("hu", "hun"),
+    ("id", "ind"),
+    ("it", "ita"),
+    ("ja", "jpn"),
+    ("jv", "jav"),
+    ("ka", "kat"),
+    ("km", "khm"),
+    ("kn", "kan"),
+    ("ko", "kor"),
+    ("la", "lat"),
+    ("lt", "lit"),
+    ("lv", "lav"),
+    ("mk", "mkd"),
+    ("ml", "mal"),
+    ("mr", "mar"),
+    ("my", "mya"),
+    ("nb", "nob"),
+    ("ne", "nep"),
+    ("nl", "nld"),
+    ("no", "nob"),
+    ("or", "ori"),
+    ("pa", "pan"),
+    ("pl", "pol"),
+    ("pt", "por"),
+    ("ro", "ron"),
+    ("ru", "rus"),
+    ("si", "sin"),
+    ("sk", "slk"),
+    ("sl", "slv"),
+    ("sn", "sna"),
+    ("sr", "srp"),
+    ("sv", "swe"),
+    ("ta", "tam"),
+    ("te", "tel"),
+    ("th", "tha"),
+    ("tk", "tuk"),
+    ("tr", "tur"),
+    ("uk", "ukr"),
+    ("ur", "urd"),
+    ("uz", "uzb"),
+    ("vi", "vie"),
+    ("yi", "yid"),
+    ("zh", "cmn"),
+    ("zu", "zul"),
+];
+
+// Regexes used to normalize subjects.
+static CONTROL_CHARS_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"[\p{Cc}\s]+").unwrap());
+static SUBJECT_PREFIX_REGEX: Lazy<Regex> = Lazy::new(|| {
+    let prefixes = [REPLY_SUBJECT_PREFIXES, FORWARD_SUBJECT_PREFIXES].concat();
+    Regex::new(&format!(
+        r"(?i)^({})\s*(\[\d+\])?\s*:\s*",
+        prefixes.join("|")
+    ))
+    .unwrap()
+});
+
+// Regexes used to extract URLs from bodies.
+static URL_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(?i)\b(?:https?://|mailto:)[^\s<>"]+"#).unwrap());
+static HREF_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(?i)\bhref\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap());
+
+/// Representation of the `X-Mailer` header, used to identify the client that built the message.
+#[derive(Debug, Clone)]
+struct XMailer(String);
+
+impl Header for XMailer {
+    fn name() -> HeaderName {
+        HeaderName::new_from_ascii_str("X-Mailer")
+    }
+
+    fn parse(s: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self(s.into()))
+    }
+
+    fn display(&self) -> String {
+        self.0.to_owned()
+    }
+}
+
+// Headers of `Msg::headers` that are not sent as they are: the ones built from the fields of the
+// message, the MIME structure ones and the `Attachment` template header.
+const UNSENT_HEADERS: &[&str] = &[
+    "from",
+    "sender",
+    "reply-to",
+    "to",
+    "cc",
+    "bcc",
+    "subject",
+    "date",
+    "message-id",
+    "in-reply-to",
+    "references",
+    "x-mailer",
+    "mime-version",
+    "attachment",
+];
+
+thread_local! {
+    // Name of the `ExtraHeader` being added to a message builder. lettre only knows headers with
+    // a static name, so it is set right before adding the header, see `with_extra_header`.
+    static EXTRA_HEADER_NAME: RefCell<Option<HeaderName>> = const { RefCell::new(None) };
+}
+
+/// Representation of a header of [`Msg::headers`] sent as it is, like `X-Priority`.
+#[derive(Debug, Clone)]
+struct ExtraHeader(String);
+
+impl Header for ExtraHeader {
+    fn name() -> HeaderName {
+        EXTRA_HEADER_NAME
+            .with(|name| name.borrow().clone())
+            .expect("extra header name should be set before adding the header")
+    }
+
+    fn parse(s: &str) -> Result<Self, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Self(s.into()))
+    }
+
+    fn display(&self) -> String {
+        self.0.to_owned()
+    }
+}
+
+/// Adds the given header to the message builder, see [`ExtraHeader`]. The lowercased name is
+/// capitalized back, like `X-Priority`.
+fn with_extra_header(
+    builder: lettre::message::MessageBuilder,
+    name: &str,
+    val: &str,
+) -> Result<lettre::message::MessageBuilder> {
+    validate_header_name(name)?;
+    let name = name
+        .split('-')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(c) => c.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("-");
+    let name = HeaderName::new_from_ascii(name.to_owned())
+        .map_err(|_| anyhow!("invalid header name {:?}", name))?;
+    EXTRA_HEADER_NAME.with(|cell| *cell.borrow_mut() = Some(name));
+    let builder = builder.header(ExtraHeader(val.to_owned()));
+    EXTRA_HEADER_NAME.with(|cell| *cell.borrow_mut() = None);
+    Ok(builder)
+}
+
 /// Representation of a message.
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct Msg {
     /// The sequence number of the message.
     ///
@@ -49,6 +273,12 @@ pub struct Msg {
     pub subject: String,
 
     pub from: Option<Vec<Addr>>,
+
+    /// The actual submitter of the message, when it differs from its author (like a secretary
+    /// sending on behalf of someone).
+    ///
+    /// [RFC5322]: https://datatracker.ietf.org/doc/html/rfc5322#section-3.6.2
+    pub sender: Option<Addr>,
     pub reply_to: Option<Vec<Addr>>,
     pub to: Option<Vec<Addr>>,
     pub cc: Option<Vec<Addr>>,
@@ -56,6 +286,11 @@ pub struct Msg {
     pub in_reply_to: Option<String>,
     pub message_id: Option<String>,
 
+    /// The message identifiers of the whole thread, from the oldest to the most recent one.
+    ///
+    /// [RFC5322]: https://datatracker.ietf.org/doc/html/rfc5322#section-3.6.4
+    pub references: Option<Vec<String>>,
+
     /// The internal date of the message.
     ///
     /// [RFC3501]: https://datatracker.ietf.org/doc/html/rfc3501#section-2.3.3
@@ -63,106 +298,562 @@ pub struct Msg {
     pub parts: Parts,
 
     pub encrypt: bool,
+    pub sign: bool,
+
+    /// All the headers of the parsed message, indexed by their lowercased name. See
+    /// [`Msg::header`].
+    pub headers: HashMap<String, Vec<String>>,
+
+    /// Prevents the default Cc and Bcc recipients of the account from being added to the
+    /// template, for example when replying to the sender only.
+    pub skip_default_recipients: bool,
 }
 
 impl Msg {
+    /// Starts building a message programmatically, see [`MsgBuilder`].
+    pub fn builder() -> MsgBuilder {
+        MsgBuilder::default()
+    }
+
+    /// Returns the (first) value of the given header. The name is case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_lowercase())
+            .and_then(|vals| vals.first())
+            .map(String::as_str)
+    }
+
+    /// Returns the ISO 639-3 code of the language of the message, like `fra`. The language
+    /// declared by the `Content-Language` header is preferred: its ISO 639-1 code (like `fr`,
+    /// without its region) is converted, ISO 639-3 codes are kept as they are. Otherwise, the
+    /// language is detected from the plain text body, when the `language-detection` feature is
+    /// enabled and the detection is reliable enough.
+    #[cfg(test)]
+    pub fn detect_language(&self) -> Option<String> {
+        let declared = self
+            .header("content-language")
+            .and_then(|langs| langs.split(',').next())
+            .and_then(|lang| lang.trim().split('-').next())
+            .map(str::to_lowercase)
+            .and_then(|lang| match lang.len() {
+                2 => ISO_639_1_TO_3
+                    .iter()
+                    .find(|(code, _)| *code == lang)
+                    .map(|(_, code)| code.to_string()),
+                3 if lang.chars().all(|c| c.is_ascii_alphabetic()) => Some(lang),
+                _ => None,
+            });
+        declared.or_else(|| self.detect_body_language())
+    }
+
+    #[cfg(all(test, feature = "language-detection"))]
+    fn detect_body_language(&self) -> Option<String> {
+        whatlang::detect(&self.fold_text_plain_parts())
+            .filter(|info| info.is_reliable())
+            .map(|info| info.lang().code().to_owned())
+    }
+
+    #[cfg(all(test, not(feature = "language-detection")))]
+    fn detect_body_language(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns the targets of the `List-Unsubscribe` header, see [`UnsubscribeTarget`].
+    pub fn unsubscribe_targets(&self) -> Vec<UnsubscribeTarget> {
+        let one_click = self
+            .header("list-unsubscribe-post")
+            .map(|val| val.eq_ignore_ascii_case("List-Unsubscribe=One-Click"))
+            .unwrap_or(false);
+        self.headers
+            .get("list-unsubscribe")
+            .into_iter()
+            .flatten()
+            .flat_map(|header| parse_unsubscribe_targets(header, one_click))
+            .collect()
+    }
+
+    /// Returns true if the message has the `\Seen` flag.
+    #[cfg(test)]
+    pub fn is_seen(&self) -> bool {
+        self.flags.contains(&Flag::Seen)
+    }
+
+    /// Returns true if the message has the `\Flagged` flag.
+    #[cfg(test)]
+    pub fn is_flagged(&self) -> bool {
+        self.flags.contains(&Flag::Flagged)
+    }
+
+    /// Returns true if the message has the `\Answered` flag.
+    #[cfg(test)]
+    pub fn is_answered(&self) -> bool {
+        self.flags.contains(&Flag::Answered)
+    }
+
+    /// Returns true if the message has the `\Draft` flag.
+    #[cfg(test)]
+    pub fn is_draft(&self) -> bool {
+        self.flags.contains(&Flag::Draft)
+    }
+
+    /// Returns true if the message has the `\Deleted` flag.
+    #[cfg(test)]
+    pub fn is_deleted(&self) -> bool {
+        self.flags.contains(&Flag::Deleted)
+    }
+
+    /// Returns the name to display for the author of the message, see [`addr_display`]. Falls
+    /// back to the sender when there is no `From` address, and to an empty string when there is
+    /// no sender either.
+    #[cfg(test)]
+    pub fn canonical_from_name(&self) -> String {
+        self.from
+            .as_ref()
+            .and_then(|addrs| addrs.first())
+            .or(self.sender.as_ref())
+            .map(addr_display)
+            .unwrap_or_default()
+    }
+
+    /// Returns true if the message is a reply: it has threading headers (`In-Reply-To` or
+    /// `References`), or its subject starts with a reply prefix like `Re:` or `AW:`.
+    #[cfg(test)]
+    pub fn is_reply(&self) -> bool {
+        self.in_reply_to.is_some()
+            || matches!(self.references.as_ref(), Some(refs) if !refs.is_empty())
+            || self.has_subject_prefix(REPLY_SUBJECT_PREFIXES)
+    }
+
+    /// Returns true if the subject of the message starts with a forward prefix like `Fwd:` or
+    /// `Fw:`.
+    #[cfg(test)]
+    pub fn is_forward(&self) -> bool {
+        self.has_subject_prefix(FORWARD_SUBJECT_PREFIXES)
+    }
+
+    /// Returns true if the first reply or forward prefix of the subject is one of the given
+    /// (lowercased) prefixes.
+    #[cfg(test)]
+    fn has_subject_prefix(&self, prefixes: &[&str]) -> bool {
+        match SUBJECT_PREFIX_REGEX.captures(self.subject.trim_start()) {
+            Some(caps) => prefixes.contains(&caps[1].to_lowercase().as_str()),
+            None => false,
+        }
+    }
+
+    /// Returns the attachments of the message. Inline parts (like images referenced from the
+    /// HTML body) are skipped, see [`Msg::all_attachments`].
     pub fn attachments(&self) -> Vec<BinaryPart> {
+        self.attachments_by_mime("*/*")
+    }
+
+    /// Returns all binary parts of the message, including inline ones.
+    pub fn all_attachments(&self) -> Vec<BinaryPart> {
+        let mut attachments: Vec<BinaryPart> = self.parts.iter_binary().cloned().collect();
+        sort_attachments(&mut attachments);
+        attachments
+    }
+
+    /// Returns attachments whose MIME type matches the given pattern. The pattern is either an
+    /// exact MIME type like `application/pdf` or a glob like `image/*`.
+    pub fn attachments_by_mime(&self, pattern: &str) -> Vec<BinaryPart> {
+        self.attachment_refs_by_mime(pattern)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Same as [`Msg::attachments_by_mime`], but borrows the attachments.
+    fn attachment_refs_by_mime(&self, pattern: &str) -> Vec<&BinaryPart> {
+        let mut attachments: Vec<&BinaryPart> = self
+            .parts
+            .iter_binary()
+            .filter(|part| {
+                part.disposition == Disposition::Attachment && mime_matches(&part.mime, pattern)
+            })
+            .collect();
+        attachments.sort_by(|a, b| cmp_attachments(a, b));
+        attachments
+    }
+
+    /// Returns the number of attachments, as listed by [`Msg::attachments`].
+    pub fn attachment_count(&self) -> usize {
+        self.attachment_refs_by_mime("*/*").len()
+    }
+
+    /// Returns the attachment at the given 0-based index, as listed by [`Msg::attachments`].
+    #[cfg(test)]
+    pub fn attachment(&self, index: usize) -> Option<&BinaryPart> {
+        self.attachment_refs_by_mime("*/*").get(index).copied()
+    }
+
+    /// Returns the attachments of the message, making sure their filenames are unique. Colliding
+    /// filenames are either renamed (`report.pdf` becomes `report (1).pdf`) or lead to an error,
+    /// depending on `rename`.
+    pub fn validate_attachments(&self, rename: bool) -> Result<Vec<BinaryPart>> {
+        let mut attachments = self.attachments();
+        let mut filenames = HashSet::new();
+
+        for i in 0..attachments.len() {
+            let filename = attachments[i].filename.to_owned();
+            if !filenames.contains(&filename) {
+                filenames.insert(filename);
+                continue;
+            }
+
+            if !rename {
+                return Err(anyhow!("duplicate attachment filename {:?}", filename));
+            }
+
+            let (stem, ext) = split_ext(&filename);
+            let unique_filename = (1..)
+                .map(|n| format!("{} ({}){}", stem, n, ext))
+                .find(|filename| {
+                    !filenames.contains(filename)
+                        && !attachments.iter().any(|part| &part.filename == filename)
+                })
+                .unwrap();
+            debug!(
+                "rename duplicate attachment {:?} to {:?}",
+                filename, unique_filename
+            );
+            filenames.insert(unique_filename.to_owned());
+            attachments[i].filename = unique_filename;
+        }
+
+        Ok(attachments)
+    }
+
+    /// Writes all binary parts of the message (including inline ones) to the given directory,
+    /// created if needed, using their [safe filename](BinaryPart::safe_filename). Existing files
+    /// are never overwritten: `report.pdf` becomes `report (1).pdf`, `report (2).pdf`… A file
+    /// that cannot be written either aborts the whole operation, or is skipped if `skip_errors`
+    /// is set. Returns the paths of the written files.
+    pub fn save_attachments_to(&self, dir: &Path, skip_errors: bool) -> Result<Vec<PathBuf>> {
+        fs::create_dir_all(dir).context(format!("cannot create directory {:?}", dir))?;
+
+        let mut paths = vec![];
+        for attachment in self.all_attachments() {
+            let filename = attachment.safe_filename();
+            debug!("downloading {}…", filename);
+            match write_new_file(dir, &filename, &attachment.content) {
+                Ok(path) => paths.push(path),
+                Err(err) => {
+                    let err = Error::new(err).context(format!(
+                        "cannot download attachment {:?}",
+                        dir.join(filename)
+                    ));
+                    if !skip_errors {
+                        return Err(err);
+                    }
+                    warn!("{:?}", err);
+                }
+            }
+        }
+
+        Ok(paths)
+    }
+
+    /// Returns the total size (in bytes) of all binary parts of the message.
+    #[cfg(test)]
+    pub fn total_attachment_size(&self) -> usize {
         self.parts
+            .iter_binary()
+            .map(|part| part.content.len())
+            .sum()
+    }
+
+    /// Returns the exact size (in bytes) of the message as it would be sent, encodings (like the
+    /// base64 of attachments) and MIME boundaries included. The sendable message is fully built,
+    /// see [`Msg::estimated_size`] for a cheaper estimation.
+    #[cfg(test)]
+    pub fn total_size(&self, account: &Account) -> Result<usize> {
+        Ok(self.formatted(account)?.len())
+    }
+
+    /// Estimates the size (in bytes) of the message as it would be sent, without building it.
+    /// Binary parts are counted base64 encoded (a third bigger, plus line breaks), and each part
+    /// gets a fixed overhead for its MIME headers and boundary.
+    pub fn estimated_size(&self) -> usize {
+        let addrs_len = |addrs: &Option<Vec<Addr>>| {
+            addrs
+                .iter()
+                .flatten()
+                .map(|addr| addr.to_string().len() + 2)
+                .sum::<usize>()
+        };
+        let headers_len = ESTIMATED_HEADERS_SIZE
+            + self.subject.len()
+            + addrs_len(&self.from)
+            + addrs_len(&self.reply_to)
+            + addrs_len(&self.to)
+            + addrs_len(&self.cc)
+            + addrs_len(&self.bcc);
+        let parts_len = self
+            .parts
             .iter()
-            .filter_map(|part| match part {
-                Part::Binary(part) => Some(part.to_owned()),
+            .map(|part| {
+                ESTIMATED_PART_OVERHEAD
+                    + match part {
+                        Part::TextPlain(part) => part.content.len(),
+                        Part::TextHtml(part) => part.content.len(),
+                        Part::Binary(part) => base64_size(part.content.len()),
+                        Part::EmbeddedMessage(part) => part.raw.len(),
+                    }
+            })
+            .sum::<usize>();
+        headers_len + parts_len
+    }
+
+    /// Returns a warning when the estimated size of the message exceeds the given maximum size
+    /// (in bytes), like the account `max-msg-size`, see [`Msg::estimated_size`].
+    pub fn size_warning(&self, max_size: Option<u64>) -> Option<String> {
+        let size = self.estimated_size() as u64;
+        match max_size {
+            Some(max_size) if size > max_size => Some(format!(
+                "Message is about {}, exceeds limit of {}",
+                format_size(size),
+                format_size(max_size)
+            )),
+            _ => None,
+        }
+    }
+
+    /// Replaces the content of the binary part matching the given filename. The MIME type is
+    /// detected again from the new content, see [`detect_mime`].
+    #[cfg(test)]
+    pub fn replace_attachment(&mut self, filename: &str, content: Vec<u8>) -> Result<()> {
+        let part = self
+            .parts
+            .iter_mut()
+            .find_map(|part| match part {
+                Part::Binary(part) if part.filename == filename => Some(part),
                 _ => None,
             })
-            .collect()
+            .ok_or_else(|| anyhow!("cannot find attachment {:?}", filename))?;
+        part.mime = detect_mime(&part.filename, &content);
+        part.content = content;
+        Ok(())
+    }
+
+    /// Removes the binary parts matching the given filename. Returns false if none was found.
+    #[cfg(test)]
+    pub fn remove_attachment(&mut self, filename: &str) -> bool {
+        let len = self.parts.len();
+        self.parts
+            .retain(|part| !matches!(part, Part::Binary(part) if part.filename == filename));
+        self.parts.len() != len
+    }
+
+    /// Normalizes the subject: control chars (like new lines, which could be used to inject
+    /// headers) and whitespace runs are replaced by single spaces, and stacked `Re:`/`Fwd:`
+    /// prefixes (localized ones included, like `AW:`) are collapsed into a single one, the first of
+    /// them (`Re: AW: Fwd: Hello` becomes `Re: Hello`).
+    pub fn normalized_subject(&self) -> String {
+        let subject = CONTROL_CHARS_REGEX.replace_all(&self.subject, " ");
+        let mut subject = subject.trim();
+        let mut prefix = None;
+        while let Some(caps) = SUBJECT_PREFIX_REGEX.captures(subject) {
+            if prefix.is_none() {
+                let is_reply = REPLY_SUBJECT_PREFIXES
+                    .iter()
+                    .any(|prefix| caps[1].eq_ignore_ascii_case(prefix));
+                prefix = Some(if is_reply { "Re" } else { "Fwd" });
+            }
+            subject = &subject[caps[0].len()..];
+        }
+
+        match prefix {
+            Some(prefix) => format!("{}: {}", prefix, subject),
+            None => subject.to_owned(),
+        }
+    }
+
+    /// Wraps the long lines of the plain text parts at the given width, between words. Quoted
+    /// lines (starting with `>`) and the signature (starting at the given delimiter) are left
+    /// untouched. Line breaks are hard ones (no `format=flowed`), so the message looks the same in
+    /// all clients.
+    pub fn wrap_body(&mut self, width: usize, sig_delim: &str) {
+        for part in self.parts.iter_mut() {
+            if let Part::TextPlain(part) = part {
+                part.content = wrap_text(&part.content, width, sig_delim.trim_end());
+            }
+        }
+    }
+
+    /// Replaces the text parts of the message by the given HTML body, alongside a plain text
+    /// alternative generated from it (HTML markup removed, whitespace merged). Binary parts are
+    /// kept.
+    #[cfg(test)]
+    pub fn set_html_body(&mut self, html: String) {
+        let plain = html_to_plain(&html);
+        self.parts
+            .retain(|part| !matches!(part, Part::TextPlain(_) | Part::TextHtml(_)));
+        self.parts.insert(0, Part::new_text_html(html));
+        self.parts.insert(0, Part::new_text_plain(plain));
     }
 
     /// Folds string body from all plain text parts into a single string body. If no plain text
     /// parts are found, HTML parts are used instead. The result is sanitized (all HTML markup is
-    /// removed).
+    /// removed, tabulations are replaced by spaces and consecutive spaces are merged into a single
+    /// one).
     pub fn fold_text_plain_parts(&self) -> String {
-        let (plain, html) = self.parts.iter().fold(
-            (String::default(), String::default()),
-            |(mut plain, mut html), part| {
-                match part {
-                    Part::TextPlain(part) => {
-                        let glue = if plain.is_empty() { "" } else { "\n\n" };
-                        plain.push_str(glue);
-                        plain.push_str(&part.content);
-                    }
-                    Part::TextHtml(part) => {
-                        let glue = if html.is_empty() { "" } else { "\n\n" };
-                        html.push_str(glue);
-                        html.push_str(&part.content);
-                    }
-                    _ => (),
-                };
-                (plain, html)
-            },
-        );
-        if plain.is_empty() {
-            // Remove HTML markup
-            let sanitized_html = ammonia::Builder::new()
-                .tags(HashSet::default())
-                .clean(&html)
-                .to_string();
-            // Merge new line chars
-            let sanitized_html = Regex::new(r"(\r?\n\s*){2,}")
-                .unwrap()
-                .replace_all(&sanitized_html, "\n\n")
-                .to_string();
-            // Replace tabulations and &npsp; by spaces
-            let sanitized_html = Regex::new(r"(\t|&nbsp;)")
-                .unwrap()
-                .replace_all(&sanitized_html, " ")
-                .to_string();
-            // Merge spaces
-            let sanitized_html = Regex::new(r" {2,}")
-                .unwrap()
-                .replace_all(&sanitized_html, "  ")
-                .to_string();
-            // Decode HTML entities
-            let sanitized_html = html_escape::decode_html_entities(&sanitized_html).to_string();
+        self.fold_text_plain_parts_with(true, DEFAULT_SIG_DELIM)
+    }
 
-            sanitized_html
+    /// Same as [`Msg::fold_text_plain_parts`], except that plain text parts are returned verbatim
+    /// when `sanitize` is false. The signature (starting at the given delimiter) is never
+    /// sanitized, so that deliberate spacing survives.
+    pub fn fold_text_plain_parts_with(&self, sanitize: bool, sig_delim: &str) -> String {
+        let glue_parts = |mut parts: String, content: &str| {
+            let glue = if parts.is_empty() { "" } else { "\n\n" };
+            parts.push_str(glue);
+            parts.push_str(content);
+            parts
+        };
+        let plain = self
+            .parts
+            .iter_text_plain()
+            .fold(String::default(), |plain, part| {
+                glue_parts(plain, &part.content)
+            });
+        let html = self
+            .parts
+            .iter_text_html()
+            .fold(String::default(), |html, part| {
+                glue_parts(html, &part.content)
+            });
+        if plain.is_empty() {
+            html_to_plain(&html)
+        } else if !sanitize {
+            plain
         } else {
+            // Split the signature from the body
+            let (plain, sig) = match find_sig_delim(&plain, sig_delim) {
+                Some(pos) => plain.split_at(pos),
+                None => (plain.as_str(), ""),
+            };
             // Merge new line chars
-            let sanitized_plain = Regex::new(r"(\r?\n\s*){2,}")
-                .unwrap()
-                .replace_all(&plain, "\n\n")
-                .to_string();
+            let sanitized_plain = NEW_LINES_REGEX.replace_all(plain, "\n\n").to_string();
             // Replace tabulations by spaces
-            let sanitized_plain = Regex::new(r"\t")
-                .unwrap()
-                .replace_all(&sanitized_plain, " ")
-                .to_string();
-            // Merge spaces
-            let sanitized_plain = Regex::new(r" {2,}")
-                .unwrap()
-                .replace_all(&sanitized_plain, "  ")
-                .to_string();
+            let sanitized_plain = TABS_REGEX.replace_all(&sanitized_plain, " ").to_string();
+            // Merge consecutive spaces into a single one
+            let sanitized_plain = SPACES_REGEX.replace_all(&sanitized_plain, " ").to_string();
+
+            sanitized_plain + sig
+        }
+    }
+
+    /// Builds a short preview of the plain text body, mostly used by listings. All whitespace
+    /// runs are merged into single spaces, and the result is truncated to `max_chars` chars
+    /// (an ellipsis is appended when truncated).
+    #[cfg(test)]
+    pub fn body_preview(&self, max_chars: usize) -> String {
+        if max_chars == 0 {
+            return String::default();
+        }
+
+        let body = self
+            .fold_text_plain_parts()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
 
-            sanitized_plain
+        match body.char_indices().nth(max_chars) {
+            Some((idx, _)) => format!("{}…", body[..idx].trim_end()),
+            None => body,
         }
     }
 
+    /// Returns the lines written by the sender in the plain text body: quoted lines (starting
+    /// with `>`), the attribution line introducing them and everything after the signature
+    /// delimiter are left out.
+    fn own_body_lines(&self, sig_delim: &str) -> Vec<String> {
+        let body = self.fold_text_plain_parts_with(false, sig_delim);
+        let lines = body
+            .lines()
+            .take_while(|line| line.trim_end() != sig_delim.trim_end())
+            .collect::<Vec<_>>();
+
+        lines
+            .iter()
+            .enumerate()
+            .filter(|(i, line)| {
+                let is_quote = line.starts_with('>');
+                let is_attribution = line.trim_end().ends_with(':')
+                    && lines
+                        .get(i + 1)
+                        .map(|next| next.starts_with('>'))
+                        .unwrap_or(false);
+                !is_quote && !is_attribution
+            })
+            .map(|(_, line)| line.to_string())
+            .collect()
+    }
+
+    /// Returns true when the sender did not write anything in the plain text body, quotes and
+    /// signature (starting at the given delimiter) excluded.
+    pub fn has_empty_body(&self, sig_delim: &str) -> bool {
+        self.own_body_lines(sig_delim)
+            .iter()
+            .all(|line| line.trim().is_empty())
+    }
+
+    /// Counts the words written by the sender in the plain text body, quotes and signature
+    /// (starting at the given delimiter) excluded.
+    #[cfg(test)]
+    pub fn word_count(&self, sig_delim: &str) -> usize {
+        self.own_body_lines(sig_delim)
+            .iter()
+            .map(|line| line.split_whitespace().count())
+            .sum()
+    }
+
     /// Fold string body from all HTML parts into a single string body.
     fn fold_text_html_parts(&self) -> String {
         let text_parts = self
             .parts
-            .iter()
-            .filter_map(|part| match part {
-                Part::TextHtml(part) => Some(part.content.to_owned()),
-                _ => None,
-            })
+            .iter_text_html()
+            .map(|part| part.content.as_str())
             .collect::<Vec<_>>()
             .join("\n\n");
-        let text_parts = Regex::new(r"(\r?\n){2,}")
-            .unwrap()
+        let text_parts = HTML_NEW_LINES_REGEX
             .replace_all(&text_parts, "\n\n")
             .to_string();
         text_parts
     }
 
+    /// Folds string body from all HTML parts into a single sanitized HTML string. Safe formatting
+    /// tags are kept, but scripts, event handler attributes (`on*`) and images are removed, which
+    /// prevents tracking pixels from leaking the user's IP.
+    #[cfg(test)]
+    pub fn sanitized_html(&self) -> String {
+        ammonia::Builder::default()
+            .rm_tags(&["img"])
+            .clean(&self.fold_text_html_parts())
+            .to_string()
+    }
+
+    /// Concatenates the content of all plain text parts exactly as they are, without any of the
+    /// transformations of [`Msg::fold_text_plain_parts`]. Parts are separated by a blank line.
+    pub fn raw_text_plain(&self) -> String {
+        self.parts
+            .iter_text_plain()
+            .map(|part| part.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+
+    /// Concatenates the content of all HTML parts exactly as they are, see
+    /// [`Msg::raw_text_plain`].
+    pub fn raw_text_html(&self) -> String {
+        self.parts
+            .iter_text_html()
+            .map(|part| part.content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+
     /// Fold string body from all text parts into a single string body. The mime allows users to
     /// choose between plain text parts and html text parts.
     pub fn fold_text_parts(&self, text_mime: &str) -> String {
@@ -173,29 +864,92 @@ impl Msg {
         }
     }
 
-    pub fn into_reply(mut self, all: bool, account: &Account) -> Result<Self> {
-        let account_addr: Addr = account.address().parse()?;
+    /// Extracts the http, https and mailto URLs of the message, from the plain text body and from
+    /// the `href` attributes of the HTML body. URLs are deduplicated and returned in the order
+    /// they first appear.
+    pub fn extract_urls(&self) -> Vec<String> {
+        let plain = self.fold_text_plain_parts();
+        let html = self.fold_text_html_parts();
+        let plain_urls = URL_REGEX
+            .find_iter(&plain)
+            .map(|m| trim_url(m.as_str()).to_owned());
+        let html_urls = HREF_REGEX
+            .captures_iter(&html)
+            .filter_map(|caps| caps.get(1).or_else(|| caps.get(2)))
+            .map(|m| html_escape::decode_html_entities(m.as_str().trim()).to_string())
+            .filter(|url| URL_REGEX.find(url).map(|m| m.start()) == Some(0));
+
+        let mut urls = HashSet::new();
+        plain_urls
+            .chain(html_urls)
+            .filter(|url| urls.insert(url.to_owned()))
+            .collect()
+    }
+
+    /// Transforms the message into a reply. The original body is quoted using the given text
+    /// mime: plain text is prefixed by `>`, HTML is wrapped into a `<blockquote>` (see
+    /// [`Msg::html_reply_quote_with`]).
+    pub fn into_reply(self, all: bool, text_mime: &str, account: &Account) -> Result<Self> {
+        self.into_reply_with(all, text_mime, account, None)
+    }
+
+    /// Transforms the message into a plain text reply quoting only the given text (like a
+    /// selection) instead of the whole body. The text is prefixed line by line like the body in
+    /// [`Msg::into_reply`], and the attribution line still comes from the original message.
+    #[cfg(test)]
+    pub fn into_reply_with_quote(self, all: bool, account: &Account, quote: &str) -> Result<Self> {
+        self.into_reply_with(all, "plain", account, Some(quote))
+    }
+
+    /// Same as [`Msg::into_reply`], quoting the given text instead of the body when some.
+    fn into_reply_with(
+        mut self,
+        all: bool,
+        text_mime: &str,
+        account: &Account,
+        quote: Option<&str>,
+    ) -> Result<Self> {
+        let account_addr = account.validated_address()?;
+        let prev_message_id = self.message_id.to_owned();
+
+        // References
+        if let Some(id) = prev_message_id.as_ref() {
+            let mut references = self
+                .references
+                .take()
+                .or_else(|| self.in_reply_to.clone().map(|id| vec![id]))
+                .unwrap_or_default();
+            references.push(id.to_owned());
+            self.references = Some(references);
+        }
 
         // Message-Id
         self.message_id = None;
 
         // In-Reply-To
-        self.in_reply_to = self.message_id.to_owned();
+        self.in_reply_to = prev_message_id;
+
+        // Date
+        let prev_date = self.date.take();
 
         // From
-        self.from = Some(vec![account_addr.to_owned()]);
+        let prev_from = self.from.replace(vec![account_addr.to_owned()]);
+
+        // Sender
+        self.sender = None;
 
         // To
-        let addrs = self
-            .reply_to
-            .as_ref()
-            .or_else(|| self.from.as_ref())
-            .map(|addrs| {
-                addrs
-                    .clone()
-                    .into_iter()
-                    .filter(|addr| addr != &account_addr)
-            });
+        let prev_senders = if account.reply_to_sender {
+            prev_from.or_else(|| self.reply_to.clone())
+        } else {
+            self.reply_to.to_owned().or(prev_from)
+        };
+        let addrs = prev_senders.as_ref().map(|addrs| {
+            addrs
+                .clone()
+                .into_iter()
+                .filter(|addr| addr != &account_addr)
+        });
         if all {
             self.to = addrs.map(|addrs| addrs.collect());
         } else {
@@ -208,58 +962,199 @@ impl Msg {
         if !all {
             self.cc = None;
             self.bcc = None;
+            self.skip_default_recipients = true;
         }
 
         // Subject
-        if !self.subject.starts_with("Re:") {
-            self.subject = format!("Re: {}", self.subject);
-        }
+        let subject = self.normalized_subject();
+        self.subject = if subject.starts_with("Re: ") {
+            subject
+        } else {
+            format!("Re: {}", subject)
+        };
 
         // Body
-        let plain_content = {
-            let date = self
-                .date
-                .as_ref()
-                .map(|date| date.format("%d %b %Y, at %H:%M").to_string())
-                .unwrap_or_else(|| "unknown date".into());
-            let sender = self
-                .reply_to
+        let attribution = {
+            let date_fmt = account
+                .reply_date_fmt
+                .as_deref()
+                .unwrap_or(DEFAULT_REPLY_DATE_FMT);
+            let date = match prev_date.as_ref() {
+                Some(date) => {
+                    let offset = if account.reply_date_local {
+                        Some(Local.offset_from_utc_datetime(&date.naive_utc()))
+                    } else {
+                        None
+                    };
+                    format_date(date, date_fmt, offset)?
+                }
+                None => "unknown date".into(),
+            };
+            let sender = prev_senders
                 .as_ref()
-                .or_else(|| self.from.as_ref())
                 .and_then(|addrs| addrs.first())
-                .map(|addr| {
-                    addr.name
-                        .to_owned()
-                        .unwrap_or_else(|| addr.email.to_string())
-                })
+                .map(addr_display)
                 .unwrap_or_else(|| "unknown sender".into());
-            let mut content = format!("\n\nOn {}, {} wrote:\n", date, sender);
+            account
+                .reply_attribution
+                .as_deref()
+                .unwrap_or(DEFAULT_REPLY_ATTRIBUTION)
+                .replace("{date}", &date)
+                .replace("{sender}", &sender)
+        };
 
-            let mut glue = "";
-            for line in self.fold_text_parts("plain").trim().lines() {
-                if line == DEFAULT_SIG_DELIM {
-                    break;
-                }
-                content.push_str(glue);
-                content.push('>');
-                content.push_str(if line.starts_with('>') { "" } else { " " });
-                content.push_str(line);
-                glue = "\n";
-            }
+        let sig_delim = account.sig_delim_line();
+
+        if text_mime == "html" {
+            let content = self.html_reply_quote_with(
+                &attribution,
+                Some(sig_delim),
+                account.reply_strip_all_sigs,
+            );
+            self.parts = Parts(vec![Part::new_text_html(content)]);
+        } else {
+            let prefix = account
+                .reply_quote_prefix
+                .as_deref()
+                .unwrap_or(DEFAULT_REPLY_QUOTE_PREFIX);
+            // The given quote is a selection made by the user, signatures are kept as they are
+            let quote = match quote {
+                Some(quote) => quote_lines(quote.trim(), prefix, None, false),
+                None => self.quote_body_with(
+                    prefix,
+                    "plain",
+                    Some(sig_delim),
+                    account.reply_strip_all_sigs,
+                ),
+            };
+            let content = format!("\n\n{}\n{}", attribution, quote);
+            self.parts = Parts(vec![Part::new_text_plain(content)]);
+        }
+
+        Ok(self)
+    }
+
+    /// Transforms the message into a reply to its mailing list: same as [`Msg::into_reply`], except
+    /// that the reply is only sent to the address of the `List-Post` header. Fails when the
+    /// message has no such header, or when posting to the list is not allowed.
+    #[cfg(test)]
+    pub fn into_reply_list(self, text_mime: &str, account: &Account) -> Result<Self> {
+        let list_addr = self.list_post_addr()?;
+        let mut reply = self.into_reply(false, text_mime, account)?;
+        reply.to = Some(vec![list_addr]);
+        reply.cc = None;
+        reply.bcc = None;
+        Ok(reply)
+    }
+
+    /// Returns the first mailto address of the `List-Post` header ([RFC2369]), like
+    /// `<mailto:list@localhost>`.
+    ///
+    /// [RFC2369]: https://datatracker.ietf.org/doc/html/rfc2369#section-3.4
+    #[cfg(test)]
+    fn list_post_addr(&self) -> Result<Addr> {
+        let header = self
+            .header("list-post")
+            .ok_or_else(|| anyhow!("cannot reply to list: cannot find List-Post header"))?;
+        // Lists that do not allow posting use `NO`, optionally followed by a comment
+        let first_word = header.split_whitespace().next().unwrap_or_default();
+        if first_word.eq_ignore_ascii_case("NO") {
+            return Err(anyhow!("cannot reply to list: posting is not allowed"));
+        }
+        header
+            .split('<')
+            .skip(1)
+            .filter_map(|target| target.split_once('>'))
+            .filter_map(|(target, _)| url::Url::parse(target.trim()).ok())
+            .filter(|url| url.scheme() == "mailto")
+            .find_map(|url| parse_addr(url.path()).ok())
+            .ok_or_else(|| {
+                anyhow!(
+                    "cannot reply to list: cannot find mailto address in List-Post header {:?}",
+                    header
+                )
+            })
+    }
+
+    /// Same as [`Msg::html_reply_quote_with`], with the default signature options.
+    #[cfg(test)]
+    pub fn html_reply_quote(&self, attribution: &str) -> String {
+        self.html_reply_quote_with(attribution, Some(DEFAULT_SIG_DELIM.trim_end()), false)
+    }
 
-            content
+    /// Builds the HTML quoted block of a reply: the given attribution line in a `<div>`, followed
+    /// by the original body in a `<blockquote type="cite">`. The HTML body is used as it is when
+    /// the message has one, otherwise the plain text body is escaped and its quoted lines are
+    /// turned into nested blockquotes. The signature is left out, see the signature options of
+    /// [`Msg::quote_body_with`].
+    fn html_reply_quote_with(
+        &self,
+        attribution: &str,
+        sig_delim: Option<&str>,
+        strip_all_sigs: bool,
+    ) -> String {
+        let html = self.fold_text_html_parts();
+        let quote = if html.trim().is_empty() {
+            plain_to_html(&self.quote_body_with("", "plain", sig_delim, strip_all_sigs))
+        } else {
+            html.trim().to_owned()
         };
+        format!(
+            "<div>{}</div>\n<blockquote type=\"cite\">\n{}\n</blockquote>",
+            html_escape::encode_text(attribution),
+            quote
+        )
+    }
 
-        self.parts = Parts(vec![Part::new_text_plain(plain_content)]);
+    /// Builds a quoted block from the body of the message, using the given text mime: plain text
+    /// lines are prefixed by `prefix` and the signature (starting at the given delimiter) is left
+    /// out, HTML is wrapped into a `<blockquote>`.
+    #[cfg(test)]
+    pub fn quote_body(&self, prefix: &str, text_mime: &str, sig_delim: &str) -> String {
+        self.quote_body_with(prefix, text_mime, Some(sig_delim.trim_end()), false)
+    }
 
-        Ok(self)
+    /// Builds a quoted block from the body of the message, using the given text mime: plain text
+    /// lines are prefixed by `prefix` and only cut at the given signature delimiter (if any), HTML
+    /// is wrapped into a `<blockquote>`. When `strip_all_sigs` is true, the body is not cut at the
+    /// first delimiter: every signature block (quoted or not) is removed, from its delimiter to
+    /// the next blank line.
+    fn quote_body_with(
+        &self,
+        prefix: &str,
+        text_mime: &str,
+        sig_delim: Option<&str>,
+        strip_all_sigs: bool,
+    ) -> String {
+        if text_mime == "html" {
+            return format!(
+                "<blockquote>\n{}\n</blockquote>",
+                self.fold_text_html_parts().trim()
+            );
+        }
+
+        quote_lines(
+            self.fold_text_plain_parts_with(true, sig_delim.unwrap_or(DEFAULT_SIG_DELIM))
+                .trim(),
+            prefix,
+            sig_delim,
+            strip_all_sigs,
+        )
     }
 
-    pub fn into_forward(mut self, account: &Account) -> Result<Self> {
-        let account_addr: Addr = account.address().parse()?;
+    /// Transforms the message into a forward. When the text mime is HTML, the original HTML body
+    /// is forwarded as well. When `with_attachments` is true, all binary parts of the original
+    /// message (including inline ones) are re-attached, otherwise they are dropped.
+    pub fn into_forward(
+        mut self,
+        with_attachments: bool,
+        text_mime: &str,
+        account: &Account,
+    ) -> Result<Self> {
+        let account_addr = account.validated_address()?;
 
         let prev_subject = self.subject.to_owned();
-        let prev_date = self.date.to_owned();
+        let prev_date = self.date.take();
         let prev_from = self.reply_to.to_owned().or_else(|| self.from.to_owned());
         let prev_to = self.to.to_owned();
 
@@ -269,9 +1164,15 @@ impl Msg {
         // In-Reply-To
         self.in_reply_to = None;
 
+        // References
+        self.references = None;
+
         // From
         self.from = Some(vec![account_addr]);
 
+        // Sender
+        self.sender = None;
+
         // To
         self.to = Some(vec![]);
 
@@ -282,13 +1183,16 @@ impl Msg {
         self.bcc = None;
 
         // Subject
-        if !self.subject.starts_with("Fwd:") {
-            self.subject = format!("Fwd: {}", self.subject);
-        }
+        let subject = self.normalized_subject();
+        self.subject = if subject.starts_with("Fwd: ") {
+            subject
+        } else {
+            format!("Fwd: {}", subject)
+        };
 
         // Body
         let mut content = String::default();
-        content.push_str("\n\n-------- Forwarded Message --------\n");
+        content.push_str("-------- Forwarded Message --------\n");
         content.push_str(&format!("Subject: {}\n", prev_subject));
         if let Some(date) = prev_date {
             content.push_str(&format!("Date: {}\n", date.to_rfc2822()));
@@ -314,17 +1218,155 @@ impl Msg {
             content.push('\n');
         }
         content.push('\n');
-        content.push_str(&self.fold_text_parts("plain"));
-        self.parts
-            .replace_text_plain_parts_with(TextPlainPart { content });
+        // The forwarded body is only quoted when a forward quote prefix is configured
+        let plain = match account.forward_quote_prefix.as_deref() {
+            Some(prefix) => self.quote_body_with(prefix, "plain", None, false),
+            None => self.fold_text_parts("plain"),
+        };
+
+        let html = if text_mime == "html" {
+            self.fold_text_html_parts()
+        } else {
+            String::default()
+        };
+        if !html.trim().is_empty() {
+            let html = match account.forward_quote_prefix {
+                Some(_) => self.quote_body_with("", "html", None, false),
+                None => html.trim().to_owned(),
+            };
+            let html = format!(
+                "<p>{}</p>\n{}",
+                html_escape::encode_text(&content).replace('\n', "<br>\n"),
+                html
+            );
+            self.parts.retain(|part| !matches!(part, Part::TextHtml(_)));
+            self.parts.push(Part::new_text_html(html));
+        }
+
+        content.push_str(&plain);
+        self.parts.replace_text_plain_parts_with(TextPlainPart {
+            content: format!("\n\n{}", content),
+        });
+
+        // Attachments
+        if with_attachments {
+            for part in self.parts.iter_mut() {
+                if let Part::Binary(part) = part {
+                    part.disposition = Disposition::Attachment;
+                }
+            }
+        } else {
+            self.parts.retain(|part| !matches!(part, Part::Binary(_)));
+        }
 
         Ok(self)
     }
 
-    fn _edit_with_editor(&self, account: &Account) -> Result<Self> {
-        let tpl = self.to_tpl(TplOverride::default(), account);
-        let tpl = editor::open_with_tpl(tpl)?;
-        Self::from_tpl(&tpl)
+    /// Builds a message from a FETCH response without requiring its body, which is enough for
+    /// listings. Parts are left empty and references unset, since they come from the body. See
+    /// the `TryFrom<(&Account, &Fetch)>` implementation for the full message.
+    pub fn from_fetch_envelope(_account: &Account, fetch: &imap::types::Fetch) -> Result<Self> {
+        let envelope = fetch
+            .envelope()
+            .ok_or_else(|| anyhow!("cannot get envelope of message {}", fetch.message))?;
+
+        // Get the sequence number
+        let id = fetch.message;
+
+        // Get the flags
+        let flags = Flags::try_from(fetch.flags())?;
+
+        // Get the subject
+        let subject = envelope
+            .subject
+            .as_ref()
+            .map(|subj| {
+                rfc2047_decoder::decode(subj).context(format!(
+                    "cannot decode subject of message {}",
+                    fetch.message
+                ))
+            })
+            .unwrap_or_else(|| Ok(String::default()))?;
+
+        // Get the author(s) address(es)
+        let from = match envelope
+            .from
+            .as_deref()
+            .or_else(|| envelope.sender.as_deref())
+            .map(to_addrs)
+        {
+            Some(addrs) => Some(addrs?),
+            None => None,
+        };
+
+        // Get the sender address, servers fill it with the author when the header is missing
+        let sender = match envelope.sender.as_deref().map(to_addrs) {
+            Some(addrs) => distinct_sender(addrs?.into_iter().next(), &from),
+            None => None,
+        };
+
+        // Get the "Reply-To" address(es)
+        let reply_to = to_some_addrs(&envelope.reply_to).context(format!(
+            r#"cannot parse "reply to" address of message {}"#,
+            id
+        ))?;
+
+        // Get the recipient(s) address(es)
+        let to = to_some_addrs(&envelope.to)
+            .context(format!(r#"cannot parse "to" address of message {}"#, id))?;
+
+        // Get the "Cc" recipient(s) address(es)
+        let cc = to_some_addrs(&envelope.cc)
+            .context(format!(r#"cannot parse "cc" address of message {}"#, id))?;
+
+        // Get the "Bcc" recipient(s) address(es)
+        let bcc = to_some_addrs(&envelope.bcc)
+            .context(format!(r#"cannot parse "bcc" address of message {}"#, id))?;
+
+        // Get the "In-Reply-To" message identifier
+        let in_reply_to = match envelope
+            .in_reply_to
+            .as_ref()
+            .map(|cow| String::from_utf8(cow.to_vec()))
+        {
+            Some(id) => Some(id?),
+            None => None,
+        };
+
+        // Get the message identifier
+        let message_id = match envelope
+            .message_id
+            .as_ref()
+            .map(|cow| String::from_utf8(cow.to_vec()))
+        {
+            Some(id) => Some(id?),
+            None => None,
+        };
+
+        // Get the internal date
+        let date = fetch.internal_date();
+
+        Ok(Self {
+            id,
+            flags,
+            subject,
+            from,
+            sender,
+            reply_to,
+            to,
+            cc,
+            bcc,
+            in_reply_to,
+            message_id,
+            date,
+            ..Self::default()
+        })
+    }
+
+    fn _edit_with_editor(&self, account: &Account, draft: &Path) -> Result<Self> {
+        let tpl = self.to_tpl(TplOverride::default(), account)?;
+        let tpl = editor::open_with_tpl(account.editor.as_deref(), draft, tpl)?;
+        Self::from_tpl_with(&tpl, account.max_attachment_size)
     }
 
     pub fn edit_with_editor<
@@ -341,18 +1383,27 @@ impl Msg {
     ) -> Result<()> {
         info!("start editing with editor");
 
-        let draft = msg_utils::local_draft_path();
+        let draft = msg_utils::local_draft_path(
+            &account.name,
+            &msg_utils::local_draft_id(self.message_id.as_deref()),
+        );
         if draft.exists() {
             loop {
                 match choice::pre_edit() {
                     Ok(choice) => match choice {
                         PreEditChoice::Edit => {
-                            let tpl = editor::open_with_draft()?;
-                            self.merge_with(Msg::from_tpl(&tpl)?);
+                            let tpl = editor::open_with_draft(account.editor.as_deref(), &draft)?;
+                            self.merge_with(Msg::from_tpl_with(&tpl, account.max_attachment_size)?);
                             break;
                         }
                         PreEditChoice::Discard => {
-                            self.merge_with(self._edit_with_editor(account)?);
+                            let msg = self._edit_with_editor(account, &draft)?;
+                            if self.content_eq(&msg, account.sig_delim_line()) {
+                                msg_utils::remove_local_draft(&draft)?;
+                                printer.print("Message unchanged, discarded")?;
+                                return Ok(());
+                            }
+                            self.merge_with(msg);
                             break;
                         }
                         PreEditChoice::Quit => return Ok(()),
@@ -364,22 +1415,81 @@ impl Msg {
                 }
             }
         } else {
-            self.merge_with(self._edit_with_editor(account)?);
+            let msg = self._edit_with_editor(account, &draft)?;
+            if self.content_eq(&msg, account.sig_delim_line()) {
+                msg_utils::remove_local_draft(&draft)?;
+                printer.print("Message unchanged, discarded")?;
+                return Ok(());
+            }
+            self.merge_with(msg);
         }
 
+        self.post_edit(&draft, account, printer, imap, smtp)
+    }
+
+    /// Resumes editing the given local draft, then proceeds like [`Msg::edit_with_editor`].
+    pub fn resume_with_editor<
+        'a,
+        Printer: PrinterService,
+        ImapService: ImapServiceInterface<'a>,
+        SmtpService: SmtpServiceInterface,
+    >(
+        draft: &Path,
+        account: &Account,
+        printer: &mut Printer,
+        imap: &mut ImapService,
+        smtp: &mut SmtpService,
+    ) -> Result<()> {
+        info!("resume editing draft {:?}", draft);
+        let tpl = editor::open_with_draft(account.editor.as_deref(), draft)?;
+        Msg::from_tpl_with(&tpl, account.max_attachment_size)?
+            .post_edit(draft, account, printer, imap, smtp)
+    }
+
+    /// Asks what to do with the edited message, until it is sent, saved or discarded.
+    fn post_edit<
+        'a,
+        Printer: PrinterService,
+        ImapService: ImapServiceInterface<'a>,
+        SmtpService: SmtpServiceInterface,
+    >(
+        mut self,
+        draft: &Path,
+        account: &Account,
+        printer: &mut Printer,
+        imap: &mut ImapService,
+        smtp: &mut SmtpService,
+    ) -> Result<()> {
         loop {
             match choice::post_edit() {
                 Ok(PostEditChoice::Send) => {
+                    if self.has_empty_body(account.sig_delim_line())
+                        && !choice::confirm("Body is empty, send anyway?")?
+                    {
+                        continue;
+                    }
+                    if let Some(warning) = self.size_warning(account.max_msg_size) {
+                        if !choice::confirm(&format!("{}, send anyway?", warning))? {
+                            continue;
+                        }
+                    }
                     let mbox = Mbox::new(&account.sent_folder);
-                    let sent_msg = smtp.send_msg(account, &self)?;
+                    let sent_msg =
+                        smtp::send_with_retry(account, printer, || smtp.send_msg(account, &self))?;
                     let flags = Flags::try_from(vec![Flag::Seen])?;
                     imap.append_raw_msg_with_flags(&mbox, &sent_msg.formatted(), flags)?;
-                    msg_utils::remove_local_draft()?;
+                    msg_utils::remove_local_draft(draft)?;
                     printer.print("Message successfully sent")?;
                     break;
                 }
+                Ok(PostEditChoice::Queue) => {
+                    self.enqueue(account)?;
+                    msg_utils::remove_local_draft(draft)?;
+                    printer.print("Message successfully queued")?;
+                    break;
+                }
                 Ok(PostEditChoice::Edit) => {
-                    self.merge_with(self._edit_with_editor(account)?);
+                    self.merge_with(self._edit_with_editor(account, draft)?);
                     continue;
                 }
                 Ok(PostEditChoice::LocalDraft) => {
@@ -389,9 +1499,9 @@ impl Msg {
                 Ok(PostEditChoice::RemoteDraft) => {
                     let mbox = Mbox::new(&account.draft_folder);
                     let flags = Flags::try_from(vec![Flag::Seen, Flag::Draft])?;
-                    let tpl = self.to_tpl(TplOverride::default(), account);
+                    let tpl = self.to_tpl(TplOverride::default(), account)?;
                     imap.append_raw_msg_with_flags(&mbox, tpl.as_bytes(), flags)?;
-                    msg_utils::remove_local_draft()?;
+                    msg_utils::remove_local_draft(draft)?;
                     printer.print(format!(
                         "Message successfully saved to {}",
                         account.draft_folder
@@ -399,7 +1509,7 @@ impl Msg {
                     break;
                 }
                 Ok(PostEditChoice::Discard) => {
-                    msg_utils::remove_local_draft()?;
+                    msg_utils::remove_local_draft(draft)?;
                     break;
                 }
                 Err(err) => {
@@ -417,34 +1527,84 @@ impl Msg {
         self
     }
 
-    pub fn add_attachments(mut self, attachments_paths: Vec<&str>) -> Result<Self> {
+    pub fn sign(mut self, sign: bool) -> Self {
+        self.sign = sign;
+        self
+    }
+
+    /// Adds an image displayed within the HTML body, where it is referenced by `cid:<cid>` URLs.
+    /// Inline images are sent in a `multipart/related` part, next to the HTML body.
+    #[cfg(test)]
+    pub fn add_inline_image(&mut self, cid: &str, bytes: Vec<u8>, mime: &str) {
+        let order = next_binary_order(&self.parts);
+        self.parts.push(Part::Binary(BinaryPart {
+            filename: cid.to_owned(),
+            mime: mime.to_owned(),
+            content: bytes,
+            disposition: Disposition::Inline,
+            order,
+            content_id: Some(cid.to_owned()),
+        }));
+    }
+
+    /// Adds the given files as attachments. Files bigger than the account's maximum attachment
+    /// size are rejected before being read.
+    pub fn add_attachments(
+        mut self,
+        attachments_paths: Vec<&str>,
+        account: &Account,
+    ) -> Result<Self> {
         for path in attachments_paths {
-            let path = shellexpand::full(path)
-                .context(format!(r#"cannot expand attachment path "{}""#, path))?;
-            let path = PathBuf::from(path.to_string());
-            let filename: String = path
-                .file_name()
-                .ok_or_else(|| anyhow!("cannot get file name of attachment {:?}", path))?
-                .to_string_lossy()
-                .into();
-            let content = fs::read(&path).context(format!("cannot read attachment {:?}", path))?;
-            let mime = tree_magic::from_u8(&content);
-
-            self.parts.push(Part::Binary(BinaryPart {
-                filename,
-                mime,
-                content,
-            }))
+            self = self.add_attachment_with_mime(path, None, account)?;
         }
 
         Ok(self)
     }
 
+    /// Adds the given file as an attachment, like [`Msg::add_attachments`]. The MIME type is
+    /// forced to the given one when set, instead of being detected.
+    pub fn add_attachment_with_mime(
+        mut self,
+        path: &str,
+        mime_override: Option<&str>,
+        account: &Account,
+    ) -> Result<Self> {
+        let order = next_binary_order(&self.parts);
+        let mut part = read_attachment(path, order, account.max_attachment_size)?;
+        if let Some(mime) = mime_override {
+            ContentType::parse(mime)
+                .map_err(|err| anyhow!(err))
+                .context(format!("cannot parse content type {:?}", mime))?;
+            part.mime = mime.to_owned();
+        }
+        self.parts.push(Part::Binary(part));
+        Ok(self)
+    }
+
+    /// Adds an attachment from in-memory content, without touching the disk.
+    pub fn add_attachment_bytes(mut self, filename: &str, content: Vec<u8>) -> Self {
+        let order = next_binary_order(&self.parts);
+        self.parts.push(Part::Binary(build_attachment(
+            filename.to_owned(),
+            content,
+            order,
+        )));
+        self
+    }
+
     pub fn merge_with(&mut self, msg: Msg) {
         if msg.from.is_some() {
             self.from = msg.from;
         }
 
+        if msg.sender.is_some() {
+            self.sender = msg.sender;
+        }
+
+        if msg.reply_to.is_some() {
+            self.reply_to = msg.reply_to;
+        }
+
         if msg.to.is_some() {
             self.to = msg.to;
         }
@@ -461,9 +1621,35 @@ impl Msg {
             self.subject = msg.subject;
         }
 
-        for part in msg.parts.0.into_iter() {
+        if msg.in_reply_to.is_some() {
+            self.in_reply_to = msg.in_reply_to;
+        }
+
+        if msg.message_id.is_some() {
+            self.message_id = msg.message_id;
+        }
+
+        if msg.references.is_some() {
+            self.references = msg.references;
+        }
+
+        if msg.date.is_some() {
+            self.date = msg.date;
+        }
+
+        // The headers of the template are the ones the user kept, the other headers of the
+        // original message (like `Received` or `DKIM-Signature`) must not be sent again
+        self.headers = msg.headers;
+
+        // Merged binary parts come after the existing ones
+        let order_offset = next_binary_order(&self.parts);
+        for mut part in msg.parts.0.into_iter() {
             match part {
-                Part::Binary(_) => self.parts.push(part),
+                Part::Binary(ref mut binary_part) => {
+                    binary_part.order += order_offset;
+                    self.parts.push(part)
+                }
+                Part::EmbeddedMessage(_) => self.parts.push(part),
                 Part::TextPlain(_) => {
                     self.parts.retain(|p| !matches!(p, Part::TextPlain(_)));
                     self.parts.push(part);
@@ -476,99 +1662,286 @@ impl Msg {
         }
     }
 
-    pub fn to_tpl(&self, opts: TplOverride, account: &Account) -> String {
+    /// Checks if the given message has the same content as this one, which is the case when they
+    /// have the same subject, recipients, plain text body, attachments and encryption and
+    /// signature flags. The order of the recipients, the signature (starting at the given
+    /// delimiter) and the trailing whitespaces of the body are ignored, as well as all the other
+    /// headers. It is mostly used to detect if a message was changed by the editor.
+    pub fn content_eq<'a>(&'a self, other: &'a Msg, sig_delim: &str) -> bool {
+        let addrs = |addrs: &Option<Vec<Addr>>| {
+            let mut addrs: Vec<String> = addrs
+                .iter()
+                .flatten()
+                .map(|addr| addr.to_string())
+                .collect();
+            addrs.sort();
+            addrs
+        };
+        let body = |msg: &Msg| {
+            let body = msg.fold_text_plain_parts_with(false, sig_delim);
+            let body = match find_sig_delim(&body, sig_delim) {
+                Some(pos) => &body[..pos],
+                None => &body,
+            };
+            body.lines()
+                .map(|line| line.trim_end())
+                .collect::<Vec<_>>()
+                .join("\n")
+                .trim_end()
+                .to_owned()
+        };
+        let attachments = |msg: &'a Msg| {
+            msg.parts
+                .iter_binary()
+                .map(|part| (&part.filename, &part.mime, &part.content))
+                .collect::<Vec<_>>()
+        };
+
+        self.subject.trim() == other.subject.trim()
+            && addrs(&self.to) == addrs(&other.to)
+            && addrs(&self.cc) == addrs(&other.cc)
+            && addrs(&self.bcc) == addrs(&other.bcc)
+            && body(self) == body(other)
+            && attachments(self) == attachments(other)
+            && self.encrypt == other.encrypt
+            && self.sign == other.sign
+    }
+
+    /// Builds the template of the message, as shown in the editor. When a template file is given
+    /// (with [`TplOverride::template_path`] or the account `template`), it is used as a skeleton:
+    /// its headers replace the `From`, `To` and `Subject` ones, and its placeholders are filled
+    /// in, see [`render_tpl_file`].
+    pub fn to_tpl(&self, opts: TplOverride, account: &Account) -> Result<String> {
+        let mut body = String::default();
+
+        if let Some(opts_body) = opts.body {
+            body.push_str(opts_body);
+        } else {
+            body.push_str(
+                &self.fold_text_plain_parts_with(
+                    !account.keep_blank_lines,
+                    account.sig_delim_line(),
+                ),
+            )
+        }
+
+        // Messages without text part (attachments only) get a note instead of an empty body,
+        // removed when parsing the template back
+        let has_text_parts = self
+            .parts
+            .iter()
+            .any(|part| matches!(part, Part::TextPlain(_) | Part::TextHtml(_)));
+        let attachment_count = self.attachment_count();
+        if opts.body.is_none()
+            && !account.keep_empty_body
+            && !has_text_parts
+            && attachment_count > 0
+        {
+            body.push_str(&format!(
+                "[no text body; {} attachment(s)]",
+                attachment_count
+            ));
+        }
+
+        // Signature
+        let sig = match (opts.sig, opts.sig_name) {
+            (Some(sig), _) => Some(sig),
+            (None, Some(name)) => Some(account.find_sig(name)?.as_str()),
+            (None, None) => account.sig.as_deref(),
+        };
+
+        let join_addrs = |addrs: &Option<Vec<Addr>>| {
+            addrs.as_ref().map(|addrs| {
+                addrs
+                    .iter()
+                    .map(|addr| addr.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            })
+        };
+        let from = opts
+            .from
+            .map(|addrs| addrs.join(", "))
+            .unwrap_or_else(|| account.address());
+        let reply_to = opts
+            .reply_to
+            .map(|addrs| addrs.join(", "))
+            .or_else(|| join_addrs(&self.reply_to));
+        let to = opts
+            .to
+            .map(|addrs| addrs.join(", "))
+            .or_else(|| join_addrs(&self.to))
+            .unwrap_or_default();
+        let cc = opts
+            .cc
+            .map(|addrs| addrs.join(", "))
+            .or_else(|| join_addrs(&self.cc));
+        let bcc = opts
+            .bcc
+            .map(|addrs| addrs.join(", "))
+            .or_else(|| join_addrs(&self.bcc));
+        let (cc, bcc) = if self.skip_default_recipients {
+            (cc, bcc)
+        } else {
+            (
+                merge_default_addrs(cc, account.default_cc.as_ref()),
+                merge_default_addrs(bcc, account.default_bcc.as_ref()),
+            )
+        };
+        let subject = opts.subject.unwrap_or(&self.subject);
+
+        // Template file
+        let tpl_path = opts
+            .template_path
+            .map(PathBuf::from)
+            .or_else(|| account.template.to_owned());
+        let (tpl_headers, body) = match tpl_path {
+            Some(path) => {
+                debug!("template file: {:?}", path);
+                let tpl_file = fs::read_to_string(&path)
+                    .context(format!("cannot read template file {:?}", path))?
+                    .replace("\r\n", "\n");
+                let rendered = render_tpl_file(
+                    &tpl_file,
+                    &[
+                        ("from", &from),
+                        ("to", &to),
+                        ("subject", subject),
+                        ("body", &body),
+                        ("signature", sig.unwrap_or_default()),
+                    ],
+                    account.template_strict,
+                )?;
+                let (headers, mut body) = match rendered.split_once("\n\n") {
+                    Some((headers, body)) => (format!("{}\n", headers), body.to_owned()),
+                    None => (rendered, String::default()),
+                };
+                if !body.ends_with('\n') {
+                    body.push('\n');
+                }
+                (Some(headers), body)
+            }
+            None => {
+                if let Some(sig) = sig {
+                    body.push_str("\n\n");
+                    body.push_str(sig);
+                }
+                body.push('\n');
+                (None, body)
+            }
+        };
+
+        // Plain ASCII bodies with reasonable lines are kept as they are (7bit), so they stay
+        // readable from the editor
+        let is_qp = !body.is_ascii() || body.lines().any(|line| line.len() > 998);
+
         let mut tpl = String::default();
 
         tpl.push_str("Content-Type: text/plain; charset=utf-8\n");
 
+        if is_qp {
+            tpl.push_str("Content-Transfer-Encoding: quoted-printable\n");
+        }
+
+        if let Some(message_id) = self.message_id.as_ref() {
+            tpl.push_str(&format!("Message-ID: {}\n", message_id))
+        }
+
         if let Some(in_reply_to) = self.in_reply_to.as_ref() {
             tpl.push_str(&format!("In-Reply-To: {}\n", in_reply_to))
         }
 
+        if let Some(references) = self.references.as_ref() {
+            tpl.push_str(&format!("References: {}\n", references.join(" ")))
+        }
+
+        if let Some(date) = self.date.as_ref() {
+            tpl.push_str(&format!("Date: {}\n", date.to_rfc2822()))
+        }
+
+        if !account.mailer.is_empty() {
+            tpl.push_str(&format!("X-Mailer: {}\n", account.mailer))
+        }
+
         // From
-        tpl.push_str(&format!(
-            "From: {}\n",
-            opts.from
-                .map(|addrs| addrs.join(", "))
-                .unwrap_or_else(|| account.address())
-        ));
+        if tpl_headers.is_none() {
+            tpl.push_str(&format!("From: {}\n", from));
+        }
+
+        // Sender, only when it differs from the author
+        let from_addrs = parse_addrs(&from).ok().flatten();
+        if let Some(sender) = distinct_sender(self.sender.to_owned(), &from_addrs) {
+            tpl.push_str(&format!("Sender: {}\n", sender));
+        }
+
+        // Reply-To
+        if let Some(addrs) = reply_to {
+            tpl.push_str(&format!("Reply-To: {}\n", addrs));
+        }
 
         // To
-        tpl.push_str(&format!(
-            "To: {}\n",
-            opts.to
-                .map(|addrs| addrs.join(", "))
-                .or_else(|| self.to.clone().map(|addrs| addrs
-                    .iter()
-                    .map(|addr| addr.to_string())
-                    .collect::<Vec<_>>()
-                    .join(", ")))
-                .unwrap_or_default()
-        ));
+        if tpl_headers.is_none() {
+            tpl.push_str(&format!("To: {}\n", to));
+        }
 
         // Cc
-        if let Some(addrs) = opts.cc.map(|addrs| addrs.join(", ")).or_else(|| {
-            self.cc.clone().map(|addrs| {
-                addrs
-                    .iter()
-                    .map(|addr| addr.to_string())
-                    .collect::<Vec<_>>()
-                    .join(", ")
-            })
-        }) {
+        if let Some(addrs) = cc {
             tpl.push_str(&format!("Cc: {}\n", addrs));
         }
 
         // Bcc
-        if let Some(addrs) = opts.bcc.map(|addrs| addrs.join(", ")).or_else(|| {
-            self.bcc.clone().map(|addrs| {
-                addrs
-                    .iter()
-                    .map(|addr| addr.to_string())
-                    .collect::<Vec<_>>()
-                    .join(", ")
-            })
-        }) {
+        if let Some(addrs) = bcc {
             tpl.push_str(&format!("Bcc: {}\n", addrs));
         }
 
-        // Subject
-        tpl.push_str(&format!(
-            "Subject: {}\n",
-            opts.subject.unwrap_or(&self.subject)
-        ));
+        // Subject, or the headers of the template file
+        match tpl_headers {
+            Some(headers) => tpl.push_str(&headers),
+            None => tpl.push_str(&format!("Subject: {}\n", subject)),
+        }
+
+        // Extra headers
+        for (key, val) in opts.headers.unwrap_or_default() {
+            validate_header_name(key)?;
+            if val.contains(['\r', '\n']) {
+                return Err(anyhow!("invalid value {:?} for header {:?}", val, key));
+            }
+            tpl.push_str(&format!("{}: {}\n", key, val));
+        }
+
+        // Headers order
+        if !account.template_header_order.is_empty() {
+            tpl = reorder_headers(&tpl, &account.template_header_order);
+        }
 
         // Headers <=> body separator
         tpl.push('\n');
 
         // Body
-        if let Some(body) = opts.body {
-            tpl.push_str(body);
+        if is_qp {
+            tpl.push_str(&encode_qp(&body));
         } else {
-            tpl.push_str(&self.fold_text_plain_parts())
-        }
-
-        // Signature
-        if let Some(sig) = opts.sig {
-            tpl.push_str("\n\n");
-            tpl.push_str(sig);
-        } else if let Some(ref sig) = account.sig {
-            tpl.push_str("\n\n");
-            tpl.push_str(sig);
+            tpl.push_str(&body);
         }
 
-        tpl.push('\n');
-
         trace!("template: {:?}", tpl);
-        tpl
+        Ok(tpl)
     }
 
     pub fn from_tpl(tpl: &str) -> Result<Self> {
-        info!("begin: building message from template");
+        Self::from_tpl_with(tpl, None)
+    }
+
+    /// Same as [`Msg::from_tpl`], but files of `Attachment` headers bigger than the given
+    /// maximum size (in bytes) lead to an error, like the account `max-attachment-size`.
+    pub fn from_tpl_with(tpl: &str, max_attachment_size: Option<u64>) -> Result<Self> {
+        info!("begin: building message from template");
         trace!("template: {:?}", tpl);
 
         let mut msg = Msg::default();
+        let tpl = insert_missing_body_separator(tpl);
         let parsed_msg = mailparse::parse_mail(tpl.as_bytes()).context("cannot parse template")?;
+        msg.headers = collect_headers(&parsed_msg.headers);
 
         debug!("parsing headers");
         for header in parsed_msg.get_headers() {
@@ -587,12 +1960,25 @@ impl Msg {
             match key.to_lowercase().as_str() {
                 "message-id" => msg.message_id = Some(val),
                 "in-reply-to" => msg.in_reply_to = Some(val),
+                "references" => msg.references = parse_msg_ids(val),
+                "date" => match DateTime::parse_from_rfc2822(&val) {
+                    Ok(date) => msg.date = Some(date),
+                    Err(err) => warn!("cannot parse date {:?} from template: {}", val, err),
+                },
                 "subject" => {
-                    msg.subject = val;
+                    msg.subject = rfc2047_decoder::decode(val.as_bytes()).unwrap_or_else(|err| {
+                        debug!("cannot decode subject {:?}: {}", val, err);
+                        val
+                    });
                 }
                 "from" => {
                     msg.from = parse_addrs(val).context(format!("cannot parse header {:?}", key))?
                 }
+                "sender" => {
+                    msg.sender = parse_addrs(val)
+                        .context(format!("cannot parse header {:?}", key))?
+                        .and_then(|addrs| addrs.into_iter().next())
+                }
                 "to" => {
                     msg.to = parse_addrs(val).context(format!("cannot parse header {:?}", key))?
                 }
@@ -606,15 +1992,36 @@ impl Msg {
                 "bcc" => {
                     msg.bcc = parse_addrs(val).context(format!("cannot parse header {:?}", key))?
                 }
+                "attachment" => {
+                    let order = next_binary_order(&msg.parts);
+                    let part = read_attachment(&val, order, max_attachment_size)
+                        .context(format!("cannot parse header {:?}", key))?;
+                    msg.parts.push(Part::Binary(part))
+                }
                 _ => (),
             }
         }
 
         debug!("parsing body");
-        let body = parsed_msg
-            .get_body_raw()
-            .context("cannot get raw body from message")
-            .and_then(|body| String::from_utf8(body).context("cannot decode body from utf8"))?;
+        let charset = parsed_msg
+            .ctype
+            .params
+            .get("charset")
+            .map(String::as_str)
+            .unwrap_or("utf-8");
+        let encoding = Encoding::for_label(charset.as_bytes())
+            .ok_or_else(|| anyhow!("cannot decode body: unknown charset {:?}", charset))?;
+        let body = match parsed_msg.get_body_encoded() {
+            mailparse::body::Body::QuotedPrintable(body) => decode_qp(body.get_raw()),
+            _ => parsed_msg
+                .get_body_raw()
+                .context("cannot get raw body from message")?,
+        };
+        let (body, _, malformed) = encoding.decode(&body);
+        if malformed {
+            return Err(anyhow!("cannot decode body from {}", encoding.name()));
+        }
+        let body = NO_TEXT_BODY_NOTE_REGEX.replace_all(&body, "").into_owned();
         trace!("body: {:?}", body);
 
         msg.parts
@@ -625,7 +2032,112 @@ impl Msg {
         Ok(msg)
     }
 
+    /// Builds a message from a complete raw message (RFC822), like the content of a `.eml` file.
+    /// Encrypted parts cannot be decrypted since no account is involved.
+    pub fn from_raw(bytes: &[u8]) -> Result<Self> {
+        info!("begin: building message from raw message");
+        let parsed_mail = mailparse::parse_mail(bytes).context("cannot parse raw message")?;
+        let msg = Self::from_parsed_mail(&Account::default(), &parsed_mail, 0)?;
+        info!("end: building message from raw message");
+        Ok(msg)
+    }
+
+    /// Builds a message from a parsed one, embedded in other messages at the given depth (0 for
+    /// top level messages), see [`Part::EmbeddedMessage`].
+    pub(crate) fn from_parsed_mail(
+        account: &Account,
+        parsed_mail: &mailparse::ParsedMail,
+        depth: usize,
+    ) -> Result<Self> {
+        let mut msg = Msg {
+            headers: collect_headers(&parsed_mail.headers),
+            ..Msg::default()
+        };
+
+        debug!("parsing headers");
+        for header in parsed_mail.get_headers() {
+            let key = header.get_key();
+            debug!("header key: {:?}", key);
+
+            let val = header.get_value();
+            let val = val.trim();
+            debug!("header value: {:?}", val);
+
+            match key.to_lowercase().as_str() {
+                "message-id" => msg.message_id = Some(val.to_owned()),
+                "in-reply-to" => msg.in_reply_to = Some(val.to_owned()),
+                "references" => msg.references = parse_msg_ids(val),
+                "subject" => msg.subject = val.to_owned(),
+                "date" => msg.date = DateTime::parse_from_rfc2822(val).ok(),
+                "from" => {
+                    msg.from = parse_addrs(val).context(format!("cannot parse header {:?}", key))?
+                }
+                "sender" => {
+                    msg.sender = parse_addrs(val)
+                        .context(format!("cannot parse header {:?}", key))?
+                        .and_then(|addrs| addrs.into_iter().next())
+                }
+                "reply-to" => {
+                    msg.reply_to =
+                        parse_addrs(val).context(format!("cannot parse header {:?}", key))?
+                }
+                "to" => {
+                    msg.to = parse_addrs(val).context(format!("cannot parse header {:?}", key))?
+                }
+                "cc" => {
+                    msg.cc = parse_addrs(val).context(format!("cannot parse header {:?}", key))?
+                }
+                "bcc" => {
+                    msg.bcc = parse_addrs(val).context(format!("cannot parse header {:?}", key))?
+                }
+                _ => (),
+            }
+        }
+
+        debug!("parsing parts");
+        msg.parts = Parts::from_parsed_mail_at_depth(account, parsed_mail, depth)?;
+
+        trace!("message: {:?}", msg);
+        Ok(msg)
+    }
+
+    /// Returns the raw message (RFC822) as it would be sent, the opposite of [`Msg::from_raw`].
+    pub fn formatted(&self, account: &Account) -> Result<Vec<u8>> {
+        Ok(self.into_sendable_msg(account)?.formatted())
+    }
+
+    /// Returns the message exactly as it would be sent, MIME boundaries included. Useful to
+    /// inspect a message (or its PGP structure) without sending it.
+    pub fn preview_sendable(&self, account: &Account) -> Result<String> {
+        Ok(String::from_utf8_lossy(&self.formatted(account)?).to_string())
+    }
+
+    /// Queues the sendable message in the outbox, so that it can be sent later with the `flush`
+    /// command (for example when offline).
+    pub fn enqueue(&self, account: &Account) -> Result<()> {
+        let msg = self.into_sendable_msg(account)?;
+        Outbox::new(Outbox::default_dir()?).enqueue(
+            &account.name,
+            msg.envelope(),
+            &msg.formatted(),
+        )?;
+        Ok(())
+    }
+
     pub fn into_sendable_msg(&self, account: &Account) -> Result<lettre::Message> {
+        match account.wrap_width {
+            Some(width) => {
+                let mut msg = self.clone();
+                msg.wrap_body(width, account.sig_delim_line());
+                msg.build_sendable_msg(account)
+            }
+            None => self.build_sendable_msg(account),
+        }
+    }
+
+    /// Same as [`Msg::into_sendable_msg`], without wrapping the body.
+    fn build_sendable_msg(&self, account: &Account) -> Result<lettre::Message> {
+        let boundaries = &mut Boundaries::new(account.deterministic_boundaries);
         let mut msg_builder = lettre::Message::builder()
             .message_id(self.message_id.to_owned())
             .subject(self.subject.to_owned());
@@ -634,12 +2146,40 @@ impl Msg {
             msg_builder = msg_builder.in_reply_to(id.to_owned());
         };
 
+        if let Some(date) = self.date {
+            msg_builder = msg_builder.date(date.into());
+        };
+
+        if let Some(references) = self.references.as_ref() {
+            msg_builder = msg_builder.references(references.join(" "));
+        };
+
+        if !account.mailer.is_empty() {
+            msg_builder = msg_builder.header(XMailer(account.mailer.to_owned()));
+        };
+
+        let mut extra_headers: Vec<(&String, &String)> = self
+            .headers
+            .iter()
+            .filter(|(key, _)| !UNSENT_HEADERS.contains(&key.as_str()))
+            .filter(|(key, _)| !key.starts_with("content-"))
+            .filter_map(|(key, vals)| vals.first().map(|val| (key, val)))
+            .collect();
+        extra_headers.sort();
+        for (key, val) in extra_headers {
+            msg_builder = with_extra_header(msg_builder, key, val)?;
+        }
+
         if let Some(addrs) = self.from.as_ref() {
             msg_builder = addrs
                 .iter()
                 .fold(msg_builder, |builder, addr| builder.from(addr.to_owned()))
         };
 
+        if let Some(addr) = distinct_sender(self.sender.to_owned(), &self.from) {
+            msg_builder = msg_builder.sender(addr)
+        };
+
         if let Some(addrs) = self.to.as_ref() {
             msg_builder = addrs
                 .iter()
@@ -653,43 +2193,139 @@ impl Msg {
         };
 
         if let Some(addrs) = self.cc.as_ref() {
-            msg_builder = addrs
-                .iter()
-                .fold(msg_builder, |builder, addr| builder.cc(addr.to_owned()))
+            msg_builder = dedup_addrs(addrs)
+                .into_iter()
+                .fold(msg_builder, |builder, addr| builder.cc(addr))
         };
 
         if let Some(addrs) = self.bcc.as_ref() {
-            msg_builder = addrs
-                .iter()
-                .fold(msg_builder, |builder, addr| builder.bcc(addr.to_owned()))
+            msg_builder = dedup_addrs(addrs)
+                .into_iter()
+                .fold(msg_builder, |builder, addr| builder.bcc(addr))
         };
 
         let mut multipart = {
-            let mut multipart =
-                MultiPart::mixed().singlepart(SinglePart::plain(self.fold_text_plain_parts()));
-            for part in self.attachments() {
-                multipart = multipart.singlepart(Attachment::new(part.filename.clone()).body(
-                    part.content,
-                    part.mime.parse().context(format!(
-                        "cannot parse content type of attachment {}",
+            let plain = self
+                .fold_text_plain_parts_with(!account.keep_blank_lines, account.sig_delim_line());
+            let has_html = self.parts.iter_text_html().next().is_some();
+            // Inline images are only kept along with an HTML body, which references them:
+            // mixed(related(alternative(plain, html), inline images...), attachments...)
+            let inline_parts: Vec<&BinaryPart> = self
+                .parts
+                .iter_binary()
+                .filter(|part| part.disposition == Disposition::Inline)
+                .filter(|part| part.content_id.is_some())
+                .collect();
+            let alternative = |boundaries: &mut Boundaries, plain| {
+                boundaries
+                    .apply(MultiPart::alternative())
+                    .singlepart(SinglePart::plain(plain))
+                    .singlepart(SinglePart::html(self.fold_text_html_parts()))
+            };
+            let mut multipart = if has_html && !inline_parts.is_empty() {
+                let alternative = alternative(boundaries, plain);
+                let mut related = boundaries
+                    .apply(MultiPart::related())
+                    .multipart(alternative);
+                for part in inline_parts {
+                    let ctype: ContentType = part.mime.parse().context(format!(
+                        "cannot parse content type of inline part {}",
                         part.filename
-                    ))?,
-                ))
+                    ))?;
+                    let cid = part.content_id.to_owned().unwrap_or_default();
+                    related = related.singlepart(
+                        SinglePart::builder()
+                            .header(ContentDisposition::inline())
+                            .header(ctype)
+                            .header(ContentId::from(format!("<{}>", cid)))
+                            .body(part.content.to_owned()),
+                    );
+                }
+                boundaries.apply(MultiPart::mixed()).multipart(related)
+            } else if has_html {
+                let alternative = alternative(boundaries, plain);
+                boundaries.apply(MultiPart::mixed()).multipart(alternative)
+            } else {
+                boundaries
+                    .apply(MultiPart::mixed())
+                    .singlepart(SinglePart::plain(plain))
+            };
+            for part in self.validate_attachments(true)? {
+                let ctype: ContentType = part.mime.parse().context(format!(
+                    "cannot parse content type of attachment {}",
+                    part.filename
+                ))?;
+                let cdisp = ContentDisposition::parse(&attachment_disposition(&part.filename))
+                    .map_err(|err| anyhow!(err))
+                    .context(format!(
+                        "cannot build content disposition of attachment {}",
+                        part.filename
+                    ))?;
+                multipart = multipart.singlepart(
+                    SinglePart::builder()
+                        .header(cdisp)
+                        .header(ctype)
+                        .body(part.content),
+                )
+            }
+            // Embedded messages are attached back unchanged, as they were received
+            for part in self.parts.iter() {
+                let part = match part {
+                    Part::EmbeddedMessage(part) => part,
+                    _ => continue,
+                };
+                let filename = match part.msg.subject.trim() {
+                    "" => String::from("message.eml"),
+                    subject => format!("{}.eml", subject),
+                };
+                let cdisp = ContentDisposition::parse(&attachment_disposition(&filename))
+                    .map_err(|err| anyhow!(err))
+                    .context(format!(
+                        "cannot build content disposition of embedded message {}",
+                        filename
+                    ))?;
+                // Messages cannot be base64 or quoted-printable encoded (RFC2046 section 5.2.1)
+                let body =
+                    Body::new_with_encoding(part.raw.to_owned(), ContentTransferEncoding::SevenBit)
+                        .or_else(|raw| {
+                            Body::new_with_encoding(raw, ContentTransferEncoding::EightBit)
+                        })
+                        .or_else(|raw| {
+                            Body::new_with_encoding(raw, ContentTransferEncoding::Binary)
+                        })
+                        .map_err(|_| anyhow!("cannot encode embedded message {}", filename))?;
+                multipart = multipart.singlepart(
+                    SinglePart::builder()
+                        .header(cdisp)
+                        .header(ContentType::parse("message/rfc822").unwrap())
+                        .body(body),
+                )
             }
             multipart
         };
 
         if self.encrypt {
+            if self.to.as_ref().map(Vec::is_empty).unwrap_or(true) {
+                return Err(anyhow!("cannot encrypt message without recipient"));
+            }
+            let addrs = self
+                .to
+                .iter()
+                .chain(self.cc.iter())
+                .flatten()
+                .map(|addr| addr.email.to_string())
+                .collect::<Vec<_>>();
             let multipart_buffer = temp_dir().join(Uuid::new_v4().to_string());
             fs::write(multipart_buffer.clone(), multipart.formatted())?;
-            let encrypted_multipart = account
-                .pgp_encrypt_file(
-                    &self.to.as_ref().unwrap().first().unwrap().email.to_string(),
-                    multipart_buffer.clone(),
-                )?
+            let encrypted_multipart = account.pgp_encrypt_file(&addrs, multipart_buffer.clone());
+            remove_multipart_buffer(&multipart_buffer);
+            let encrypted_multipart = encrypted_multipart?
                 .ok_or_else(|| anyhow!("cannot find pgp encrypt command in config"))?;
             trace!("encrypted multipart: {:#?}", encrypted_multipart);
-            multipart = MultiPart::encrypted(String::from("application/pgp-encrypted"))
+            multipart = boundaries
+                .apply(MultiPart::encrypted(String::from(
+                    "application/pgp-encrypted",
+                )))
                 .singlepart(
                     SinglePart::builder()
                         .header(ContentType::parse("application/pgp-encrypted").unwrap())
@@ -702,12 +2338,452 @@ impl Msg {
                 )
         }
 
+        if self.sign {
+            let multipart_buffer = temp_dir().join(Uuid::new_v4().to_string());
+            fs::write(multipart_buffer.clone(), multipart.formatted())?;
+            let signature = account.pgp_sign_file(multipart_buffer.clone());
+            remove_multipart_buffer(&multipart_buffer);
+            let signature =
+                signature?.ok_or_else(|| anyhow!("cannot find pgp sign command in config"))?;
+            trace!("multipart signature: {:#?}", signature);
+            multipart = boundaries
+                .apply(MultiPart::signed(
+                    String::from("application/pgp-signature"),
+                    String::from("pgp-sha256"),
+                ))
+                .multipart(multipart)
+                .singlepart(
+                    SinglePart::builder()
+                        .header(ContentType::parse("application/pgp-signature").unwrap())
+                        .body(signature),
+                )
+        }
+
         msg_builder
             .multipart(multipart)
             .context("cannot build sendable message")
     }
 }
 
+/// Removes the temporary file holding the plain multipart given to the PGP commands, so that it
+/// does not stay on disk. Failing to remove it is not fatal.
+fn remove_multipart_buffer(path: &Path) {
+    if let Err(err) = fs::remove_file(path) {
+        warn!("cannot remove temporary multipart {:?}: {}", path, err);
+    }
+}
+
+/// Generates the MIME boundaries of sendable messages. They are left to lettre (random ones)
+/// unless deterministic, see [`Account::deterministic_boundaries`].
+struct Boundaries {
+    count: Option<usize>,
+}
+
+impl Boundaries {
+    fn new(deterministic: bool) -> Self {
+        Self {
+            count: if deterministic { Some(0) } else { None },
+        }
+    }
+
+    /// Sets the next boundary of the given multipart builder, if deterministic.
+    fn apply(&mut self, builder: MultiPartBuilder) -> MultiPartBuilder {
+        match self.count.as_mut() {
+            Some(count) => {
+                *count += 1;
+                builder.boundary(format!("himalaya-boundary-{}", count))
+            }
+            None => builder,
+        }
+    }
+}
+
+/// Encodes the given text using the quoted-printable transfer encoding. Line breaks are kept
+/// as `\n`, and trailing whitespaces are encoded so they survive decoding (like the `-- `
+/// signature delimiter).
+fn encode_qp(text: &str) -> String {
+    text.split('\n')
+        .map(|line| {
+            let trimmed_line = line.trim_end_matches(&[' ', '\t'][..]);
+            let mut encoded_line = quoted_printable::encode_to_str(trimmed_line);
+            for c in line[trimmed_line.len()..].chars() {
+                encoded_line.push_str(if c == ' ' { "=20" } else { "=09" });
+            }
+            encoded_line.replace("\r\n", "\n")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Decodes the given quoted-printable text, the opposite of [`encode_qp`]. Line breaks are
+/// kept as `\n`, including the trailing one.
+fn decode_qp(text: &[u8]) -> Vec<u8> {
+    let mut decoded = quoted_printable::decode(text, quoted_printable::ParseMode::Robust)
+        .unwrap_or_else(|_| text.to_vec());
+    decoded.retain(|byte| *byte != b'\r');
+    if text.ends_with(b"\n") && !decoded.ends_with(b"\n") {
+        decoded.push(b'\n');
+    }
+    decoded
+}
+
+/// Builds the `Content-Disposition` value of an attachment. Non-ASCII filenames (or filenames
+/// that cannot be quoted) are encoded using the `filename*` parameter, as defined in [RFC2231].
+///
+/// [RFC2231]: https://datatracker.ietf.org/doc/html/rfc2231#section-4
+fn attachment_disposition(filename: &str) -> String {
+    let is_quotable = |c: char| c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\';
+    if filename.chars().all(is_quotable) {
+        return format!("attachment; filename=\"{}\"", filename);
+    }
+
+    let mut encoded_filename = String::default();
+    for byte in filename.bytes() {
+        match byte {
+            b'a'..=b'z'
+            | b'A'..=b'Z'
+            | b'0'..=b'9'
+            | b'!'
+            | b'#'
+            | b'$'
+            | b'&'
+            | b'+'
+            | b'-'
+            | b'.'
+            | b'^'
+            | b'_'
+            | b'`'
+            | b'|'
+            | b'~' => encoded_filename.push(byte as char),
+            _ => encoded_filename.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    format!("attachment; filename*=UTF-8''{}", encoded_filename)
+}
+
+/// Returns the given sender, unless it is one of the authors of the message. Addresses are
+/// compared by email, case-insensitively.
+fn distinct_sender(sender: Option<Addr>, from: &Option<Vec<Addr>>) -> Option<Addr> {
+    sender.filter(|sender| {
+        let email = sender.email.to_string().to_lowercase();
+        !from
+            .iter()
+            .flatten()
+            .any(|addr| addr.email.to_string().to_lowercase() == email)
+    })
+}
+
+/// Appends the given default addresses to the given comma-separated addresses, skipping the
+/// ones already present. Addresses are compared by email, case-insensitively.
+fn merge_default_addrs(
+    addrs: Option<String>,
+    default_addrs: Option<&Vec<String>>,
+) -> Option<String> {
+    let email = |raw_addr: &str| {
+        parse_addr(raw_addr)
+            .map(|addr| addr.email.to_string())
+            .unwrap_or_else(|_| raw_addr.trim().to_owned())
+            .to_lowercase()
+    };
+    let mut merged_addrs = addrs.unwrap_or_default();
+    let mut emails = merged_addrs
+        .split(',')
+        .filter(|raw_addr| !raw_addr.trim().is_empty())
+        .map(email)
+        .collect::<HashSet<_>>();
+    for default_addr in default_addrs.into_iter().flatten() {
+        if !emails.insert(email(default_addr)) {
+            continue;
+        }
+        if !merged_addrs.trim().is_empty() {
+            merged_addrs.push_str(", ");
+        }
+        merged_addrs.push_str(default_addr.trim());
+    }
+
+    if merged_addrs.trim().is_empty() {
+        None
+    } else {
+        Some(merged_addrs)
+    }
+}
+
+/// Converts an HTML body into a plain text one: the HTML markup is removed and whitespace is
+/// merged.
+fn html_to_plain(html: &str) -> String {
+    // Remove HTML markup
+    let plain = ammonia::Builder::new()
+        .tags(HashSet::default())
+        .clean(html)
+        .to_string();
+    // Merge new line chars
+    let plain = NEW_LINES_REGEX.replace_all(&plain, "\n\n").to_string();
+    // Replace tabulations and &npsp; by spaces
+    let plain = TABS_AND_NBSP_REGEX.replace_all(&plain, " ").to_string();
+    // Merge consecutive spaces into a single one
+    let plain = SPACES_REGEX.replace_all(&plain, " ").to_string();
+    // Decode HTML entities
+    html_escape::decode_html_entities(&plain).to_string()
+}
+
+/// Returns the position of the line delimiting the signature in the given text, if any. Lines are
+/// compared without their trailing spaces.
+fn find_sig_delim(text: &str, sig_delim: &str) -> Option<usize> {
+    let mut pos = 0;
+    for line in text.split_inclusive('\n') {
+        if line.trim_end() == sig_delim.trim_end() {
+            return Some(pos);
+        }
+        pos += line.len();
+    }
+    None
+}
+
+/// Prefixes each line of the given plain text by `prefix`, see [`Msg::quote_body_with`] for the
+/// signature options.
+fn quote_lines(text: &str, prefix: &str, sig_delim: Option<&str>, strip_all_sigs: bool) -> String {
+    // Nested quotes are prefixed without the trailing spaces, so `> > ` becomes `>> `
+    let nested_prefix = prefix.trim_end();
+    let mut content = String::default();
+    let mut glue = "";
+    let mut in_sig = false;
+    for line in text.lines() {
+        if strip_all_sigs {
+            let unquoted_line = line.trim_start_matches(&['>', ' '][..]).trim_end();
+            if in_sig && !unquoted_line.is_empty() {
+                continue;
+            }
+            in_sig = Some(unquoted_line) == sig_delim;
+            if in_sig {
+                continue;
+            }
+        } else if Some(line.trim_end()) == sig_delim {
+            break;
+        }
+        content.push_str(glue);
+        if !nested_prefix.is_empty() && line.starts_with(nested_prefix) {
+            content.push_str(nested_prefix);
+        } else {
+            content.push_str(prefix);
+        }
+        content.push_str(line);
+        glue = "\n";
+    }
+    content
+}
+
+/// Reorders the given headers (one per line, folded lines included): headers listed in `order`
+/// (case-insensitive) come first in that order, then the unlisted ones in their original order.
+/// Listed names matching no header are ignored.
+fn reorder_headers(headers: &str, order: &[String]) -> String {
+    let mut blocks: Vec<(String, String)> = vec![];
+    for line in headers.lines() {
+        match blocks.last_mut() {
+            Some((_, block)) if line.starts_with([' ', '\t']) => {
+                block.push_str(line);
+                block.push('\n');
+            }
+            _ => {
+                let name = line.split(':').next().unwrap_or_default().trim();
+                blocks.push((name.to_lowercase(), format!("{}\n", line)));
+            }
+        }
+    }
+    blocks.sort_by_key(|(name, _)| {
+        order
+            .iter()
+            .position(|key| key.eq_ignore_ascii_case(name))
+            .unwrap_or(order.len())
+    });
+    blocks.into_iter().map(|(_, block)| block).collect()
+}
+
+/// Renders a plain text body as HTML: the text is escaped, lines are separated by `<br>` and
+/// quoted lines (starting with `>`) are wrapped into nested `<blockquote type="cite">`, one per
+/// quote level.
+fn plain_to_html(plain: &str) -> String {
+    let mut html = String::default();
+    let mut depth = 0;
+    for line in plain.lines() {
+        let unquoted_line = line.trim_start_matches(&['>', ' '][..]);
+        let level = line[..line.len() - unquoted_line.len()]
+            .matches('>')
+            .count();
+        while depth < level {
+            html.push_str("<blockquote type=\"cite\">\n");
+            depth += 1;
+        }
+        while depth > level {
+            html.push_str("</blockquote>\n");
+            depth -= 1;
+        }
+        let line = if level > 0 { unquoted_line } else { line };
+        html.push_str(&html_escape::encode_text(line));
+        html.push_str("<br>\n");
+    }
+    for _ in 0..depth {
+        html.push_str("</blockquote>\n");
+    }
+    html.trim_end().to_owned()
+}
+
+/// Fills the `{{name}}` placeholders of the given template file with the given values. Unknown
+/// placeholders are left as they are, unless `strict` is set.
+fn render_tpl_file(tpl: &str, vals: &[(&str, &str)], strict: bool) -> Result<String> {
+    let mut unknown = vec![];
+    let rendered = TPL_PLACEHOLDER_REGEX.replace_all(tpl, |caps: &regex::Captures| {
+        match vals.iter().find(|(name, _)| *name == &caps[1]) {
+            Some((_, val)) => val.to_string(),
+            None => {
+                unknown.push(caps[0].to_owned());
+                caps[0].to_owned()
+            }
+        }
+    });
+    if strict && !unknown.is_empty() {
+        return Err(anyhow!(
+            "cannot render template file: unknown placeholders {}",
+            unknown.join(", ")
+        ));
+    }
+    Ok(rendered.into_owned())
+}
+
+/// Inserts the blank line separating the headers from the body of a template when it is
+/// missing: the body starts at the first line that is neither a header nor the continuation of
+/// one (a line without a colon, or with spaces in the header name).
+fn insert_missing_body_separator(tpl: &str) -> Cow<'_, str> {
+    let mut offset = 0;
+    for line in tpl.split_inclusive('\n') {
+        let content = line.trim_end_matches(&['\r', '\n'][..]);
+        if content.is_empty() {
+            break;
+        }
+        let is_continuation = content.starts_with(&[' ', '\t'][..]);
+        let is_header = match content.split_once(':') {
+            Some((key, _)) => !key.is_empty() && key.bytes().all(|c| c.is_ascii_graphic()),
+            None => false,
+        };
+        if !is_continuation && !is_header {
+            warn!(
+                "missing blank line between headers and body, body starts at {:?}",
+                content
+            );
+            let newline = if line.ends_with("\r\n") { "\r\n" } else { "\n" };
+            return Cow::Owned(format!("{}{}{}", &tpl[..offset], newline, &tpl[offset..]));
+        }
+        offset += line.len();
+    }
+    Cow::Borrowed(tpl)
+}
+
+/// Collects the (decoded) values of the given headers, indexed by their lowercased name.
+fn collect_headers(headers: &[mailparse::MailHeader]) -> HashMap<String, Vec<String>> {
+    headers.iter().fold(HashMap::new(), |mut map, header| {
+        map.entry(header.get_key().to_lowercase())
+            .or_insert_with(Vec::new)
+            .push(header.get_value().trim().to_owned());
+        map
+    })
+}
+
+/// Wraps the lines of the given text longer than `width` (in columns), between words. The
+/// indentation of a wrapped line is repeated on each new line. Quoted lines and everything after
+/// the signature delimiter are kept as they are, as well as words longer than the width (like
+/// URLs).
+fn wrap_text(text: &str, width: usize, sig_delim: &str) -> String {
+    let mut wrapped = String::with_capacity(text.len());
+    let mut in_sig = false;
+    for (i, line) in text.split('\n').enumerate() {
+        if i > 0 {
+            wrapped.push('\n');
+        }
+        in_sig = in_sig || line.trim_end() == sig_delim;
+        if in_sig || line.starts_with('>') || line.trim_end().width() <= width {
+            wrapped.push_str(line);
+            continue;
+        }
+
+        let words = line.trim_start();
+        let indent = &line[..line.len() - words.len()];
+        wrapped.push_str(indent);
+        let mut line_width = indent.width();
+        let mut line_empty = true;
+        for word in words.split_whitespace() {
+            let word_width = word.width();
+            if line_empty {
+                line_width += word_width;
+            } else if line_width + 1 + word_width > width {
+                wrapped.push('\n');
+                wrapped.push_str(indent);
+                line_width = indent.width() + word_width;
+            } else {
+                wrapped.push(' ');
+                line_width += 1 + word_width;
+            }
+            wrapped.push_str(word);
+            line_empty = false;
+        }
+    }
+    wrapped
+}
+
+/// Removes the addresses whose email appears more than once, keeping the first one.
+fn dedup_addrs(addrs: &[Addr]) -> Vec<Addr> {
+    let mut emails = HashSet::new();
+    addrs
+        .iter()
+        .filter(|addr| emails.insert(addr.email.to_string().to_lowercase()))
+        .cloned()
+        .collect()
+}
+
+/// Removes the trailing punctuation of a URL found in plain text, like the final dot of a
+/// sentence. Closing parenthesis are kept when balanced, since some URLs contain them.
+fn trim_url(url: &str) -> &str {
+    let mut url = url;
+    loop {
+        let trimmed = url.trim_end_matches(&['.', ',', ';', ':', '!', '?', '\'', '*'][..]);
+        let trimmed = match trimmed.strip_suffix(')') {
+            Some(rest) if rest.matches('(').count() < trimmed.matches(')').count() => rest,
+            _ => trimmed,
+        };
+        if trimmed == url {
+            return url;
+        }
+        url = trimmed;
+    }
+}
+
+/// Formats the given size in bytes to a human readable size (like `42 MB`).
+fn format_size(size: u64) -> String {
+    let units = ["B", "KB", "MB", "GB"];
+    let mut size = size as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    let size = format!("{:.1}", size);
+    format!("{} {}", size.trim_end_matches(".0"), units[unit])
+}
+
+/// Formats the given date with the given strftime format, after converting it to the given
+/// offset (if any).
+fn format_date(
+    date: &DateTime<FixedOffset>,
+    fmt: &str,
+    offset: Option<FixedOffset>,
+) -> Result<String> {
+    let date = offset
+        .map(|offset| date.with_timezone(&offset))
+        .unwrap_or(*date);
+    let mut formatted_date = String::default();
+    write!(formatted_date, "{}", date.format(fmt))
+        .map_err(|_| anyhow!("cannot format date with {:?}", fmt))?;
+    Ok(formatted_date)
+}
+
 impl TryInto<lettre::address::Envelope> for Msg {
     type Error = Error;
 
@@ -718,8 +2794,16 @@ impl TryInto<lettre::address::Envelope> for Msg {
             .map(|addr| addr.email);
         let to = self
             .to
-            .map(|addrs| addrs.into_iter().map(|addr| addr.email).collect())
-            .unwrap_or_default();
+            .into_iter()
+            .chain(self.cc)
+            .chain(self.bcc)
+            .flatten()
+            .fold(Vec::<lettre::Address>::new(), |mut to, addr| {
+                if !to.contains(&addr.email) {
+                    to.push(addr.email)
+                }
+                to
+            });
         let envelope =
             lettre::address::Envelope::new(from, to).context("cannot create envelope")?;
 
@@ -731,133 +2815,291 @@ impl<'a> TryFrom<(&'a Account, &'a imap::types::Fetch)> for Msg {
     type Error = Error;
 
     fn try_from((account, fetch): (&'a Account, &'a imap::types::Fetch)) -> Result<Msg> {
-        let envelope = fetch
-            .envelope()
-            .ok_or_else(|| anyhow!("cannot get envelope of message {}", fetch.message))?;
-
-        // Get the sequence number
+        let mut msg = Msg::from_fetch_envelope(account, fetch)?;
         let id = fetch.message;
 
-        // Get the flags
-        let flags = Flags::try_from(fetch.flags())?;
+        // Get all parts
+        let body = fetch
+            .body()
+            .ok_or_else(|| anyhow!("cannot get body of message {}", id))?;
+        let parsed_mail =
+            mailparse::parse_mail(body).context(format!("cannot parse body of message {}", id))?;
+        msg.parts = Parts::from_parsed_mail(account, &parsed_mail)?;
+        msg.headers = collect_headers(&parsed_mail.headers);
 
-        // Get the subject
-        let subject = envelope
-            .subject
-            .as_ref()
-            .map(|subj| {
-                rfc2047_decoder::decode(subj).context(format!(
-                    "cannot decode subject of message {}",
-                    fetch.message
-                ))
-            })
-            .unwrap_or_else(|| Ok(String::default()))?;
+        // Get the message identifiers of the thread
+        msg.references = parsed_mail
+            .get_headers()
+            .get_first_value("references")
+            .and_then(parse_msg_ids);
 
-        // Get the sender(s) address(es)
-        let from = match envelope
-            .sender
-            .as_deref()
-            .or_else(|| envelope.from.as_deref())
-            .map(to_addrs)
-        {
-            Some(addrs) => Some(addrs?),
-            None => None,
-        };
+        Ok(msg)
+    }
+}
 
-        // Get the "Reply-To" address(es)
-        let reply_to = to_some_addrs(&envelope.reply_to).context(format!(
-            r#"cannot parse "reply to" address of message {}"#,
-            id
-        ))?;
+/// Represents the reason why an address cannot be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AddrParseError {
+    /// The address is empty.
+    Empty,
+    /// The address is not a valid mailbox.
+    Malformed(String),
+    /// The address has no domain (like `john` or `john@`).
+    MissingDomain(String),
+}
 
-        // Get the recipient(s) address(es)
-        let to = to_some_addrs(&envelope.to)
-            .context(format!(r#"cannot parse "to" address of message {}"#, id))?;
+impl fmt::Display for AddrParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty address"),
+            Self::Malformed(addr) => write!(f, "cannot parse address {:?}", addr),
+            Self::MissingDomain(addr) => write!(f, "missing domain in address {:?}", addr),
+        }
+    }
+}
 
-        // Get the "Cc" recipient(s) address(es)
-        let cc = to_some_addrs(&envelope.cc)
-            .context(format!(r#"cannot parse "cc" address of message {}"#, id))?;
+impl error::Error for AddrParseError {}
 
-        // Get the "Bcc" recipient(s) address(es)
-        let bcc = to_some_addrs(&envelope.bcc)
-            .context(format!(r#"cannot parse "bcc" address of message {}"#, id))?;
+/// Represents the failures of a list of addresses, one per bad address.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddrsParseError(pub Vec<AddrParseError>);
 
-        // Get the "In-Reply-To" message identifier
-        let in_reply_to = match envelope
-            .in_reply_to
-            .as_ref()
-            .map(|cow| String::from_utf8(cow.to_vec()))
-        {
-            Some(id) => Some(id?),
-            None => None,
-        };
+impl fmt::Display for AddrsParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let errs = self.0.iter().map(|err| err.to_string()).collect::<Vec<_>>();
+        write!(f, "{}", errs.join(", "))
+    }
+}
 
-        // Get the message identifier
-        let message_id = match envelope
-            .message_id
-            .as_ref()
-            .map(|cow| String::from_utf8(cow.to_vec()))
-        {
-            Some(id) => Some(id?),
-            None => None,
+impl error::Error for AddrsParseError {}
+
+/// Parses the given address. An RFC2047 encoded display name is decoded, or kept as it is if it
+/// cannot be decoded.
+pub fn parse_addr<S: AsRef<str>>(raw_addr: S) -> Result<Addr, AddrParseError> {
+    let raw_addr = raw_addr.as_ref().trim();
+    if raw_addr.is_empty() {
+        return Err(AddrParseError::Empty);
+    }
+    let addr: Addr = raw_addr.parse().map_err(|_| {
+        let email = raw_addr
+            .rsplit('<')
+            .next()
+            .unwrap_or(raw_addr)
+            .trim_end_matches('>')
+            .trim();
+        match email.split_once('@') {
+            Some((_, domain)) if !domain.is_empty() => {
+                AddrParseError::Malformed(raw_addr.to_owned())
+            }
+            _ => AddrParseError::MissingDomain(raw_addr.to_owned()),
+        }
+    })?;
+    let name = addr.name.map(|name| {
+        rfc2047_decoder::decode(name.as_bytes()).unwrap_or_else(|err| {
+            debug!("cannot decode address name {:?}: {}", name, err);
+            name
+        })
+    });
+    Ok(Addr::new(name, addr.email))
+}
+
+/// Parses a comma-separated list of addresses. Empty tokens (like in `a@b.com, , c@d.com`) are
+/// skipped, and an empty list gives `None`. All bad addresses are reported, not only the first
+/// one.
+pub fn parse_addrs<S: AsRef<str>>(raw_addrs: S) -> Result<Option<Vec<Addr>>, AddrsParseError> {
+    let mut addrs: Vec<Addr> = vec![];
+    let mut errs: Vec<AddrParseError> = vec![];
+    for raw_addr in raw_addrs.as_ref().split(',') {
+        if raw_addr.trim().is_empty() {
+            continue;
+        }
+        match parse_addr(raw_addr) {
+            Ok(addr) => addrs.push(addr),
+            Err(err) => errs.push(err),
+        }
+    }
+    if !errs.is_empty() {
+        return Err(AddrsParseError(errs));
+    }
+    Ok(if addrs.is_empty() { None } else { Some(addrs) })
+}
+
+/// Reads the attachment located at the given path. The path is expanded first, so it can contain
+/// `~` or environment variables.
+fn read_attachment(path: &str, order: usize, max_size: Option<u64>) -> Result<BinaryPart> {
+    let path =
+        shellexpand::full(path).context(format!(r#"cannot expand attachment path "{}""#, path))?;
+    let path = PathBuf::from(path.to_string());
+    let filename: String = path
+        .file_name()
+        .ok_or_else(|| anyhow!("cannot get file name of attachment {:?}", path))?
+        .to_string_lossy()
+        .into();
+    if max_size.is_some() {
+        let size = fs::metadata(&path)
+            .context(format!("cannot read attachment {:?}", path))?
+            .len();
+        check_attachment_size(&filename, size, max_size)?;
+    }
+    let content = fs::read(&path).context(format!("cannot read attachment {:?}", path))?;
+
+    Ok(build_attachment(filename, content, order))
+}
+
+/// Fails when the given attachment size exceeds the given maximum size, if any.
+fn check_attachment_size(filename: &str, size: u64, max_size: Option<u64>) -> Result<()> {
+    match max_size {
+        Some(max_size) if size > max_size => Err(anyhow!(
+            "attachment {} is {}, exceeds limit of {}",
+            filename,
+            format_size(size),
+            format_size(max_size)
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the size of the given number of bytes once base64 encoded, line breaks included.
+fn base64_size(size: usize) -> usize {
+    let base64_len = size.div_ceil(3) * 4;
+    base64_len + base64_len.div_ceil(76) * 2
+}
+
+/// Builds an attachment from in-memory content, detecting its MIME type.
+fn build_attachment(filename: String, content: Vec<u8>, order: usize) -> BinaryPart {
+    let mime = detect_mime(&filename, &content);
+
+    BinaryPart {
+        filename,
+        mime,
+        content,
+        disposition: Disposition::Attachment,
+        order,
+        content_id: None,
+    }
+}
+
+/// Detects the MIME type of an attachment. Text formats that cannot be told apart from plain
+/// text by their content (like calendars or CSV) are detected by their extension first.
+fn detect_mime(filename: &str, content: &[u8]) -> String {
+    let ext = Path::new(filename)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase());
+    let mime = match ext.as_deref() {
+        Some("ics") => "text/calendar",
+        Some("csv") => "text/csv",
+        Some("tsv") => "text/tab-separated-values",
+        Some("vcf") => "text/vcard",
+        Some("md") => "text/markdown",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        _ => return tree_magic::from_u8(content),
+    };
+    mime.to_owned()
+}
+
+/// Sorts attachments by their original position, then by filename.
+fn sort_attachments(attachments: &mut [BinaryPart]) {
+    attachments.sort_by(cmp_attachments);
+}
+
+/// Compares attachments by order, then by filename.
+fn cmp_attachments(a: &BinaryPart, b: &BinaryPart) -> Ordering {
+    a.order
+        .cmp(&b.order)
+        .then_with(|| a.filename.cmp(&b.filename))
+}
+
+/// Splits the filename into its stem and its extension (dot included). Leading dots are part of
+/// the stem, so `.bashrc` has no extension.
+fn split_ext(filename: &str) -> (&str, &str) {
+    match filename.rfind('.') {
+        Some(idx) if idx > 0 => filename.split_at(idx),
+        _ => (filename, ""),
+    }
+}
+
+/// Writes the content to a new file of the given directory. If the filename is already taken, a
+/// number is appended to its stem (`report (1).pdf`) until a free filename is found.
+fn write_new_file(dir: &Path, filename: &str, content: &[u8]) -> io::Result<PathBuf> {
+    let (stem, ext) = split_ext(filename);
+    for n in 0.. {
+        let path = if n == 0 {
+            dir.join(filename)
+        } else {
+            dir.join(format!("{} ({}){}", stem, n, ext))
         };
+        // Creating the file exclusively prevents overwriting a file created in the meantime
+        match fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(mut file) => return file.write_all(content).map(|_| path),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    unreachable!()
+}
 
-        // Get the internal date
-        let date = fetch.internal_date();
+/// Checks if the given MIME type matches the pattern. Both the type and the subtype of the
+/// pattern can be a `*` wildcard. The comparison is case-insensitive.
+fn mime_matches(mime: &str, pattern: &str) -> bool {
+    let mime = mime.split(';').next().unwrap_or_default().trim();
+    let (mime_type, mime_subtype) = mime.split_once('/').unwrap_or((mime, ""));
+    let (pattern_type, pattern_subtype) = pattern.trim().split_once('/').unwrap_or((pattern, "*"));
 
-        // Get all parts
-        let body = fetch
-            .body()
-            .ok_or_else(|| anyhow!("cannot get body of message {}", id))?;
-        let parsed_mail =
-            mailparse::parse_mail(body).context(format!("cannot parse body of message {}", id))?;
-        let parts = Parts::from_parsed_mail(account, &parsed_mail)?;
+    let type_matches = pattern_type == "*" || pattern_type.eq_ignore_ascii_case(mime_type);
+    let subtype_matches =
+        pattern_subtype == "*" || pattern_subtype.eq_ignore_ascii_case(mime_subtype);
+    type_matches && subtype_matches
+}
 
-        Ok(Self {
-            id,
-            flags,
-            subject,
-            from,
-            reply_to,
-            to,
-            cc,
-            bcc,
-            in_reply_to,
-            message_id,
-            date,
-            parts,
-            encrypt: false,
-        })
+/// Checks that the given header name is valid, as defined in [RFC5322]: a non-empty sequence of
+/// printable ASCII chars, colon excepted.
+///
+/// [RFC5322]: https://datatracker.ietf.org/doc/html/rfc5322#section-2.2
+pub fn validate_header_name(name: &str) -> Result<()> {
+    if name.is_empty() || !name.bytes().all(|b| (33..=126).contains(&b) && b != b':') {
+        return Err(anyhow!("invalid header name {:?}", name));
     }
+    Ok(())
 }
 
-pub fn parse_addr<S: AsRef<str> + Debug>(raw_addr: S) -> Result<Addr> {
-    raw_addr
+/// Parses a space-separated list of message identifiers, as found in the `References` header.
+pub fn parse_msg_ids<S: AsRef<str>>(raw_ids: S) -> Option<Vec<String>> {
+    let ids: Vec<String> = raw_ids
         .as_ref()
-        .trim()
-        .parse()
-        .context(format!("cannot parse address {:?}", raw_addr))
+        .split_whitespace()
+        .map(String::from)
+        .collect();
+    if ids.is_empty() {
+        None
+    } else {
+        Some(ids)
+    }
 }
 
-pub fn parse_addrs<S: AsRef<str> + Debug>(raw_addrs: S) -> Result<Option<Vec<Addr>>> {
-    let mut addrs: Vec<Addr> = vec![];
-    for raw_addr in raw_addrs.as_ref().split(',') {
-        addrs
-            .push(parse_addr(raw_addr).context(format!("cannot parse addresses {:?}", raw_addrs))?);
+/// Returns the name to display for the given address: its display name, or its email when the
+/// name is missing or blank.
+pub fn addr_display(addr: &Addr) -> String {
+    match addr.name.as_deref().map(str::trim) {
+        Some(name) if !name.is_empty() => name.to_owned(),
+        _ => addr.email.to_string(),
     }
-    Ok(if addrs.is_empty() { None } else { Some(addrs) })
 }
 
+/// Converts an IMAP address into a mailbox. A display name that cannot be decoded is not fatal:
+/// it falls back to its raw (lossy UTF-8) value. The mailbox and the host must be valid.
 pub fn to_addr(addr: &imap_proto::Address) -> Result<Addr> {
-    let name = addr
-        .name
-        .as_ref()
-        .map(|name| {
-            rfc2047_decoder::decode(&name.to_vec())
-                .context("cannot decode address name")
-                .map(Some)
+    let name = addr.name.as_ref().map(|name| {
+        rfc2047_decoder::decode(&name.to_vec()).unwrap_or_else(|err| {
+            let name = String::from_utf8_lossy(name).to_string();
+            warn!("cannot decode address name {:?}: {}", name, err);
+            name
         })
-        .unwrap_or(Ok(None))?;
+    });
     let mbox = addr
         .mailbox
         .as_ref()
@@ -890,3 +3132,2702 @@ pub fn to_some_addrs(addrs: &Option<Vec<imap_proto::Address>>) -> Result<Option<
         None => None,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an empty attachment with the given order, filename and MIME type.
+    fn attachment(order: usize, filename: &str, mime: &str) -> BinaryPart {
+        BinaryPart {
+            filename: filename.into(),
+            mime: mime.into(),
+            order,
+            ..BinaryPart::default()
+        }
+    }
+
+    #[test]
+    fn it_should_display_addr() {
+        let addr = |name: Option<&str>| {
+            Addr::new(name.map(String::from), "test@localhost".parse().unwrap())
+        };
+        assert_eq!("Test", addr_display(&addr(Some("Test"))));
+        assert_eq!("Test", addr_display(&addr(Some(" Test "))));
+        assert_eq!("test@localhost", addr_display(&addr(None)));
+        assert_eq!("test@localhost", addr_display(&addr(Some(""))));
+        assert_eq!("test@localhost", addr_display(&addr(Some("  "))));
+
+        let msg = Msg {
+            from: Some(vec![addr(Some("Author")), addr(Some("Other"))]),
+            sender: Some(addr(Some("Sender"))),
+            ..Msg::default()
+        };
+        assert_eq!("Author", msg.canonical_from_name());
+        let msg = Msg { from: None, ..msg };
+        assert_eq!("Sender", msg.canonical_from_name());
+        assert_eq!("", Msg::default().canonical_from_name());
+    }
+
+    #[test]
+    fn it_should_fall_back_to_raw_addr_name() {
+        let addr = imap_proto::Address {
+            name: Some(b"=?UTF-8?B?!!!?=".as_ref().into()),
+            adl: None,
+            mailbox: Some(b"test".as_ref().into()),
+            host: Some(b"localhost".as_ref().into()),
+        };
+        assert_eq!(
+            "=?UTF-8?B?!!!?= <test@localhost>".parse::<Addr>().unwrap(),
+            to_addr(&addr).unwrap()
+        );
+
+        let addr = imap_proto::Address {
+            name: Some(b"=?UTF-8?Q?Caf=C3=A9?=".as_ref().into()),
+            ..addr
+        };
+        assert_eq!(Some("Café".into()), to_addr(&addr).unwrap().name);
+
+        let addr = imap_proto::Address { host: None, ..addr };
+        assert!(to_addr(&addr).is_err());
+    }
+
+    #[test]
+    fn it_should_send_embedded_msgs_unchanged() {
+        let account = Account::default();
+        let embedded_msg = concat![
+            "DKIM-Signature: v=1; a=rsa-sha256; d=localhost; b=c2lnbmF0dXJl\r\n",
+            "From: sender@localhost\r\n",
+            "Subject: Hello\r\n",
+            "X-Unknown:   kept as is\r\n",
+            "\r\n",
+            "Hello, world!\r\n",
+        ];
+        let raw_msg = [
+            "From: from@localhost\r\n",
+            "To: to@localhost\r\n",
+            "Subject: Fwd: Hello\r\n",
+            "Content-Type: multipart/mixed; boundary=\"boundary\"\r\n",
+            "\r\n",
+            "--boundary\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "See below.\r\n",
+            "--boundary\r\n",
+            "Content-Type: message/rfc822\r\n",
+            "\r\n",
+            embedded_msg,
+            "--boundary--\r\n",
+        ]
+        .concat();
+        let msg = Msg::from_raw(raw_msg.as_bytes()).unwrap();
+        assert_eq!(
+            "Hello",
+            msg.parts.iter_embedded_msgs().next().unwrap().subject
+        );
+
+        let formatted = String::from_utf8(msg.formatted(&account).unwrap()).unwrap();
+        assert!(formatted.contains(embedded_msg));
+    }
+
+    #[test]
+    fn it_should_keep_sender_distinct_from_author() {
+        let account = Account {
+            email: "boss@localhost".into(),
+            mailer: String::new(),
+            ..Account::default()
+        };
+        let msg = Msg::from_raw(
+            concat![
+                "From: Boss <boss@localhost>\r\n",
+                "Sender: Assistant <assistant@localhost>\r\n",
+                "To: to@localhost\r\n",
+                "Subject: Hello\r\n",
+                "\r\n",
+                "Hello!\r\n",
+            ]
+            .as_bytes(),
+        )
+        .unwrap();
+        let sender: Addr = "Assistant <assistant@localhost>".parse().unwrap();
+        assert_eq!(Some(sender.clone()), msg.sender);
+        assert_eq!(
+            Some(vec!["Boss <boss@localhost>".parse().unwrap()]),
+            msg.from
+        );
+
+        let tpl = msg.to_tpl(TplOverride::default(), &account).unwrap();
+        assert!(tpl.contains("From: boss@localhost\nSender: Assistant <assistant@localhost>\n"));
+        assert_eq!(Some(sender), Msg::from_tpl(&tpl).unwrap().sender);
+
+        let formatted = String::from_utf8(msg.formatted(&account).unwrap()).unwrap();
+        assert!(formatted.contains("From: Boss <boss@localhost>\r\n"));
+        assert!(formatted.contains("Sender: Assistant <assistant@localhost>\r\n"));
+
+        let msg = Msg {
+            sender: Some("boss@localhost".parse().unwrap()),
+            ..msg
+        };
+        let tpl = msg.to_tpl(TplOverride::default(), &account).unwrap();
+        assert!(!tpl.contains("Sender:"));
+
+        let msg = Msg {
+            sender: Some("Boss <boss@localhost>".parse().unwrap()),
+            ..msg
+        };
+        let formatted = String::from_utf8(msg.formatted(&account).unwrap()).unwrap();
+        assert!(!formatted.contains("Sender:"));
+
+        // Only the emails are compared, not the display names
+        let msg = Msg {
+            sender: Some("Big Boss <Boss@localhost>".parse().unwrap()),
+            ..msg
+        };
+        let tpl = msg.to_tpl(TplOverride::default(), &account).unwrap();
+        assert!(!tpl.contains("Sender:"));
+        let formatted = String::from_utf8(msg.formatted(&account).unwrap()).unwrap();
+        assert!(!formatted.contains("Sender:"));
+
+        let msg = Msg {
+            sender: Some("assistant@localhost".parse().unwrap()),
+            ..msg
+        };
+        assert_eq!(
+            None,
+            msg.into_reply(false, "plain", &account).unwrap().sender
+        );
+    }
+
+    #[test]
+    fn it_should_note_attachments_of_msg_without_text_part() {
+        let mut account = Account {
+            email: "test@localhost".into(),
+            mailer: String::new(),
+            ..Account::default()
+        };
+        let msg = Msg::default()
+            .add_attachment_bytes("a.pdf", b"%PDF-1.4".to_vec())
+            .add_attachment_bytes("b.pdf", b"%PDF-1.4".to_vec());
+
+        let tpl = msg.to_tpl(TplOverride::default(), &account).unwrap();
+        assert!(tpl.ends_with("\n\n[no text body; 2 attachment(s)]\n"));
+        assert_eq!("", Msg::from_tpl(&tpl).unwrap().raw_text_plain());
+        let tpl = tpl.replace("[no text body;", "Hello!\n[no text body;");
+        assert_eq!("Hello!\n", Msg::from_tpl(&tpl).unwrap().raw_text_plain());
+
+        account.keep_empty_body = true;
+        let tpl = msg.to_tpl(TplOverride::default(), &account).unwrap();
+        assert!(tpl.ends_with("To: \nSubject: \n\n\n"));
+
+        account.keep_empty_body = false;
+        let msg = Msg {
+            parts: Parts(vec![Part::new_text_plain("".into())]),
+            ..Msg::default()
+        }
+        .add_attachment_bytes("a.pdf", b"%PDF-1.4".to_vec());
+        let tpl = msg.to_tpl(TplOverride::default(), &account).unwrap();
+        assert!(!tpl.contains("[no text body"));
+    }
+
+    #[test]
+    fn it_should_compute_msg_size() {
+        let account = Account {
+            email: "test@localhost".into(),
+            ..Account::default()
+        };
+        let content = (0..30_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let msg = Msg {
+            subject: "Report".into(),
+            from: Some(vec!["test@localhost".parse().unwrap()]),
+            to: Some(vec!["to@localhost".parse().unwrap()]),
+            parts: Parts(vec![Part::new_text_plain("Hello!".into())]),
+            ..Msg::default()
+        }
+        .add_attachment_bytes("report.bin", content);
+
+        let total_size = msg.total_size(&account).unwrap();
+        assert_eq!(msg.formatted(&account).unwrap().len(), total_size);
+        assert!(total_size > 40_000, "base64 should expand attachments");
+
+        let estimated_size = msg.estimated_size();
+        let diff = (estimated_size as f64 - total_size as f64).abs() / total_size as f64;
+        assert!(
+            diff < 0.05,
+            "estimated size {} too far from total size {}",
+            estimated_size,
+            total_size
+        );
+
+        assert_eq!(None, msg.size_warning(None));
+        assert_eq!(None, msg.size_warning(Some(50_000)));
+        let warning = msg.size_warning(Some(20_000)).unwrap();
+        assert!(warning.starts_with("Message is about "));
+        assert!(warning.ends_with(", exceeds limit of 19.5 KB"));
+    }
+
+    #[test]
+    fn it_should_round_trip_reply_to_header() {
+        let account = Account {
+            email: "test@localhost".into(),
+            ..Account::default()
+        };
+
+        let msg = Msg::from_tpl(concat![
+            "Reply-To: foo@bar.com\n",
+            "To: to@localhost\n",
+            "Subject: Test\n",
+            "\n",
+            "Hello, world!\n",
+        ])
+        .unwrap();
+        let tpl = msg.to_tpl(TplOverride::default(), &account).unwrap();
+        assert!(tpl.contains("Reply-To: foo@bar.com\n"));
+
+        let sendable_msg = Msg::from_tpl(&tpl)
+            .unwrap()
+            .into_sendable_msg(&account)
+            .unwrap();
+        let formatted = String::from_utf8(sendable_msg.formatted()).unwrap();
+        assert!(formatted.contains("Reply-To: foo@bar.com\r\n"));
+    }
+
+    #[test]
+    fn it_should_override_reply_to_header() {
+        let account = Account {
+            email: "test@localhost".into(),
+            ..Account::default()
+        };
+        let opts = TplOverride {
+            reply_to: Some(vec!["foo@bar.com", "baz@bar.com"]),
+            ..TplOverride::default()
+        };
+
+        let tpl = Msg::default().to_tpl(opts, &account).unwrap();
+        assert!(tpl.contains("Reply-To: foo@bar.com, baz@bar.com\n"));
+
+        let tpl = Msg::default()
+            .to_tpl(TplOverride::default(), &account)
+            .unwrap();
+        assert!(!tpl.contains("Reply-To:"));
+    }
+
+    #[test]
+    fn it_should_add_extra_headers() {
+        let account = Account {
+            email: "test@localhost".into(),
+            mailer: String::new(),
+            ..Account::default()
+        };
+        let msg = Msg {
+            subject: "Hello".into(),
+            ..Msg::default()
+        };
+        let opts = TplOverride {
+            headers: Some(vec![("X-Priority", "1"), ("Importance", "high")]),
+            ..TplOverride::default()
+        };
+
+        let tpl = msg.to_tpl(opts, &account).unwrap();
+        assert!(tpl.contains("Subject: Hello\nX-Priority: 1\nImportance: high\n\n"));
+
+        let msg = Msg::from_tpl(&tpl).unwrap();
+        assert_eq!(Some("1"), msg.header("x-priority"));
+        assert_eq!(Some("high"), msg.header("Importance"));
+
+        let tpl = tpl.replace("Subject:", "Bcc: bcc@localhost\nSubject:");
+        let msg = Msg::from_tpl(&tpl).unwrap();
+        let formatted = String::from_utf8(msg.formatted(&account).unwrap()).unwrap();
+        assert!(formatted.contains("Importance: high\r\nX-Priority: 1\r\n"));
+        assert_eq!(1, formatted.matches("Subject:").count());
+        assert!(!formatted.contains("Bcc:"));
+
+        // Headers of the original message are replaced by the ones of the template
+        let mut reply =
+            Msg::from_raw(b"Received: from localhost\r\nX-Priority: 5\r\n\r\n").unwrap();
+        reply.merge_with(msg);
+        let formatted = String::from_utf8(reply.formatted(&account).unwrap()).unwrap();
+        assert!(formatted.contains("X-Priority: 1\r\n"));
+        assert!(!formatted.contains("Received:"));
+
+        for header in [
+            ("X Priority", "1"),
+            ("", "1"),
+            ("Café", "1"),
+            ("X-Bcc", "a\nBcc: b"),
+        ] {
+            let opts = TplOverride {
+                headers: Some(vec![header]),
+                ..TplOverride::default()
+            };
+            assert!(Msg::default().to_tpl(opts, &account).is_err());
+        }
+    }
+
+    #[test]
+    fn it_should_reorder_tpl_headers() {
+        let mut account = Account {
+            email: "test@localhost".into(),
+            mailer: String::new(),
+            ..Account::default()
+        };
+        let msg = Msg {
+            subject: "Hello".into(),
+            to: Some(vec!["to@localhost".parse().unwrap()]),
+            cc: Some(vec!["cc@localhost".parse().unwrap()]),
+            in_reply_to: Some("<id@localhost>".into()),
+            ..Msg::default()
+        };
+
+        let tpl = msg.to_tpl(TplOverride::default(), &account).unwrap();
+        assert!(tpl.starts_with(concat!(
+            "Content-Type: text/plain; charset=utf-8\n",
+            "In-Reply-To: <id@localhost>\n",
+            "From: test@localhost\n",
+            "To: to@localhost\n",
+            "Cc: cc@localhost\n",
+            "Subject: Hello\n\n",
+        )));
+
+        account.template_header_order = ["from", "To", "Unknown", "Cc", "Bcc", "Subject"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        let tpl = msg.to_tpl(TplOverride::default(), &account).unwrap();
+        assert!(tpl.starts_with(concat!(
+            "From: test@localhost\n",
+            "To: to@localhost\n",
+            "Cc: cc@localhost\n",
+            "Subject: Hello\n",
+            "Content-Type: text/plain; charset=utf-8\n",
+            "In-Reply-To: <id@localhost>\n\n",
+        )));
+        assert_eq!(
+            Some("<id@localhost>".into()),
+            Msg::from_tpl(&tpl).unwrap().in_reply_to
+        );
+    }
+
+    #[test]
+    fn it_should_select_named_signature() {
+        let account = Account {
+            email: "test@localhost".into(),
+            sig: Some("-- \nDefault".into()),
+            signatures: vec![
+                ("work".to_string(), "-- \nRegards".to_string()),
+                ("casual".to_string(), "-- \nCheers".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+            ..Account::default()
+        };
+
+        let tpl = Msg::default()
+            .to_tpl(TplOverride::default(), &account)
+            .unwrap();
+        assert!(tpl.ends_with("-- \nDefault\n"));
+
+        let opts = TplOverride {
+            sig_name: Some("casual"),
+            ..TplOverride::default()
+        };
+        let tpl = Msg::default().to_tpl(opts, &account).unwrap();
+        assert!(tpl.ends_with("-- \nCheers\n"));
+
+        let opts = TplOverride {
+            sig_name: Some("formal"),
+            ..TplOverride::default()
+        };
+        let err = Msg::default().to_tpl(opts, &account).unwrap_err();
+        assert_eq!(
+            r#"cannot find signature "formal" (available: casual, work)"#,
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn it_should_render_tpl_file() {
+        let path = temp_dir().join(format!("himalaya-tpl-{}.eml", Uuid::new_v4()));
+        fs::write(
+            &path,
+            "From: {{from}}\nTo: {{ to }}\nSubject: [Team] {{subject}}\n\nHi,\n\n{{body}}\n\n{{signature}}\n{{unknown}}\n",
+        )
+        .unwrap();
+        let account = Account {
+            email: "test@localhost".into(),
+            sig: Some("-- \nRegards".into()),
+            mailer: String::new(),
+            ..Account::default()
+        };
+        let msg = Msg {
+            subject: "Status".into(),
+            to: Some(vec!["to@localhost".parse().unwrap()]),
+            cc: Some(vec!["cc@localhost".parse().unwrap()]),
+            ..Msg::default()
+        };
+        let opts = TplOverride {
+            body: Some("All good."),
+            template_path: path.to_str(),
+            ..TplOverride::default()
+        };
+
+        assert_eq!(
+            concat![
+                "Content-Type: text/plain; charset=utf-8\n",
+                "Cc: cc@localhost\n",
+                "From: test@localhost\n",
+                "To: to@localhost\n",
+                "Subject: [Team] Status\n",
+                "\n",
+                "Hi,\n",
+                "\n",
+                "All good.\n",
+                "\n",
+                "-- \n",
+                "Regards\n",
+                "{{unknown}}\n",
+            ],
+            msg.to_tpl(opts, &account).unwrap()
+        );
+
+        let account = Account {
+            template: Some(path.to_owned()),
+            template_strict: true,
+            ..account
+        };
+        assert!(msg.to_tpl(TplOverride::default(), &account).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn it_should_round_trip_message_id_header() {
+        let account = Account {
+            email: "test@localhost".into(),
+            ..Account::default()
+        };
+
+        let tpl = Msg::default()
+            .to_tpl(TplOverride::default(), &account)
+            .unwrap();
+        assert!(!tpl.contains("Message-ID:"));
+
+        let msg = Msg {
+            message_id: Some("<1@localhost>".into()),
+            to: Some(vec!["to@localhost".parse().unwrap()]),
+            ..Msg::default()
+        };
+        let tpl = msg.to_tpl(TplOverride::default(), &account).unwrap();
+        assert!(tpl.contains("Message-ID: <1@localhost>\n"));
+
+        let msg = Msg::from_tpl(&tpl).unwrap();
+        assert_eq!(Some("<1@localhost>"), msg.message_id.as_deref());
+        let formatted =
+            String::from_utf8(msg.into_sendable_msg(&account).unwrap().formatted()).unwrap();
+        assert!(formatted.contains("Message-ID: <1@localhost>\r\n"));
+    }
+
+    #[test]
+    fn it_should_report_addr_parse_errors() {
+        assert_eq!(Err(AddrParseError::Empty), parse_addr("  "));
+        assert_eq!(
+            Err(AddrParseError::MissingDomain("john".into())),
+            parse_addr("john")
+        );
+        assert_eq!(
+            Err(AddrParseError::MissingDomain("John <john@>".into())),
+            parse_addr("John <john@>")
+        );
+        assert_eq!(
+            Err(AddrParseError::Malformed("john@@localhost".into())),
+            parse_addr("john@@localhost")
+        );
+
+        let err = parse_addrs("john, to@localhost, a b@localhost").unwrap_err();
+        assert_eq!(
+            vec![
+                AddrParseError::MissingDomain("john".into()),
+                AddrParseError::Malformed("a b@localhost".into()),
+            ],
+            err.0
+        );
+        assert_eq!(
+            r#"missing domain in address "john", cannot parse address "a b@localhost""#,
+            err.to_string()
+        );
+
+        let err = Msg::from_tpl("To: john\n\nBody").unwrap_err();
+        assert_eq!(
+            r#"cannot parse header "To": missing domain in address "john""#,
+            format!("{:#}", err)
+        );
+    }
+
+    #[test]
+    fn it_should_skip_empty_addr_tokens() {
+        let emails = |raw_addrs: &str| {
+            parse_addrs(raw_addrs).unwrap().map(|addrs| {
+                addrs
+                    .into_iter()
+                    .map(|addr| addr.email.to_string())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(
+            Some(vec!["a@b.com".to_string(), "c@d.com".to_string()]),
+            emails("a@b.com, c@d.com,")
+        );
+        assert_eq!(
+            Some(vec!["a@b.com".to_string(), "c@d.com".to_string()]),
+            emails("a@b.com,, c@d.com")
+        );
+        assert_eq!(
+            Some(vec!["a@b.com".to_string(), "c@d.com".to_string()]),
+            emails("a@b.com,  \t , c@d.com")
+        );
+        assert_eq!(None, emails(""));
+        assert_eq!(None, emails("  ,  "));
+
+        let msg = Msg::from_tpl("To: \nCc: cc@localhost, \n\nBody").unwrap();
+        assert_eq!(None, msg.to);
+        assert_eq!(Some(vec!["cc@localhost".parse().unwrap()]), msg.cc);
+    }
+
+    #[test]
+    fn it_should_round_trip_date_header() {
+        let account = Account {
+            email: "from@localhost".into(),
+            ..Account::default()
+        };
+
+        let msg = Msg::from_tpl(concat![
+            "Date: Tue, 8 Feb 2022 10:00:00 +0100\n",
+            "To: to@localhost\n",
+            "\n",
+            "Hello, world!\n",
+        ])
+        .unwrap();
+        assert_eq!("2022-02-08T10:00:00+01:00", msg.date.unwrap().to_rfc3339());
+
+        let tpl = msg.to_tpl(TplOverride::default(), &account).unwrap();
+        assert!(tpl.contains("Date: Tue, 08 Feb 2022 10:00:00 +0100\n"));
+
+        let msg = Msg::from_tpl(&tpl).unwrap();
+        let formatted =
+            String::from_utf8(msg.into_sendable_msg(&account).unwrap().formatted()).unwrap();
+        assert!(formatted.contains("Date: Tue, 08 Feb 2022 09:00:00 -0000\r\n"));
+
+        let msg = Msg::from_tpl("Date: yesterday\nTo: to@localhost\n\nHello!\n").unwrap();
+        assert_eq!(None, msg.date);
+    }
+
+    #[test]
+    fn it_should_filter_attachments_by_mime() {
+        let msg = Msg {
+            parts: Parts(vec![
+                Part::new_text_plain("Hello!".into()),
+                Part::Binary(attachment(0, "image.png", "image/png")),
+                Part::Binary(attachment(1, "doc.pdf", "application/pdf")),
+                Part::Binary(attachment(2, "invite.ics", "text/calendar")),
+            ]),
+            ..Msg::default()
+        };
+        let filenames = |parts: Vec<BinaryPart>| {
+            parts
+                .into_iter()
+                .map(|part| part.filename)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            vec!["image.png", "doc.pdf", "invite.ics"],
+            filenames(msg.attachments())
+        );
+        assert_eq!(
+            vec!["image.png"],
+            filenames(msg.attachments_by_mime("image/*"))
+        );
+        assert_eq!(
+            vec!["doc.pdf"],
+            filenames(msg.attachments_by_mime("application/pdf"))
+        );
+        assert_eq!(
+            vec!["invite.ics"],
+            filenames(msg.attachments_by_mime("TEXT/*"))
+        );
+        assert!(msg.attachments_by_mime("video/*").is_empty());
+    }
+
+    #[test]
+    fn it_should_keep_attachments_order_on_merge() {
+        let account = Account::default();
+        let dir = temp_dir().join(format!("himalaya-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let path = |filename: &str| {
+            let path = dir.join(filename);
+            fs::write(&path, filename).unwrap();
+            path.to_string_lossy().to_string()
+        };
+        let (b, a, c) = (path("b.txt"), path("a.txt"), path("c.txt"));
+
+        let merge = || {
+            let mut msg = Msg::default()
+                .add_attachments(vec![&b, &a], &account)
+                .unwrap();
+            let tpl = format!("To: to@localhost\nAttachment: {}\n\nBody\n", c);
+            msg.merge_with(Msg::from_tpl(&tpl).unwrap());
+            msg.attachments()
+                .into_iter()
+                .map(|part| String::from_utf8(part.content).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let expected = vec!["b.txt", "a.txt", "c.txt"];
+        assert_eq!(expected, merge());
+        assert_eq!(expected, merge());
+
+        let sendable_msg = Msg {
+            from: Some(vec!["from@localhost".parse().unwrap()]),
+            to: Some(vec!["to@localhost".parse().unwrap()]),
+            ..Msg::default()
+        }
+        .add_attachments(vec![&b, &a], &account)
+        .unwrap()
+        .into_sendable_msg(&account)
+        .unwrap();
+        let formatted = String::from_utf8(sendable_msg.formatted()).unwrap();
+        assert!(formatted.find("b.txt").unwrap() < formatted.find("a.txt").unwrap());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_should_rename_duplicate_attachments() {
+        let msg = Msg {
+            parts: Parts(vec![
+                Part::Binary(attachment(0, "report.pdf", "application/pdf")),
+                Part::Binary(attachment(1, "report.pdf", "application/pdf")),
+                Part::Binary(attachment(2, "report.pdf", "application/pdf")),
+                Part::Binary(attachment(3, "notes", "application/pdf")),
+            ]),
+            ..Msg::default()
+        };
+
+        let filenames = msg
+            .validate_attachments(true)
+            .unwrap()
+            .into_iter()
+            .map(|part| part.filename)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec!["report.pdf", "report (1).pdf", "report (2).pdf", "notes"],
+            filenames
+        );
+
+        let err = msg.validate_attachments(false).unwrap_err();
+        assert_eq!(
+            r#"duplicate attachment filename "report.pdf""#,
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn it_should_save_attachments_to_dir() {
+        let files = [
+            ("report.pdf", "a"),
+            ("report.pdf", "b"),
+            ("../../escape.txt", "c"),
+            (".hidden", "d"),
+        ];
+        let msg = Msg {
+            parts: Parts(
+                files
+                    .iter()
+                    .enumerate()
+                    .map(|(order, (filename, content))| {
+                        Part::Binary(BinaryPart {
+                            content: content.as_bytes().to_vec(),
+                            ..attachment(order, filename, "text/plain")
+                        })
+                    })
+                    .collect(),
+            ),
+            ..Msg::default()
+        };
+        let dir = temp_dir()
+            .join(format!("himalaya-attachments-{}", Uuid::new_v4()))
+            .join("downloads");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("report.pdf"), "existing").unwrap();
+
+        let paths = msg.save_attachments_to(&dir, false).unwrap();
+        assert_eq!(
+            vec![
+                dir.join("report (1).pdf"),
+                dir.join("report (2).pdf"),
+                dir.join("escape.txt"),
+                dir.join("hidden"),
+            ],
+            paths
+        );
+        assert_eq!(
+            "existing",
+            fs::read_to_string(dir.join("report.pdf")).unwrap()
+        );
+        assert_eq!("a", fs::read_to_string(&paths[0]).unwrap());
+        assert_eq!("b", fs::read_to_string(&paths[1]).unwrap());
+        assert_eq!("c", fs::read_to_string(&paths[2]).unwrap());
+        assert!(!dir.parent().unwrap().join("escape.txt").exists());
+
+        // Saving again keeps the previous files
+        let paths = msg.save_attachments_to(&dir, false).unwrap();
+        assert_eq!(dir.join("report (3).pdf"), paths[0]);
+        assert_eq!(dir.join("escape (1).txt"), paths[2]);
+
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_should_keep_blank_lines_of_signature() {
+        let mut account = Account::default();
+        let msg = Msg {
+            parts: Parts(vec![Part::new_text_plain(
+                "Hello\n\n\n\nworld\n-- \n /\\_/\\\n\n\n\n ( o.o )\n".into(),
+            )]),
+            ..Msg::default()
+        };
+
+        assert_eq!(
+            "Hello\n\nworld\n-- \n /\\_/\\\n\n\n\n ( o.o )\n",
+            msg.fold_text_plain_parts()
+        );
+
+        account.keep_blank_lines = true;
+        let tpl = msg.to_tpl(TplOverride::default(), &account).unwrap();
+        assert!(tpl.contains("\n\nHello\n\n\n\nworld\n-- \n /\\_/\\\n\n\n\n ( o.o )\n"));
+    }
+
+    #[test]
+    fn it_should_build_msg_from_raw() {
+        let raw_msg = concat![
+            "Message-ID: <2@localhost>\r\n",
+            "In-Reply-To: <1@localhost>\r\n",
+            "Date: Tue, 8 Feb 2022 10:00:00 +0100\r\n",
+            "From: Sender <from@localhost>\r\n",
+            "To: to1@localhost, to2@localhost\r\n",
+            "Cc: cc@localhost\r\n",
+            "Subject: =?UTF-8?B?Q2Fmw6k=?=\r\n",
+            "Content-Type: multipart/mixed; boundary=\"mixed\"\r\n",
+            "\r\n",
+            "--mixed\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "Hello!\r\n",
+            "--mixed\r\n",
+            "Content-Type: application/pdf\r\n",
+            "Content-Disposition: attachment; filename=\"doc.pdf\"\r\n",
+            "\r\n",
+            "doc\r\n",
+            "--mixed--\r\n",
+        ];
+        let msg = Msg::from_raw(raw_msg.as_bytes()).unwrap();
+
+        assert_eq!(Some("<2@localhost>".into()), msg.message_id);
+        assert_eq!(Some("<1@localhost>".into()), msg.in_reply_to);
+        assert_eq!("Café", msg.subject);
+        assert_eq!("2022-02-08T10:00:00+01:00", msg.date.unwrap().to_rfc3339());
+        assert_eq!(
+            "Sender <from@localhost>",
+            msg.from.as_ref().unwrap()[0].to_string()
+        );
+        assert_eq!(2, msg.to.as_ref().unwrap().len());
+        assert_eq!(1, msg.cc.as_ref().unwrap().len());
+        assert_eq!(None, msg.bcc);
+        assert_eq!("Hello!\r\n", msg.fold_text_plain_parts());
+        assert_eq!("doc.pdf", msg.attachments()[0].filename);
+    }
+
+    #[test]
+    fn it_should_decode_addr_names_from_tpl() {
+        let msg = Msg::from_tpl(concat![
+            "From: =?UTF-8?B?Q2Fmw6k=?= <from@localhost>\n",
+            "To: =?UTF-8?Q?Doe=2C_J=C3=B6hn?= <to@localhost>, Jane Doe <jane@localhost>\n",
+            "Cc: =?UTF-8?B?Q2Fmw6?= <cc@localhost>\n",
+            "\n",
+            "Hello!\n",
+        ])
+        .unwrap();
+
+        let from = msg.from.unwrap();
+        assert_eq!(Some("Café".into()), from[0].name);
+
+        let to = msg.to.unwrap();
+        assert_eq!(Some("Doe, Jöhn".into()), to[0].name);
+        assert_eq!("to@localhost", to[0].email.to_string());
+        assert_eq!(Some("Jane Doe".into()), to[1].name);
+
+        let cc = msg.cc.unwrap();
+        assert_eq!(Some("=?UTF-8?B?Q2Fmw6?=".into()), cc[0].name);
+    }
+
+    #[test]
+    fn it_should_check_flags() {
+        let msg = Msg {
+            flags: Flags::try_from(vec![Flag::Seen, Flag::Draft]).unwrap(),
+            ..Msg::default()
+        };
+        assert!(msg.is_seen());
+        assert!(!msg.is_flagged());
+        assert!(!msg.is_answered());
+        assert!(msg.is_draft());
+        assert!(!msg.is_deleted());
+
+        let msg = Msg {
+            flags: Flags::try_from(vec![Flag::Flagged, Flag::Answered, Flag::Deleted]).unwrap(),
+            ..Msg::default()
+        };
+        assert!(!msg.is_seen());
+        assert!(msg.is_flagged());
+        assert!(msg.is_answered());
+        assert!(!msg.is_draft());
+        assert!(msg.is_deleted());
+
+        let msg = Msg::default();
+        assert!(!msg.is_seen());
+        assert!(!msg.is_flagged());
+        assert!(!msg.is_answered());
+        assert!(!msg.is_draft());
+        assert!(!msg.is_deleted());
+    }
+
+    #[test]
+    fn it_should_sanitize_html() {
+        let msg = Msg {
+            parts: Parts(vec![Part::new_text_html(
+                concat![
+                    "<p onclick=\"track()\"><b>Hello</b>!</p>",
+                    "<script>track()</script>",
+                    "<img src=\"https://tracker.com/pixel.png\" width=\"1\">",
+                    "<img src=\"cid:logo\">",
+                ]
+                .into(),
+            )]),
+            ..Msg::default()
+        };
+
+        let html = msg.sanitized_html();
+        assert!(html.contains("<p><b>Hello</b>!</p>"));
+        assert!(!html.contains("script"));
+        assert!(!html.contains("onclick"));
+        assert!(!html.contains("tracker.com"));
+        assert!(!html.contains("<img"));
+    }
+
+    #[test]
+    fn it_should_build_body_preview() {
+        let msg = Msg {
+            parts: Parts(vec![Part::new_text_plain(
+                "  Héllo,\n\n\tworld!   How are you?  ".into(),
+            )]),
+            ..Msg::default()
+        };
+
+        assert_eq!("", msg.body_preview(0));
+        assert_eq!("H…", msg.body_preview(1));
+        assert_eq!("Héllo…", msg.body_preview(5));
+        assert_eq!("Héllo, world!…", msg.body_preview(13));
+        assert_eq!("Héllo, world! How are you?", msg.body_preview(26));
+        assert_eq!("Héllo, world! How are you?", msg.body_preview(100));
+    }
+
+    #[test]
+    fn it_should_parse_attachments_from_tpl() {
+        let path = temp_dir().join(format!("himalaya-{}.txt", Uuid::new_v4()));
+        fs::write(&path, "Hello, world!").unwrap();
+
+        let tpl = format!(
+            "To: to@localhost\nAttachment: {}\nSubject: Test\n\nBody\n",
+            path.to_string_lossy()
+        );
+        let msg = Msg::from_tpl(&tpl).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let attachments = msg.attachments();
+        assert_eq!(1, attachments.len());
+        assert_eq!(
+            path.file_name().unwrap().to_string_lossy(),
+            attachments[0].filename
+        );
+        assert_eq!(b"Hello, world!".to_vec(), attachments[0].content);
+        assert_eq!("Body\n", msg.fold_text_plain_parts());
+
+        let err = Msg::from_tpl(&tpl).unwrap_err();
+        assert!(format!("{:?}", err).contains(&path.to_string_lossy().to_string()));
+    }
+
+    #[test]
+    fn it_should_decode_tpl_body_from_charset() {
+        let tpl = concat!(
+            "To: to@localhost\n",
+            "Content-Type: text/plain; charset=iso-8859-1\n",
+            "Content-Transfer-Encoding: quoted-printable\n",
+            "\n",
+            "Caf=E9\n",
+        );
+        let msg = Msg::from_tpl(tpl).unwrap();
+        assert_eq!("Café\n", msg.fold_text_plain_parts());
+
+        let tpl = "To: to@localhost\n\nCafé\n";
+        let msg = Msg::from_tpl(tpl).unwrap();
+        assert_eq!("Café\n", msg.fold_text_plain_parts());
+
+        let tpl = "To: to@localhost\nContent-Type: text/plain; charset=unknown\n\nBody\n";
+        let err = Msg::from_tpl(tpl).unwrap_err();
+        assert!(err.to_string().contains("unknown charset \"unknown\""));
+    }
+
+    #[test]
+    fn it_should_encode_tpl_body_with_quoted_printable() {
+        let account = Account {
+            email: "test@localhost".into(),
+            sig: Some("-- \nSignature".into()),
+            ..Account::default()
+        };
+        let tpl = |body: &str| {
+            Msg {
+                to: Some(vec!["to@localhost".parse().unwrap()]),
+                parts: Parts(vec![Part::new_text_plain(body.into())]),
+                ..Msg::default()
+            }
+            .to_tpl(TplOverride::default(), &account)
+            .unwrap()
+        };
+
+        let ascii_tpl = tpl("Hello, world!");
+        assert!(!ascii_tpl.contains("Content-Transfer-Encoding"));
+        assert!(ascii_tpl.ends_with("\n\nHello, world!\n\n-- \nSignature\n"));
+
+        let utf8_tpl = tpl("Café = coffee");
+        assert!(utf8_tpl.contains("Content-Transfer-Encoding: quoted-printable\n"));
+        assert!(utf8_tpl.contains("\n\nCaf=C3=A9 =3D coffee\n\n--=20\nSignature\n"));
+        assert_eq!(
+            "Café = coffee\n\n-- \nSignature\n",
+            Msg::from_tpl(&utf8_tpl)
+                .unwrap()
+                .fold_text_plain_parts_with(false, DEFAULT_SIG_DELIM)
+        );
+
+        let long_line = "a".repeat(1000);
+        let long_tpl = tpl(&long_line);
+        assert!(long_tpl.contains("Content-Transfer-Encoding: quoted-printable\n"));
+        assert!(long_tpl.lines().all(|line| line.len() <= 998));
+        assert!(Msg::from_tpl(&long_tpl)
+            .unwrap()
+            .fold_text_plain_parts_with(false, DEFAULT_SIG_DELIM)
+            .starts_with(&format!("{}\n\n-- \n", long_line)));
+    }
+
+    #[test]
+    fn it_should_wrap_body() {
+        let long_line = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor incididunt ut labore et dolore magna aliqua. Ut enim ad minim veniam, quis nostrud exercitation ullamco laboris nisi aut.";
+        assert_eq!(200, long_line.len());
+        let quote = format!("> {}", long_line);
+        let sig = format!("-- \n{}", long_line);
+        let mut msg = Msg {
+            parts: Parts(vec![Part::new_text_plain(format!(
+                "Short line\n{}\n{}\n{}",
+                long_line, quote, sig
+            ))]),
+            ..Msg::default()
+        };
+
+        msg.wrap_body(72, DEFAULT_SIG_DELIM);
+        let body = msg.fold_text_plain_parts_with(false, DEFAULT_SIG_DELIM);
+        let lines = body.lines().collect::<Vec<_>>();
+        assert_eq!("Short line", lines[0]);
+        assert_eq!(
+            "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod",
+            lines[1]
+        );
+        assert_eq!(
+            "tempor incididunt ut labore et dolore magna aliqua. Ut enim ad minim",
+            lines[2]
+        );
+        assert_eq!(
+            "veniam, quis nostrud exercitation ullamco laboris nisi aut.",
+            lines[3]
+        );
+        assert!(lines[1..4].iter().all(|line| line.len() <= 72));
+        assert_eq!(quote, lines[4]);
+        assert!(body.ends_with(&sig));
+
+        assert_eq!(
+            "  indented\n  http://localhost/a-very-long-url\n  end",
+            wrap_text("  indented  http://localhost/a-very-long-url end", 10, "--")
+        );
+    }
+
+    #[test]
+    fn it_should_wrap_body_when_sending() {
+        let account = Account {
+            wrap_width: Some(20),
+            ..Account::default()
+        };
+        let msg = Msg {
+            from: Some(vec!["from@localhost".parse().unwrap()]),
+            to: Some(vec!["to@localhost".parse().unwrap()]),
+            parts: Parts(vec![Part::new_text_plain(
+                "This line is a bit too long to fit.".into(),
+            )]),
+            ..Msg::default()
+        };
+        let formatted = msg.preview_sendable(&account).unwrap();
+        assert!(formatted.contains("This line is a bit\r\ntoo long to fit."));
+
+        let account = Account {
+            wrap_width: Some(20),
+            sig_delim: "~~~\n".into(),
+            ..Account::default()
+        };
+        let msg = Msg {
+            parts: Parts(vec![Part::new_text_plain(
+                "Hello\n~~~\nThis signature is long, but kept.".into(),
+            )]),
+            ..msg
+        };
+        let formatted = msg.preview_sendable(&account).unwrap();
+        assert!(formatted.contains("~~~\r\nThis signature is long, but kept."));
+    }
+
+    #[test]
+    fn it_should_set_html_body() {
+        let mut msg = Msg {
+            from: Some(vec!["from@localhost".parse().unwrap()]),
+            to: Some(vec!["to@localhost".parse().unwrap()]),
+            parts: Parts(vec![Part::new_text_plain("Old body".into())]),
+            ..Msg::default()
+        }
+        .add_attachment_bytes("notes.txt", b"notes".to_vec());
+
+        msg.set_html_body("<h1>Hello</h1>\n\n\n<p>World&nbsp;&amp;\t\tall!</p>".into());
+        assert_eq!("Hello\n\nWorld & all!", msg.fold_text_plain_parts());
+        assert_eq!(
+            "<h1>Hello</h1>\n\n<p>World&nbsp;&amp;\t\tall!</p>",
+            msg.fold_text_html_parts()
+        );
+        assert_eq!(1, msg.attachment_count());
+
+        let formatted = String::from_utf8(
+            msg.into_sendable_msg(&Account::default())
+                .unwrap()
+                .formatted(),
+        )
+        .unwrap();
+        assert!(formatted.contains("multipart/alternative"));
+        assert!(!formatted.contains("Old body"));
+    }
+
+    #[test]
+    fn it_should_generate_deterministic_boundaries() {
+        let account = Account {
+            mailer: String::new(),
+            deterministic_boundaries: true,
+            pgp_encrypt_cmd: Some("printf encrypted #".into()),
+            ..Account::default()
+        };
+        let mut msg = Msg {
+            message_id: Some("<1@localhost>".into()),
+            date: DateTime::parse_from_rfc2822("Tue, 8 Feb 2022 10:00:00 +0100").ok(),
+            subject: "Hello".into(),
+            from: Some(vec!["from@localhost".parse().unwrap()]),
+            to: Some(vec!["to@localhost".parse().unwrap()]),
+            parts: Parts(vec![
+                Part::new_text_plain("Hello!".into()),
+                Part::new_text_html("<p>Hello!</p>".into()),
+            ]),
+            ..Msg::default()
+        };
+        let formatted = |msg: &Msg| {
+            String::from_utf8(msg.into_sendable_msg(&account).unwrap().formatted()).unwrap()
+        };
+
+        let headers = concat![
+            "Message-ID: <1@localhost>\r\n",
+            "Subject: Hello\r\n",
+            "Date: Tue, 08 Feb 2022 09:00:00 -0000\r\n",
+            "From: from@localhost\r\n",
+            "To: to@localhost\r\n",
+            "MIME-Version: 1.0\r\n",
+        ];
+
+        assert_eq!(formatted(&msg), formatted(&msg));
+        assert_eq!(
+            [
+                headers,
+                "Content-Type: multipart/mixed; boundary=\"himalaya-boundary-2\"\r\n",
+                "\r\n",
+                "--himalaya-boundary-2\r\n",
+                "Content-Type: multipart/alternative; boundary=\"himalaya-boundary-1\"\r\n",
+                "\r\n",
+                "--himalaya-boundary-1\r\n",
+                "Content-Type: text/plain; charset=utf-8\r\n",
+                "Content-Transfer-Encoding: 7bit\r\n",
+                "\r\n",
+                "Hello!\r\n",
+                "--himalaya-boundary-1\r\n",
+                "Content-Type: text/html; charset=utf-8\r\n",
+                "Content-Transfer-Encoding: 7bit\r\n",
+                "\r\n",
+                "<p>Hello!</p>\r\n",
+                "--himalaya-boundary-1--\r\n",
+                "--himalaya-boundary-2--\r\n",
+            ]
+            .concat(),
+            formatted(&msg)
+        );
+
+        msg.encrypt = true;
+        assert_eq!(formatted(&msg), formatted(&msg));
+        assert_eq!(
+            [
+                headers,
+                "Content-Type: multipart/encrypted; boundary=\"himalaya-boundary-3\"; \r\n",
+                " protocol=\"application/pgp-encrypted\"\r\n",
+                "\r\n",
+                "--himalaya-boundary-3\r\n",
+                "Content-Type: application/pgp-encrypted\r\n",
+                "Content-Transfer-Encoding: 7bit\r\n",
+                "\r\n",
+                "Version: 1\r\n",
+                "--himalaya-boundary-3\r\n",
+                "Content-Type: application/octet-stream\r\n",
+                "Content-Transfer-Encoding: 7bit\r\n",
+                "\r\n",
+                "encrypted\r\n",
+                "--himalaya-boundary-3--\r\n",
+            ]
+            .concat(),
+            formatted(&msg)
+        );
+    }
+
+    #[test]
+    fn it_should_send_html_as_alternative() {
+        let account = Account::default();
+        let mut msg = Msg {
+            from: Some(vec!["from@localhost".parse().unwrap()]),
+            to: Some(vec!["to@localhost".parse().unwrap()]),
+            parts: Parts(vec![Part::new_text_plain("Hello!".into())]),
+            ..Msg::default()
+        };
+
+        let sendable_msg = msg.into_sendable_msg(&account).unwrap();
+        let formatted = String::from_utf8(sendable_msg.formatted()).unwrap();
+        assert!(!formatted.contains("multipart/alternative"));
+        assert!(!formatted.contains("text/html"));
+
+        msg.parts.push(Part::new_text_html("<p>Hello!</p>".into()));
+        let sendable_msg = msg.into_sendable_msg(&account).unwrap();
+        let formatted = String::from_utf8(sendable_msg.formatted()).unwrap();
+        assert!(formatted.contains("multipart/alternative"));
+        assert!(formatted.contains("text/plain"));
+        assert!(formatted.contains("text/html"));
+        assert!(formatted.contains("<p>Hello!</p>"));
+    }
+
+    #[test]
+    fn it_should_send_inline_images_as_related() {
+        let account = Account::default();
+        let mut msg = Msg {
+            from: Some(vec!["from@localhost".parse().unwrap()]),
+            to: Some(vec!["to@localhost".parse().unwrap()]),
+            ..Msg::default()
+        };
+        msg.set_html_body(r#"<p>Hello!</p><img src="cid:logo@localhost">"#.into());
+        msg.add_inline_image("logo@localhost", b"PNG".to_vec(), "image/png");
+        msg = msg.add_attachment_bytes("report.pdf", b"%PDF-1.4".to_vec());
+
+        let sendable_msg = msg.into_sendable_msg(&account).unwrap();
+        let formatted = String::from_utf8(sendable_msg.formatted()).unwrap();
+        let pos = |pattern: &str| {
+            formatted
+                .find(pattern)
+                .unwrap_or_else(|| panic!("cannot find {:?} in {}", pattern, formatted))
+        };
+
+        // mixed(related(alternative(plain, html), image), attachment)
+        assert!(pos("multipart/mixed") < pos("multipart/related"));
+        assert!(pos("multipart/related") < pos("multipart/alternative"));
+        assert!(pos("multipart/alternative") < pos("text/plain"));
+        assert!(pos("text/plain") < pos("text/html"));
+        assert!(pos("text/html") < pos("Content-Type: image/png"));
+        assert!(pos("Content-Type: image/png") < pos("report.pdf"));
+        assert!(formatted.contains("Content-ID: <logo@localhost>\r\n"));
+        assert!(formatted.contains("Content-Disposition: inline\r\n"));
+        let related_boundary = formatted[pos("multipart/related")..]
+            .split("boundary=\"")
+            .nth(1)
+            .and_then(|s| s.split('"').next())
+            .unwrap()
+            .to_owned();
+        let related_end = pos(&format!("--{}--", related_boundary));
+        assert!(pos("Content-Type: image/png") < related_end);
+        assert!(related_end < pos("report.pdf"));
+
+        // Without HTML body, inline images are not sent
+        msg.parts.retain(|part| !matches!(part, Part::TextHtml(_)));
+        let sendable_msg = msg.into_sendable_msg(&account).unwrap();
+        let formatted = String::from_utf8(sendable_msg.formatted()).unwrap();
+        assert!(!formatted.contains("multipart/related"));
+        assert!(!formatted.contains("image/png"));
+    }
+
+    #[test]
+    fn it_should_encrypt_msg_for_all_recipients() {
+        let account = Account {
+            pgp_encrypt_cmd: Some("echo".into()),
+            ..Account::default()
+        };
+        let mut msg = Msg {
+            from: Some(vec!["from@localhost".parse().unwrap()]),
+            parts: Parts(vec![Part::new_text_plain("Hello!".into())]),
+            ..Msg::default()
+        }
+        .encrypt(true);
+
+        let err = msg.into_sendable_msg(&account).unwrap_err();
+        assert_eq!("cannot encrypt message without recipient", err.to_string());
+
+        msg.to = Some(vec![
+            "to1@localhost".parse().unwrap(),
+            "to2@localhost".parse().unwrap(),
+        ]);
+        msg.cc = Some(vec!["cc@localhost".parse().unwrap()]);
+        let sendable_msg = msg.into_sendable_msg(&account).unwrap();
+        let formatted = String::from_utf8(sendable_msg.formatted()).unwrap();
+        assert!(formatted.contains("multipart/encrypted"));
+        assert!(formatted.contains("to1@localhost to2@localhost cc@localhost "));
+    }
+
+    #[test]
+    fn it_should_preview_sendable_msg() {
+        let mut account = Account::default();
+        let msg = Msg {
+            from: Some(vec!["from@localhost".parse().unwrap()]),
+            to: Some(vec!["to@localhost".parse().unwrap()]),
+            subject: "Test".into(),
+            parts: Parts(vec![Part::new_text_plain("Hello!".into())]),
+            ..Msg::default()
+        };
+
+        let preview = msg.preview_sendable(&account).unwrap();
+        assert!(preview.contains("Subject: Test\r\n"));
+        assert!(preview.contains("Content-Type: multipart/mixed"));
+        assert!(preview.contains("Hello!"));
+
+        account.pgp_encrypt_cmd = Some("echo ENCRYPTED; true".into());
+        let preview = msg.encrypt(true).preview_sendable(&account).unwrap();
+        assert!(preview.contains("Content-Type: multipart/encrypted"));
+        assert!(preview.contains("ENCRYPTED"));
+        assert!(!preview.contains("Hello!"));
+    }
+
+    #[test]
+    fn it_should_add_default_recipients() {
+        let account = Account {
+            email: "test@localhost".into(),
+            default_cc: Some(vec!["team@localhost".into()]),
+            default_bcc: Some(vec!["Archive <archive@localhost>".into()]),
+            ..Account::default()
+        };
+
+        let tpl = Msg::default()
+            .to_tpl(TplOverride::default(), &account)
+            .unwrap();
+        assert!(tpl.contains("Cc: team@localhost\n"));
+        assert!(tpl.contains("Bcc: Archive <archive@localhost>\n"));
+
+        let msg = Msg {
+            cc: Some(vec!["Team <TEAM@localhost>".parse().unwrap()]),
+            bcc: Some(vec!["boss@localhost".parse().unwrap()]),
+            ..Msg::default()
+        };
+        let tpl = msg.to_tpl(TplOverride::default(), &account).unwrap();
+        assert!(tpl.contains("Cc: Team <TEAM@localhost>\n"));
+        assert!(tpl.contains("Bcc: boss@localhost, Archive <archive@localhost>\n"));
+
+        let msg = || Msg {
+            from: Some(vec!["sender@localhost".parse().unwrap()]),
+            cc: Some(vec!["cc@localhost".parse().unwrap()]),
+            ..Msg::default()
+        };
+        let reply = msg().into_reply(false, "plain", &account).unwrap();
+        let tpl = reply.to_tpl(TplOverride::default(), &account).unwrap();
+        assert!(!tpl.contains("Cc:"));
+        assert!(!tpl.contains("Bcc:"));
+        let reply_all = msg().into_reply(true, "plain", &account).unwrap();
+        let tpl = reply_all.to_tpl(TplOverride::default(), &account).unwrap();
+        assert!(tpl.contains("Cc: cc@localhost, team@localhost\n"));
+
+        let msg = Msg {
+            cc: Some(vec!["old-team@localhost".parse().unwrap()]),
+            ..Msg::default()
+        };
+        let tpl = msg.to_tpl(TplOverride::default(), &account).unwrap();
+        assert!(tpl.contains("Cc: old-team@localhost, team@localhost\n"));
+    }
+
+    #[test]
+    fn it_should_fold_text_parts() {
+        let msg = Msg {
+            parts: Parts(vec![Part::new_text_plain(
+                "Hello\t world,\n\n\n\nHow   are you?\n-- \nSig\n\n\nname".into(),
+            )]),
+            ..Msg::default()
+        };
+        let expected = "Hello world,\n\nHow are you?\n-- \nSig\n\n\nname";
+        assert_eq!(expected, msg.fold_text_plain_parts());
+        assert_eq!(expected, msg.fold_text_plain_parts());
+
+        let msg = Msg {
+            parts: Parts(vec![
+                Part::new_text_html("<p>Hello&nbsp;world</p>\n\n\n<p>Bye\t\tnow</p>".into()),
+                Part::new_text_html("<p>Again</p>".into()),
+            ]),
+            ..Msg::default()
+        };
+        assert_eq!(
+            "Hello world\n\nBye now\n\nAgain",
+            msg.fold_text_plain_parts()
+        );
+        assert_eq!(
+            "<p>Hello&nbsp;world</p>\n\n<p>Bye\t\tnow</p>\n\n<p>Again</p>",
+            msg.fold_text_html_parts()
+        );
+    }
+
+    #[test]
+    fn it_should_merge_consecutive_spaces() {
+        let msg = Msg {
+            parts: Parts(vec![Part::new_text_plain("a     b".into())]),
+            ..Msg::default()
+        };
+        assert_eq!("a b", msg.fold_text_plain_parts());
+        assert_eq!(
+            "a     b",
+            msg.fold_text_plain_parts_with(false, DEFAULT_SIG_DELIM)
+        );
+
+        let msg = Msg {
+            parts: Parts(vec![Part::new_text_html("<p>a     b</p>".into())]),
+            ..Msg::default()
+        };
+        assert_eq!("a b", msg.fold_text_plain_parts());
+    }
+
+    #[test]
+    fn it_should_extract_urls() {
+        let msg = Msg {
+            parts: Parts(vec![
+                Part::new_text_plain(
+                    "See <https://example.com/a?b=c>, or https://example.com/wiki/Rust_(language).\n\
+                     Write to mailto:test@localhost! Again: https://example.com/a?b=c."
+                        .into(),
+                ),
+                Part::new_text_html(
+                    r#"<a href="https://example.com/x?a=1&amp;b=2">x</a> <a href='mailto:test@localhost'>me</a> <a href="/relative">rel</a>"#
+                        .into(),
+                ),
+            ]),
+            ..Msg::default()
+        };
+
+        assert_eq!(
+            vec![
+                "https://example.com/a?b=c",
+                "https://example.com/wiki/Rust_(language)",
+                "mailto:test@localhost",
+                "https://example.com/x?a=1&b=2",
+            ],
+            msg.extract_urls()
+        );
+        assert!(Msg::default().extract_urls().is_empty());
+    }
+
+    #[test]
+    fn it_should_clone_msg() {
+        let msg = Msg::builder()
+            .subject("Hello")
+            .from("from@localhost")
+            .to("to@localhost")
+            .cc("cc@localhost")
+            .text_plain("Hello!")
+            .attachment("notes.txt", b"notes".to_vec())
+            .build()
+            .unwrap();
+        let msg = Msg {
+            id: 1,
+            flags: Flags::from(vec!["seen", "flagged"]),
+            message_id: Some("<id@localhost>".into()),
+            date: DateTime::parse_from_rfc2822("Tue, 1 Jul 2003 10:52:37 +0200").ok(),
+            ..msg
+        };
+
+        let clone = msg.clone();
+        assert_eq!(msg.id, clone.id);
+        assert_eq!(msg.flags.0, clone.flags.0);
+        assert_eq!(msg.subject, clone.subject);
+        assert_eq!(msg.from, clone.from);
+        assert_eq!(msg.to, clone.to);
+        assert_eq!(msg.cc, clone.cc);
+        assert_eq!(msg.message_id, clone.message_id);
+        assert_eq!(msg.date, clone.date);
+        assert_eq!(msg.fold_text_plain_parts(), clone.fold_text_plain_parts());
+        assert_eq!(msg.attachments()[0].content, clone.attachments()[0].content);
+    }
+
+    #[test]
+    fn it_should_dedup_cc_and_bcc_when_sending() {
+        let msg = Msg {
+            from: Some(vec!["from@localhost".parse().unwrap()]),
+            to: Some(vec!["to@localhost".parse().unwrap()]),
+            cc: Some(vec![
+                "cc@localhost".parse().unwrap(),
+                "CC <CC@localhost>".parse().unwrap(),
+            ]),
+            ..Msg::default()
+        };
+        let formatted = String::from_utf8(
+            msg.into_sendable_msg(&Account::default())
+                .unwrap()
+                .formatted(),
+        )
+        .unwrap();
+        assert!(formatted.contains("Cc: cc@localhost\r\n"));
+    }
+
+    #[test]
+    fn it_should_keep_bcc_in_envelope_only() {
+        let msg = Msg {
+            from: Some(vec!["from@localhost".parse().unwrap()]),
+            to: Some(vec!["to@localhost".parse().unwrap()]),
+            cc: Some(vec!["cc@localhost".parse().unwrap()]),
+            bcc: Some(vec!["bcc@localhost".parse().unwrap()]),
+            parts: Parts(vec![Part::new_text_plain("Hello!".into())]),
+            ..Msg::default()
+        };
+
+        let formatted = String::from_utf8(
+            msg.into_sendable_msg(&Account::default())
+                .unwrap()
+                .formatted(),
+        )
+        .unwrap();
+        assert!(formatted.contains("Cc: cc@localhost\r\n"));
+        assert!(!formatted.contains("Bcc:"));
+        assert!(!formatted.contains("bcc@localhost"));
+
+        let envelope: lettre::address::Envelope = msg.try_into().unwrap();
+        let recipients = envelope
+            .to()
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec!["to@localhost", "cc@localhost", "bcc@localhost"],
+            recipients
+        );
+    }
+
+    #[test]
+    fn it_should_build_envelope_with_all_recipients() {
+        let msg = Msg {
+            from: Some(vec!["from@localhost".parse().unwrap()]),
+            to: Some(vec!["to@localhost".parse().unwrap()]),
+            cc: Some(vec![
+                "cc@localhost".parse().unwrap(),
+                "To <to@localhost>".parse().unwrap(),
+            ]),
+            bcc: Some(vec!["bcc@localhost".parse().unwrap()]),
+            ..Msg::default()
+        };
+
+        let envelope: lettre::address::Envelope = msg.try_into().unwrap();
+        let recipients = envelope
+            .to()
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec!["to@localhost", "cc@localhost", "bcc@localhost"],
+            recipients
+        );
+    }
+
+    #[test]
+    fn it_should_detect_empty_body() {
+        let body = |content: &str| Msg {
+            parts: Parts(vec![Part::new_text_plain(content.into())]),
+            ..Msg::default()
+        };
+
+        let msg =
+            body("\n\nOn 01 Jan 2021, at 00:00, from@localhost wrote:\n> Hello!\n\n-- \nRegards");
+        assert!(msg.has_empty_body(DEFAULT_SIG_DELIM));
+        assert_eq!(0, msg.word_count(DEFAULT_SIG_DELIM));
+
+        let msg = body("Hi, see below:\n\n> Hello!\n\nThanks a lot!\n-- \nRegards");
+        assert!(!msg.has_empty_body(DEFAULT_SIG_DELIM));
+        assert_eq!(6, msg.word_count(DEFAULT_SIG_DELIM));
+
+        assert!(body("").has_empty_body(DEFAULT_SIG_DELIM));
+    }
+
+    #[test]
+    fn it_should_format_date_with_offset() {
+        let date = DateTime::parse_from_rfc2822("Fri, 01 Jan 2021 09:30:00 +0900").unwrap();
+
+        assert_eq!(
+            "01 Jan 2021, at 09:30",
+            format_date(&date, DEFAULT_REPLY_DATE_FMT, None).unwrap()
+        );
+        assert_eq!(
+            "01 Jan 2021, at 01:30 +01:00",
+            format_date(
+                &date,
+                "%d %b %Y, at %H:%M %:z",
+                Some(FixedOffset::east(3600))
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            "31 Dec 2020, at 19:30",
+            format_date(
+                &date,
+                DEFAULT_REPLY_DATE_FMT,
+                Some(FixedOffset::west(5 * 3600))
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn it_should_sign_msg() {
+        let mut account = Account::default();
+        let msg = Msg {
+            from: Some(vec!["from@localhost".parse().unwrap()]),
+            to: Some(vec!["to@localhost".parse().unwrap()]),
+            parts: Parts(vec![Part::new_text_plain("Hello!".into())]),
+            ..Msg::default()
+        }
+        .sign(true);
+
+        let err = msg.into_sendable_msg(&account).unwrap_err();
+        assert_eq!("cannot find pgp sign command in config", err.to_string());
+
+        account.pgp_sign_cmd = Some("echo SIGNATURE; true".into());
+        let sendable_msg = msg.into_sendable_msg(&account).unwrap();
+        let formatted = String::from_utf8(sendable_msg.formatted()).unwrap();
+        assert!(formatted.contains("multipart/signed"));
+        assert!(formatted.contains("protocol=\"application/pgp-signature\""));
+        assert!(formatted.contains("micalg=\"pgp-sha256\""));
+        assert!(formatted.contains("Hello!"));
+        assert!(formatted.contains("SIGNATURE"));
+
+        // The plain multipart given to the sign command does not stay on disk
+        account.pgp_sign_cmd = Some("echo".into());
+        let sendable_msg = msg.into_sendable_msg(&account).unwrap();
+        let formatted = String::from_utf8(sendable_msg.formatted()).unwrap();
+        let tmp_dir = temp_dir().to_string_lossy().to_string();
+        let path = formatted
+            .lines()
+            .find(|line| line.starts_with(&tmp_dir))
+            .unwrap();
+        assert!(!Path::new(path.trim()).exists());
+    }
+
+    #[test]
+    fn it_should_set_x_mailer_header() {
+        let mut account = Account {
+            mailer: "himalaya 0.0.0".into(),
+            ..Account::default()
+        };
+        let msg = Msg {
+            from: Some(vec!["from@localhost".parse().unwrap()]),
+            to: Some(vec!["to@localhost".parse().unwrap()]),
+            ..Msg::default()
+        };
+
+        let sendable_msg = msg.into_sendable_msg(&account).unwrap();
+        let formatted = String::from_utf8(sendable_msg.formatted()).unwrap();
+        assert!(formatted.contains("X-Mailer: himalaya 0.0.0\r\n"));
+        assert!(msg
+            .to_tpl(TplOverride::default(), &account)
+            .unwrap()
+            .contains("X-Mailer: himalaya 0.0.0\n"));
+
+        account.mailer = String::default();
+        let sendable_msg = msg.into_sendable_msg(&account).unwrap();
+        let formatted = String::from_utf8(sendable_msg.formatted()).unwrap();
+        assert!(!formatted.contains("X-Mailer"));
+        assert!(!msg
+            .to_tpl(TplOverride::default(), &account)
+            .unwrap()
+            .contains("X-Mailer"));
+    }
+
+    #[test]
+    fn it_should_set_in_reply_to_on_reply() {
+        let account = Account {
+            email: "test@localhost".into(),
+            ..Account::default()
+        };
+        let msg = Msg {
+            message_id: Some("<1@localhost>".into()),
+            ..Msg::default()
+        };
+
+        let reply = msg.into_reply(false, "plain", &account).unwrap();
+        assert_eq!(Some("<1@localhost>".into()), reply.in_reply_to);
+        assert_eq!(None, reply.message_id);
+    }
+
+    #[test]
+    fn it_should_quote_body_on_reply() {
+        let account = Account {
+            email: "test@localhost".into(),
+            ..Account::default()
+        };
+        let msg = Msg {
+            reply_to: Some(vec!["sender@localhost".parse().unwrap()]),
+            parts: Parts(vec![
+                Part::new_text_plain("Hello!\n-- \nSignature".into()),
+                Part::new_text_html("<p>Hello!</p>".into()),
+            ]),
+            ..Msg::default()
+        };
+
+        let reply = msg.into_reply(false, "plain", &account).unwrap();
+        assert_eq!(
+            "\n\nOn unknown date, sender@localhost wrote:\n> Hello!",
+            reply.fold_text_plain_parts()
+        );
+
+        let msg = Msg {
+            reply_to: Some(vec!["sender@localhost".parse().unwrap()]),
+            parts: Parts(vec![
+                Part::new_text_plain("Hello!".into()),
+                Part::new_text_html("<p>Hello!</p>".into()),
+            ]),
+            ..Msg::default()
+        };
+        let reply = msg.into_reply(false, "html", &account).unwrap();
+        assert_eq!(
+            "<div>On unknown date, sender@localhost wrote:</div>\n<blockquote type=\"cite\">\n<p>Hello!</p>\n</blockquote>",
+            reply.fold_text_parts("html")
+        );
+    }
+
+    #[test]
+    fn it_should_render_html_reply_quote_from_plain() {
+        let account = Account {
+            email: "test@localhost".into(),
+            ..Account::default()
+        };
+        let msg = Msg {
+            reply_to: Some(vec!["sender@localhost".parse().unwrap()]),
+            parts: Parts(vec![Part::new_text_plain(
+                "Hello <you> & all!\n> Quoted\n> > Nested\n>>Nested too\nBack\n-- \nSignature"
+                    .into(),
+            )]),
+            ..Msg::default()
+        };
+        let expected = concat![
+            "<div>On unknown date, sender@localhost wrote:</div>\n",
+            "<blockquote type=\"cite\">\n",
+            "Hello &lt;you&gt; &amp; all!<br>\n",
+            "<blockquote type=\"cite\">\n",
+            "Quoted<br>\n",
+            "<blockquote type=\"cite\">\n",
+            "Nested<br>\n",
+            "Nested too<br>\n",
+            "</blockquote>\n",
+            "</blockquote>\n",
+            "Back<br>\n",
+            "</blockquote>",
+        ];
+
+        assert_eq!(
+            expected,
+            msg.html_reply_quote("On unknown date, sender@localhost wrote:")
+        );
+        let reply = msg.into_reply(false, "html", &account).unwrap();
+        assert_eq!(expected, reply.fold_text_parts("html"));
+    }
+
+    #[test]
+    fn it_should_customize_quote_on_reply() {
+        let account = Account {
+            email: "test@localhost".into(),
+            reply_quote_prefix: Some("| ".into()),
+            reply_attribution: Some("Le {date}, {sender} a écrit :".into()),
+            reply_date_fmt: Some("%d/%m/%Y".into()),
+            ..Account::default()
+        };
+        let msg = Msg {
+            reply_to: Some(vec!["Sender <sender@localhost>".parse().unwrap()]),
+            date: DateTime::parse_from_rfc3339("2022-02-08T10:00:00+01:00").ok(),
+            parts: Parts(vec![Part::new_text_plain("Hello!\n| Quoted".into())]),
+            ..Msg::default()
+        };
+
+        let reply = msg.into_reply(false, "plain", &account).unwrap();
+        assert_eq!(
+            "\n\nLe 08/02/2022, Sender a écrit :\n| Hello!\n|| Quoted",
+            reply.fold_text_plain_parts()
+        );
+    }
+
+    #[test]
+    fn it_should_stop_quote_at_custom_sig_delim_on_reply() {
+        let account = Account {
+            email: "test@localhost".into(),
+            sig_delim: "~~~~ \n".into(),
+            ..Account::default()
+        };
+        let msg = || Msg {
+            reply_to: Some(vec!["sender@localhost".parse().unwrap()]),
+            parts: Parts(vec![Part::new_text_plain(
+                "Hello!\n-- \nNot a signature\n~~~~\nSignature".into(),
+            )]),
+            ..Msg::default()
+        };
+
+        let reply = msg().into_reply(false, "plain", &account).unwrap();
+        assert_eq!(
+            "\n\nOn unknown date, sender@localhost wrote:\n> Hello!\n> -- \n> Not a signature",
+            reply.fold_text_plain_parts_with(false, DEFAULT_SIG_DELIM)
+        );
+
+        let account = Account {
+            email: "test@localhost".into(),
+            ..Account::default()
+        };
+        let reply = msg().into_reply(false, "plain", &account).unwrap();
+        assert!(reply.fold_text_plain_parts().ends_with("> Hello!"));
+    }
+
+    #[test]
+    fn it_should_reply_to_list() {
+        let account = Account {
+            email: "test@localhost".into(),
+            ..Account::default()
+        };
+        let msg = |list_post: Option<&str>| {
+            let mut raw = String::from(
+                "From: sender@localhost\nTo: list@localhost\nCc: other@localhost\nSubject: Hello\n",
+            );
+            if let Some(list_post) = list_post {
+                raw.push_str(&format!("List-Post: {}\n", list_post));
+            }
+            raw.push_str("\nHello!\n");
+            Msg::from_raw(raw.as_bytes()).unwrap()
+        };
+
+        let reply = msg(Some(
+            "<http://localhost/post>, <mailto:list@localhost?subject=post>",
+        ))
+        .into_reply_list("plain", &account)
+        .unwrap();
+        assert_eq!(Some(vec!["list@localhost".parse().unwrap()]), reply.to);
+        assert_eq!(None, reply.cc);
+        assert_eq!("Re: Hello", reply.subject);
+
+        let err = msg(None).into_reply_list("plain", &account).unwrap_err();
+        assert_eq!(
+            "cannot reply to list: cannot find List-Post header",
+            err.to_string()
+        );
+        let err = msg(Some("<https://localhost/post>"))
+            .into_reply_list("plain", &account)
+            .unwrap_err();
+        assert!(err.to_string().contains("cannot find mailto address"));
+        for list_post in ["NO", "no (posting not allowed)"] {
+            let err = msg(Some(list_post))
+                .into_reply_list("plain", &account)
+                .unwrap_err();
+            assert_eq!(
+                "cannot reply to list: posting is not allowed",
+                err.to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn it_should_reply_with_quote() {
+        let account = Account {
+            email: "test@localhost".into(),
+            ..Account::default()
+        };
+        let msg = Msg {
+            from: Some(vec!["Author <author@localhost>".parse().unwrap()]),
+            subject: "Hello".into(),
+            parts: Parts(vec![Part::new_text_plain(
+                "First paragraph.\n\nSecond paragraph,\nselected.\n\n-- \nAuthor".into(),
+            )]),
+            ..Msg::default()
+        };
+        let full_reply = msg.clone().into_reply(false, "plain", &account).unwrap();
+        let attribution = full_reply.fold_text_plain_parts();
+        let attribution = attribution.trim().lines().next().unwrap();
+
+        let reply = msg
+            .into_reply_with_quote(false, &account, "\nSecond paragraph,\n> selected.\n")
+            .unwrap();
+        assert_eq!("Re: Hello", reply.subject);
+        assert_eq!(
+            Some(vec!["Author <author@localhost>".parse().unwrap()]),
+            reply.to
+        );
+        assert_eq!(
+            format!("\n\n{}\n> Second paragraph,\n>> selected.", attribution),
+            reply.fold_text_plain_parts()
+        );
+    }
+
+    #[test]
+    fn it_should_reply_to_sender() {
+        let msg = || Msg {
+            from: Some(vec!["Author <author@localhost>".parse().unwrap()]),
+            reply_to: Some(vec!["List <list@localhost>".parse().unwrap()]),
+            parts: Parts(vec![Part::new_text_plain("Hello!".into())]),
+            ..Msg::default()
+        };
+
+        let account = Account {
+            email: "test@localhost".into(),
+            ..Account::default()
+        };
+        let reply = msg().into_reply(false, "plain", &account).unwrap();
+        assert_eq!(
+            Some(vec!["List <list@localhost>".parse().unwrap()]),
+            reply.to
+        );
+        assert!(reply
+            .fold_text_plain_parts()
+            .starts_with("\n\nOn unknown date, List wrote:"));
+
+        let account = Account {
+            email: "test@localhost".into(),
+            reply_to_sender: true,
+            ..Account::default()
+        };
+        let reply = msg().into_reply(false, "plain", &account).unwrap();
+        assert_eq!(
+            Some(vec!["Author <author@localhost>".parse().unwrap()]),
+            reply.to
+        );
+        assert!(reply
+            .fold_text_plain_parts()
+            .starts_with("\n\nOn unknown date, Author wrote:"));
+
+        let account = Account {
+            email: "test@localhost".into(),
+            ..Account::default()
+        };
+        let msg = Msg {
+            reply_to: None,
+            ..msg()
+        };
+        let reply = msg.into_reply(false, "plain", &account).unwrap();
+        assert!(reply
+            .fold_text_plain_parts()
+            .starts_with("\n\nOn unknown date, Author wrote:"));
+    }
+
+    #[test]
+    fn it_should_strip_all_sigs_on_reply() {
+        let account = Account {
+            email: "test@localhost".into(),
+            reply_strip_all_sigs: true,
+            ..Account::default()
+        };
+        let msg = Msg {
+            reply_to: Some(vec!["sender@localhost".parse().unwrap()]),
+            parts: Parts(vec![Part::new_text_plain(
+                "Thanks!\n-- \nBob\n\nOn Monday, alice@localhost wrote:\n> Hello!\n> -- \n> Alice\n> Company\n>\n> Bye"
+                    .into(),
+            )]),
+            ..Msg::default()
+        };
+
+        let reply = msg.into_reply(false, "plain", &account).unwrap();
+        assert_eq!(
+            "\n\nOn unknown date, sender@localhost wrote:\n> Thanks!\n> \n> On Monday, alice@localhost wrote:\n>> Hello!\n>>\n>> Bye",
+            reply.fold_text_plain_parts_with(false, DEFAULT_SIG_DELIM)
+        );
+    }
+
+    #[test]
+    fn it_should_limit_attachment_size() {
+        let dir = temp_dir().join(format!("himalaya-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let small = dir.join("small.txt");
+        fs::write(&small, vec![b'a'; 512]).unwrap();
+        let big = dir.join("report.pdf");
+        fs::write(&big, vec![b'a'; 3 * 1024]).unwrap();
+        let (small, big) = (small.to_string_lossy(), big.to_string_lossy());
+
+        let msg = Msg::default()
+            .add_attachments(vec![&small, &big], &Account::default())
+            .unwrap();
+        assert_eq!(512 + 3 * 1024, msg.total_attachment_size());
+
+        let account = Account {
+            max_attachment_size: Some(2 * 1024 + 512),
+            ..Account::default()
+        };
+        let msg = Msg::default()
+            .add_attachments(vec![&small], &account)
+            .unwrap();
+        assert_eq!(512, msg.total_attachment_size());
+        let err = Msg::default()
+            .add_attachments(vec![&small, &big], &account)
+            .unwrap_err();
+        assert_eq!(
+            "attachment report.pdf is 3 KB, exceeds limit of 2.5 KB",
+            err.to_string()
+        );
+
+        let tpl = format!("To: to@localhost\nAttachment: {}\n\nBody\n", big);
+        assert!(Msg::from_tpl(&tpl).is_ok());
+        let err = Msg::from_tpl_with(&tpl, account.max_attachment_size).unwrap_err();
+        assert_eq!(
+            "attachment report.pdf is 3 KB, exceeds limit of 2.5 KB",
+            err.root_cause().to_string()
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_should_select_attachment_by_index() {
+        let msg = Msg {
+            parts: Parts(vec![
+                Part::Binary(attachment(3, "c.bin", "application/octet-stream")),
+                Part::Binary(BinaryPart {
+                    disposition: Disposition::Inline,
+                    ..attachment(0, "logo.png", "image/png")
+                }),
+                Part::Binary(attachment(1, "a.bin", "application/octet-stream")),
+                Part::Binary(attachment(2, "b.bin", "application/octet-stream")),
+            ]),
+            ..Msg::default()
+        };
+
+        assert_eq!(3, msg.attachment_count());
+        let filenames = (0..msg.attachment_count())
+            .map(|i| msg.attachment(i).unwrap().filename.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["a.bin", "b.bin", "c.bin"], filenames);
+        assert_eq!(
+            msg.attachments()
+                .iter()
+                .map(|part| part.filename.as_str())
+                .collect::<Vec<_>>(),
+            filenames
+        );
+        assert!(msg.attachment(3).is_none());
+        assert_eq!(0, Msg::default().attachment_count());
+        assert!(Msg::default().attachment(0).is_none());
+    }
+
+    #[test]
+    fn it_should_encode_non_ascii_attachment_filenames() {
+        let account = Account::default();
+        let msg = Msg {
+            from: Some(vec!["from@localhost".parse().unwrap()]),
+            to: Some(vec!["to@localhost".parse().unwrap()]),
+            ..Msg::default()
+        }
+        .add_attachment_bytes("rapport-été.pdf", b"rapport".to_vec())
+        .add_attachment_bytes("report \"final\".pdf", b"report".to_vec())
+        .add_attachment_bytes("notes.txt", b"notes".to_vec());
+
+        let formatted =
+            String::from_utf8(msg.into_sendable_msg(&account).unwrap().formatted()).unwrap();
+        assert!(formatted.contains(
+            "Content-Disposition: attachment; filename*=UTF-8''rapport-%C3%A9t%C3%A9.pdf\r\n"
+        ));
+        assert!(formatted.contains(
+            "Content-Disposition: attachment; filename*=UTF-8''report%20%22final%22.pdf\r\n"
+        ));
+        assert!(formatted.contains("Content-Disposition: attachment; filename=\"notes.txt\"\r\n"));
+
+        let parsed_mail = mailparse::parse_mail(formatted.as_bytes()).unwrap();
+        let msg = Msg {
+            parts: Parts::from_parsed_mail(&account, &parsed_mail).unwrap(),
+            ..Msg::default()
+        };
+        assert_eq!(
+            vec!["rapport-été.pdf", "report \"final\".pdf", "notes.txt"],
+            msg.attachments()
+                .iter()
+                .map(|part| part.filename.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn it_should_add_attachment_from_bytes() {
+        let msg = Msg::default()
+            .add_attachment_bytes("chart.png", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec())
+            .add_attachment_bytes("notes.txt", b"notes".to_vec());
+
+        let attachments = msg.attachments();
+        assert_eq!(2, attachments.len());
+        assert_eq!("chart.png", attachments[0].filename);
+        assert_eq!("image/png", attachments[0].mime);
+        assert_eq!("notes.txt", attachments[1].filename);
+        assert_eq!("text/plain", attachments[1].mime);
+        assert_eq!(b"notes".to_vec(), attachments[1].content);
+    }
+
+    #[test]
+    fn it_should_detect_attachment_mime_by_extension() {
+        let msg = Msg::default()
+            .add_attachment_bytes("invite.ics", b"BEGIN:VCALENDAR\nEND:VCALENDAR\n".to_vec())
+            .add_attachment_bytes("data.CSV", b"a,b\n1,2\n".to_vec())
+            .add_attachment_bytes("notes.txt", b"notes".to_vec())
+            .add_attachment_bytes("noext", b"notes".to_vec());
+
+        let mimes = msg
+            .attachments()
+            .into_iter()
+            .map(|part| part.mime)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec!["text/calendar", "text/csv", "text/plain", "text/plain"],
+            mimes
+        );
+    }
+
+    #[test]
+    fn it_should_add_attachment_with_mime() {
+        let account = Account::default();
+        let dir = temp_dir().join(format!("himalaya-attachments-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let ics = dir.join("invite.ics");
+        fs::write(&ics, "BEGIN:VCALENDAR\nEND:VCALENDAR\n").unwrap();
+        let log = dir.join("report.log");
+        fs::write(&log, "a,b\n1,2\n").unwrap();
+
+        let msg = Msg::default()
+            .add_attachment_with_mime(ics.to_str().unwrap(), None, &account)
+            .unwrap()
+            .add_attachment_with_mime(log.to_str().unwrap(), Some("text/csv"), &account)
+            .unwrap();
+        let attachments = msg.attachments();
+        assert_eq!("text/calendar", attachments[0].mime);
+        assert_eq!("text/csv", attachments[1].mime);
+        assert_eq!(1, attachments[1].order);
+
+        let err = Msg::default()
+            .add_attachment_with_mime(log.to_str().unwrap(), Some("not a mime"), &account)
+            .unwrap_err();
+        assert_eq!(r#"cannot parse content type "not a mime""#, err.to_string());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_should_replace_and_remove_attachments() {
+        let mut msg = Msg {
+            parts: Parts(vec![
+                Part::new_text_plain("Hello!".into()),
+                Part::Binary(BinaryPart {
+                    content: b"draft".to_vec(),
+                    ..attachment(0, "notes.txt", "text/plain")
+                }),
+            ]),
+            ..Msg::default()
+        };
+
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR".to_vec();
+        msg.replace_attachment("notes.txt", png.clone()).unwrap();
+        let attachments = msg.attachments();
+        assert_eq!(1, attachments.len());
+        assert_eq!("image/png", attachments[0].mime);
+        assert_eq!(png, attachments[0].content);
+
+        let err = msg.replace_attachment("unknown.txt", vec![]).unwrap_err();
+        assert_eq!(r#"cannot find attachment "unknown.txt""#, err.to_string());
+
+        assert!(!msg.remove_attachment("unknown.txt"));
+        assert!(msg.remove_attachment("notes.txt"));
+        assert!(msg.attachments().is_empty());
+        assert_eq!("Hello!", msg.fold_text_plain_parts());
+    }
+
+    #[test]
+    fn it_should_normalize_subject() {
+        let subject = |subject: &str| {
+            Msg {
+                subject: subject.into(),
+                ..Msg::default()
+            }
+            .normalized_subject()
+        };
+
+        assert_eq!("Hello", subject("Hello"));
+        assert_eq!(
+            "Hello Bcc: evil@localhost",
+            subject("Hello\r\nBcc: evil@localhost")
+        );
+        assert_eq!("Hello world", subject(" Hello\t\x07world\n "));
+        assert_eq!("Re: Hello", subject("Re: Re: RE: Hello"));
+        assert_eq!("Re: Hello", subject("Re: Fwd: re[2]: Hello"));
+        assert_eq!("Fwd: Hello", subject("FW: Fwd:Re: Hello"));
+        assert_eq!("Hello: Re: world", subject("Hello: Re: world"));
+        assert_eq!("Re: Hello", subject("AW: SV: Hello"));
+    }
+
+    #[test]
+    fn it_should_detect_reply_and_forward() {
+        let msg = |subject: &str| Msg {
+            subject: subject.into(),
+            ..Msg::default()
+        };
+
+        for subject in ["RE: Hello", "Re : Hello", "AW: Hello", "re[2]: Hello"] {
+            assert!(msg(subject).is_reply(), "{:?} should be a reply", subject);
+            assert!(
+                !msg(subject).is_forward(),
+                "{:?} should not be a forward",
+                subject
+            );
+        }
+        for subject in ["Fw: Hello", "FWD: Hello", "fwd : Hello"] {
+            assert!(
+                msg(subject).is_forward(),
+                "{:?} should be a forward",
+                subject
+            );
+            assert!(
+                !msg(subject).is_reply(),
+                "{:?} should not be a reply",
+                subject
+            );
+        }
+        for subject in ["Hello", "Hello: Re: world", "Rebooting: done", "Awesome"] {
+            assert!(
+                !msg(subject).is_reply(),
+                "{:?} should not be a reply",
+                subject
+            );
+            assert!(
+                !msg(subject).is_forward(),
+                "{:?} should not be a forward",
+                subject
+            );
+        }
+
+        let msg = Msg {
+            in_reply_to: Some("<id@localhost>".into()),
+            ..msg("Hello")
+        };
+        assert!(msg.is_reply());
+        let msg = Msg {
+            in_reply_to: None,
+            references: Some(vec!["<id@localhost>".into()]),
+            ..msg
+        };
+        assert!(msg.is_reply());
+    }
+
+    #[test]
+    fn it_should_normalize_subject_on_reply_and_forward() {
+        let account = Account {
+            email: "test@localhost".into(),
+            ..Account::default()
+        };
+        let msg = || Msg {
+            from: Some(vec!["sender@localhost".parse().unwrap()]),
+            subject: "Re: Re: Re: Hello\n".into(),
+            ..Msg::default()
+        };
+
+        let reply = msg().into_reply(false, "plain", &account).unwrap();
+        assert_eq!("Re: Hello", reply.subject);
+        let fwd = msg().into_forward(false, "plain", &account).unwrap();
+        assert_eq!("Fwd: Re: Hello", fwd.subject);
+        let reply = Msg {
+            subject: "Fwd: Fwd: Hello".into(),
+            ..msg()
+        }
+        .into_reply(false, "plain", &account)
+        .unwrap();
+        assert_eq!("Re: Fwd: Hello", reply.subject);
+    }
+
+    #[test]
+    fn it_should_parse_tpl_without_body_separator() {
+        let msg = Msg::from_tpl(concat![
+            "To: to@localhost\n",
+            "Subject: Hello\n",
+            "Hello world,\n",
+            "Note: this line is part of the body.\n",
+        ])
+        .unwrap();
+        assert_eq!(Some(vec!["to@localhost".parse().unwrap()]), msg.to);
+        assert_eq!("Hello", msg.subject);
+        assert_eq!(
+            "Hello world,\nNote: this line is part of the body.\n",
+            msg.fold_text_plain_parts()
+        );
+
+        let msg = Msg::from_tpl("Subject: Hello\r\nDear John: hi!\r\n").unwrap();
+        assert_eq!("Hello", msg.subject);
+        assert_eq!("Dear John: hi!\r\n", msg.fold_text_plain_parts());
+
+        let tpl = "Subject: Hello\n  world\n\nBody";
+        assert!(matches!(
+            insert_missing_body_separator(tpl),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn it_should_get_custom_headers() {
+        let msg = Msg::from_raw(
+            concat![
+                "From: from@localhost\r\n",
+                "To: to@localhost\r\n",
+                "List-Unsubscribe: <mailto:leave@localhost>\r\n",
+                "X-Spam-Score: 1.5\r\n",
+                "Received: first\r\n",
+                "Received: second\r\n",
+                "X-Encoded: =?UTF-8?Q?Caf=C3=A9?=\r\n",
+                "\r\n",
+                "Hello!\r\n",
+            ]
+            .as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            Some("<mailto:leave@localhost>"),
+            msg.header("list-unsubscribe")
+        );
+        assert_eq!(Some("1.5"), msg.header("X-SPAM-SCORE"));
+        assert_eq!(Some("first"), msg.header("Received"));
+        assert_eq!(2, msg.headers["received"].len());
+        assert_eq!(Some("Café"), msg.header("X-Encoded"));
+        assert_eq!(None, msg.header("X-Missing"));
+
+        let msg = Msg::from_tpl("To: to@localhost\nX-Custom: value\n\nHello!").unwrap();
+        assert_eq!(Some("value"), msg.header("x-custom"));
+    }
+
+    #[test]
+    fn it_should_get_unsubscribe_targets() {
+        let raw = concat![
+            "From: list@localhost\r\n",
+            "List-Unsubscribe: <mailto:leave@localhost?subject=unsubscribe>,\r\n",
+            " <https://localhost/unsubscribe>\r\n",
+            "List-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n",
+            "\r\n",
+            "Hello!\r\n",
+        ];
+        let targets = Msg::from_raw(raw.as_bytes()).unwrap().unsubscribe_targets();
+        assert_eq!(2, targets.len());
+        assert!(matches!(
+            &targets[1],
+            UnsubscribeTarget::Http { url, one_click: true } if url.as_str() == "https://localhost/unsubscribe"
+        ));
+
+        let raw = raw.replace("List-Unsubscribe-Post", "X-Other");
+        let targets = Msg::from_raw(raw.as_bytes()).unwrap().unsubscribe_targets();
+        assert!(matches!(
+            &targets[1],
+            UnsubscribeTarget::Http {
+                one_click: false,
+                ..
+            }
+        ));
+        assert!(Msg::default().unsubscribe_targets().is_empty());
+    }
+
+    #[test]
+    fn it_should_detect_language() {
+        let msg = Msg::from_raw(
+            b"Content-Language: fr-FR, en\r\nSubject: Bonjour\r\n\r\nHello, how are you?\r\n",
+        )
+        .unwrap();
+        assert_eq!(Some("fr-FR, en"), msg.header("Content-Language"));
+        assert_eq!(Some("fra".into()), msg.detect_language());
+
+        let msg = Msg::from_raw(b"Content-Language: DEU\r\n\r\nHallo\r\n").unwrap();
+        assert_eq!(Some("deu".into()), msg.detect_language());
+    }
+
+    #[cfg(feature = "language-detection")]
+    #[test]
+    fn it_should_detect_body_language() {
+        let msg = Msg::from_raw(
+            concat![
+                "Subject: Réunion\r\n",
+                "Content-Type: text/plain; charset=utf-8\r\n",
+                "\r\n",
+                "Bonjour à tous, la réunion de demain est reportée à la semaine prochaine. ",
+                "Merci de confirmer votre présence avant vendredi.\r\n",
+            ]
+            .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(Some("fra".into()), msg.detect_language());
+
+        assert_eq!(None, Msg::default().detect_language());
+    }
+
+    #[test]
+    fn it_should_get_raw_text_parts() {
+        let msg = Msg {
+            parts: Parts(vec![
+                Part::new_text_plain("fn main() {\n    let  x =     1;\n}\n\n\n\n-- \nSig".into()),
+                Part::new_text_html("<pre>a     b</pre>\n\n\n".into()),
+                Part::new_text_plain("\tsecond".into()),
+            ]),
+            ..Msg::default()
+        };
+
+        assert_eq!(
+            "fn main() {\n    let  x =     1;\n}\n\n\n\n-- \nSig\n\n\tsecond",
+            msg.raw_text_plain()
+        );
+        assert_eq!("<pre>a     b</pre>\n\n\n", msg.raw_text_html());
+        assert_ne!(msg.raw_text_plain(), msg.fold_text_plain_parts());
+    }
+
+    #[test]
+    fn it_should_merge_threading_headers() {
+        let mut msg = Msg {
+            subject: "Re: Hello".into(),
+            message_id: Some("<draft@localhost>".into()),
+            ..Msg::default()
+        };
+
+        msg.merge_with(Msg {
+            in_reply_to: Some("<original@localhost>".into()),
+            references: Some(vec!["<original@localhost>".into()]),
+            reply_to: Some(vec!["reply@localhost".parse().unwrap()]),
+            ..Msg::default()
+        });
+
+        assert_eq!(Some("<original@localhost>"), msg.in_reply_to.as_deref());
+        assert_eq!(
+            Some(vec!["<original@localhost>".to_string()]),
+            msg.references
+        );
+        assert_eq!(Some(vec!["reply@localhost".parse().unwrap()]), msg.reply_to);
+        assert_eq!(Some("<draft@localhost>"), msg.message_id.as_deref());
+        assert_eq!("Re: Hello", msg.subject);
+    }
+
+    #[test]
+    fn it_should_compare_content() {
+        let msg = Msg::from_tpl(
+            "To: a@localhost, b@localhost\nSubject: Hello\nX-Custom: 1\n\nHello,\nworld\n\n-- \nSig",
+        )
+        .unwrap();
+
+        let other = Msg::from_tpl(
+            "Subject: Hello  \nTo: b@localhost, a@localhost\n\nHello,  \nworld\n\n\n\t",
+        )
+        .unwrap();
+        assert!(msg.content_eq(&other, DEFAULT_SIG_DELIM));
+
+        let other =
+            Msg::from_tpl("To: a@localhost, b@localhost\nSubject: Hello\n\nHello, world").unwrap();
+        assert!(!msg.content_eq(&other, DEFAULT_SIG_DELIM));
+
+        let other = Msg::from_tpl("To: a@localhost\nSubject: Hello\n\nHello,\nworld").unwrap();
+        assert!(!msg.content_eq(&other, DEFAULT_SIG_DELIM));
+
+        let other = Msg {
+            encrypt: true,
+            ..msg.clone()
+        };
+        assert!(!msg.content_eq(&other, DEFAULT_SIG_DELIM));
+
+        // Adding an attachment is a change, even if the rest is the same
+        let path = temp_dir().join(format!("himalaya-{}.txt", Uuid::new_v4()));
+        fs::write(&path, "Hello, world!").unwrap();
+        let other = Msg::from_tpl(&format!(
+            "To: a@localhost, b@localhost\nSubject: Hello\nAttachment: {}\n\nHello,\nworld",
+            path.to_string_lossy()
+        ))
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+        assert!(!msg.content_eq(&other, DEFAULT_SIG_DELIM));
+    }
+
+    #[test]
+    fn it_should_use_custom_sig_delim() {
+        let msg = |body: &str| Msg {
+            parts: Parts(vec![Part::new_text_plain(body.into())]),
+            ..Msg::default()
+        };
+        let body = "Hello  world!\n~~~\nJohn  Doe";
+
+        assert_eq!(
+            "Hello world!\n~~~\nJohn  Doe",
+            msg(body).fold_text_plain_parts_with(true, "~~~")
+        );
+        assert_eq!(2, msg(body).word_count("~~~"));
+        assert!(msg("~~~\nJohn Doe").has_empty_body("~~~"));
+        assert!(!msg("~~~\nJohn Doe").has_empty_body(DEFAULT_SIG_DELIM));
+        assert!(msg(body).content_eq(&msg("Hello  world!\n~~~\nJane"), "~~~"));
+        assert_eq!("> Hello world!", msg(body).quote_body("> ", "plain", "~~~"));
+    }
+
+    #[test]
+    fn it_should_quote_body() {
+        let msg = Msg {
+            parts: Parts(vec![
+                Part::new_text_plain("Hello!\n> Quoted\n-- \nSignature".into()),
+                Part::new_text_html("<p>Hello!</p>".into()),
+            ]),
+            ..Msg::default()
+        };
+
+        assert_eq!(
+            "> Hello!\n>> Quoted",
+            msg.quote_body("> ", "plain", DEFAULT_SIG_DELIM)
+        );
+        assert_eq!(
+            "<blockquote>\n<p>Hello!</p>\n</blockquote>",
+            msg.quote_body("> ", "html", DEFAULT_SIG_DELIM)
+        );
+    }
+
+    #[test]
+    fn it_should_quote_body_on_forward() {
+        let account = Account {
+            email: "test@localhost".into(),
+            forward_quote_prefix: Some("> ".into()),
+            ..Account::default()
+        };
+        let msg = Msg {
+            subject: "Hello".into(),
+            parts: Parts(vec![Part::new_text_plain("Hello!\n-- \nSignature".into())]),
+            ..Msg::default()
+        };
+
+        let fwd = msg.into_forward(false, "plain", &account).unwrap();
+        assert_eq!(
+            concat![
+                "\n\n-------- Forwarded Message --------\n",
+                "Subject: Hello\n",
+                "\n",
+                "> Hello!\n",
+                "> -- \n",
+                "> Signature",
+            ],
+            fwd.fold_text_plain_parts_with(false, DEFAULT_SIG_DELIM)
+        );
+    }
+
+    #[test]
+    fn it_should_keep_attachments_on_forward() {
+        let account = Account {
+            email: "test@localhost".into(),
+            ..Account::default()
+        };
+        let msg = || Msg {
+            parts: Parts(vec![
+                Part::new_text_plain("Hello!".into()),
+                Part::Binary(BinaryPart {
+                    content: b"doc".to_vec(),
+                    ..attachment(0, "doc.pdf", "application/pdf")
+                }),
+                Part::Binary(BinaryPart {
+                    content: b"logo".to_vec(),
+                    disposition: Disposition::Inline,
+                    ..attachment(1, "logo.png", "image/png")
+                }),
+            ]),
+            ..Msg::default()
+        };
+
+        let fwd = msg().into_forward(true, "plain", &account).unwrap();
+        let attachments = fwd.attachments();
+        assert_eq!(2, attachments.len());
+        assert_eq!("doc.pdf", attachments[0].filename);
+        assert_eq!(b"doc".to_vec(), attachments[0].content);
+        assert_eq!("logo.png", attachments[1].filename);
+
+        let fwd = msg().into_forward(false, "plain", &account).unwrap();
+        assert_eq!(0, fwd.all_attachments().len());
+        assert!(fwd.fold_text_plain_parts().ends_with("Hello!"));
+    }
+
+    #[test]
+    fn it_should_chain_references_on_reply() {
+        let account = Account {
+            email: "test@localhost".into(),
+            ..Account::default()
+        };
+
+        let msg = Msg {
+            message_id: Some("<1@localhost>".into()),
+            ..Msg::default()
+        };
+        let reply = msg.into_reply(false, "plain", &account).unwrap();
+        assert_eq!(Some(vec!["<1@localhost>".into()]), reply.references);
+
+        let msg = Msg {
+            reply_to: Some(vec!["sender@localhost".parse().unwrap()]),
+            message_id: Some("<3@localhost>".into()),
+            in_reply_to: Some("<2@localhost>".into()),
+            references: Some(vec!["<1@localhost>".into(), "<2@localhost>".into()]),
+            ..Msg::default()
+        };
+        let reply = msg.into_reply(false, "plain", &account).unwrap();
+        assert_eq!(
+            Some(vec![
+                "<1@localhost>".into(),
+                "<2@localhost>".into(),
+                "<3@localhost>".into()
+            ]),
+            reply.references
+        );
+
+        let tpl = reply.to_tpl(TplOverride::default(), &account).unwrap();
+        assert!(tpl.contains("References: <1@localhost> <2@localhost> <3@localhost>\n"));
+        assert_eq!(reply.references, Msg::from_tpl(&tpl).unwrap().references);
+    }
+
+    #[test]
+    fn it_should_round_trip_base64_utf8_body() {
+        let account = Account::default();
+        let msg = Msg::from_raw(
+            concat![
+                "From: from@localhost\r\n",
+                "To: to@localhost\r\n",
+                "Content-Type: text/plain; charset=utf-8\r\n",
+                "Content-Transfer-Encoding: base64\r\n",
+                "\r\n",
+                "Q2Fmw6kg4piVLCDDp2EgdmEgPw0K\r\n",
+            ]
+            .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!("Café ☕, ça va ?\r\n", msg.raw_text_plain());
+
+        let msg = Msg::from_raw(&msg.formatted(&account).unwrap()).unwrap();
+        assert_eq!("Café ☕, ça va ?\r\n", msg.raw_text_plain());
+
+        let tpl = concat![
+            "Content-Type: text/plain; charset=utf-8\n",
+            "Content-Transfer-Encoding: base64\n",
+            "\n",
+            "Q2Fmw6kg4piVLCDDp2EgdmEgPw0K\n",
+        ];
+        assert_eq!(
+            "Café ☕, ça va ?\r\n",
+            Msg::from_tpl(tpl).unwrap().raw_text_plain()
+        );
+    }
+
+    #[test]
+    fn it_should_round_trip_qp_latin1_body() {
+        let account = Account::default();
+        let msg = Msg::from_raw(
+            concat![
+                "From: from@localhost\r\n",
+                "To: to@localhost\r\n",
+                "Content-Type: text/plain; charset=iso-8859-1\r\n",
+                "Content-Transfer-Encoding: quoted-printable\r\n",
+                "\r\n",
+                "Caf=E9, =E7a va =3F\r\n",
+            ]
+            .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!("Café, ça va ?", msg.raw_text_plain());
+
+        let msg = Msg::from_raw(&msg.formatted(&account).unwrap()).unwrap();
+        assert_eq!("Café, ça va ?", msg.raw_text_plain());
+
+        let tpl = concat![
+            "Content-Type: text/plain; charset=iso-8859-1\n",
+            "Content-Transfer-Encoding: quoted-printable\n",
+            "\n",
+            "Caf=E9, =E7a va =3F\n",
+        ];
+        assert_eq!(
+            "Café, ça va ?\n",
+            Msg::from_tpl(tpl).unwrap().raw_text_plain()
+        );
+    }
+
+    #[test]
+    fn it_should_round_trip_rfc2047_subject() {
+        let account = Account::default();
+        let msg = Msg::from_raw(
+            concat![
+                "From: from@localhost\r\n",
+                "To: to@localhost\r\n",
+                "Subject: =?ISO-8859-1?Q?Caf=E9?= =?UTF-8?B?4piV?=\r\n",
+                "\r\n",
+                "Hello!\r\n",
+            ]
+            .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!("Café☕", msg.subject);
+
+        let msg = Msg::from_raw(&msg.formatted(&account).unwrap()).unwrap();
+        assert_eq!("Café☕", msg.subject);
+
+        let tpl = "Subject: =?ISO-8859-1?Q?Caf=E9?= =?UTF-8?B?4piV?=\n\nHello!\n";
+        assert_eq!("Café☕", Msg::from_tpl(tpl).unwrap().subject);
+        let tpl = "Subject: Café ☕\n\nHello!\n";
+        assert_eq!("Café ☕", Msg::from_tpl(tpl).unwrap().subject);
+    }
+
+    #[test]
+    fn it_should_round_trip_multipart_alternative() {
+        let account = Account::default();
+        let msg = Msg::from_raw(
+            concat![
+                "From: from@localhost\r\n",
+                "To: to@localhost\r\n",
+                "Content-Type: multipart/alternative; boundary=\"alt\"\r\n",
+                "\r\n",
+                "--alt\r\n",
+                "Content-Type: text/plain; charset=iso-8859-1\r\n",
+                "Content-Transfer-Encoding: quoted-printable\r\n",
+                "\r\n",
+                "Caf=E9\r\n",
+                "--alt\r\n",
+                "Content-Type: text/html; charset=utf-8\r\n",
+                "Content-Transfer-Encoding: base64\r\n",
+                "\r\n",
+                "PHA+Q2Fmw6k8L3A+\r\n",
+                "--alt--\r\n",
+            ]
+            .as_bytes(),
+        )
+        .unwrap();
+        assert_eq!("Café", msg.raw_text_plain());
+        assert_eq!("<p>Café</p>", msg.raw_text_html());
+
+        let formatted = msg.formatted(&account).unwrap();
+        assert!(String::from_utf8_lossy(&formatted).contains("multipart/alternative"));
+        let msg = Msg::from_raw(&formatted).unwrap();
+        assert_eq!("Café", msg.fold_text_plain_parts());
+        assert_eq!("<p>Café</p>", msg.raw_text_html());
+    }
+}
@@ -2,14 +2,14 @@
 //!
 //! This module gathers all message commands.  
 
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use atty::Stream;
+use chrono::{DateTime, Local};
 use imap::types::Flag;
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
 use std::{
     borrow::Cow,
     convert::{TryFrom, TryInto},
-    fs,
     io::{self, BufRead},
 };
 use url::Url;
@@ -17,39 +17,37 @@ use url::Url;
 use crate::{
     config::Account,
     domain::{
-        imap::ImapServiceInterface,
+        imap::{ImapServiceInterface, SearchQuery},
         mbox::Mbox,
-        msg::{Flags, Msg, Part, TextPlainPart},
-        smtp::SmtpServiceInterface,
+        msg::{msg_utils, Flags, Msg, Outbox, Part, TextPlainPart, TplOverride, UnsubscribeTarget},
+        smtp::{self, SentOutcome, SmtpServiceInterface},
         Parts,
     },
     output::{PrintTableOpts, PrinterService},
+    ui::editor,
 };
 
-/// Download all message attachments to the user account downloads directory.
+/// Download all message attachments (including inline ones) to the user account downloads
+/// directory. Existing files are not overwritten, see [`Msg::save_attachments_to`].
 pub fn attachments<'a, Printer: PrinterService, ImapService: ImapServiceInterface<'a>>(
     seq: &str,
     account: &Account,
     printer: &mut Printer,
     imap: &mut ImapService,
 ) -> Result<()> {
-    let attachments = imap.find_msg(account, seq)?.attachments();
-    let attachments_len = attachments.len();
+    let paths = imap
+        .find_msg(account, seq)?
+        .save_attachments_to(&account.downloads_dir, false)?;
     debug!(
-        r#"{} attachment(s) found for message "{}""#,
-        attachments_len, seq
+        r#"{} attachment(s) downloaded for message "{}""#,
+        paths.len(),
+        seq
     );
 
-    for attachment in attachments {
-        let filepath = account.downloads_dir.join(&attachment.filename);
-        debug!("downloading {}…", attachment.filename);
-        fs::write(&filepath, &attachment.content)
-            .context(format!("cannot download attachment {:?}", filepath))?;
-    }
-
     printer.print(format!(
         "{} attachment(s) successfully downloaded to {:?}",
-        attachments_len, account.downloads_dir
+        paths.len(),
+        account.downloads_dir
     ))
 }
 
@@ -83,6 +81,7 @@ pub fn delete<'a, Printer: PrinterService, ImapService: ImapServiceInterface<'a>
 }
 
 /// Forward the given message UID from the selected mailbox.
+#[allow(clippy::too_many_arguments)]
 pub fn forward<
     'a,
     Printer: PrinterService,
@@ -90,17 +89,20 @@ pub fn forward<
     SmtpService: SmtpServiceInterface,
 >(
     seq: &str,
+    text_mime: &str,
     attachments_paths: Vec<&str>,
     encrypt: bool,
+    sign: bool,
     account: &Account,
     printer: &mut Printer,
     imap: &mut ImapService,
     smtp: &mut SmtpService,
 ) -> Result<()> {
     imap.find_msg(account, seq)?
-        .into_forward(account)?
-        .add_attachments(attachments_paths)?
+        .into_forward(true, text_mime, account)?
+        .add_attachments(attachments_paths, account)?
         .encrypt(encrypt)
+        .sign(sign)
         .edit_with_editor(account, printer, imap, smtp)
 }
 
@@ -167,7 +169,7 @@ pub fn mailto<
     }
 
     let msg = Msg {
-        from: Some(vec![account.address().parse()?]),
+        from: Some(vec![account.validated_address()?]),
         to: if to.is_empty() { None } else { Some(to) },
         cc: if cc.is_empty() { None } else { Some(cc) },
         bcc: if bcc.is_empty() { None } else { Some(bcc) },
@@ -186,21 +188,15 @@ pub fn mailto<
 pub fn move_<'a, Printer: PrinterService, ImapService: ImapServiceInterface<'a>>(
     // The sequence number of the message to move
     seq: &str,
+    // The mailbox the message is in
+    mbox_src: &Mbox,
     // The mailbox to move the message in
     mbox: &str,
     printer: &mut Printer,
     imap: &mut ImapService,
 ) -> Result<()> {
-    // Copy the message to targetted mailbox
     let mbox = Mbox::new(mbox);
-    let msg = imap.find_raw_msg(seq)?;
-    let flags = Flags::try_from(vec![Flag::Seen])?;
-    imap.append_raw_msg_with_flags(&mbox, &msg, flags)?;
-
-    // Delete the original message
-    let flags = Flags::try_from(vec![Flag::Seen, Flag::Deleted])?;
-    imap.add_flags(seq, &flags)?;
-    imap.expunge()?;
+    imap.move_msg(mbox_src, &mbox, seq)?;
 
     printer.print(format!(
         r#"Message {} successfully moved to folder "{}""#,
@@ -209,10 +205,13 @@ pub fn move_<'a, Printer: PrinterService, ImapService: ImapServiceInterface<'a>>
 }
 
 /// Read a message by its sequence number.
+#[allow(clippy::too_many_arguments)]
 pub fn read<'a, Printer: PrinterService, ImapService: ImapServiceInterface<'a>>(
     seq: &str,
     text_mime: &str,
     raw: bool,
+    raw_body: bool,
+    urls: bool,
     account: &Account,
     printer: &mut Printer,
     imap: &mut ImapService,
@@ -220,14 +219,63 @@ pub fn read<'a, Printer: PrinterService, ImapService: ImapServiceInterface<'a>>(
     let msg = if raw {
         // Emails don't always have valid utf8. Using "lossy" to display what we can.
         String::from_utf8_lossy(&imap.find_raw_msg(seq)?).into_owned()
+    } else if urls {
+        imap.find_msg(account, seq)?.extract_urls().join("\n")
     } else {
-        imap.find_msg(account, seq)?.fold_text_parts(text_mime)
+        let msg = imap.find_msg(account, seq)?;
+        let mut text = match (raw_body, text_mime) {
+            (true, "html") => msg.raw_text_html(),
+            (true, _) => msg.raw_text_plain(),
+            (false, _) => msg.fold_text_parts(text_mime),
+        };
+        for embedded_msg in msg.parts.iter_embedded_msgs() {
+            text.push_str(&format!("\n\nForwarded message: {}", embedded_msg.subject));
+        }
+        text
     };
 
     printer.print(msg)
 }
 
+/// Reads the given text part (like `1.2`) of a message, without downloading the rest of the
+/// message (like big attachments).
+pub fn read_part<'a, Printer: PrinterService, ImapService: ImapServiceInterface<'a>>(
+    seq: &str,
+    part: &str,
+    mbox: &Mbox,
+    printer: &mut Printer,
+    imap: &mut ImapService,
+) -> Result<()> {
+    let seq = seq
+        .parse::<u32>()
+        .context(format!(r#"invalid sequence number "{}""#, seq))?;
+    let parts = imap.fetch_bodystructure(mbox, seq)?;
+    let part = parts.iter().find(|p| p.num == part).ok_or_else(|| {
+        let parts = parts
+            .iter()
+            .map(|p| format!("{} ({})", p.num, p.mime))
+            .collect::<Vec<_>>();
+        anyhow!(
+            r#"cannot find part "{}" of message "{}", available parts: {}"#,
+            part,
+            seq,
+            parts.join(", ")
+        )
+    })?;
+    if !part.mime.starts_with("text/") {
+        return Err(anyhow!(
+            r#"cannot read part "{}" of type {}, download it with the attachments command"#,
+            part.num,
+            part.mime
+        ));
+    }
+
+    let content = imap.fetch_part(mbox, seq, &part.num)?;
+    printer.print(part.decode_text(&content)?)
+}
+
 /// Reply to the given message UID.
+#[allow(clippy::too_many_arguments)]
 pub fn reply<
     'a,
     Printer: PrinterService,
@@ -236,17 +284,20 @@ pub fn reply<
 >(
     seq: &str,
     all: bool,
+    text_mime: &str,
     attachments_paths: Vec<&str>,
     encrypt: bool,
+    sign: bool,
     account: &Account,
     printer: &mut Printer,
     imap: &mut ImapService,
     smtp: &mut SmtpService,
 ) -> Result<()> {
     imap.find_msg(account, seq)?
-        .into_reply(all, account)?
-        .add_attachments(attachments_paths)?
+        .into_reply(all, text_mime, account)?
+        .add_attachments(attachments_paths, account)?
         .encrypt(encrypt)
+        .sign(sign)
         .edit_with_editor(account, printer, imap, smtp)?;
     let flags = Flags::try_from(vec![Flag::Answered])?;
     imap.add_flags(seq, &flags)
@@ -285,7 +336,7 @@ pub fn save<'a, Printer: PrinterService, ImapService: ImapServiceInterface<'a>>(
 
 /// Paginate messages from the selected mailbox matching the specified query.
 pub fn search<'a, Printer: PrinterService, ImapService: ImapServiceInterface<'a>>(
-    query: String,
+    query: SearchQuery,
     max_width: Option<usize>,
     page_size: Option<usize>,
     page: usize,
@@ -301,6 +352,148 @@ pub fn search<'a, Printer: PrinterService, ImapService: ImapServiceInterface<'a>
     printer.print_table(msgs, PrintTableOpts { max_width })
 }
 
+/// Lists the local drafts of all the accounts, or resumes editing the one at the given index
+/// (starting from 1). Only the drafts of the current account can be resumed.
+pub fn drafts<
+    'a,
+    Printer: PrinterService,
+    ImapService: ImapServiceInterface<'a>,
+    SmtpService: SmtpServiceInterface,
+>(
+    index: Option<usize>,
+    account: &Account,
+    printer: &mut Printer,
+    imap: &mut ImapService,
+    smtp: &mut SmtpService,
+) -> Result<()> {
+    info!("entering drafts handler");
+
+    let drafts = msg_utils::list_drafts()?;
+    debug!("{} local drafts", drafts.len());
+
+    match index {
+        None => {
+            if drafts.is_empty() {
+                return printer.print("No local draft found");
+            }
+            let list = drafts
+                .iter()
+                .enumerate()
+                .map(|(i, draft)| {
+                    let mtime: DateTime<Local> = draft.mtime.into();
+                    format!(
+                        "{}. [{}] {} ({})",
+                        i + 1,
+                        draft.account,
+                        draft.subject,
+                        mtime.format("%Y-%m-%d %H:%M")
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n");
+            printer.print(list)
+        }
+        Some(index) => {
+            let draft = index
+                .checked_sub(1)
+                .and_then(|i| drafts.get(i))
+                .context(format!("cannot find local draft {}", index))?;
+            if !draft
+                .path
+                .starts_with(msg_utils::local_drafts_dir(&account.name))
+            {
+                return Err(anyhow!(
+                    r#"local draft {} belongs to account "{}", select it with --account"#,
+                    index,
+                    draft.account
+                ));
+            }
+            Msg::resume_with_editor(&draft.path, account, printer, imap, smtp)
+        }
+    }
+}
+
+/// Send the messages queued in the outbox for the current account. Messages that cannot be sent
+/// stay in the outbox.
+pub fn flush<
+    'a,
+    Printer: PrinterService,
+    ImapService: ImapServiceInterface<'a>,
+    SmtpService: SmtpServiceInterface,
+>(
+    account: &Account,
+    printer: &mut Printer,
+    imap: &mut ImapService,
+    smtp: &mut SmtpService,
+) -> Result<()> {
+    info!("entering flush outbox handler");
+    let outbox = Outbox::new(Outbox::default_dir()?);
+    flush_outbox(&outbox, account, printer, imap, smtp)
+}
+
+/// Same as [`flush`], with the given outbox.
+fn flush_outbox<
+    'a,
+    Printer: PrinterService,
+    ImapService: ImapServiceInterface<'a>,
+    SmtpService: SmtpServiceInterface,
+>(
+    outbox: &Outbox,
+    account: &Account,
+    printer: &mut Printer,
+    imap: &mut ImapService,
+    smtp: &mut SmtpService,
+) -> Result<()> {
+    let mbox = Mbox::new(&account.sent_folder);
+    let flags = Flags::try_from(vec![Flag::Seen])?;
+    let msgs = outbox.list(&account.name)?;
+    debug!("{} queued messages", msgs.len());
+
+    let batch = msgs
+        .iter()
+        .map(|msg| (&msg.envelope, msg.raw.as_slice()))
+        .collect::<Vec<_>>();
+    let outcomes = smtp.send_batch(&batch)?;
+
+    // Sent messages leave the outbox before being saved, so that a saving error cannot get
+    // them sent again by the next flush
+    let mut sent_msgs = vec![];
+    for (msg, outcome) in msgs.iter().zip(outcomes) {
+        match outcome {
+            SentOutcome::Sent => {
+                if let Err(err) = outbox.remove(msg) {
+                    warn!(
+                        "cannot remove sent message {} from outbox: {:?}",
+                        msg.id, err
+                    );
+                }
+                sent_msgs.push(msg);
+            }
+            SentOutcome::Failed(err) => warn!("cannot send queued message {}: {:?}", msg.id, err),
+        }
+    }
+
+    for msg in sent_msgs.iter() {
+        if let Err(err) = imap.append_raw_msg_with_flags(&mbox, &msg.raw, flags.clone()) {
+            warn!(
+                "cannot save sent message {} to folder {}: {:?}",
+                msg.id, mbox.name, err
+            );
+        }
+    }
+
+    let sent = sent_msgs.len();
+
+    if sent < msgs.len() {
+        return Err(anyhow!(
+            "cannot send {} of {} queued messages, they stay in the outbox",
+            msgs.len() - sent,
+            msgs.len()
+        ));
+    }
+    printer.print(format!("{} queued message(s) successfully sent", sent))
+}
+
 /// Send a raw message.
 pub fn send<
     'a,
@@ -337,14 +530,66 @@ pub fn send<
             .join("\r\n")
     };
     trace!("raw message: {:?}", raw_msg);
-    let envelope: lettre::address::Envelope = Msg::from_tpl(&raw_msg)?.try_into()?;
+    let envelope: lettre::address::Envelope = Msg::from_raw(raw_msg.as_bytes())?.try_into()?;
     trace!("envelope: {:?}", envelope);
+    let raw_msg = msg_utils::strip_bcc_header(&raw_msg);
 
-    smtp.send_raw_msg(&envelope, raw_msg.as_bytes())?;
+    smtp::send_with_retry(account, printer, || {
+        smtp.send_raw_msg(&envelope, raw_msg.as_bytes())
+    })?;
     imap.append_raw_msg_with_flags(&mbox, raw_msg.as_bytes(), flags)
 }
 
-/// Compose a new message.
+/// Unsubscribe from the mailing list of the given message, using its `List-Unsubscribe` header.
+/// Mailto targets are preferred: the unsubscribe message is sent and saved to the sent folder.
+/// Otherwise, the URL to visit is printed.
+pub fn unsubscribe<
+    'a,
+    Printer: PrinterService,
+    ImapService: ImapServiceInterface<'a>,
+    SmtpService: SmtpServiceInterface,
+>(
+    seq: &str,
+    account: &Account,
+    printer: &mut Printer,
+    imap: &mut ImapService,
+    smtp: &mut SmtpService,
+) -> Result<()> {
+    let targets = imap.find_msg(account, seq)?.unsubscribe_targets();
+    debug!("unsubscribe targets: {:?}", targets);
+
+    if let Some(builder) = targets.iter().find_map(UnsubscribeTarget::to_msg_builder) {
+        let msg = builder
+            .from(account.address())
+            .text_plain("unsubscribe")
+            .build()?;
+        let sent_msg = smtp::send_with_retry(account, printer, || smtp.send_msg(account, &msg))?;
+        let mbox = Mbox::new(&account.sent_folder);
+        let flags = Flags::try_from(vec![Flag::Seen])?;
+        imap.append_raw_msg_with_flags(&mbox, &sent_msg.formatted(), flags)?;
+        return printer.print("Unsubscribe message successfully sent");
+    }
+
+    match targets.first() {
+        Some(UnsubscribeTarget::Http {
+            url,
+            one_click: true,
+        }) => printer.print(format!(
+            r#"Send a POST request with the body "List-Unsubscribe=One-Click" to {} to unsubscribe"#,
+            url
+        )),
+        Some(UnsubscribeTarget::Http { url, .. }) => {
+            printer.print(format!("Visit {} to unsubscribe", url))
+        }
+        _ => Err(anyhow!(
+            "cannot find unsubscribe target of message {}",
+            seq
+        )),
+    }
+}
+
+/// Compose a new message. In dry run mode, the message is printed as it would be sent instead.
+#[allow(clippy::too_many_arguments)]
 pub fn write<
     'a,
     Printer: PrinterService,
@@ -353,13 +598,197 @@ pub fn write<
 >(
     attachments_paths: Vec<&str>,
     encrypt: bool,
+    sign: bool,
+    dry_run: bool,
     account: &Account,
     printer: &mut Printer,
     imap: &mut ImapService,
     smtp: &mut SmtpService,
 ) -> Result<()> {
-    Msg::default()
-        .add_attachments(attachments_paths)?
+    let mut msg = Msg::default()
+        .add_attachments(attachments_paths, account)?
         .encrypt(encrypt)
-        .edit_with_editor(account, printer, imap, smtp)
+        .sign(sign);
+
+    if dry_run {
+        let draft = msg_utils::local_draft_path(&account.name, &msg_utils::local_draft_id(None));
+        let tpl = editor::open_with_tpl(
+            account.editor.as_deref(),
+            &draft,
+            msg.to_tpl(TplOverride::default(), account)?,
+        )?;
+        msg_utils::remove_local_draft(&draft)?;
+        msg.merge_with(Msg::from_tpl_with(&tpl, account.max_attachment_size)?);
+        return printer.print(msg.preview_sendable(account)?);
+    }
+
+    msg.edit_with_editor(account, printer, imap, smtp)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use serde::Serialize;
+    use std::{env, fmt::Debug};
+    use uuid::Uuid;
+
+    use crate::{
+        config::Config,
+        domain::{Envelopes, ImapEvent, Mboxes, PartStructure},
+        output::{Print, PrintTable},
+    };
+
+    use super::*;
+
+    #[test]
+    fn it_should_remove_sent_msgs_from_outbox_when_saving_fails() {
+        #[derive(Debug, Default)]
+        struct PrinterServiceTest;
+
+        impl PrinterService for PrinterServiceTest {
+            fn print<T: Debug + Print + Serialize>(&mut self, _data: T) -> Result<()> {
+                Ok(())
+            }
+            fn print_table<T: Debug + PrintTable + Serialize>(
+                &mut self,
+                _data: T,
+                _opts: PrintTableOpts,
+            ) -> Result<()> {
+                unimplemented!()
+            }
+            fn is_json(&self) -> bool {
+                unimplemented!()
+            }
+        }
+
+        #[derive(Default)]
+        struct ImapServiceTest {
+            appends: usize,
+        }
+
+        impl<'a> ImapServiceInterface<'a> for ImapServiceTest {
+            fn append_raw_msg_with_flags(&mut self, _: &Mbox, _: &[u8], _: Flags) -> Result<()> {
+                self.appends += 1;
+                Err(anyhow!("cannot append message"))
+            }
+
+            fn notify(&mut self, _: &Config, _: &Account, _: u64) -> Result<()> {
+                unimplemented!()
+            }
+            fn watch(&mut self, _: &Account, _: u64) -> Result<()> {
+                unimplemented!()
+            }
+            fn fetch_mboxes(&'a mut self) -> Result<Mboxes<'a>> {
+                unimplemented!()
+            }
+            fn fetch_envelopes(&mut self, _: &usize, _: &usize) -> Result<Envelopes<'_>> {
+                unimplemented!()
+            }
+            fn fetch_envelopes_with(
+                &mut self,
+                _: &SearchQuery,
+                _: &usize,
+                _: &usize,
+            ) -> Result<Envelopes<'_>> {
+                unimplemented!()
+            }
+            fn find_msg(&mut self, _: &Account, _: &str) -> Result<Msg> {
+                unimplemented!()
+            }
+            fn find_raw_msg(&mut self, _: &str) -> Result<Vec<u8>> {
+                unimplemented!()
+            }
+            fn append_msg(&mut self, _: &Mbox, _: &Account, _: Msg) -> Result<()> {
+                unimplemented!()
+            }
+            fn expunge(&mut self) -> Result<()> {
+                unimplemented!()
+            }
+            fn logout(&mut self) -> Result<()> {
+                unimplemented!()
+            }
+            fn has_capability(&mut self, _: &str) -> Result<bool> {
+                unimplemented!()
+            }
+            fn move_msg(&mut self, _: &Mbox, _: &Mbox, _: &str) -> Result<()> {
+                unimplemented!()
+            }
+            fn idle(
+                &mut self,
+                _: &Mbox,
+                _: u64,
+                _: impl FnMut(&mut Self, ImapEvent) -> Result<()>,
+            ) -> Result<()> {
+                unimplemented!()
+            }
+            fn search(&mut self, _: &Mbox, _: &SearchQuery) -> Result<Vec<u32>> {
+                unimplemented!()
+            }
+            fn fetch_part(&mut self, _: &Mbox, _: u32, _: &str) -> Result<Vec<u8>> {
+                unimplemented!()
+            }
+            fn fetch_bodystructure(&mut self, _: &Mbox, _: u32) -> Result<Vec<PartStructure>> {
+                unimplemented!()
+            }
+            fn add_flags(&mut self, _: &str, _: &Flags) -> Result<()> {
+                unimplemented!()
+            }
+            fn set_flags(&mut self, _: &str, _: &Flags) -> Result<()> {
+                unimplemented!()
+            }
+            fn remove_flags(&mut self, _: &str, _: &Flags) -> Result<()> {
+                unimplemented!()
+            }
+        }
+
+        struct SmtpServiceTest;
+
+        impl SmtpServiceInterface for SmtpServiceTest {
+            fn send_msg(&mut self, _: &Account, _: &Msg) -> Result<lettre::Message> {
+                unimplemented!()
+            }
+            fn send_raw_msg(&mut self, _: &lettre::address::Envelope, _: &[u8]) -> Result<()> {
+                unimplemented!()
+            }
+            fn send_batch(
+                &mut self,
+                msgs: &[(&lettre::address::Envelope, &[u8])],
+            ) -> Result<Vec<SentOutcome>> {
+                Ok(msgs.iter().map(|_| SentOutcome::Sent).collect())
+            }
+        }
+
+        let account = Account {
+            name: "default".into(),
+            ..Account::default()
+        };
+        let dir = env::temp_dir().join(format!("himalaya-outbox-{}", Uuid::new_v4()));
+        let outbox = Outbox::new(dir.to_owned());
+        let envelope = lettre::address::Envelope::new(
+            Some("from@localhost".parse().unwrap()),
+            vec!["to@localhost".parse().unwrap()],
+        )
+        .unwrap();
+        outbox
+            .enqueue(&account.name, &envelope, b"Subject: 1\r\n\r\nHello")
+            .unwrap();
+        outbox
+            .enqueue(&account.name, &envelope, b"Subject: 2\r\n\r\nHello")
+            .unwrap();
+
+        let mut imap = ImapServiceTest::default();
+        flush_outbox(
+            &outbox,
+            &account,
+            &mut PrinterServiceTest,
+            &mut imap,
+            &mut SmtpServiceTest,
+        )
+        .unwrap();
+
+        assert_eq!(2, imap.appends);
+        assert!(outbox.list(&account.name).unwrap().is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
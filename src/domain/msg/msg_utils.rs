@@ -1,15 +1,225 @@
 use anyhow::{Context, Result};
-use log::{debug, trace};
-use std::{env, fs, path::PathBuf};
+use log::{debug, trace, warn};
+use serde::Serialize;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use uuid::Uuid;
 
-pub fn local_draft_path() -> PathBuf {
-    let path = env::temp_dir().join("himalaya-draft.mail");
+use crate::domain::msg::Msg;
+
+/// Represents a local draft, left by a composition that was saved locally or interrupted.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DraftMeta {
+    pub path: PathBuf,
+    /// Holds the account name, as found in the draft path.
+    pub account: String,
+    pub subject: String,
+    /// Holds the last modification time of the draft.
+    pub mtime: SystemTime,
+}
+
+/// Replaces the characters that cannot safely be part of a file name by underscores.
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// Returns the directory holding the local drafts of all the accounts.
+fn local_drafts_root() -> PathBuf {
+    env::temp_dir().join("himalaya-drafts")
+}
+
+/// Returns the directory holding the local drafts of the given account.
+pub fn local_drafts_dir(account: &str) -> PathBuf {
+    local_drafts_root().join(sanitize_file_name(account))
+}
+
+/// Returns the path of the local draft of a message, scoped by the given account name and
+/// message id, so that concurrent compositions never overwrite each other.
+pub fn local_draft_path(account: &str, id: &str) -> PathBuf {
+    let path = local_drafts_dir(account).join(format!("{}.mail", sanitize_file_name(id)));
     trace!("local draft path: {:?}", path);
     path
 }
 
-pub fn remove_local_draft() -> Result<()> {
-    let path = local_draft_path();
+/// Lists the local drafts of all the accounts, oldest first.
+pub fn list_drafts() -> Result<Vec<DraftMeta>> {
+    list_drafts_in(&local_drafts_root())
+}
+
+fn list_drafts_in(root: &Path) -> Result<Vec<DraftMeta>> {
+    if !root.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut drafts = vec![];
+    for account_dir in fs::read_dir(root).context(format!("cannot read drafts at {:?}", root))? {
+        let account_dir = account_dir?.path();
+        if !account_dir.is_dir() {
+            continue;
+        }
+        let account = account_dir
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+            .to_owned();
+        for entry in fs::read_dir(&account_dir)
+            .context(format!("cannot read drafts at {:?}", account_dir))?
+        {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("mail") {
+                continue;
+            }
+            let mtime = path.metadata()?.modified()?;
+            // Drafts that cannot be parsed are still listed, so that they can be recovered
+            let subject = match fs::read_to_string(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|tpl| Msg::from_tpl(&tpl))
+            {
+                Ok(msg) => msg.subject,
+                Err(err) => {
+                    warn!("cannot parse local draft at {:?}: {:?}", path, err);
+                    String::new()
+                }
+            };
+            drafts.push(DraftMeta {
+                path,
+                account: account.to_owned(),
+                subject,
+                mtime,
+            });
+        }
+    }
+    drafts.sort_by(|a, b| a.mtime.cmp(&b.mtime).then_with(|| a.path.cmp(&b.path)));
+
+    Ok(drafts)
+}
+
+/// Returns the id scoping the local draft of a message: its message id when it has one (so that
+/// an interrupted draft can be recovered), otherwise a newly generated one.
+pub fn local_draft_id(message_id: Option<&str>) -> String {
+    match message_id {
+        Some(id) => id.to_owned(),
+        None => Uuid::new_v4().to_string(),
+    }
+}
+
+pub fn remove_local_draft(path: &Path) -> Result<()> {
     debug!("remove draft path at {:?}", path);
-    fs::remove_file(&path).context(format!("cannot remove local draft at {:?}", path))
+    fs::remove_file(path).context(format!("cannot remove local draft at {:?}", path))
+}
+
+/// Removes the `Bcc` header (including its folded lines) from the given raw message, so that
+/// blind carbon copy recipients do not leak into the delivered or saved copies. The body is left
+/// untouched.
+pub fn strip_bcc_header(raw_msg: &str) -> String {
+    let mut stripped = String::with_capacity(raw_msg.len());
+    let mut in_headers = true;
+    let mut in_bcc = false;
+
+    for line in raw_msg.split_inclusive('\n') {
+        if in_headers {
+            if line.trim_end().is_empty() {
+                in_headers = false;
+            } else if line.starts_with(' ') || line.starts_with('\t') {
+                if in_bcc {
+                    continue;
+                }
+            } else {
+                in_bcc = line.to_lowercase().starts_with("bcc:");
+                if in_bcc {
+                    continue;
+                }
+            }
+        }
+        stripped.push_str(line);
+    }
+
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_scope_local_draft_paths() {
+        let work = local_draft_path("work", &local_draft_id(None));
+        let work2 = local_draft_path("work", &local_draft_id(None));
+        let perso = local_draft_path("perso", &local_draft_id(Some("<1@localhost>")));
+        assert_ne!(work, work2);
+        assert_ne!(work, perso);
+        assert_eq!(
+            env::temp_dir()
+                .join("himalaya-drafts")
+                .join("perso")
+                .join("_1_localhost_.mail"),
+            perso
+        );
+        assert_eq!(
+            perso,
+            local_draft_path("perso", &local_draft_id(Some("<1@localhost>")))
+        );
+        assert_ne!(
+            perso,
+            local_draft_path("work", &local_draft_id(Some("<1@localhost>")))
+        );
+    }
+
+    #[test]
+    fn it_should_list_drafts() {
+        let root = env::temp_dir().join(format!("himalaya-drafts-{}", Uuid::new_v4()));
+        assert!(list_drafts_in(&root).unwrap().is_empty());
+
+        fs::create_dir_all(root.join("work")).unwrap();
+        fs::create_dir_all(root.join("perso")).unwrap();
+        fs::write(root.join("work").join("1.mail"), "Subject: Hello\n\nHello").unwrap();
+        fs::write(root.join("perso").join("2.mail"), "Subject: Hi\n\nHi").unwrap();
+        fs::write(root.join("perso").join("ignored.txt"), "").unwrap();
+
+        let mut drafts: Vec<(String, String)> = list_drafts_in(&root)
+            .unwrap()
+            .into_iter()
+            .map(|draft| (draft.account, draft.subject))
+            .collect();
+        drafts.sort();
+        assert_eq!(
+            vec![
+                ("perso".to_string(), "Hi".to_string()),
+                ("work".to_string(), "Hello".to_string())
+            ],
+            drafts
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn it_should_strip_bcc_header() {
+        let raw_msg = concat![
+            "From: from@localhost\r\n",
+            "BCC: hidden@localhost,\r\n",
+            " hidden2@localhost\r\n",
+            "To: to@localhost\r\n",
+            "\r\n",
+            "Bcc: kept in body\r\n",
+        ];
+
+        assert_eq!(
+            concat![
+                "From: from@localhost\r\n",
+                "To: to@localhost\r\n",
+                "\r\n",
+                "Bcc: kept in body\r\n",
+            ],
+            strip_bcc_header(raw_msg)
+        );
+    }
 }
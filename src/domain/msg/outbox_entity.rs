@@ -0,0 +1,210 @@
+//! Outbox entity module.
+//!
+//! This module contains the definition of the outbox, a local directory where messages are
+//! queued when they cannot be sent, until they are flushed.
+
+use anyhow::{Context, Result};
+use log::{debug, trace};
+use serde::{Deserialize, Serialize};
+use std::{env, fs, path::PathBuf};
+use uuid::Uuid;
+
+/// Represents the metadata of a queued message, stored next to its raw content.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct QueuedMsgMeta {
+    account: String,
+    envelope: lettre::address::Envelope,
+}
+
+/// Represents a message waiting in the outbox.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedMsg {
+    pub id: String,
+    /// Holds the name of the account the message should be sent with.
+    pub account: String,
+    pub envelope: lettre::address::Envelope,
+    /// Holds the sendable message, as it will be sent.
+    pub raw: Vec<u8>,
+}
+
+/// Represents the outbox directory. Each message is stored as a `<id>.eml` file holding the raw
+/// message and a `<id>.json` file holding its account and envelope, so that queued messages
+/// survive process restarts.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    dir: PathBuf,
+}
+
+impl Outbox {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Returns the default outbox directory: `$XDG_DATA_HOME/himalaya/outbox`, or
+    /// `$HOME/.local/share/himalaya/outbox` when `XDG_DATA_HOME` is not set.
+    pub fn default_dir() -> Result<PathBuf> {
+        let data_dir = match env::var("XDG_DATA_HOME") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => {
+                let home_var = if cfg!(target_family = "windows") {
+                    "USERPROFILE"
+                } else {
+                    "HOME"
+                };
+                let mut path: PathBuf = env::var(home_var)
+                    .context(format!("cannot find `{}` env var", home_var))?
+                    .into();
+                path.push(".local");
+                path.push("share");
+                path
+            }
+        };
+        Ok(data_dir.join("himalaya").join("outbox"))
+    }
+
+    /// Queues the given sendable message for the given account.
+    pub fn enqueue(
+        &self,
+        account: &str,
+        envelope: &lettre::address::Envelope,
+        raw: &[u8],
+    ) -> Result<QueuedMsg> {
+        fs::create_dir_all(&self.dir)
+            .context(format!("cannot create outbox directory {:?}", self.dir))?;
+
+        let id = Uuid::new_v4().to_string();
+        let raw_path = self.dir.join(format!("{}.eml", id));
+        let meta_path = self.dir.join(format!("{}.json", id));
+        debug!("enqueue message at {:?}", raw_path);
+
+        fs::write(&raw_path, raw).context(format!("cannot write queued message {:?}", raw_path))?;
+        // The metadata is written last: a message without metadata is not listed
+        let meta = QueuedMsgMeta {
+            account: account.to_owned(),
+            envelope: envelope.to_owned(),
+        };
+        let meta_json =
+            serde_json::to_vec(&meta).context("cannot serialize queued message metadata")?;
+        fs::write(&meta_path, meta_json).context(format!(
+            "cannot write queued message metadata {:?}",
+            meta_path
+        ))?;
+
+        Ok(QueuedMsg {
+            id,
+            account: meta.account,
+            envelope: meta.envelope,
+            raw: raw.to_owned(),
+        })
+    }
+
+    /// Lists the messages queued for the given account, oldest first.
+    pub fn list(&self, account: &str) -> Result<Vec<QueuedMsg>> {
+        if !self.dir.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut entries = vec![];
+        for entry in fs::read_dir(&self.dir)
+            .context(format!("cannot read outbox directory {:?}", self.dir))?
+        {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let modified = path.metadata()?.modified()?;
+            entries.push((modified, path));
+        }
+        entries.sort();
+
+        let mut msgs = vec![];
+        for (_, meta_path) in entries {
+            trace!("queued message metadata path: {:?}", meta_path);
+            let meta = fs::read(&meta_path).context(format!(
+                "cannot read queued message metadata {:?}",
+                meta_path
+            ))?;
+            let meta: QueuedMsgMeta = serde_json::from_slice(&meta).context(format!(
+                "cannot parse queued message metadata {:?}",
+                meta_path
+            ))?;
+            if meta.account != account {
+                continue;
+            }
+            let raw_path = meta_path.with_extension("eml");
+            let raw = fs::read(&raw_path)
+                .context(format!("cannot read queued message {:?}", raw_path))?;
+            let id = meta_path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or_default()
+                .to_owned();
+            msgs.push(QueuedMsg {
+                id,
+                account: meta.account,
+                envelope: meta.envelope,
+                raw,
+            });
+        }
+
+        Ok(msgs)
+    }
+
+    /// Removes the given message from the outbox, once sent.
+    pub fn remove(&self, msg: &QueuedMsg) -> Result<()> {
+        let meta_path = self.dir.join(format!("{}.json", msg.id));
+        let raw_path = self.dir.join(format!("{}.eml", msg.id));
+        debug!("remove queued message at {:?}", raw_path);
+        fs::remove_file(&meta_path).context(format!(
+            "cannot remove queued message metadata {:?}",
+            meta_path
+        ))?;
+        fs::remove_file(&raw_path).context(format!("cannot remove queued message {:?}", raw_path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_enqueue_list_and_remove_msgs() {
+        let dir = env::temp_dir().join(format!("himalaya-outbox-{}", Uuid::new_v4()));
+        let outbox = Outbox::new(dir.to_owned());
+        let envelope = lettre::address::Envelope::new(
+            Some("from@localhost".parse().unwrap()),
+            vec!["to@localhost".parse().unwrap()],
+        )
+        .unwrap();
+
+        assert!(outbox.list("default").unwrap().is_empty());
+
+        let queued = outbox
+            .enqueue("default", &envelope, b"Subject: test\r\n\r\nHello")
+            .unwrap();
+        outbox
+            .enqueue("other", &envelope, b"Subject: other\r\n\r\nHello")
+            .unwrap();
+
+        // A new outbox reads the queued messages back from the disk
+        let msgs = Outbox::new(dir.to_owned()).list("default").unwrap();
+        assert_eq!(vec![queued.to_owned()], msgs);
+        let to: Vec<String> = msgs[0]
+            .envelope
+            .to()
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect();
+        assert_eq!(vec!["to@localhost"], to);
+        assert_eq!(
+            Ok("Subject: test\r\n\r\nHello"),
+            std::str::from_utf8(&msgs[0].raw)
+        );
+
+        outbox.remove(&queued).unwrap();
+        assert!(outbox.list("default").unwrap().is_empty());
+        assert_eq!(1, outbox.list("other").unwrap().len());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
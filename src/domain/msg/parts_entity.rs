@@ -1,13 +1,27 @@
 use anyhow::{anyhow, Context, Result};
+use log::warn;
 use mailparse::MailHeaderMap;
-use serde::Serialize;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{ser::SerializeStruct, Serialize, Serializer};
 use std::{
     env, fs,
     ops::{Deref, DerefMut},
 };
 use uuid::Uuid;
 
-use crate::config::Account;
+use crate::{config::Account, domain::msg::Msg};
+
+/// Defines how deep messages embedded in messages (`message/rfc822` parts) are parsed. Deeper
+/// ones are kept as binary parts, so that maliciously nested messages cannot overflow the stack.
+pub const MAX_EMBEDDED_MSG_DEPTH: usize = 8;
+
+// Regexes used to convert `text/enriched` bodies, see `enriched_to_plain`.
+static ENRICHED_PARAM_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?is)<param>.*?</param>").unwrap());
+static ENRICHED_CMD_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"<(/?[A-Za-z0-9-]+)>|<<").unwrap());
+static ENRICHED_NEW_LINES_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"\n+").unwrap());
 
 #[derive(Debug, Clone, Default, Serialize)]
 pub struct TextPlainPart {
@@ -19,11 +33,52 @@ pub struct TextHtmlPart {
     pub content: String,
 }
 
+/// Represents the way a binary part should be presented, as defined by its
+/// `Content-Disposition` header, see [RFC2183].
+///
+/// [RFC2183]: https://datatracker.ietf.org/doc/html/rfc2183
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Disposition {
+    /// The part is a regular attachment.
+    #[default]
+    Attachment,
+    /// The part is displayed within the body, for example an image referenced by a `cid:` URL.
+    Inline,
+}
+
 #[derive(Debug, Clone, Default, Serialize)]
 pub struct BinaryPart {
     pub filename: String,
     pub mime: String,
     pub content: Vec<u8>,
+    pub disposition: Disposition,
+    /// The position of the part among the binary parts of the message, used to keep attachments
+    /// in a stable order.
+    pub order: usize,
+    /// The identifier of the part (without angle brackets), referenced from the HTML body by
+    /// `cid:` URLs.
+    pub content_id: Option<String>,
+}
+
+impl BinaryPart {
+    /// Returns a filename safe to be written on disk: path separators, control characters and
+    /// leading dots are removed, so a filename like `../../.ssh/authorized_keys` cannot escape
+    /// the destination directory. A random name is used when nothing remains. Callers must use
+    /// it instead of `filename` before writing attachments to disk.
+    pub fn safe_filename(&self) -> String {
+        let filename: String = self
+            .filename
+            .chars()
+            .filter(|c| !c.is_control() && *c != '/' && *c != '\\')
+            .collect();
+        let filename = filename.trim().trim_start_matches('.').trim_start();
+        if filename.is_empty() {
+            format!("attachment-{}", Uuid::new_v4())
+        } else {
+            filename.to_owned()
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -32,12 +87,39 @@ pub enum Part {
     TextPlain(TextPlainPart),
     TextHtml(TextHtmlPart),
     Binary(BinaryPart),
+    /// A message embedded in the message (`message/rfc822`), like a message forwarded as an
+    /// attachment.
+    EmbeddedMessage(#[serde(serialize_with = "serialize_embedded_msg")] EmbeddedMsgPart),
+}
+
+/// Represents a message embedded in a message, both parsed and raw. The raw message is the one
+/// sent back, so that it is forwarded exactly as it was received (headers and signatures
+/// included).
+#[derive(Debug, Clone)]
+pub struct EmbeddedMsgPart {
+    pub msg: Box<Msg>,
+    pub raw: Vec<u8>,
+}
+
+/// Serializes an embedded message as its subject and its parts.
+fn serialize_embedded_msg<S: Serializer>(
+    part: &EmbeddedMsgPart,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    let mut state = serializer.serialize_struct("EmbeddedMessage", 2)?;
+    state.serialize_field("subject", &part.msg.subject)?;
+    state.serialize_field("parts", &part.msg.parts)?;
+    state.end()
 }
 
 impl Part {
     pub fn new_text_plain(content: String) -> Self {
         Self::TextPlain(TextPlainPart { content })
     }
+
+    pub fn new_text_html(content: String) -> Self {
+        Self::TextHtml(TextHtmlPart { content })
+    }
 }
 
 #[derive(Debug, Clone, Default, Serialize)]
@@ -50,12 +132,53 @@ impl Parts {
         self.push(Part::TextPlain(part));
     }
 
+    /// Iterates over the plain text parts.
+    pub fn iter_text_plain(&self) -> impl Iterator<Item = &TextPlainPart> {
+        self.iter().filter_map(|part| match part {
+            Part::TextPlain(part) => Some(part),
+            _ => None,
+        })
+    }
+
+    /// Iterates over the HTML parts.
+    pub fn iter_text_html(&self) -> impl Iterator<Item = &TextHtmlPart> {
+        self.iter().filter_map(|part| match part {
+            Part::TextHtml(part) => Some(part),
+            _ => None,
+        })
+    }
+
+    /// Iterates over the binary parts, attachments and inline parts.
+    pub fn iter_binary(&self) -> impl Iterator<Item = &BinaryPart> {
+        self.iter().filter_map(|part| match part {
+            Part::Binary(part) => Some(part),
+            _ => None,
+        })
+    }
+
+    /// Iterates over the embedded messages.
+    pub fn iter_embedded_msgs(&self) -> impl Iterator<Item = &Msg> {
+        self.iter().filter_map(|part| match part {
+            Part::EmbeddedMessage(part) => Some(part.msg.as_ref()),
+            _ => None,
+        })
+    }
+
     pub fn from_parsed_mail<'a>(
         account: &'a Account,
         part: &'a mailparse::ParsedMail<'a>,
+    ) -> Result<Self> {
+        Self::from_parsed_mail_at_depth(account, part, 0)
+    }
+
+    /// Same as [`Parts::from_parsed_mail`], for a message embedded at the given depth.
+    pub(crate) fn from_parsed_mail_at_depth<'a>(
+        account: &'a Account,
+        part: &'a mailparse::ParsedMail<'a>,
+        depth: usize,
     ) -> Result<Self> {
         let mut parts = vec![];
-        build_parts_map_rec(account, part, &mut parts)?;
+        build_parts_map_rec(account, part, depth, &mut parts)?;
         Ok(Self(parts))
     }
 }
@@ -74,13 +197,64 @@ impl DerefMut for Parts {
     }
 }
 
+/// Parses the given embedded message (`message/rfc822`), unless it is nested too deep or it
+/// cannot be parsed.
+fn parse_embedded_msg(
+    account: &Account,
+    parsed_mail: &mailparse::ParsedMail,
+    depth: usize,
+) -> Option<EmbeddedMsgPart> {
+    if depth >= MAX_EMBEDDED_MSG_DEPTH {
+        warn!("embedded message nested too deep, kept as binary part");
+        return None;
+    }
+    let raw = parsed_mail.get_body_raw().ok()?;
+    let parsed_msg = mailparse::parse_mail(&raw).ok()?;
+    match Msg::from_parsed_mail(account, &parsed_msg, depth + 1) {
+        Ok(msg) => Some(EmbeddedMsgPart {
+            msg: Box::new(msg),
+            raw,
+        }),
+        Err(err) => {
+            warn!(
+                "cannot parse embedded message, kept as binary part: {:?}",
+                err
+            );
+            None
+        }
+    }
+}
+
 fn build_parts_map_rec(
     account: &Account,
     parsed_mail: &mailparse::ParsedMail,
+    depth: usize,
     parts: &mut Vec<Part>,
 ) -> Result<()> {
     if parsed_mail.subparts.is_empty() {
         let cdisp = parsed_mail.get_content_disposition();
+        let content_id = parsed_mail
+            .get_headers()
+            .get_first_value("content-id")
+            .map(|id| {
+                id.trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_owned()
+            });
+        let embedded_msg = if parsed_mail
+            .ctype
+            .mimetype
+            .eq_ignore_ascii_case("message/rfc822")
+        {
+            parse_embedded_msg(account, parsed_mail, depth)
+        } else {
+            None
+        };
+        if let Some(part) = embedded_msg {
+            parts.push(Part::EmbeddedMessage(part));
+            return Ok(());
+        }
         match cdisp.disposition {
             mailparse::DispositionType::Attachment => {
                 let filename = cdisp
@@ -90,20 +264,51 @@ fn build_parts_map_rec(
                     .unwrap_or_else(|| String::from("noname"));
                 let content = parsed_mail.get_body_raw().unwrap_or_default();
                 let mime = tree_magic::from_u8(&content);
+                let order = next_binary_order(parts);
                 parts.push(Part::Binary(BinaryPart {
                     filename,
                     mime,
                     content,
+                    disposition: Disposition::Attachment,
+                    order,
+                    content_id,
                 }));
             }
             // TODO: manage other use cases
             _ => {
                 if let Some(ctype) = parsed_mail.get_headers().get_first_value("content-type") {
-                    let content = parsed_mail.get_body().unwrap_or_default();
                     if ctype.starts_with("text/plain") {
+                        let content = parsed_mail.get_body().unwrap_or_default();
                         parts.push(Part::TextPlain(TextPlainPart { content }))
                     } else if ctype.starts_with("text/html") {
+                        let content = parsed_mail.get_body().unwrap_or_default();
                         parts.push(Part::TextHtml(TextHtmlPart { content }))
+                    } else if ctype.starts_with("text/markdown") {
+                        // Markdown is meant to be readable as it is
+                        let content = parsed_mail.get_body().unwrap_or_default();
+                        parts.push(Part::TextPlain(TextPlainPart { content }))
+                    } else if ctype.starts_with("text/enriched") {
+                        let content = parsed_mail.get_body().unwrap_or_default();
+                        let content = enriched_to_plain(&content);
+                        parts.push(Part::TextPlain(TextPlainPart { content }))
+                    } else if !ctype.starts_with("text/") {
+                        let filename = cdisp
+                            .params
+                            .get("filename")
+                            .or_else(|| parsed_mail.ctype.params.get("name"))
+                            .map(String::from)
+                            .unwrap_or_else(|| String::from("noname"));
+                        let content = parsed_mail.get_body_raw().unwrap_or_default();
+                        let mime = tree_magic::from_u8(&content);
+                        let order = next_binary_order(parts);
+                        parts.push(Part::Binary(BinaryPart {
+                            filename,
+                            mime,
+                            content,
+                            disposition: Disposition::Inline,
+                            order,
+                            content_id,
+                        }));
                     }
                 };
             }
@@ -122,10 +327,10 @@ fn build_parts_map_rec(
                 .context("cannot decrypt part of multipart")?;
             let parsed_mail = mailparse::parse_mail(decrypted_part.as_bytes())
                 .context("cannot parse decrypted part of multipart")?;
-            build_parts_map_rec(account, &parsed_mail, parts)?;
+            build_parts_map_rec(account, &parsed_mail, depth, parts)?;
         } else {
             for part in parsed_mail.subparts.iter() {
-                build_parts_map_rec(account, part, parts)?;
+                build_parts_map_rec(account, part, depth, parts)?;
             }
         }
     }
@@ -133,6 +338,35 @@ fn build_parts_map_rec(
     Ok(())
 }
 
+/// Converts a `text/enriched` body (as defined in [RFC1896]) to plain text: formatting commands
+/// are removed, and line breaks are interpreted (a single newline is a space, n newlines are n-1
+/// line breaks).
+///
+/// [RFC1896]: https://datatracker.ietf.org/doc/html/rfc1896
+fn enriched_to_plain(content: &str) -> String {
+    let content = content.replace("\r\n", "\n");
+    let content = ENRICHED_PARAM_REGEX.replace_all(&content, "");
+    let content =
+        ENRICHED_CMD_REGEX.replace_all(&content, |caps: &regex::Captures| match caps.get(1) {
+            Some(_) => "",
+            None => "<",
+        });
+    ENRICHED_NEW_LINES_REGEX
+        .replace_all(&content, |caps: &regex::Captures| match caps[0].len() {
+            1 => " ".to_owned(),
+            len => "\n".repeat(len - 1),
+        })
+        .to_string()
+}
+
+/// Returns the order of the next binary part to be added to the given parts.
+pub fn next_binary_order(parts: &[Part]) -> usize {
+    parts
+        .iter()
+        .filter(|part| matches!(part, Part::Binary(_)))
+        .count()
+}
+
 fn decrypt_part(account: &Account, msg: &mailparse::ParsedMail) -> Result<String> {
     let msg_path = env::temp_dir().join(Uuid::new_v4().to_string());
     let msg_body = msg
@@ -144,3 +378,214 @@ fn decrypt_part(account: &Account, msg: &mailparse::ParsedMail) -> Result<String
         .pgp_decrypt_file(msg_path.clone())?
         .ok_or_else(|| anyhow!("cannot find pgp decrypt command in config"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_iter_parts_by_type() {
+        let parts = Parts(vec![
+            Part::new_text_plain("plain 1".into()),
+            Part::Binary(BinaryPart {
+                filename: "doc.pdf".into(),
+                ..BinaryPart::default()
+            }),
+            Part::new_text_html("html".into()),
+            Part::new_text_plain("plain 2".into()),
+        ]);
+
+        assert_eq!(
+            vec!["plain 1", "plain 2"],
+            parts
+                .iter_text_plain()
+                .map(|part| part.content.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["html"],
+            parts
+                .iter_text_html()
+                .map(|part| part.content.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec!["doc.pdf"],
+            parts
+                .iter_binary()
+                .map(|part| part.filename.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(0, Parts::default().iter_binary().count());
+    }
+
+    #[test]
+    fn it_should_parse_embedded_msg() {
+        let raw_msg = concat![
+            "Subject: Fwd: Hello\r\n",
+            "Content-Type: multipart/mixed; boundary=\"boundary\"\r\n",
+            "\r\n",
+            "--boundary\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "See below.\r\n",
+            "--boundary\r\n",
+            "Content-Type: message/rfc822\r\n",
+            "Content-Disposition: attachment; filename=\"hello.eml\"\r\n",
+            "\r\n",
+            "From: sender@localhost\r\n",
+            "Subject: Hello\r\n",
+            "Content-Type: text/plain\r\n",
+            "\r\n",
+            "Hello, world!\r\n",
+            "--boundary--\r\n",
+        ];
+        let parsed_mail = mailparse::parse_mail(raw_msg.as_bytes()).unwrap();
+        let parts = Parts::from_parsed_mail(&Account::default(), &parsed_mail).unwrap();
+
+        assert_eq!(0, parts.iter_binary().count());
+        let embedded_msgs: Vec<&Msg> = parts.iter_embedded_msgs().collect();
+        assert_eq!(1, embedded_msgs.len());
+        assert_eq!("Hello", embedded_msgs[0].subject);
+        assert_eq!(
+            Some(vec!["sender@localhost".parse().unwrap()]),
+            embedded_msgs[0].from
+        );
+        assert_eq!(
+            "Hello, world!\r\n",
+            embedded_msgs[0].fold_text_plain_parts()
+        );
+    }
+
+    #[test]
+    fn it_should_limit_embedded_msgs_depth() {
+        let mut raw_msg = String::from("Subject: Deepest\r\n\r\nHello\r\n");
+        for _ in 0..MAX_EMBEDDED_MSG_DEPTH + 1 {
+            raw_msg = format!("Content-Type: message/rfc822\r\n\r\n{}", raw_msg);
+        }
+        let parsed_mail = mailparse::parse_mail(raw_msg.as_bytes()).unwrap();
+        let mut parts = Parts::from_parsed_mail(&Account::default(), &parsed_mail).unwrap();
+
+        let mut depth = 0;
+        loop {
+            let msg = match parts.iter_embedded_msgs().next() {
+                Some(msg) => msg.to_owned(),
+                None => break,
+            };
+            parts = msg.parts;
+            depth += 1;
+        }
+        assert_eq!(MAX_EMBEDDED_MSG_DEPTH, depth);
+        assert_eq!(1, parts.iter_binary().count());
+    }
+
+    #[test]
+    fn it_should_parse_text_markdown_and_enriched_parts() {
+        let raw_msg = concat![
+            "Content-Type: text/markdown; charset=utf-8\r\n",
+            "\r\n",
+            "# Title\r\n",
+            "\r\n",
+            "Some *emphasis*.\r\n",
+        ];
+        let parsed_mail = mailparse::parse_mail(raw_msg.as_bytes()).unwrap();
+        let parts = Parts::from_parsed_mail(&Account::default(), &parsed_mail).unwrap();
+        assert_eq!(0, parts.iter_binary().count());
+        assert_eq!(
+            vec!["# Title\r\n\r\nSome *emphasis*.\r\n"],
+            parts
+                .iter_text_plain()
+                .map(|part| part.content.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        let raw_msg = concat![
+            "Content-Type: text/enriched\r\n",
+            "\r\n",
+            "<bold>Hello</bold>\r\n",
+            "<color><param>red</param>world</color> <<3\r\n",
+            "\r\n",
+            "Bye\r\n",
+        ];
+        let parsed_mail = mailparse::parse_mail(raw_msg.as_bytes()).unwrap();
+        let parts = Parts::from_parsed_mail(&Account::default(), &parsed_mail).unwrap();
+        assert_eq!(
+            vec!["Hello world <3\nBye "],
+            parts
+                .iter_text_plain()
+                .map(|part| part.content.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn it_should_sanitize_filename() {
+        let safe_filename = |filename: &str| {
+            BinaryPart {
+                filename: filename.into(),
+                ..BinaryPart::default()
+            }
+            .safe_filename()
+        };
+
+        assert_eq!("report.pdf", safe_filename("report.pdf"));
+        assert_eq!(
+            "sshauthorized_keys",
+            safe_filename("../../.ssh/authorized_keys")
+        );
+        assert_eq!(
+            "Windowssystem.ini",
+            safe_filename("..\\Windows\\system.ini")
+        );
+        assert_eq!("evil.txt", safe_filename(".evil\0.txt"));
+        assert_eq!("bashrc", safe_filename(" .bashrc"));
+        assert!(safe_filename("").starts_with("attachment-"));
+        assert!(safe_filename("../..").starts_with("attachment-"));
+        assert!(safe_filename("\n\t").starts_with("attachment-"));
+    }
+
+    #[test]
+    fn it_should_distinguish_inline_parts() {
+        let raw_msg = concat![
+            "Content-Type: multipart/mixed; boundary=\"mixed\"\r\n",
+            "\r\n",
+            "--mixed\r\n",
+            "Content-Type: multipart/related; boundary=\"related\"\r\n",
+            "\r\n",
+            "--related\r\n",
+            "Content-Type: text/html\r\n",
+            "\r\n",
+            "<img src=\"cid:logo\">\r\n",
+            "--related\r\n",
+            "Content-Type: image/png; name=\"logo.png\"\r\n",
+            "Content-Disposition: inline\r\n",
+            "Content-ID: <logo>\r\n",
+            "\r\n",
+            "logo\r\n",
+            "--related--\r\n",
+            "--mixed\r\n",
+            "Content-Type: application/pdf\r\n",
+            "Content-Disposition: attachment; filename=\"doc.pdf\"\r\n",
+            "\r\n",
+            "doc\r\n",
+            "--mixed--\r\n",
+        ];
+        let parsed_mail = mailparse::parse_mail(raw_msg.as_bytes()).unwrap();
+        let parts = Parts::from_parsed_mail(&Account::default(), &parsed_mail).unwrap();
+
+        let binary_parts: Vec<(&str, Disposition)> = parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::Binary(part) => Some((part.filename.as_str(), part.disposition)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                ("logo.png", Disposition::Inline),
+                ("doc.pdf", Disposition::Attachment)
+            ],
+            binary_parts
+        );
+    }
+}
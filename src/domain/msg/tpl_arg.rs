@@ -6,10 +6,11 @@ use anyhow::Result;
 use clap::{self, App, AppSettings, Arg, ArgMatches, SubCommand};
 use log::{debug, info, trace};
 
-use crate::domain::msg::msg_arg;
+use crate::domain::msg::{msg_arg, msg_entity};
 
 type Seq<'a> = &'a str;
 type ReplyAll = bool;
+type TextMime<'a> = &'a str;
 type AttachmentPaths<'a> = Vec<&'a str>;
 type Tpl<'a> = &'a str;
 
@@ -17,12 +18,17 @@ type Tpl<'a> = &'a str;
 pub struct TplOverride<'a> {
     pub subject: Option<&'a str>,
     pub from: Option<Vec<&'a str>>,
+    pub reply_to: Option<Vec<&'a str>>,
     pub to: Option<Vec<&'a str>>,
     pub cc: Option<Vec<&'a str>>,
     pub bcc: Option<Vec<&'a str>>,
-    pub headers: Option<Vec<&'a str>>,
+    /// Extra headers (like `X-Priority` or `Importance`), added after the known ones.
+    pub headers: Option<Vec<(&'a str, &'a str)>>,
     pub body: Option<&'a str>,
     pub sig: Option<&'a str>,
+    pub sig_name: Option<&'a str>,
+    /// Overrides the template file of the account, see [`crate::domain::Msg::to_tpl`].
+    pub template_path: Option<&'a str>,
 }
 
 impl<'a> From<&'a ArgMatches<'a>> for TplOverride<'a> {
@@ -30,21 +36,40 @@ impl<'a> From<&'a ArgMatches<'a>> for TplOverride<'a> {
         Self {
             subject: matches.value_of("subject"),
             from: matches.values_of("from").map(|v| v.collect()),
+            reply_to: matches.values_of("reply-to").map(|v| v.collect()),
             to: matches.values_of("to").map(|v| v.collect()),
             cc: matches.values_of("cc").map(|v| v.collect()),
             bcc: matches.values_of("bcc").map(|v| v.collect()),
-            headers: matches.values_of("headers").map(|v| v.collect()),
+            headers: matches
+                .values_of("header")
+                .map(|v| v.filter_map(split_header).collect()),
             body: matches.value_of("body"),
             sig: matches.value_of("signature"),
+            sig_name: matches.value_of("signature-name"),
+            template_path: matches.value_of("template-path"),
         }
     }
 }
 
+/// Splits a `KEY: VAL` header argument into its trimmed name and value.
+fn split_header(header: &str) -> Option<(&str, &str)> {
+    header
+        .split_once(':')
+        .map(|(key, val)| (key.trim(), val.trim()))
+}
+
+/// Checks that a header argument follows the `KEY: VAL` format, with a valid header name.
+fn validate_header(header: String) -> Result<(), String> {
+    let (key, _) =
+        split_header(&header).ok_or_else(|| format!("cannot find colon in header {:?}", header))?;
+    msg_entity::validate_header_name(key).map_err(|err| err.to_string())
+}
+
 /// Message template commands.
 pub enum Command<'a> {
     New(TplOverride<'a>),
-    Reply(Seq<'a>, ReplyAll, TplOverride<'a>),
-    Forward(Seq<'a>, TplOverride<'a>),
+    Reply(Seq<'a>, ReplyAll, TextMime<'a>, TplOverride<'a>),
+    Forward(Seq<'a>, TextMime<'a>, TplOverride<'a>),
     Save(AttachmentPaths<'a>, Tpl<'a>),
     Send(AttachmentPaths<'a>, Tpl<'a>),
 }
@@ -66,18 +91,22 @@ pub fn matches<'a>(m: &'a ArgMatches) -> Result<Option<Command<'a>>> {
         debug!("sequence: {}", seq);
         let all = m.is_present("reply-all");
         debug!("reply all: {}", all);
+        let mime = m.value_of("mime-type").unwrap();
+        debug!("text mime: {}", mime);
         let tpl = TplOverride::from(m);
         trace!("template override: {:?}", tpl);
-        return Ok(Some(Command::Reply(seq, all, tpl)));
+        return Ok(Some(Command::Reply(seq, all, mime, tpl)));
     }
 
     if let Some(m) = m.subcommand_matches("forward") {
         info!("forward subcommand matched");
         let seq = m.value_of("seq").unwrap();
         debug!("sequence: {}", seq);
+        let mime = m.value_of("mime-type").unwrap();
+        debug!("text mime: {}", mime);
         let tpl = TplOverride::from(m);
         trace!("template args: {:?}", tpl);
-        return Ok(Some(Command::Forward(seq, tpl)));
+        return Ok(Some(Command::Forward(seq, mime, tpl)));
     }
 
     if let Some(m) = m.subcommand_matches("save") {
@@ -115,6 +144,12 @@ pub fn tpl_args<'a>() -> Vec<Arg<'a, 'a>> {
             .long("from")
             .value_name("ADDR")
             .multiple(true),
+        Arg::with_name("reply-to")
+            .help("Overrides the Reply-To header")
+            .short("r")
+            .long("reply-to")
+            .value_name("ADDR")
+            .multiple(true),
         Arg::with_name("to")
             .help("Overrides the To header")
             .short("t")
@@ -138,7 +173,9 @@ pub fn tpl_args<'a>() -> Vec<Arg<'a, 'a>> {
             .short("h")
             .long("header")
             .value_name("KEY: VAL")
-            .multiple(true),
+            .multiple(true)
+            .number_of_values(1)
+            .validator(validate_header),
         Arg::with_name("body")
             .help("Overrides the body")
             .short("B")
@@ -149,6 +186,15 @@ pub fn tpl_args<'a>() -> Vec<Arg<'a, 'a>> {
             .short("S")
             .long("signature")
             .value_name("STRING"),
+        Arg::with_name("signature-name")
+            .help("Selects one of the named signatures of the account")
+            .long("signature-name")
+            .value_name("NAME")
+            .conflicts_with("signature"),
+        Arg::with_name("template-path")
+            .help("Uses the given template file as a skeleton, with placeholders like {{body}}")
+            .long("template-path")
+            .value_name("PATH"),
     ]
 }
 
@@ -170,6 +216,7 @@ pub fn subcmds<'a>() -> Vec<App<'a, 'a>> {
                 .about("Generates a reply message template")
                 .arg(msg_arg::seq_arg())
                 .arg(msg_arg::reply_all_arg())
+                .arg(msg_arg::text_mime_arg())
                 .args(&tpl_args()),
         )
         .subcommand(
@@ -177,6 +224,7 @@ pub fn subcmds<'a>() -> Vec<App<'a, 'a>> {
                 .aliases(&["fwd", "fw", "f"])
                 .about("Generates a forward message template")
                 .arg(msg_arg::seq_arg())
+                .arg(msg_arg::text_mime_arg())
                 .args(&tpl_args()),
         )
         .subcommand(
@@ -15,7 +15,7 @@ use crate::{
     domain::{
         imap::ImapServiceInterface,
         msg::{Msg, TplOverride},
-        Flags, Mbox, SmtpServiceInterface,
+        smtp, Flags, Mbox, SmtpServiceInterface,
     },
     output::PrinterService,
 };
@@ -26,7 +26,7 @@ pub fn new<'a, Printer: PrinterService>(
     account: &'a Account,
     printer: &'a mut Printer,
 ) -> Result<()> {
-    let tpl = Msg::default().to_tpl(opts, account);
+    let tpl = Msg::default().to_tpl(opts, account)?;
     printer.print(tpl)
 }
 
@@ -34,6 +34,7 @@ pub fn new<'a, Printer: PrinterService>(
 pub fn reply<'a, Printer: PrinterService, ImapService: ImapServiceInterface<'a>>(
     seq: &str,
     all: bool,
+    text_mime: &str,
     opts: TplOverride<'a>,
     account: &'a Account,
     printer: &'a mut Printer,
@@ -41,14 +42,15 @@ pub fn reply<'a, Printer: PrinterService, ImapService: ImapServiceInterface<'a>>
 ) -> Result<()> {
     let tpl = imap
         .find_msg(account, seq)?
-        .into_reply(all, account)?
-        .to_tpl(opts, account);
+        .into_reply(all, text_mime, account)?
+        .to_tpl(opts, account)?;
     printer.print(tpl)
 }
 
 /// Generate a forward message template.
 pub fn forward<'a, Printer: PrinterService, ImapService: ImapServiceInterface<'a>>(
     seq: &str,
+    text_mime: &str,
     opts: TplOverride<'a>,
     account: &'a Account,
     printer: &'a mut Printer,
@@ -56,8 +58,8 @@ pub fn forward<'a, Printer: PrinterService, ImapService: ImapServiceInterface<'a
 ) -> Result<()> {
     let tpl = imap
         .find_msg(account, seq)?
-        .into_forward(account)?
-        .to_tpl(opts, account);
+        .into_forward(true, text_mime, account)?
+        .to_tpl(opts, account)?;
     printer.print(tpl)
 }
 
@@ -80,7 +82,8 @@ pub fn save<'a, Printer: PrinterService, ImapService: ImapServiceInterface<'a>>(
             .collect::<Vec<String>>()
             .join("\n")
     };
-    let msg = Msg::from_tpl(&tpl)?.add_attachments(attachments_paths)?;
+    let msg = Msg::from_tpl_with(&tpl, account.max_attachment_size)?
+        .add_attachments(attachments_paths, account)?;
     let raw_msg = msg.into_sendable_msg(account)?.formatted();
     let flags = Flags::try_from(vec![Flag::Seen])?;
     imap.append_raw_msg_with_flags(mbox, &raw_msg, flags)?;
@@ -112,8 +115,9 @@ pub fn send<
             .collect::<Vec<String>>()
             .join("\n")
     };
-    let msg = Msg::from_tpl(&tpl)?.add_attachments(attachments_paths)?;
-    let sent_msg = smtp.send_msg(account, &msg)?;
+    let msg = Msg::from_tpl_with(&tpl, account.max_attachment_size)?
+        .add_attachments(attachments_paths, account)?;
+    let sent_msg = smtp::send_with_retry(account, printer, || smtp.send_msg(account, &msg))?;
     let flags = Flags::try_from(vec![Flag::Seen])?;
     imap.append_raw_msg_with_flags(mbox, &sent_msg.formatted(), flags)?;
     printer.print("Template successfully sent")
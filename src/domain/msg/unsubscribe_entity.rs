@@ -0,0 +1,135 @@
+//! Unsubscribe entity module.
+//!
+//! This module contains the representation of the `List-Unsubscribe` targets of a message, see
+//! [RFC2369].
+//!
+//! [RFC2369]: https://datatracker.ietf.org/doc/html/rfc2369
+
+use lettre::message::Mailbox;
+use log::warn;
+use url::Url;
+
+use crate::domain::msg::{parse_addr, Msg, MsgBuilder};
+
+/// Represents a way to unsubscribe from a mailing list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnsubscribeTarget {
+    /// Unsubscribes by sending a message to the given address, using the given subject.
+    Mailto {
+        addr: Mailbox,
+        subject: Option<String>,
+    },
+    /// Unsubscribes by visiting the given URL. When `one_click` is true, a single POST request
+    /// with the `List-Unsubscribe=One-Click` body is enough, as defined in [RFC8058].
+    ///
+    /// [RFC8058]: https://datatracker.ietf.org/doc/html/rfc8058
+    Http { url: Url, one_click: bool },
+}
+
+impl UnsubscribeTarget {
+    /// Starts building the unsubscribe message of a mailto target. Returns `None` for HTTP
+    /// targets.
+    pub fn to_msg_builder(&self) -> Option<MsgBuilder> {
+        match self {
+            Self::Mailto { addr, subject } => Some(
+                Msg::builder()
+                    .to(addr)
+                    .subject(subject.as_deref().unwrap_or("unsubscribe")),
+            ),
+            Self::Http { .. } => None,
+        }
+    }
+}
+
+/// Parses the comma-separated targets of a `List-Unsubscribe` header, like
+/// `<mailto:leave@localhost>, <https://localhost/unsubscribe>`. Targets are enclosed in angle
+/// brackets (so they can contain commas), the ones that cannot be parsed are skipped.
+/// `one_click` comes from the `List-Unsubscribe-Post` header, and only applies to HTTPS targets.
+pub fn parse_unsubscribe_targets(header: &str, one_click: bool) -> Vec<UnsubscribeTarget> {
+    header
+        .split('<')
+        .skip(1)
+        .filter_map(|target| {
+            let (target, _) = target.split_once('>')?;
+            match parse_unsubscribe_target(target.trim(), one_click) {
+                Some(target) => Some(target),
+                None => {
+                    warn!("cannot parse unsubscribe target {:?}", target);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+fn parse_unsubscribe_target(target: &str, one_click: bool) -> Option<UnsubscribeTarget> {
+    let url = Url::parse(target).ok()?;
+    match url.scheme() {
+        "mailto" => Some(UnsubscribeTarget::Mailto {
+            addr: parse_addr(url.path()).ok()?,
+            subject: url
+                .query_pairs()
+                .find(|(key, _)| key.eq_ignore_ascii_case("subject"))
+                .map(|(_, subject)| subject.to_string()),
+        }),
+        "https" => Some(UnsubscribeTarget::Http { url, one_click }),
+        "http" => Some(UnsubscribeTarget::Http {
+            url,
+            one_click: false,
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_parse_unsubscribe_targets() {
+        let targets = parse_unsubscribe_targets(
+            "<mailto:leave@localhost?subject=Leave%20list>, <https://localhost/unsub?id=1,2>,\r\n <http://localhost/unsub>, <ftp://localhost>, garbage",
+            true,
+        );
+
+        assert_eq!(
+            vec![
+                UnsubscribeTarget::Mailto {
+                    addr: "leave@localhost".parse().unwrap(),
+                    subject: Some("Leave list".into()),
+                },
+                UnsubscribeTarget::Http {
+                    url: Url::parse("https://localhost/unsub?id=1,2").unwrap(),
+                    one_click: true,
+                },
+                UnsubscribeTarget::Http {
+                    url: Url::parse("http://localhost/unsub").unwrap(),
+                    one_click: false,
+                },
+            ],
+            targets
+        );
+    }
+
+    #[test]
+    fn it_should_build_unsubscribe_msg() {
+        let target = UnsubscribeTarget::Mailto {
+            addr: "leave@localhost".parse().unwrap(),
+            subject: None,
+        };
+        let msg = target
+            .to_msg_builder()
+            .unwrap()
+            .from("test@localhost")
+            .build()
+            .unwrap();
+        assert_eq!(Some(vec!["leave@localhost".parse().unwrap()]), msg.to);
+        assert_eq!("unsubscribe", msg.subject);
+
+        let target = UnsubscribeTarget::Http {
+            url: Url::parse("https://localhost").unwrap(),
+            one_click: false,
+        };
+        assert!(target.to_msg_builder().is_none());
+    }
+}
@@ -1,19 +1,37 @@
-use anyhow::Result;
+use anyhow::{Error, Result};
 use lettre::{
     self,
+    address::Envelope,
     transport::smtp::{
+        self,
+        authentication::Mechanism,
         client::{Tls, TlsParameters},
+        response::{Code, Severity},
         SmtpTransport,
     },
     Transport,
 };
-use log::debug;
+use log::{debug, warn};
+use std::{io, thread, time::Duration};
 
-use crate::{config::Account, domain::msg::Msg};
+use crate::{config::Account, domain::msg::Msg, output::PrinterService};
+
+/// Represents the outcome of a message sent within a batch.
+#[derive(Debug)]
+pub enum SentOutcome {
+    Sent,
+    Failed(Error),
+}
 
 pub trait SmtpServiceInterface {
     fn send_msg(&mut self, account: &Account, msg: &Msg) -> Result<lettre::Message>;
     fn send_raw_msg(&mut self, envelope: &lettre::address::Envelope, msg: &[u8]) -> Result<()>;
+    /// Sends the given raw messages over the same connection. A failing message does not abort
+    /// the batch, the outcome of each message is returned in the same order. Messages are taken
+    /// already built (envelope and raw bytes) rather than as [`Msg`], since queued messages are
+    /// stored ready to be sent. Commands are not pipelined (`PIPELINING` is not supported by
+    /// lettre), the connection is only kept open between messages.
+    fn send_batch(&mut self, msgs: &[(&Envelope, &[u8])]) -> Result<Vec<SentOutcome>>;
 }
 
 pub struct SmtpService<'a> {
@@ -42,31 +60,143 @@ impl<'a> SmtpService<'a> {
                 Tls::Wrapper(tls)
             };
 
-            self.transport = Some(
-                builder
-                    .tls(tls)
-                    .port(self.account.smtp_port)
-                    .credentials(self.account.smtp_creds()?)
-                    .build(),
-            );
+            let builder = builder.tls(tls).port(self.account.smtp_port);
+            let builder = match self.account.auth.token()? {
+                Some(token) => builder
+                    .authentication(vec![Mechanism::Xoauth2])
+                    .credentials((self.account.smtp_login.to_owned(), token).into()),
+                None => builder.credentials(self.account.smtp_creds()?),
+            };
+
+            self.transport = Some(builder.build());
 
             Ok(self.transport.as_ref().unwrap())
         }
     }
+
+    /// Sends the message once. On transient (4xx) errors or timeouts the connection is dropped,
+    /// so that the next attempt (see [`send_with_retry`]) reconnects. Retrying is left to the
+    /// caller, so that a message is never sent twice by a single attempt.
+    fn send_or_reset(&mut self, envelope: &Envelope, msg: &[u8]) -> Result<()> {
+        if let Err(err) = self.transport()?.send_raw(envelope, msg) {
+            if is_transient(&err) {
+                warn!("transient SMTP error ({}), resetting connection", err);
+                self.transport = None;
+            }
+            return Err(err.into());
+        }
+        Ok(())
+    }
+}
+
+/// Returns true if sending the message again may succeed: the server answered with a transient
+/// (4xx) reply or the connection failed. Permanent (5xx) replies are not worth a retry.
+fn is_transient(err: &smtp::Error) -> bool {
+    match err.status() {
+        Some(code) => is_transient_code(code),
+        None => err.is_timeout() || !(err.is_client() || err.is_response() || err.is_tls()),
+    }
+}
+
+fn is_transient_code(code: Code) -> bool {
+    code.severity == Severity::TransientNegativeCompletion
+}
+
+/// Returns true if the error of a send is transient, see [`is_transient`]. Other I/O errors are
+/// considered transient as well.
+pub fn is_transient_err(err: &Error) -> bool {
+    if let Some(err) = err.downcast_ref::<smtp::Error>() {
+        is_transient(err)
+    } else {
+        err.downcast_ref::<io::Error>().is_some()
+    }
+}
+
+/// Calls `send` for each message, the outcome of each message being collected so that a failing
+/// message does not prevent the others from being sent.
+fn send_each<T>(msgs: &[T], mut send: impl FnMut(&T) -> Result<()>) -> Vec<SentOutcome> {
+    msgs.iter()
+        .map(|msg| match send(msg) {
+            Ok(()) => SentOutcome::Sent,
+            Err(err) => {
+                warn!("cannot send message: {}", err);
+                SentOutcome::Failed(err)
+            }
+        })
+        .collect()
+}
+
+/// Calls `send` with the retries configured by the account, see [`retry_with_backoff`]. Retries
+/// are reported through the printer.
+pub fn send_with_retry<T, Printer: PrinterService>(
+    account: &Account,
+    printer: &mut Printer,
+    send: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    retry_with_backoff(
+        account.send_retries,
+        Duration::from_millis(account.send_retry_delay),
+        send,
+        is_transient_err,
+        |attempt, delay, err| {
+            let msg = format!(
+                "Cannot send message ({}), retrying in {:.1}s ({}/{})…",
+                err,
+                delay.as_secs_f32(),
+                attempt,
+                account.send_retries
+            );
+            if let Err(err) = printer.print(msg) {
+                warn!("{:?}", err);
+            }
+        },
+    )
+}
+
+/// Calls `send` until it succeeds or fails with an error that is not transient, at most
+/// `retries` more times. The delay between two attempts starts at `base_delay` and doubles
+/// after each attempt. The `on_retry` hook is called before each wait with the attempt number,
+/// the delay and the error.
+pub fn retry_with_backoff<T, E>(
+    retries: usize,
+    base_delay: Duration,
+    mut send: impl FnMut() -> Result<T, E>,
+    is_transient: impl Fn(&E) -> bool,
+    mut on_retry: impl FnMut(usize, Duration, &E),
+) -> Result<T, E> {
+    let mut attempt = 0;
+    loop {
+        match send() {
+            Err(err) if attempt < retries && is_transient(&err) => {
+                let delay = base_delay * 2u32.saturating_pow(attempt as u32);
+                attempt += 1;
+                on_retry(attempt, delay, &err);
+                thread::sleep(delay);
+            }
+            res => return res,
+        }
+    }
 }
 
 impl<'a> SmtpServiceInterface for SmtpService<'a> {
     fn send_msg(&mut self, account: &Account, msg: &Msg) -> Result<lettre::Message> {
         debug!("sending message…");
         let sendable_msg = msg.into_sendable_msg(account)?;
-        self.transport()?.send(&sendable_msg)?;
+        self.send_or_reset(sendable_msg.envelope(), &sendable_msg.formatted())?;
         Ok(sendable_msg)
     }
 
     fn send_raw_msg(&mut self, envelope: &lettre::address::Envelope, msg: &[u8]) -> Result<()> {
         debug!("sending raw message…");
-        self.transport()?.send_raw(envelope, msg)?;
-        Ok(())
+        self.send_or_reset(envelope, msg)
+    }
+
+    fn send_batch(&mut self, msgs: &[(&Envelope, &[u8])]) -> Result<Vec<SentOutcome>> {
+        debug!("sending batch of {} messages…", msgs.len());
+        // The transport is kept between sends, and its pool keeps the connection open
+        Ok(send_each(msgs, |(envelope, msg)| {
+            self.send_or_reset(envelope, msg)
+        }))
     }
 }
 
@@ -79,3 +209,153 @@ impl<'a> From<&'a Account> for SmtpService<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use super::*;
+
+    /// Starts a fake SMTP server on a local port, answering the `MAIL` commands with the given
+    /// replies (in order, then with success) and the other commands with success. Returns the
+    /// port and the number of `MAIL` commands received.
+    fn start_smtp_server(replies: Vec<&'static str>) -> (u16, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mail_count = Arc::new(AtomicUsize::new(0));
+        let server_mail_count = mail_count.clone();
+        thread::spawn(move || {
+            let mut replies = replies.into_iter();
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                stream.write_all(b"220 localhost ESMTP\r\n").unwrap();
+                let mut line = String::new();
+                let mut in_data = false;
+                while reader.read_line(&mut line).unwrap_or_default() > 0 {
+                    let reply = if in_data {
+                        in_data = line != ".\r\n";
+                        if in_data {
+                            None
+                        } else {
+                            Some("250 2.0.0 Ok: queued")
+                        }
+                    } else {
+                        match line.get(..4).unwrap_or_default().to_uppercase().as_str() {
+                            "EHLO" => Some("250 localhost"),
+                            "MAIL" => {
+                                server_mail_count.fetch_add(1, Ordering::SeqCst);
+                                Some(replies.next().unwrap_or("250 2.1.0 Ok"))
+                            }
+                            "DATA" => {
+                                in_data = true;
+                                Some("354 End data with <CR><LF>.<CR><LF>")
+                            }
+                            "QUIT" => Some("221 2.0.0 Bye"),
+                            _ => Some("250 2.0.0 Ok"),
+                        }
+                    };
+                    if let Some(reply) = reply {
+                        stream
+                            .write_all(format!("{}\r\n", reply).as_bytes())
+                            .unwrap();
+                    }
+                    line.clear();
+                }
+            }
+        });
+        (port, mail_count)
+    }
+
+    /// Sends a message to the fake SMTP server listening on the given port, with retries.
+    fn send_with_retries(port: u16, retries: usize) -> (Result<(), smtp::Error>, usize) {
+        let transport = SmtpTransport::builder_dangerous("127.0.0.1")
+            .port(port)
+            .build();
+        let envelope = Envelope::new(
+            Some("from@localhost".parse().unwrap()),
+            vec!["to@localhost".parse().unwrap()],
+        )
+        .unwrap();
+        let mut retries_count = 0;
+        let res = retry_with_backoff(
+            retries,
+            Duration::from_millis(1),
+            || transport.send_raw(&envelope, b"Subject: Test\r\n\r\nHello!\r\n"),
+            is_transient,
+            |_, _, _| retries_count += 1,
+        );
+        (res.map(|_| ()), retries_count)
+    }
+
+    #[test]
+    fn it_should_retry_transient_failures() {
+        let (port, mail_count) = start_smtp_server(vec![
+            "451 4.3.0 Try again later",
+            "421 4.7.0 Too many connections",
+        ]);
+        let (res, retries_count) = send_with_retries(port, 3);
+
+        assert!(res.is_ok());
+        assert_eq!(2, retries_count);
+        assert_eq!(3, mail_count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn it_should_not_retry_permanent_failures() {
+        let (port, mail_count) = start_smtp_server(vec!["550 5.1.1 No such user"]);
+        let (res, retries_count) = send_with_retries(port, 3);
+
+        let err = res.unwrap_err();
+        assert!(err.is_permanent());
+        assert!(!is_transient(&err));
+        assert_eq!(0, retries_count);
+        assert_eq!(1, mail_count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn it_should_give_up_after_retries() {
+        let (port, mail_count) = start_smtp_server(vec!["451 4.3.0 Try again later"; 3]);
+        let (res, retries_count) = send_with_retries(port, 2);
+
+        assert!(res.unwrap_err().is_transient());
+        assert_eq!(2, retries_count);
+        assert_eq!(3, mail_count.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn it_should_send_each_msg_of_batch() {
+        let mut sent = vec![];
+        let outcomes = send_each(&["first", "bad", "last"], |msg| {
+            if *msg == "bad" {
+                return Err(anyhow::anyhow!("invalid recipient"));
+            }
+            sent.push(*msg);
+            Ok(())
+        });
+
+        assert_eq!(vec!["first", "last"], sent);
+        assert!(matches!(
+            outcomes.as_slice(),
+            [
+                SentOutcome::Sent,
+                SentOutcome::Failed(err),
+                SentOutcome::Sent,
+            ] if err.to_string() == "invalid recipient"
+        ));
+    }
+
+    #[test]
+    fn it_should_retry_io_errors() {
+        let err = Error::new(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert!(is_transient_err(&err));
+        assert!(!is_transient_err(&anyhow::anyhow!("cannot build message")));
+    }
+}
@@ -68,7 +68,7 @@ fn main() -> Result<()> {
     let config = Config::try_from(m.value_of("config"))?;
     let account = Account::try_from((&config, m.value_of("account")))?;
     let mbox = Mbox::new(m.value_of("mbox-source").unwrap_or(&account.inbox_folder));
-    let mut printer = StdoutPrinter::try_from(m.value_of("output"))?;
+    let mut printer = StdoutPrinter::try_from((m.value_of("output"), m.value_of("color")))?;
     let mut imap = ImapService::from((&account, &mbox));
     let mut smtp = SmtpService::from(&account);
 
@@ -102,11 +102,13 @@ fn main() -> Result<()> {
         Some(msg_arg::Command::Delete(seq)) => {
             return msg_handler::delete(seq, &mut printer, &mut imap);
         }
-        Some(msg_arg::Command::Forward(seq, attachment_paths, encrypt)) => {
+        Some(msg_arg::Command::Forward(seq, text_mime, attachment_paths, encrypt, sign)) => {
             return msg_handler::forward(
                 seq,
+                text_mime,
                 attachment_paths,
                 encrypt,
+                sign,
                 &account,
                 &mut printer,
                 &mut imap,
@@ -123,18 +125,32 @@ fn main() -> Result<()> {
                 &mut imap,
             );
         }
-        Some(msg_arg::Command::Move(seq, mbox)) => {
-            return msg_handler::move_(seq, mbox, &mut printer, &mut imap);
+        Some(msg_arg::Command::Move(seq, mbox_dst)) => {
+            return msg_handler::move_(seq, &mbox, mbox_dst, &mut printer, &mut imap);
         }
-        Some(msg_arg::Command::Read(seq, text_mime, raw)) => {
-            return msg_handler::read(seq, text_mime, raw, &account, &mut printer, &mut imap);
+        Some(msg_arg::Command::Read(seq, _, _, _, _, Some(part))) => {
+            return msg_handler::read_part(seq, part, &mbox, &mut printer, &mut imap);
         }
-        Some(msg_arg::Command::Reply(seq, all, attachment_paths, encrypt)) => {
+        Some(msg_arg::Command::Read(seq, text_mime, raw, raw_body, urls, None)) => {
+            return msg_handler::read(
+                seq,
+                text_mime,
+                raw,
+                raw_body,
+                urls,
+                &account,
+                &mut printer,
+                &mut imap,
+            );
+        }
+        Some(msg_arg::Command::Reply(seq, all, text_mime, attachment_paths, encrypt, sign)) => {
             return msg_handler::reply(
                 seq,
                 all,
+                text_mime,
                 attachment_paths,
                 encrypt,
+                sign,
                 &account,
                 &mut printer,
                 &mut imap,
@@ -155,11 +171,29 @@ fn main() -> Result<()> {
                 &mut imap,
             );
         }
+        Some(msg_arg::Command::Drafts(index)) => {
+            return msg_handler::drafts(index, &account, &mut printer, &mut imap, &mut smtp);
+        }
+        Some(msg_arg::Command::Flush) => {
+            return msg_handler::flush(&account, &mut printer, &mut imap, &mut smtp);
+        }
         Some(msg_arg::Command::Send(raw_msg)) => {
             return msg_handler::send(raw_msg, &account, &mut printer, &mut imap, &mut smtp);
         }
-        Some(msg_arg::Command::Write(atts, encrypt)) => {
-            return msg_handler::write(atts, encrypt, &account, &mut printer, &mut imap, &mut smtp);
+        Some(msg_arg::Command::Unsubscribe(seq)) => {
+            return msg_handler::unsubscribe(seq, &account, &mut printer, &mut imap, &mut smtp);
+        }
+        Some(msg_arg::Command::Write(atts, encrypt, sign, dry_run)) => {
+            return msg_handler::write(
+                atts,
+                encrypt,
+                sign,
+                dry_run,
+                &account,
+                &mut printer,
+                &mut imap,
+                &mut smtp,
+            );
         }
         Some(msg_arg::Command::Flag(m)) => match m {
             Some(flag_arg::Command::Set(seq_range, flags)) => {
@@ -177,11 +211,26 @@ fn main() -> Result<()> {
             Some(tpl_arg::Command::New(tpl)) => {
                 return tpl_handler::new(tpl, &account, &mut printer);
             }
-            Some(tpl_arg::Command::Reply(seq, all, tpl)) => {
-                return tpl_handler::reply(seq, all, tpl, &account, &mut printer, &mut imap);
+            Some(tpl_arg::Command::Reply(seq, all, text_mime, tpl)) => {
+                return tpl_handler::reply(
+                    seq,
+                    all,
+                    text_mime,
+                    tpl,
+                    &account,
+                    &mut printer,
+                    &mut imap,
+                );
             }
-            Some(tpl_arg::Command::Forward(seq, tpl)) => {
-                return tpl_handler::forward(seq, tpl, &account, &mut printer, &mut imap);
+            Some(tpl_arg::Command::Forward(seq, text_mime, tpl)) => {
+                return tpl_handler::forward(
+                    seq,
+                    text_mime,
+                    tpl,
+                    &account,
+                    &mut printer,
+                    &mut imap,
+                );
             }
             Some(tpl_arg::Command::Save(atts, tpl)) => {
                 return tpl_handler::save(&mbox, &account, atts, tpl, &mut printer, &mut imap);
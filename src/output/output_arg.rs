@@ -14,6 +14,12 @@ pub fn args<'a>() -> Vec<Arg<'a, 'a>> {
             .value_name("FMT")
             .possible_values(&["plain", "json"])
             .default_value("plain"),
+        Arg::with_name("color")
+            .long("color")
+            .help("Defines when to colorize the output, auto colorizes only terminals")
+            .value_name("WHEN")
+            .possible_values(&["auto", "always", "never"])
+            .default_value("auto"),
         Arg::with_name("log-level")
             .long("log-level")
             .alias("log")
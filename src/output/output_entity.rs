@@ -44,6 +44,29 @@ impl Display for OutputFmt {
     }
 }
 
+/// Represents the available color modes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorFmt {
+    /// Colors only if the standard output is a terminal.
+    Auto,
+    Always,
+    Never,
+}
+
+impl TryFrom<Option<&str>> for ColorFmt {
+    type Error = Error;
+
+    fn try_from(color: Option<&str>) -> Result<Self, Self::Error> {
+        match color {
+            Some(color) if color.eq_ignore_ascii_case("auto") => Ok(Self::Auto),
+            Some(color) if color.eq_ignore_ascii_case("always") => Ok(Self::Always),
+            Some(color) if color.eq_ignore_ascii_case("never") => Ok(Self::Never),
+            None => Ok(Self::Auto),
+            Some(color) => Err(anyhow!(r#"cannot parse color mode "{}""#, color)),
+        }
+    }
+}
+
 /// Defines a struct-wrapper to provide a JSON output.
 #[derive(Debug, Serialize, Clone)]
 pub struct OutputJson<T: Serialize> {
@@ -55,3 +78,19 @@ impl<T: Serialize> OutputJson<T> {
         Self { response }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_parse_color_fmt() {
+        assert_eq!(ColorFmt::Auto, ColorFmt::try_from(None).unwrap());
+        assert_eq!(
+            ColorFmt::Always,
+            ColorFmt::try_from(Some("always")).unwrap()
+        );
+        assert_eq!(ColorFmt::Never, ColorFmt::try_from(Some("NEVER")).unwrap());
+        assert!(ColorFmt::try_from(Some("sometimes")).is_err());
+    }
+}
@@ -1,9 +1,8 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use log::debug;
-use std::process::Command;
+use std::process::{Command, Output};
 
-/// TODO: move this in a more approriate place.
-pub fn run_cmd(cmd: &str) -> Result<String> {
+fn spawn_cmd(cmd: &str) -> Result<Output> {
     debug!("running command: {}", cmd);
 
     let output = if cfg!(target_os = "windows") {
@@ -12,5 +11,25 @@ pub fn run_cmd(cmd: &str) -> Result<String> {
         Command::new("sh").arg("-c").arg(cmd).output()
     }?;
 
+    Ok(output)
+}
+
+/// TODO: move this in a more approriate place.
+pub fn run_cmd(cmd: &str) -> Result<String> {
+    let output = spawn_cmd(cmd)?;
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+/// Same as [`run_cmd`], but fails if the command exits with an error. The error holds the
+/// standard error output of the command, never its standard output (which can be a secret).
+pub fn run_cmd_checked(cmd: &str) -> Result<String> {
+    let output = spawn_cmd(cmd)?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "command exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
     Ok(String::from_utf8(output.stdout)?)
 }
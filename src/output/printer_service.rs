@@ -4,7 +4,9 @@ use serde::Serialize;
 use std::{convert::TryFrom, fmt::Debug};
 use termcolor::{ColorChoice, StandardStream};
 
-use crate::output::{OutputFmt, OutputJson, Print, PrintTable, PrintTableOpts, WriteColor};
+use crate::output::{
+    ColorFmt, OutputFmt, OutputJson, Print, PrintTable, PrintTableOpts, WriteColor,
+};
 
 pub trait PrinterService {
     fn print<T: Debug + Print + Serialize>(&mut self, data: T) -> Result<()>;
@@ -49,30 +51,57 @@ impl PrinterService for StdoutPrinter {
 
 impl From<OutputFmt> for StdoutPrinter {
     fn from(fmt: OutputFmt) -> Self {
-        let writter = StandardStream::stdout(if atty::isnt(Stream::Stdin) {
-            // Colors should be deactivated if the terminal is not a tty.
-            ColorChoice::Never
-        } else {
-            // Otherwise let's `termcolor` decide by inspecting the environment. From the [doc]:
-            // - If `NO_COLOR` is set to any value, then colors will be suppressed.
-            // - If `TERM` is set to dumb, then colors will be suppressed.
-            // - In non-Windows environments, if `TERM` is not set, then colors will be suppressed.
-            //
-            // [doc]: https://github.com/BurntSushi/termcolor#automatic-color-selection
-            ColorChoice::Auto
-        });
+        Self::from((fmt, ColorFmt::Auto))
+    }
+}
+
+impl From<(OutputFmt, ColorFmt)> for StdoutPrinter {
+    fn from((fmt, color): (OutputFmt, ColorFmt)) -> Self {
+        let writter = StandardStream::stdout(to_color_choice(color, atty::is(Stream::Stdout)));
         let writter = Box::new(writter);
         Self { writter, fmt }
     }
 }
 
-impl TryFrom<Option<&str>> for StdoutPrinter {
+/// Converts the color mode to a `termcolor` choice. Colors are deactivated in auto mode if the
+/// output is not a tty (like when piping to a file). Otherwise `termcolor` decides by inspecting
+/// the environment. From the [doc]:
+/// - If `NO_COLOR` is set to any value, then colors will be suppressed.
+/// - If `TERM` is set to dumb, then colors will be suppressed.
+/// - In non-Windows environments, if `TERM` is not set, then colors will be suppressed.
+///
+/// [doc]: https://github.com/BurntSushi/termcolor#automatic-color-selection
+fn to_color_choice(color: ColorFmt, is_tty: bool) -> ColorChoice {
+    match color {
+        ColorFmt::Auto if is_tty => ColorChoice::Auto,
+        ColorFmt::Auto | ColorFmt::Never => ColorChoice::Never,
+        ColorFmt::Always => ColorChoice::Always,
+    }
+}
+
+impl TryFrom<(Option<&str>, Option<&str>)> for StdoutPrinter {
     type Error = Error;
 
-    fn try_from(fmt: Option<&str>) -> Result<Self> {
-        Ok(Self {
-            fmt: OutputFmt::try_from(fmt)?,
-            ..Self::from(OutputFmt::Plain)
-        })
+    fn try_from((fmt, color): (Option<&str>, Option<&str>)) -> Result<Self> {
+        Ok(Self::from((
+            OutputFmt::try_from(fmt)?,
+            ColorFmt::try_from(color)?,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_not_colorize_non_tty_in_auto_mode() {
+        assert_eq!(ColorChoice::Never, to_color_choice(ColorFmt::Auto, false));
+        assert_eq!(ColorChoice::Auto, to_color_choice(ColorFmt::Auto, true));
+        assert_eq!(
+            ColorChoice::Always,
+            to_color_choice(ColorFmt::Always, false)
+        );
+        assert_eq!(ColorChoice::Never, to_color_choice(ColorFmt::Never, true));
     }
 }
@@ -44,6 +44,7 @@ pub fn pre_edit() -> Result<PreEditChoice> {
 
 pub enum PostEditChoice {
     Send,
+    Queue,
     Edit,
     LocalDraft,
     RemoteDraft,
@@ -51,7 +52,7 @@ pub enum PostEditChoice {
 }
 
 pub fn post_edit() -> Result<PostEditChoice> {
-    print!("(s)end, (e)dit, (l)ocal/(r)emote draft or (d)iscard? ");
+    print!("(s)end, (q)ueue, (e)dit, (l)ocal/(r)emote draft or (d)iscard? ");
     io::stdout().flush().context("cannot flush stdout")?;
 
     let mut buf = String::new();
@@ -64,6 +65,10 @@ pub fn post_edit() -> Result<PostEditChoice> {
             debug!("send choice matched");
             Ok(PostEditChoice::Send)
         }
+        Some('q') => {
+            debug!("queue choice matched");
+            Ok(PostEditChoice::Queue)
+        }
         Some('l') => {
             debug!("save local draft choice matched");
             Ok(PostEditChoice::LocalDraft)
@@ -90,3 +95,24 @@ pub fn post_edit() -> Result<PostEditChoice> {
         }
     }
 }
+
+pub fn confirm(question: &str) -> Result<bool> {
+    print!("{} (y/n) ", question);
+    io::stdout().flush().context("cannot flush stdout")?;
+
+    let mut buf = String::new();
+    io::stdin()
+        .read_line(&mut buf)
+        .context("cannot read stdin")?;
+
+    match buf.bytes().next().map(|bytes| bytes as char) {
+        Some('y') | Some('Y') => {
+            debug!("yes choice matched");
+            Ok(true)
+        }
+        _ => {
+            debug!("no choice matched");
+            Ok(false)
+        }
+    }
+}
@@ -1,31 +1,131 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Error, Result};
 use log::debug;
-use std::{env, fs, process::Command};
+use std::{env, fs, io, path::Path, process::Command};
 
-use crate::domain::msg::msg_utils;
+/// Finds the editor command: the configured one first, then `$VISUAL`, then `$EDITOR`, then
+/// `vi` (or `notepad` on Windows). Empty values are skipped.
+fn find_editor(config: Option<&str>, visual: Option<String>, editor: Option<String>) -> String {
+    config
+        .map(String::from)
+        .into_iter()
+        .chain(visual)
+        .chain(editor)
+        .find(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| {
+            if cfg!(target_family = "windows") {
+                "notepad".into()
+            } else {
+                "vi".into()
+            }
+        })
+}
+
+/// Splits the given command into a program and its arguments, the way a shell would: arguments
+/// are separated by spaces, and can be quoted with single or double quotes. Backslashes escape
+/// the next char, except inside single quotes.
+fn split_cmd(cmd: &str) -> Result<Vec<String>> {
+    let mut args = vec![];
+    let mut arg: Option<String> = None;
+    let mut quote: Option<char> = None;
+    let mut chars = cmd.chars();
 
-pub fn open_with_tpl(tpl: String) -> Result<String> {
-    let path = msg_utils::local_draft_path();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('\''), c) => arg.get_or_insert_with(String::new).push(c),
+            (_, '\\') => {
+                let c = chars
+                    .next()
+                    .ok_or_else(|| anyhow!("cannot split command {:?}: trailing backslash", cmd))?;
+                arg.get_or_insert_with(String::new).push(c);
+            }
+            (Some(_), c) => arg.get_or_insert_with(String::new).push(c),
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                arg.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => args.extend(arg.take()),
+            (None, c) => arg.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if quote.is_some() {
+        return Err(anyhow!("cannot split command {:?}: unclosed quote", cmd));
+    }
+    args.extend(arg);
+    Ok(args)
+}
 
+/// Writes the given template to the given draft path, then opens it with the editor. The
+/// `editor` command overrides the one from the environment, see [`find_editor`].
+pub fn open_with_tpl(editor: Option<&str>, path: &Path, tpl: String) -> Result<String> {
     debug!("create draft");
-    fs::write(&path, tpl.as_bytes()).context(format!("cannot write local draft at {:?}", path))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).context(format!("cannot create drafts directory {:?}", dir))?;
+    }
+    fs::write(path, tpl.as_bytes()).context(format!("cannot write local draft at {:?}", path))?;
 
     debug!("open editor");
-    Command::new(env::var("EDITOR").context(r#"cannot find "$EDITOR" env var"#)?)
-        .arg(&path)
+    let editor = find_editor(editor, env::var("VISUAL").ok(), env::var("EDITOR").ok());
+    debug!("editor: {:?}", editor);
+    let mut args = split_cmd(&editor)?;
+    if args.is_empty() {
+        return Err(anyhow!("cannot launch empty editor command {:?}", editor));
+    }
+    let program = args.remove(0);
+    Command::new(&program)
+        .args(args)
+        .arg(path)
         .status()
-        .context("cannot launch editor")?;
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => anyhow!("cannot find editor {:?} in PATH", program),
+            _ => Error::new(err).context(format!("cannot launch editor {:?}", program)),
+        })?;
 
     debug!("read draft");
     let content =
-        fs::read_to_string(&path).context(format!("cannot read local draft at {:?}", path))?;
+        fs::read_to_string(path).context(format!("cannot read local draft at {:?}", path))?;
 
     Ok(content)
 }
 
-pub fn open_with_draft() -> Result<String> {
-    let path = msg_utils::local_draft_path();
-    let tpl =
-        fs::read_to_string(&path).context(format!("cannot read local draft at {:?}", path))?;
-    open_with_tpl(tpl)
+pub fn open_with_draft(editor: Option<&str>, path: &Path) -> Result<String> {
+    let tpl = fs::read_to_string(path).context(format!("cannot read local draft at {:?}", path))?;
+    open_with_tpl(editor, path, tpl)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_should_find_editor() {
+        let env = |val: &str| Some(String::from(val));
+        assert_eq!("nvim", find_editor(Some("nvim"), env("emacs"), env("nano")));
+        assert_eq!("emacs", find_editor(None, env("emacs"), env("nano")));
+        assert_eq!("nano", find_editor(None, None, env("nano")));
+        assert_eq!("nano", find_editor(Some(""), env(" "), env("nano")));
+        let default = if cfg!(target_family = "windows") {
+            "notepad"
+        } else {
+            "vi"
+        };
+        assert_eq!(default, find_editor(None, None, None));
+    }
+
+    #[test]
+    fn it_should_split_cmd() {
+        assert_eq!(vec!["vi"], split_cmd("vi").unwrap());
+        assert_eq!(
+            vec!["code", "--wait"],
+            split_cmd("  code   --wait ").unwrap()
+        );
+        assert_eq!(
+            vec!["/opt/My Editor/bin/edit", "-c", "set tw=72", "it's"],
+            split_cmd(r#"'/opt/My Editor/bin/edit' -c "set tw=72" it\'s"#).unwrap()
+        );
+        assert_eq!(vec!["edit", ""], split_cmd(r#"edit """#).unwrap());
+        assert!(split_cmd("edit 'unclosed").is_err());
+        assert!(split_cmd("").unwrap().is_empty());
+    }
 }
@@ -30,6 +30,8 @@ pub struct Cell {
     value: String,
     /// (Dis)allowes the cell to shrink when the table exceeds the container width.
     shrinkable: bool,
+    /// Aligns the content of the cell on the right instead of the left.
+    right_aligned: bool,
 }
 
 impl Cell {
@@ -57,6 +59,44 @@ impl Cell {
         self.shrinkable
     }
 
+    /// Truncates the cell's value to the given unicode width, ending with `…`. Unlike a shrinkable
+    /// cell, the cell is truncated even if the table does not exceed the terminal width.
+    pub fn max_width(mut self, max_width: usize) -> Self {
+        if self.unicode_width() > max_width {
+            let mut value = String::new();
+            let mut chars_width = 0;
+            for c in self.value.chars() {
+                let char_width = UnicodeWidthStr::width(c.to_string().as_str());
+                if chars_width + char_width >= max_width {
+                    break;
+                }
+                chars_width += char_width;
+                value.push(c);
+            }
+            value.push('…');
+            self.value = value;
+        }
+        self
+    }
+
+    /// Aligns the cell's value on the right (like numbers).
+    pub fn align_right(mut self) -> Self {
+        self.right_aligned = true;
+        self
+    }
+
+    /// Pads the cell's value with spaces up to the given width, according to its alignment, plus
+    /// one space separating it from the next cell.
+    fn pad(&mut self, width: usize) {
+        let spaces = " ".repeat(width.saturating_sub(self.unicode_width()));
+        trace!("number of spaces added to value: {}", spaces.len());
+        self.value = if self.right_aligned {
+            format!("{}{} ", spaces, self.value)
+        } else {
+            format!("{}{} ", self.value, spaces)
+        };
+    }
+
     /// Applies the bold style to the cell.
     pub fn bold(mut self) -> Self {
         self.style.set_bold(true);
@@ -72,6 +112,14 @@ impl Cell {
         }
     }
 
+    /// Applies the dimmed style to the cell conditionally.
+    pub fn dimmed_if(mut self, predicate: bool) -> Self {
+        if predicate {
+            self.style.set_dimmed(true);
+        }
+        self
+    }
+
     /// Applies the underline style to the cell.
     pub fn underline(mut self) -> Self {
         self.style.set_underline(true);
@@ -229,17 +277,13 @@ where
                         cell.value = value;
                     } else {
                         trace!("cell is not overflowing");
-                        let spaces_count = cell_width - cell.unicode_width() + 1;
-                        trace!("number of spaces added to value: {}", spaces_count);
-                        cell.value.push_str(&" ".repeat(spaces_count));
+                        cell.pad(cell_width);
                     }
                 } else {
                     trace!("table is not overflowing or cell is not shrinkable");
                     trace!("cell width: {}", cell_widths[i]);
                     trace!("cell unicode width: {}", cell.unicode_width());
-                    let spaces_count = cell_widths[i] - cell.unicode_width() + 1;
-                    trace!("number of spaces added to value: {}", spaces_count);
-                    cell.value.push_str(&" ".repeat(spaces_count));
+                    cell.pad(cell_widths[i]);
                 }
                 cell.print(writter)?;
                 glue = Cell::new("│").ansi_256(8);
@@ -322,6 +366,25 @@ mod tests {
         }
     }
 
+    struct Contact {
+        id: u16,
+        name: String,
+    }
+
+    impl Table for Contact {
+        fn head() -> Row {
+            Row::new()
+                .cell(Cell::new("ID").align_right())
+                .cell(Cell::new("NAME"))
+        }
+
+        fn row(&self) -> Row {
+            Row::new()
+                .cell(Cell::new(self.id.to_string()).align_right())
+                .cell(Cell::new(self.name.as_str()).max_width(6))
+        }
+    }
+
     macro_rules! write_items {
         ($writter:expr, $($item:expr),*) => {
             Table::print($writter, &[$($item,)*], PrintTableOpts { max_width: Some(20) }).unwrap();
@@ -427,4 +490,32 @@ mod tests {
         ];
         assert_eq!(expected, writter.content);
     }
+
+    #[test]
+    fn max_width_and_align_right() {
+        let mut writter = StringWritter::default();
+        let contacts = [
+            Contact {
+                id: 1,
+                name: "Alice".into(),
+            },
+            Contact {
+                id: 1234,
+                name: "Bartholomew".into(),
+            },
+            Contact {
+                id: 56,
+                name: "山田太郎さん".into(),
+            },
+        ];
+        Table::print(&mut writter, &contacts, PrintTableOpts { max_width: None }).unwrap();
+
+        let expected = concat![
+            "  ID │NAME   \n",
+            "   1 │Alice  \n",
+            "1234 │Barth… \n",
+            "  56 │山田…  \n",
+        ];
+        assert_eq!(expected, writter.content);
+    }
 }